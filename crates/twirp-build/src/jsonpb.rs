@@ -0,0 +1,94 @@
+//! `serde` attributes for the proto3 JSON mapping, derived from the field types of the messages.
+//!
+//! See `twirp::jsonpb` for the helpers these attributes refer to.
+
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, FileDescriptorSet};
+
+/// Add the `serde` attributes that make the messages of `fds` (de)serialize with the [proto3
+/// JSON mapping], as far as `twirp::jsonpb` supports it: lowerCamelCase field names, 64-bit
+/// integers as strings, `NaN` and infinite floats as strings, and bytes as base64.
+///
+/// This only adds the attributes; deriving `serde::Serialize` and `serde::Deserialize` is still
+/// up to the `type_attribute`s of `config`. Load the descriptors with
+/// `prost_build::Config::load_fds` and compile them with `prost_build::Config::compile_fds`:
+///
+/// ```ignore
+/// let mut config = prost_build::Config::new();
+/// let fds = config.load_fds(&["proto/haberdash.proto"], &["proto"])?;
+/// config.type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]");
+/// twirp_build::configure_json_pb(&mut config, &fds);
+/// config
+///     .service_generator(twirp_build::service_generator())
+///     .compile_fds(fds)?;
+/// ```
+///
+/// `optional` fields and the fields of a `oneof` are `Option`s, which the helpers don't cover, so
+/// they keep their numbers and byte arrays. Fields configured as `bytes::Bytes` (see
+/// `prost_build::Config::bytes`) aren't supported either. As `prost_build` matches the paths of
+/// nested types too, the oneofs and enums declared inside a message are renamed along with it;
+/// enum fields are plain `i32`s and aren't affected.
+///
+/// [proto3 JSON mapping]: https://protobuf.dev/programming-guides/proto3/#json
+pub fn configure_json_pb(config: &mut prost_build::Config, fds: &FileDescriptorSet) {
+    let (types, fields) = json_pb_attributes(fds);
+    for (path, attribute) in types {
+        config.type_attribute(path, attribute);
+    }
+    for (path, attribute) in fields {
+        config.field_attribute(path, attribute);
+    }
+}
+
+/// `(path, attribute)` pairs, in declaration order.
+type Attributes = Vec<(String, String)>;
+
+/// The attributes of the top-level messages, and those of all the fields.
+pub(crate) fn json_pb_attributes(fds: &FileDescriptorSet) -> (Attributes, Attributes) {
+    let mut types = vec![];
+    let mut fields = vec![];
+    for file in &fds.file {
+        let package = match file.package() {
+            "" => String::new(),
+            package => format!(".{package}"),
+        };
+        for message in &file.message_type {
+            let path = format!("{package}.{}", message.name());
+            // `prost_build` matches the paths of the nested types too, which would get the
+            // attribute twice if it was also added to them
+            types.push((path, r#"#[serde(rename_all = "camelCase")]"#.to_string()));
+            field_attributes(&package, message, &mut fields);
+        }
+    }
+    (types, fields)
+}
+
+fn field_attributes(parent: &str, message: &DescriptorProto, fields: &mut Attributes) {
+    let path = format!("{parent}.{}", message.name());
+    // map entries are generated as `HashMap`s, not as messages
+    if message.options.as_ref().is_some_and(|o| o.map_entry()) {
+        return;
+    }
+    for field in &message.field {
+        if field.oneof_index.is_some() || field.proto3_optional() {
+            continue;
+        }
+        let helper = match field.r#type() {
+            Type::Int64 | Type::Uint64 | Type::Sint64 | Type::Fixed64 | Type::Sfixed64 => "int64",
+            Type::Float | Type::Double => "float",
+            Type::Bytes => "bytes",
+            _ => continue,
+        };
+        let helper = match field.label() {
+            Label::Repeated => format!("twirp::jsonpb::{helper}::repeated"),
+            _ => format!("twirp::jsonpb::{helper}"),
+        };
+        fields.push((
+            format!("{path}.{}", field.name()),
+            format!(r#"#[serde(with = "{helper}")]"#),
+        ));
+    }
+    for nested in &message.nested_type {
+        field_attributes(&path, nested, fields);
+    }
+}
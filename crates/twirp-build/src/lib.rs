@@ -1,4 +1,14 @@
 use std::fmt::Write;
+use std::path::PathBuf;
+
+use prost_types::method_options::IdempotencyLevel;
+
+mod jsonpb;
+mod options;
+
+pub use jsonpb::configure_json_pb;
+use options::MethodOption;
+pub use options::OptionType;
 
 /// Generates twirp services for protobuf rpc service definitions.
 ///
@@ -8,27 +18,590 @@ use std::fmt::Write;
 /// Add a call to `.service_generator(twirp_build::service_generator())` in
 /// main() of `build.rs`.
 pub fn service_generator() -> Box<ServiceGenerator> {
-    Box::new(ServiceGenerator {})
+    Box::new(ServiceGenerator::default())
 }
 
-pub struct ServiceGenerator;
+#[derive(Debug, Default)]
+pub struct ServiceGenerator {
+    descriptor_set_path: Option<PathBuf>,
+    method_options: Vec<MethodOption>,
+    timeout_option: Option<u32>,
+    method_handlers: bool,
+    schema_hash: bool,
+    arc_client: bool,
+    service_descriptor: bool,
+    server_error_type: Option<String>,
+    skip_server: bool,
+    skip_client: bool,
+    visibility: Option<String>,
+    client_result_alias: bool,
+    mock_server: bool,
+    in_process_client: bool,
+}
 
-impl prost_build::ServiceGenerator for ServiceGenerator {
-    fn generate(&mut self, service: prost_build::Service, buf: &mut String) {
-        let service_name = service.name;
+impl ServiceGenerator {
+    /// The path `prost_build` writes the file descriptor set to, i.e. the same path passed to
+    /// `prost_build::Config::file_descriptor_set_path`. This is required to read custom method
+    /// options (see [`ServiceGenerator::with_method_option`]).
+    pub fn with_descriptor_set_path(mut self: Box<Self>, path: impl Into<PathBuf>) -> Box<Self> {
+        self.descriptor_set_path = Some(path.into());
+        self
+    }
+
+    /// Read the custom method option `name`, with the given extension field number, from the
+    /// proto definitions.
+    ///
+    /// For every service, the options set on its methods are emitted as a table of
+    /// `(method, option, value)`, named after the service (e.g.
+    /// `HABERDASHER_API_METHOD_OPTIONS`), with the values rendered as strings according to
+    /// their [`OptionType`]. For example, with this extension:
+    ///
+    /// ```proto
+    /// extend google.protobuf.MethodOptions {
+    ///   string required_role = 50001;
+    /// }
+    /// ```
+    ///
+    /// configured with `.with_method_option("required_role", 50001, OptionType::String)`, a
+    /// method with `option (required_role) = "admin";` gets a `("MakeHat", "required_role",
+    /// "admin")` entry.
+    pub fn with_method_option(
+        mut self: Box<Self>,
+        name: &str,
+        field_number: u32,
+        option_type: OptionType,
+    ) -> Box<Self> {
+        self.method_options.push(MethodOption {
+            name: name.to_string(),
+            field_number,
+            option_type,
+        });
+        self
+    }
+
+    /// Read per-method handler timeouts, in milliseconds, from the unsigned integer method option
+    /// with the given extension field number. They're generated as `TIMEOUTS` in the paths
+    /// module of each service, keyed by path, for `twirp::server::ServerOptions::with_timeouts`.
+    /// Requires [`ServiceGenerator::with_descriptor_set_path`]. For example, with this
+    /// extension and `.with_timeout_option(50002)`:
+    ///
+    /// ```proto
+    /// extend google.protobuf.MethodOptions {
+    ///   uint32 timeout_ms = 50002;
+    /// }
+    ///
+    /// service Haberdasher {
+    ///   rpc ExportHats(ExportHatsRequest) returns (ExportHatsResponse) {
+    ///     option (timeout_ms) = 30000;
+    ///   }
+    /// }
+    /// ```
+    ///
+    /// ```ignore
+    /// let options = ServerOptions::default()
+    ///     .with_timeouts(Duration::from_secs(1), haberdasher_paths::TIMEOUTS);
+    /// ```
+    pub fn with_timeout_option(mut self: Box<Self>, field_number: u32) -> Box<Self> {
+        self.timeout_option = Some(field_number);
+        self
+    }
+
+    /// Also generate a handler factory per method, like `make_hat_handler(api)`, returning an
+    /// `axum::routing::MethodRouter` for that method alone. Use these instead of `router(api)` to
+    /// give individual methods their own layers or state:
+    ///
+    /// ```ignore
+    /// let app = Router::new().route(
+    ///     &format!("{}/MakeHat", haberdash::SERVICE_FQN),
+    ///     haberdash::make_hat_handler(api).layer(ConcurrencyLimitLayer::new(10)),
+    /// );
+    /// ```
+    pub fn with_method_handlers(mut self: Box<Self>, enabled: bool) -> Box<Self> {
+        self.method_handlers = enabled;
+        self
+    }
+
+    /// Also generate a `SERVICE_SCHEMA_HASH` constant per service: a stable hash of the service's
+    /// name and its methods' names, input and output types. CI can compare it against a known
+    /// value to catch changes to the service's signatures (renamed or removed methods, changed
+    /// message types, ...). Changes inside the messages don't affect the hash.
+    pub fn with_schema_hash(mut self: Box<Self>, enabled: bool) -> Box<Self> {
+        self.schema_hash = enabled;
+        self
+    }
+
+    /// Also implement the client trait for `Arc<C>` where `C` implements it, mirroring the
+    /// server trait's `Arc<T>` impl, so a client shared behind an `Arc` (including an
+    /// `Arc<dyn HaberdasherApiClient>`) can be used directly.
+    pub fn with_arc_client(mut self: Box<Self>, enabled: bool) -> Box<Self> {
+        self.arc_client = enabled;
+        self
+    }
+
+    fn generate_arc_client(
+        &self,
+        service_name: &str,
+        methods: &[prost_build::Method],
+        buf: &mut String,
+    ) {
+        if !self.arc_client {
+            return;
+        }
+        writeln!(buf, "#[twirp::async_trait::async_trait]").unwrap();
+        writeln!(buf, "impl<C> {service_name}Client for std::sync::Arc<C>").unwrap();
+        writeln!(buf, "where").unwrap();
+        writeln!(buf, "    C: {service_name}Client + ?Sized,").unwrap();
+        writeln!(buf, "{{").unwrap();
+        for m in methods {
+            writeln!(
+                buf,
+                "    async fn {}(&self, req: {}) -> {} {{",
+                m.name,
+                m.input_type,
+                self.client_result(&m.output_type),
+            )
+            .unwrap();
+            writeln!(buf, "        C::{}(&**self, req).await", m.name).unwrap();
+            writeln!(buf, "    }}").unwrap();
+        }
+        writeln!(buf, "}}").unwrap();
+    }
+
+    /// Also generate a `SERVICE_DESCRIPTOR` constant per service, a
+    /// `twirp::descriptor::ServiceDescriptor` listing the service's methods with their paths and
+    /// message types, for introspection tooling shared with gRPC services.
+    pub fn with_service_descriptor(mut self: Box<Self>, enabled: bool) -> Box<Self> {
+        self.service_descriptor = enabled;
+        self
+    }
+
+    /// Generate server traits whose methods return `error_type` (e.g.
+    /// `twirp::TwirpErrorResponse`), instead of an associated `Error` type every implementation
+    /// has to specify. The type must implement `twirp::IntoTwirpResponse`.
+    pub fn with_server_error_type(mut self: Box<Self>, error_type: impl Into<String>) -> Box<Self> {
+        self.server_error_type = Some(error_type.into());
+        self
+    }
+
+    /// Generate the server: the service trait, its `Arc` impl, `router` and the method handlers.
+    /// Enabled by default; a crate that only calls the service can leave it out entirely.
+    pub fn with_server(mut self: Box<Self>, enabled: bool) -> Box<Self> {
+        self.skip_server = !enabled;
+        self
+    }
+
+    /// Generate the client: the `{Service}Client` trait and its impls. Enabled by default; a
+    /// crate that only implements the service can leave it out entirely.
+    pub fn with_client(mut self: Box<Self>, enabled: bool) -> Box<Self> {
+        self.skip_client = !enabled;
+        self
+    }
+
+    /// Declare the generated items (traits, `router`, constants, ...) with `visibility`, e.g.
+    /// `pub(crate)`, instead of `pub`, to keep them out of the public API of a library crate.
+    pub fn with_visibility(mut self: Box<Self>, visibility: impl Into<String>) -> Box<Self> {
+        self.visibility = Some(visibility.into());
+        self
+    }
+
+    fn visibility(&self) -> &str {
+        self.visibility.as_deref().unwrap_or("pub")
+    }
+
+    /// Declare the client methods as returning `twirp::Result<Resp>`, the crate's alias for
+    /// `Result<Resp, twirp::ClientError>`, instead of spelling out the error type. Both are the
+    /// same type, so existing implementations of the client trait keep compiling. Disabled by
+    /// default.
+    pub fn with_client_result_alias(mut self: Box<Self>, enabled: bool) -> Box<Self> {
+        self.client_result_alias = enabled;
+        self
+    }
+
+    /// Also generate a `Mock{Service}` implementing the server trait with canned responses, for
+    /// testing code that consumes the trait. Each method's response is set with a closure, like
+    /// `MockHaberdasherApi::default().expect_make_hat(|ctx, req| Ok(...))`, and methods without
+    /// one fail with `unimplemented`. With [`ServiceGenerator::with_server_error_type`], the error
+    /// type must implement `From<twirp::TwirpErrorResponse>`. Disabled by default.
+    pub fn with_mock_server(mut self: Box<Self>, enabled: bool) -> Box<Self> {
+        self.mock_server = enabled;
+        self
+    }
+
+    fn generate_mock_server(&self, service: &prost_build::Service, buf: &mut String) {
+        if !self.mock_server {
+            return;
+        }
+        let service_name = &service.name;
+        let mock_name = format!("Mock{service_name}");
+        let error_type = self
+            .server_error_type
+            .as_deref()
+            .unwrap_or("twirp::TwirpErrorResponse");
+        writeln!(buf).unwrap();
+        writeln!(
+            buf,
+            "/// A [`{service_name}`] with canned responses, for tests. Methods without one fail with"
+        )
+        .unwrap();
+        writeln!(buf, "/// `unimplemented`.").unwrap();
+        writeln!(buf, "#[derive(Default)]").unwrap();
+        writeln!(buf, "#[allow(clippy::type_complexity)]").unwrap();
+        writeln!(buf, "{} struct {mock_name} {{", self.visibility()).unwrap();
+        for m in &service.methods {
+            writeln!(
+                buf,
+                "    {}: Option<Box<dyn Fn(twirp::Context, {}) -> Result<{}, {error_type}> + Send + Sync>>,",
+                m.name, m.input_type, m.output_type,
+            )
+            .unwrap();
+        }
+        writeln!(buf, "}}").unwrap();
+        writeln!(buf).unwrap();
+        writeln!(buf, "impl {mock_name} {{").unwrap();
+        for m in &service.methods {
+            writeln!(
+                buf,
+                "    /// Respond to `{}` with the result of `f`.",
+                m.proto_name
+            )
+            .unwrap();
+            writeln!(
+                buf,
+                "    pub fn expect_{}<F>(mut self, f: F) -> Self",
+                m.name
+            )
+            .unwrap();
+            writeln!(buf, "    where").unwrap();
+            writeln!(
+                buf,
+                "        F: Fn(twirp::Context, {}) -> Result<{}, {error_type}> + Send + Sync + 'static,",
+                m.input_type, m.output_type,
+            )
+            .unwrap();
+            writeln!(buf, "    {{").unwrap();
+            writeln!(buf, "        self.{} = Some(Box::new(f));", m.name).unwrap();
+            writeln!(buf, "        self").unwrap();
+            writeln!(buf, "    }}").unwrap();
+        }
+        writeln!(buf, "}}").unwrap();
+        writeln!(buf).unwrap();
+        writeln!(buf, "#[twirp::async_trait::async_trait]").unwrap();
+        writeln!(buf, "impl {service_name} for {mock_name} {{").unwrap();
+        if self.server_error_type.is_none() {
+            writeln!(buf, "    type Error = twirp::TwirpErrorResponse;\n").unwrap();
+        }
+        // Only a custom error type needs converting into.
+        let into = if self.server_error_type.is_some() {
+            ".into()"
+        } else {
+            ""
+        };
+        for m in &service.methods {
+            writeln!(
+                buf,
+                "    async fn {}(&self, ctx: twirp::Context, req: {}) -> Result<{}, {error_type}> {{",
+                m.name, m.input_type, m.output_type,
+            )
+            .unwrap();
+            writeln!(buf, "        match &self.{} {{", m.name).unwrap();
+            writeln!(buf, "            Some(f) => f(ctx, req),").unwrap();
+            writeln!(
+                buf,
+                "            None => Err(twirp::unimplemented(\"{} is not mocked\"){into}),",
+                m.proto_name
+            )
+            .unwrap();
+            writeln!(buf, "        }}").unwrap();
+            writeln!(buf, "    }}").unwrap();
+        }
+        writeln!(buf, "}}").unwrap();
+    }
+
+    /// Also generate an `InProcess{Service}Client`, implementing the client trait by calling an
+    /// implementation of the server trait directly instead of over HTTP, e.g. to run code that is
+    /// generic over the client trait against a local implementation in tests. Errors returned by
+    /// the implementation become `twirp::ClientError::TwirpError`. Only generated along with both
+    /// the server and the client. Disabled by default.
+    pub fn with_in_process_client(mut self: Box<Self>, enabled: bool) -> Box<Self> {
+        self.in_process_client = enabled;
+        self
+    }
+
+    fn generate_in_process_client(&self, service: &prost_build::Service, buf: &mut String) {
+        if !self.in_process_client || self.skip_server || self.skip_client {
+            return;
+        }
+        let service_name = &service.name;
+        let client_name = format!("InProcess{service_name}Client");
+        writeln!(buf).unwrap();
+        writeln!(
+            buf,
+            "/// A [`{service_name}Client`] calling a [`{service_name}`] implementation in-process,"
+        )
+        .unwrap();
+        writeln!(buf, "/// without going over HTTP.").unwrap();
+        writeln!(buf, "{} struct {client_name}<T> {{", self.visibility()).unwrap();
+        writeln!(buf, "    api: std::sync::Arc<T>,").unwrap();
+        writeln!(buf, "}}").unwrap();
+        writeln!(buf).unwrap();
+        writeln!(buf, "impl<T> {client_name}<T> {{").unwrap();
+        writeln!(buf, "    pub fn new(api: std::sync::Arc<T>) -> Self {{").unwrap();
+        writeln!(buf, "        Self {{ api }}").unwrap();
+        writeln!(buf, "    }}").unwrap();
+        writeln!(buf, "}}").unwrap();
+        writeln!(buf).unwrap();
+        writeln!(buf, "impl<T> Clone for {client_name}<T> {{").unwrap();
+        writeln!(buf, "    fn clone(&self) -> Self {{").unwrap();
+        writeln!(buf, "        Self {{ api: self.api.clone() }}").unwrap();
+        writeln!(buf, "    }}").unwrap();
+        writeln!(buf, "}}").unwrap();
+        writeln!(buf).unwrap();
+        writeln!(buf, "#[twirp::async_trait::async_trait]").unwrap();
+        writeln!(buf, "impl<T> {service_name}Client for {client_name}<T>").unwrap();
+        writeln!(buf, "where").unwrap();
+        writeln!(buf, "    T: {service_name} + Send + Sync + 'static,").unwrap();
+        self.write_error_bound(service_name, buf);
+        writeln!(buf, "{{").unwrap();
+        for m in &service.methods {
+            writeln!(
+                buf,
+                "    async fn {}(&self, req: {}) -> {} {{",
+                m.name,
+                m.input_type,
+                self.client_result(&m.output_type),
+            )
+            .unwrap();
+            writeln!(
+                buf,
+                "        self.api.{}(twirp::Context::default(), req).await.map_err(|err| {{",
+                m.name
+            )
+            .unwrap();
+            writeln!(
+                buf,
+                "            let resp = twirp::IntoTwirpResponse::into_twirp_response(err);"
+            )
+            .unwrap();
+            writeln!(
+                buf,
+                "            twirp::ClientError::TwirpError {{ status: resp.status(), error: resp.into_body() }}"
+            )
+            .unwrap();
+            writeln!(buf, "        }})").unwrap();
+            writeln!(buf, "    }}").unwrap();
+        }
+        writeln!(buf, "}}").unwrap();
+    }
+
+    /// The return type of a client method returning `output_type`.
+    fn client_result(&self, output_type: &str) -> String {
+        if self.client_result_alias {
+            format!("twirp::Result<{output_type}>")
+        } else {
+            format!("Result<{output_type}, twirp::ClientError>")
+        }
+    }
+
+    /// The bound on the server trait's error type of generic code using the trait, unless the
+    /// error type is concrete (see [`ServiceGenerator::with_server_error_type`]).
+    fn write_error_bound(&self, service_name: &str, buf: &mut String) {
+        if self.server_error_type.is_none() {
+            writeln!(
+                buf,
+                "    <T as {service_name}>::Error: twirp::IntoTwirpResponse,"
+            )
+            .unwrap();
+        }
+    }
+
+    fn generate_service_descriptor(&self, service: &prost_build::Service, buf: &mut String) {
+        if !self.service_descriptor {
+            return;
+        }
         let service_fqn = format!("{}.{}", service.package, service.proto_name);
         writeln!(buf).unwrap();
+        writeln!(buf, "/// A description of `{service_fqn}` and its methods.").unwrap();
+        writeln!(
+            buf,
+            "{} const SERVICE_DESCRIPTOR: twirp::descriptor::ServiceDescriptor = twirp::descriptor::ServiceDescriptor {{",
+            self.visibility()
+        )
+        .unwrap();
+        writeln!(buf, "    name: {:?},", service.proto_name).unwrap();
+        writeln!(buf, "    package: {:?},", service.package).unwrap();
+        writeln!(buf, "    full_name: {service_fqn:?},").unwrap();
+        writeln!(buf, "    methods: &[").unwrap();
+        for m in &service.methods {
+            writeln!(buf, "        twirp::descriptor::MethodDescriptor {{").unwrap();
+            writeln!(buf, "            name: {:?},", m.proto_name).unwrap();
+            writeln!(
+                buf,
+                "            path: \"/{service_fqn}/{}\",",
+                m.proto_name
+            )
+            .unwrap();
+            writeln!(buf, "            input_type: {:?},", m.input_proto_type).unwrap();
+            writeln!(buf, "            output_type: {:?},", m.output_proto_type).unwrap();
+            writeln!(buf, "        }},").unwrap();
+        }
+        writeln!(buf, "    ],").unwrap();
+        writeln!(buf, "}};").unwrap();
+    }
 
-        //
-        // generate the twirp server
-        //
+    fn generate_schema_hash(&self, service: &prost_build::Service, buf: &mut String) {
+        if !self.schema_hash {
+            return;
+        }
+        let service_fqn = format!("{}.{}", service.package, service.proto_name);
+        let methods: Vec<_> = service
+            .methods
+            .iter()
+            .map(|m| {
+                (
+                    m.proto_name.as_str(),
+                    m.input_proto_type.as_str(),
+                    m.output_proto_type.as_str(),
+                )
+            })
+            .collect();
+        writeln!(buf).unwrap();
+        writeln!(
+            buf,
+            "/// A hash of the method signatures of `{service_fqn}`, which changes when they do."
+        )
+        .unwrap();
+        writeln!(
+            buf,
+            "{} const SERVICE_SCHEMA_HASH: &str = \"{}\";",
+            self.visibility(),
+            schema_hash(&service_fqn, &methods)
+        )
+        .unwrap();
+    }
+
+    fn generate_method_handlers(
+        &self,
+        service_name: &str,
+        methods: &[prost_build::Method],
+        buf: &mut String,
+    ) {
+        if !self.method_handlers {
+            return;
+        }
+        for m in methods {
+            writeln!(buf).unwrap();
+            writeln!(
+                buf,
+                "/// The handler for `{}` alone, to mount at `{{SERVICE_FQN}}/{}`.",
+                m.proto_name, m.proto_name
+            )
+            .unwrap();
+            writeln!(
+                buf,
+                "{} fn {}_handler<T, S>(api: T) -> twirp::axum::routing::MethodRouter<S>",
+                self.visibility(),
+                m.name
+            )
+            .unwrap();
+            writeln!(buf, "where").unwrap();
+            writeln!(
+                buf,
+                "    T: {service_name} + Clone + Send + Sync + 'static,"
+            )
+            .unwrap();
+            self.write_error_bound(service_name, buf);
+            writeln!(buf, "    S: Clone + Send + Sync + 'static,").unwrap();
+            writeln!(buf, "{{").unwrap();
+            writeln!(
+                buf,
+                "    twirp::details::method_handler(api, \"/{}\", |api: T, ctx: twirp::Context, req: {}| async move {{",
+                m.proto_name, m.input_type,
+            )
+            .unwrap();
+            writeln!(buf, "        api.{}(ctx, req).await", m.name).unwrap();
+            writeln!(buf, "    }})").unwrap();
+            writeln!(buf, "}}").unwrap();
+        }
+    }
+
+    fn generate_method_options(&self, service: &prost_build::Service, buf: &mut String) {
+        if self.method_options.is_empty() {
+            return;
+        }
+        let service_fqn = format!("{}.{}", service.package, service.proto_name);
+        let found = options::find_method_options(
+            &self.read_descriptor_set(),
+            &service_fqn,
+            &self.method_options,
+        );
+
+        writeln!(buf).unwrap();
+        writeln!(
+            buf,
+            "/// Custom options set on the methods of `{service_fqn}`, as `(method, option, value)`."
+        )
+        .unwrap();
+        writeln!(
+            buf,
+            "{} const {}_METHOD_OPTIONS: &[(&str, &str, &str)] = &[",
+            self.visibility(),
+            to_shouty_snake_case(&service.name)
+        )
+        .unwrap();
+        for (method, option, value) in found {
+            writeln!(buf, "    ({method:?}, {option:?}, {value:?}),").unwrap();
+        }
+        writeln!(buf, "];").unwrap();
+    }
+
+    /// The `(method, milliseconds)` timeouts set on the methods of `service_fqn`, if
+    /// [`ServiceGenerator::with_timeout_option`] is configured.
+    fn method_timeouts(&self, service_fqn: &str) -> Option<Vec<(String, String)>> {
+        let field_number = self.timeout_option?;
+        let option = MethodOption {
+            name: "timeout_ms".to_string(),
+            field_number,
+            option_type: OptionType::UInt,
+        };
+        let found =
+            options::find_method_options(&self.read_descriptor_set(), service_fqn, &[option]);
+        Some(
+            found
+                .into_iter()
+                .map(|(method, _, value)| (method, value))
+                .collect(),
+        )
+    }
+
+    fn read_descriptor_set(&self) -> Vec<u8> {
+        let path = self
+            .descriptor_set_path
+            .as_ref()
+            .expect("reading method options requires `with_descriptor_set_path`");
+        std::fs::read(path).unwrap_or_else(|e| {
+            panic!(
+                "failed to read file descriptor set {}: {}",
+                path.display(),
+                e
+            )
+        })
+    }
+
+    fn generate_server(&self, service: &prost_build::Service, service_fqn: &str, buf: &mut String) {
+        let service_name = &service.name;
+        service.comments.append_with_indent(0, buf);
         writeln!(buf, "#[twirp::async_trait::async_trait]").unwrap();
-        writeln!(buf, "pub trait {} {{", service_name).unwrap();
-        writeln!(buf, "    type Error;").unwrap();
+        writeln!(buf, "{} trait {} {{", self.visibility(), service_name).unwrap();
+        if self.server_error_type.is_none() {
+            writeln!(buf, "    type Error;").unwrap();
+        }
+        generate_service_fqn_fn(service_fqn, buf);
+        let error_type = self.server_error_type.as_deref().unwrap_or("Self::Error");
         for m in &service.methods {
+            m.comments.append_with_indent(1, buf);
             writeln!(
                 buf,
-                "    async fn {}(&self, ctx: twirp::Context, req: {}) -> Result<{}, Self::Error>;",
+                "    async fn {}(&self, ctx: twirp::Context, req: {}) -> Result<{}, {error_type}>;",
                 m.name, m.input_type, m.output_type,
             )
             .unwrap();
@@ -40,11 +613,13 @@ impl prost_build::ServiceGenerator for ServiceGenerator {
         writeln!(buf, "where").unwrap();
         writeln!(buf, "    T: {service_name} + Sync + Send").unwrap();
         writeln!(buf, "{{").unwrap();
-        writeln!(buf, "    type Error = T::Error;\n").unwrap();
+        if self.server_error_type.is_none() {
+            writeln!(buf, "    type Error = T::Error;\n").unwrap();
+        }
         for m in &service.methods {
             writeln!(
                 buf,
-                "    async fn {}(&self, ctx: twirp::Context, req: {}) -> Result<{}, Self::Error> {{",
+                "    async fn {}(&self, ctx: twirp::Context, req: {}) -> Result<{}, {error_type}> {{",
                 m.name, m.input_type, m.output_type,
             )
                 .unwrap();
@@ -53,18 +628,66 @@ impl prost_build::ServiceGenerator for ServiceGenerator {
         }
         writeln!(buf, "}}").unwrap();
 
-        //
-        // generate the twirp client
-        //
+        // Build the axum router for the service
+        writeln!(buf).unwrap();
+        writeln!(
+            buf,
+            "{} fn router<T>(api: T) -> twirp::Router",
+            self.visibility()
+        )
+        .unwrap();
+        writeln!(buf, "where").unwrap();
+        writeln!(
+            buf,
+            "    T: {service_name} + Clone + Send + Sync + 'static,"
+        )
+        .unwrap();
+        self.write_error_bound(service_name, buf);
+        writeln!(buf, "{{").unwrap();
+        writeln!(buf, "    twirp::details::TwirpRouterBuilder::new(api)").unwrap();
+        for m in &service.methods {
+            writeln!(
+                buf,
+                "        .route(\"/{}\", |api: T, ctx: twirp::Context, req: {}| async move {{",
+                m.proto_name, m.input_type,
+            )
+            .unwrap();
+            writeln!(buf, "            api.{}(ctx, req).await", m.name).unwrap();
+            writeln!(buf, "        }})").unwrap();
+        }
+        writeln!(buf, "        .build()").unwrap();
+        writeln!(buf, "}}").unwrap();
+        self.generate_method_handlers(service_name, &service.methods, buf);
+        self.generate_mock_server(service, buf);
+    }
+
+    fn generate_client(
+        &self,
+        service: &prost_build::Service,
+        service_fqn: &str,
+        paths_mod: &str,
+        buf: &mut String,
+    ) {
+        let service_name = &service.name;
         writeln!(buf).unwrap();
+        service.comments.append_with_indent(0, buf);
         writeln!(buf, "#[twirp::async_trait::async_trait]").unwrap();
-        writeln!(buf, "pub trait {service_name}Client: Send + Sync {{",).unwrap();
+        writeln!(
+            buf,
+            "{} trait {service_name}Client: Send + Sync {{",
+            self.visibility()
+        )
+        .unwrap();
+        generate_service_fqn_fn(service_fqn, buf);
         for m in &service.methods {
             // Define: <METHOD>
+            m.comments.append_with_indent(1, buf);
             writeln!(
                 buf,
-                "    async fn {}(&self, req: {}) -> Result<{}, twirp::ClientError>;",
-                m.name, m.input_type, m.output_type,
+                "    async fn {}(&self, req: {}) -> {};",
+                m.name,
+                m.input_type,
+                self.client_result(&m.output_type),
             )
             .unwrap();
         }
@@ -81,18 +704,641 @@ impl prost_build::ServiceGenerator for ServiceGenerator {
             // Define the rpc `<METHOD>`
             writeln!(
                 buf,
-                "    async fn {}(&self, req: {}) -> Result<{}, twirp::ClientError> {{",
-                m.name, m.input_type, m.output_type,
+                "    async fn {}(&self, req: {}) -> {} {{",
+                m.name,
+                m.input_type,
+                self.client_result(&m.output_type),
             )
             .unwrap();
             writeln!(
                 buf,
-                r#"    self.request("{}/{}", req).await"#,
-                service_fqn, m.proto_name
+                r#"    self.request({paths_mod}::{}, req).await"#,
+                to_shouty_snake_case(&m.proto_name)
             )
             .unwrap();
             writeln!(buf, "    }}").unwrap();
         }
         writeln!(buf, "}}").unwrap();
+        self.generate_arc_client(service_name, &service.methods, buf);
+    }
+}
+
+/// Hash a service's signature, given as its fully qualified name and `(method, input type, output
+/// type)` for each method, with 64-bit FNV-1a so that the result is stable across Rust versions
+/// and platforms.
+fn schema_hash(service_fqn: &str, methods: &[(&str, &str, &str)]) -> String {
+    let mut schema = format!("service {service_fqn}\n");
+    for (name, input, output) in methods {
+        writeln!(schema, "rpc {name}({input}) returns ({output})").unwrap();
+    }
+    let hash = schema.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    format!("{hash:016x}")
+}
+
+/// A module of constants with the path of each method relative to the Twirp prefix, like
+/// `example.Haberdasher/MakeHat`, for matching requests against. `NO_SIDE_EFFECTS` lists the
+/// methods with `option idempotency_level = NO_SIDE_EFFECTS;`, the ones that can be called with
+/// `GET` (see `twirp::server::ServerOptions::with_get_methods`). `TIMEOUTS` is only generated
+/// when `timeouts` are read, see [`ServiceGenerator::with_timeout_option`].
+fn generate_paths(
+    visibility: &str,
+    paths_mod: &str,
+    service_fqn: &str,
+    methods: &[prost_build::Method],
+    timeouts: Option<&[(String, String)]>,
+    buf: &mut String,
+) {
+    writeln!(buf).unwrap();
+    writeln!(
+        buf,
+        "/// The paths of the methods of `{service_fqn}`, relative to the Twirp prefix."
+    )
+    .unwrap();
+    writeln!(buf, "{visibility} mod {paths_mod} {{").unwrap();
+    for m in methods {
+        writeln!(
+            buf,
+            "    pub const {}: &str = \"{service_fqn}/{}\";",
+            to_shouty_snake_case(&m.proto_name),
+            m.proto_name
+        )
+        .unwrap();
+    }
+    let no_side_effects: Vec<_> = methods
+        .iter()
+        .filter(|m| m.options.idempotency_level() == IdempotencyLevel::NoSideEffects)
+        .map(|m| to_shouty_snake_case(&m.proto_name))
+        .collect();
+    writeln!(
+        buf,
+        "    pub const NO_SIDE_EFFECTS: [&str; {}] = [{}];",
+        no_side_effects.len(),
+        no_side_effects.join(", ")
+    )
+    .unwrap();
+    if let Some(timeouts) = timeouts {
+        let timeouts: Vec<_> = timeouts
+            .iter()
+            .map(|(method, ms)| {
+                format!(
+                    "({}, std::time::Duration::from_millis({ms}))",
+                    to_shouty_snake_case(method)
+                )
+            })
+            .collect();
+        writeln!(
+            buf,
+            "    pub const TIMEOUTS: [(&str, std::time::Duration); {}] = [{}];",
+            timeouts.len(),
+            timeouts.join(", ")
+        )
+        .unwrap();
+    }
+    writeln!(buf, "}}").unwrap();
+}
+
+/// An enum with a variant per method, convertible to and from the method's path, for labelling
+/// requests without matching on path strings everywhere.
+fn generate_method_enum(
+    visibility: &str,
+    service_name: &str,
+    service_fqn: &str,
+    paths_mod: &str,
+    methods: &[prost_build::Method],
+    buf: &mut String,
+) {
+    let enum_name = format!("{service_name}Method");
+    writeln!(buf).unwrap();
+    writeln!(buf, "/// The methods of `{service_fqn}`.").unwrap();
+    writeln!(buf, "#[derive(Debug, Clone, Copy, PartialEq, Eq)]").unwrap();
+    writeln!(buf, "{visibility} enum {enum_name} {{").unwrap();
+    for m in methods {
+        writeln!(buf, "    {},", m.proto_name).unwrap();
+    }
+    writeln!(buf, "}}").unwrap();
+    writeln!(buf).unwrap();
+    writeln!(buf, "impl {enum_name} {{").unwrap();
+    writeln!(
+        buf,
+        "    /// Every method, in the order they are defined in."
+    )
+    .unwrap();
+    writeln!(buf, "    pub const ALL: &[{enum_name}] = &[").unwrap();
+    for m in methods {
+        writeln!(buf, "        {enum_name}::{},", m.proto_name).unwrap();
+    }
+    writeln!(buf, "    ];").unwrap();
+    writeln!(buf).unwrap();
+    writeln!(
+        buf,
+        "    /// The method with the given path, relative to the Twirp prefix (e.g. `{service_fqn}/Method`),"
+    )
+    .unwrap();
+    writeln!(buf, "    /// with or without a leading `/`.").unwrap();
+    writeln!(buf, "    pub fn from_path(path: &str) -> Option<Self> {{").unwrap();
+    writeln!(
+        buf,
+        "        match path.strip_prefix('/').unwrap_or(path) {{"
+    )
+    .unwrap();
+    for m in methods {
+        writeln!(
+            buf,
+            "            {paths_mod}::{} => Some({enum_name}::{}),",
+            to_shouty_snake_case(&m.proto_name),
+            m.proto_name
+        )
+        .unwrap();
+    }
+    writeln!(buf, "            _ => None,").unwrap();
+    writeln!(buf, "        }}").unwrap();
+    writeln!(buf, "    }}").unwrap();
+    writeln!(buf).unwrap();
+    writeln!(
+        buf,
+        "    /// The path of the method, relative to the Twirp prefix."
+    )
+    .unwrap();
+    writeln!(buf, "    pub fn path(&self) -> &'static str {{").unwrap();
+    // Matching on `*self` rather than `self`, so that the match is exhaustive for services
+    // without methods too.
+    writeln!(buf, "        match *self {{").unwrap();
+    for m in methods {
+        writeln!(
+            buf,
+            "            {enum_name}::{} => {paths_mod}::{},",
+            m.proto_name,
+            to_shouty_snake_case(&m.proto_name)
+        )
+        .unwrap();
+    }
+    writeln!(buf, "        }}").unwrap();
+    writeln!(buf, "    }}").unwrap();
+    writeln!(buf, "}}").unwrap();
+}
+
+/// A provided trait method returning the service's fully qualified name. It is only available on
+/// sized types, so that the trait stays dyn-compatible.
+fn generate_service_fqn_fn(service_fqn: &str, buf: &mut String) {
+    writeln!(
+        buf,
+        "    /// The fully qualified name of the service, `{service_fqn}`."
+    )
+    .unwrap();
+    writeln!(buf, "    fn service_fqn() -> &'static str").unwrap();
+    writeln!(buf, "    where").unwrap();
+    writeln!(buf, "        Self: Sized,").unwrap();
+    writeln!(buf, "    {{").unwrap();
+    writeln!(buf, "        {service_fqn:?}").unwrap();
+    writeln!(buf, "    }}").unwrap();
+}
+
+fn to_shouty_snake_case(name: &str) -> String {
+    let mut out = String::with_capacity(name.len() + 4);
+    let mut prev_lower = false;
+    for c in name.chars() {
+        if c.is_uppercase() && prev_lower {
+            out.push('_');
+        }
+        prev_lower = c.is_lowercase() || c.is_ascii_digit();
+        out.push(c.to_ascii_uppercase());
+    }
+    out
+}
+
+impl prost_build::ServiceGenerator for ServiceGenerator {
+    fn generate(&mut self, service: prost_build::Service, buf: &mut String) {
+        self.generate_method_options(&service, buf);
+        self.generate_schema_hash(&service, buf);
+        self.generate_service_descriptor(&service, buf);
+
+        let service_fqn = format!("{}.{}", service.package, service.proto_name);
+        writeln!(buf).unwrap();
+        writeln!(
+            buf,
+            "{} const SERVICE_FQN: &str = \"/{service_fqn}\";",
+            self.visibility()
+        )
+        .unwrap();
+        let paths_mod = format!(
+            "{}_paths",
+            to_shouty_snake_case(&service.name).to_lowercase()
+        );
+        generate_paths(
+            self.visibility(),
+            &paths_mod,
+            &service_fqn,
+            &service.methods,
+            self.method_timeouts(&service_fqn).as_deref(),
+            buf,
+        );
+        generate_method_enum(
+            self.visibility(),
+            &service.name,
+            &service_fqn,
+            &paths_mod,
+            &service.methods,
+            buf,
+        );
+
+        if !self.skip_server {
+            self.generate_server(&service, &service_fqn, buf);
+        }
+        if !self.skip_client {
+            self.generate_client(&service, &service_fqn, &paths_mod, buf);
+        }
+        self.generate_in_process_client(&service, buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_hat_service() -> prost_build::Service {
+        prost_build::Service {
+            name: "HaberdasherApi".to_string(),
+            proto_name: "HaberdasherAPI".to_string(),
+            package: "example".to_string(),
+            comments: Default::default(),
+            methods: vec![prost_build::Method {
+                name: "make_hat".to_string(),
+                proto_name: "MakeHat".to_string(),
+                comments: Default::default(),
+                input_type: "MakeHatRequest".to_string(),
+                output_type: "Hat".to_string(),
+                input_proto_type: ".example.MakeHatRequest".to_string(),
+                output_proto_type: ".example.Hat".to_string(),
+                options: Default::default(),
+                client_streaming: false,
+                server_streaming: false,
+            }],
+            options: Default::default(),
+        }
+    }
+
+    // The builder methods hand out boxes, see `service_generator`.
+    #[allow(clippy::boxed_local)]
+    fn generate(mut generator: Box<ServiceGenerator>) -> String {
+        let mut buf = String::new();
+        prost_build::ServiceGenerator::generate(&mut *generator, make_hat_service(), &mut buf);
+        buf
+    }
+
+    #[test]
+    fn test_method_paths() {
+        let generated = generate(service_generator());
+        assert!(generated.contains(
+            "pub mod haberdasher_api_paths {\n    pub const MAKE_HAT: &str = \"example.HaberdasherAPI/MakeHat\";\n    pub const NO_SIDE_EFFECTS: [&str; 0] = [];\n}"
+        ));
+        assert!(generated.contains("self.request(haberdasher_api_paths::MAKE_HAT, req)"));
+    }
+
+    #[test]
+    fn test_no_side_effects() {
+        let mut service = make_hat_service();
+        let mut get_hat = service.methods[0].clone();
+        get_hat.name = "get_hat".to_string();
+        get_hat.proto_name = "GetHat".to_string();
+        get_hat
+            .options
+            .set_idempotency_level(IdempotencyLevel::NoSideEffects);
+        let mut list_hats = get_hat.clone();
+        list_hats.name = "list_hats".to_string();
+        list_hats.proto_name = "ListHats".to_string();
+        // idempotent methods may still have side effects
+        service.methods[0]
+            .options
+            .set_idempotency_level(IdempotencyLevel::Idempotent);
+        service.methods.extend([get_hat, list_hats]);
+        let mut buf = String::new();
+        prost_build::ServiceGenerator::generate(&mut *service_generator(), service, &mut buf);
+        assert!(buf.contains("    pub const NO_SIDE_EFFECTS: [&str; 2] = [GET_HAT, LIST_HATS];\n"));
+    }
+
+    #[test]
+    fn test_timeouts() {
+        fn varint(mut v: u64, buf: &mut Vec<u8>) {
+            while v >= 0x80 {
+                buf.push(v as u8 | 0x80);
+                v >>= 7;
+            }
+            buf.push(v as u8);
+        }
+        fn field(number: u64, bytes: &[u8]) -> Vec<u8> {
+            let mut buf = vec![];
+            varint(number << 3 | 2, &mut buf);
+            varint(bytes.len() as u64, &mut buf);
+            buf.extend_from_slice(bytes);
+            buf
+        }
+        // FileDescriptorSet { file { package, service { name, method { name, options } } } }
+        let mut timeout = vec![];
+        varint(50002 << 3, &mut timeout);
+        varint(5000, &mut timeout);
+        let method = [field(1, b"MakeHat"), field(4, &timeout)].concat();
+        let service = [field(1, b"HaberdasherAPI"), field(2, &method)].concat();
+        let file = [field(2, b"example"), field(6, &service)].concat();
+        let path = std::env::temp_dir().join(format!("twirp-build-{}.bin", std::process::id()));
+        std::fs::write(&path, field(1, &file)).unwrap();
+
+        let generated = generate(
+            service_generator()
+                .with_descriptor_set_path(&path)
+                .with_timeout_option(50002),
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert!(generated.contains(
+            "    pub const TIMEOUTS: [(&str, std::time::Duration); 1] = [(MAKE_HAT, std::time::Duration::from_millis(5000))];\n"
+        ));
+    }
+
+    #[test]
+    fn test_json_pb_attributes() {
+        use prost_types::field_descriptor_proto::{Label, Type};
+        use prost_types::{
+            DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+            MessageOptions,
+        };
+
+        let field = |name: &str, r#type: Type, label: Label| FieldDescriptorProto {
+            name: Some(name.to_string()),
+            r#type: Some(r#type as i32),
+            label: Some(label as i32),
+            ..Default::default()
+        };
+        let entry = DescriptorProto {
+            name: Some("LabelsEntry".to_string()),
+            field: vec![
+                field("key", Type::String, Label::Optional),
+                field("value", Type::Int64, Label::Optional),
+            ],
+            options: Some(MessageOptions {
+                map_entry: Some(true),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let size = DescriptorProto {
+            name: Some("Size".to_string()),
+            field: vec![field("inches", Type::Double, Label::Optional)],
+            ..Default::default()
+        };
+        let hat = DescriptorProto {
+            name: Some("Hat".to_string()),
+            field: vec![
+                field("hat_id", Type::Uint64, Label::Optional),
+                field("sizes_seen", Type::Sfixed64, Label::Repeated),
+                field("color", Type::String, Label::Optional),
+                field("raw", Type::Bytes, Label::Optional),
+                field("weights", Type::Float, Label::Repeated),
+                FieldDescriptorProto {
+                    proto3_optional: Some(true),
+                    oneof_index: Some(0),
+                    ..field("stock", Type::Int64, Label::Optional)
+                },
+                field("labels", Type::Message, Label::Repeated),
+            ],
+            nested_type: vec![size, entry],
+            ..Default::default()
+        };
+        let fds = FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                package: Some("example".to_string()),
+                message_type: vec![hat],
+                ..Default::default()
+            }],
+        };
+
+        let (types, fields) = jsonpb::json_pb_attributes(&fds);
+        let camel_case = r#"#[serde(rename_all = "camelCase")]"#.to_string();
+        assert_eq!(types, [(".example.Hat".to_string(), camel_case)]);
+        let with = |path: &str, helper: &str| {
+            (
+                path.to_string(),
+                format!(r#"#[serde(with = "twirp::jsonpb::{helper}")]"#),
+            )
+        };
+        assert_eq!(
+            fields,
+            [
+                with(".example.Hat.hat_id", "int64"),
+                with(".example.Hat.sizes_seen", "int64::repeated"),
+                with(".example.Hat.raw", "bytes"),
+                with(".example.Hat.weights", "float::repeated"),
+                with(".example.Hat.Size.inches", "float"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_method_enum() {
+        let generated = generate(service_generator());
+        assert!(generated.contains(
+            "#[derive(Debug, Clone, Copy, PartialEq, Eq)]\npub enum HaberdasherApiMethod {\n    MakeHat,\n}"
+        ));
+        assert!(generated.contains(
+            "pub const ALL: &[HaberdasherApiMethod] = &[\n        HaberdasherApiMethod::MakeHat,\n    ];"
+        ));
+        assert!(generated
+            .contains("haberdasher_api_paths::MAKE_HAT => Some(HaberdasherApiMethod::MakeHat),"));
+        assert!(
+            generated.contains("HaberdasherApiMethod::MakeHat => haberdasher_api_paths::MAKE_HAT,")
+        );
+    }
+
+    #[test]
+    fn test_empty_service() {
+        let mut service = make_hat_service();
+        service.methods.clear();
+        let mut buf = String::new();
+        prost_build::ServiceGenerator::generate(&mut *service_generator(), service, &mut buf);
+        assert!(buf.contains("pub enum HaberdasherApiMethod {\n}"));
+        assert!(buf.contains("pub const ALL: &[HaberdasherApiMethod] = &[\n    ];"));
+        assert!(buf.contains("        match *self {\n        }"));
+    }
+
+    #[test]
+    fn test_server_and_client() {
+        let both = generate(service_generator());
+        assert!(both.contains("pub trait HaberdasherApi {"));
+        assert!(both.contains("pub trait HaberdasherApiClient: Send + Sync {"));
+
+        let server = generate(service_generator().with_client(false));
+        assert!(server.contains("pub fn router<T>(api: T)"));
+        assert!(!server.contains("Client"));
+
+        let client = generate(service_generator().with_server(false).with_arc_client(true));
+        assert!(client.contains("impl<C> HaberdasherApiClient for std::sync::Arc<C>"));
+        assert!(!client.contains("pub trait HaberdasherApi {"));
+        assert!(!client.contains("router"));
+    }
+
+    #[test]
+    fn test_visibility() {
+        let generated = generate(
+            service_generator()
+                .with_visibility("pub(crate)")
+                .with_method_handlers(true)
+                .with_schema_hash(true)
+                .with_service_descriptor(true),
+        );
+        assert!(generated.contains("pub(crate) const SERVICE_FQN: &str"));
+        assert!(generated.contains("pub(crate) mod haberdasher_api_paths {"));
+        assert!(generated.contains("pub(crate) enum HaberdasherApiMethod {"));
+        assert!(generated.contains("pub(crate) trait HaberdasherApi {"));
+        assert!(generated.contains("pub(crate) fn router<T>(api: T)"));
+        assert!(generated.contains("pub(crate) fn make_hat_handler<T, S>(api: T)"));
+        assert!(generated.contains("pub(crate) trait HaberdasherApiClient: Send + Sync {"));
+        // nothing is left `pub` at the top level
+        assert!(
+            !generated.lines().any(|line| line.starts_with("pub ")),
+            "{generated}"
+        );
+    }
+
+    #[test]
+    fn test_client_result_alias() {
+        let explicit = generate(service_generator().with_arc_client(true));
+        assert!(explicit.contains(
+            "async fn make_hat(&self, req: MakeHatRequest) -> Result<Hat, twirp::ClientError>;"
+        ));
+
+        let alias = generate(
+            service_generator()
+                .with_arc_client(true)
+                .with_client_result_alias(true),
+        );
+        assert!(
+            alias.contains("async fn make_hat(&self, req: MakeHatRequest) -> twirp::Result<Hat>;")
+        );
+        // the trait, the `Client` impl and the `Arc` impl
+        assert_eq!(
+            alias
+                .matches("(&self, req: MakeHatRequest) -> twirp::Result<Hat>")
+                .count(),
+            3
+        );
+        assert!(!alias.contains("twirp::ClientError"), "{alias}");
+    }
+
+    #[test]
+    fn test_mock_server() {
+        assert!(!generate(service_generator()).contains("Mock"));
+
+        let generated = generate(service_generator().with_mock_server(true));
+        assert!(generated.contains(
+            "#[derive(Default)]\n#[allow(clippy::type_complexity)]\npub struct MockHaberdasherApi {"
+        ));
+        assert!(generated.contains("pub fn expect_make_hat<F>(mut self, f: F) -> Self"));
+        assert!(generated.contains(
+            "F: Fn(twirp::Context, MakeHatRequest) -> Result<Hat, twirp::TwirpErrorResponse> + Send + Sync + 'static,"
+        ));
+        assert!(generated.contains("impl HaberdasherApi for MockHaberdasherApi {"));
+        assert!(generated.contains("    type Error = twirp::TwirpErrorResponse;\n"));
+        assert!(generated.contains("None => Err(twirp::unimplemented(\"MakeHat is not mocked\")),"));
+
+        let custom = generate(
+            service_generator()
+                .with_mock_server(true)
+                .with_server_error_type("MyError"),
+        );
+        assert!(custom.contains("-> Result<Hat, MyError> + Send + Sync>>,"));
+        assert!(
+            custom.contains("None => Err(twirp::unimplemented(\"MakeHat is not mocked\").into()),")
+        );
+
+        // the mock is part of the server
+        let client = generate(
+            service_generator()
+                .with_mock_server(true)
+                .with_server(false),
+        );
+        assert!(!client.contains("Mock"));
+    }
+
+    #[test]
+    fn test_in_process_client() {
+        assert!(!generate(service_generator()).contains("InProcess"));
+
+        let generated = generate(service_generator().with_in_process_client(true));
+        assert!(generated.contains("pub struct InProcessHaberdasherApiClient<T> {"));
+        assert!(generated.contains("pub fn new(api: std::sync::Arc<T>) -> Self {"));
+        assert!(generated
+            .contains("impl<T> HaberdasherApiClient for InProcessHaberdasherApiClient<T>\nwhere"));
+        assert!(generated.contains("<T as HaberdasherApi>::Error: twirp::IntoTwirpResponse,"));
+        assert!(generated.contains("self.api.make_hat(twirp::Context::default(), req).await"));
+
+        // both the server and the client are needed
+        for generator in [
+            service_generator().with_server(false),
+            service_generator().with_client(false),
+        ] {
+            let generated = generate(generator.with_in_process_client(true));
+            assert!(!generated.contains("InProcess"));
+        }
+    }
+
+    #[test]
+    fn test_server_error_type() {
+        let generic = generate(service_generator());
+        assert!(generic.contains("    type Error;\n"));
+        assert!(generic.contains("-> Result<Hat, Self::Error>;"));
+        assert!(generic.contains("<T as HaberdasherApi>::Error: twirp::IntoTwirpResponse"));
+
+        let concrete =
+            generate(service_generator().with_server_error_type("twirp::TwirpErrorResponse"));
+        assert!(!concrete.contains("type Error"));
+        assert!(concrete.contains(
+            "async fn make_hat(&self, ctx: twirp::Context, req: MakeHatRequest) -> Result<Hat, twirp::TwirpErrorResponse>;"
+        ));
+        assert!(!concrete.contains("::Error: twirp::IntoTwirpResponse"));
+    }
+
+    #[test]
+    fn test_doc_comments() {
+        let mut generator = service_generator();
+        let mut service = make_hat_service();
+        service.comments.leading = vec![
+            " A Haberdasher makes hats, see [Hat].".to_string(),
+            "".to_string(),
+            "/ Not a comment.".to_string(),
+        ];
+        service.methods[0].comments.leading =
+            vec![" Makes a hat, see https://example.com/hats".to_string()];
+        let mut buf = String::new();
+        prost_build::ServiceGenerator::generate(&mut *generator, service, &mut buf);
+        assert!(buf.contains(
+            "/// A Haberdasher makes hats, see \\[Hat\\].\n///\n/// / Not a comment.\n#[twirp::async_trait::async_trait]\npub trait HaberdasherApi {"
+        ));
+        assert!(buf.contains(
+            "    /// Makes a hat, see <https://example.com/hats>\n    async fn make_hat(&self, ctx"
+        ));
+
+        // nothing is written for empty comments
+        assert!(generate(service_generator())
+            .contains("\n#[twirp::async_trait::async_trait]\npub trait HaberdasherApi {"));
+    }
+
+    #[test]
+    fn test_schema_hash() {
+        let methods = [("MakeHat", ".example.MakeHatRequest", ".example.Hat")];
+        let hash = schema_hash("example.Haberdasher", &methods);
+        assert_eq!(hash.len(), 16);
+        assert_eq!(hash, schema_hash("example.Haberdasher", &methods));
+
+        // adding a method changes the hash
+        let more_methods = [
+            methods[0],
+            ("GetHat", ".example.GetHatRequest", ".example.Hat"),
+        ];
+        assert_ne!(hash, schema_hash("example.Haberdasher", &more_methods));
+
+        // as does changing a type or the service name
+        let changed = [("MakeHat", ".example.MakeHatRequest", ".example.Cap")];
+        assert_ne!(hash, schema_hash("example.Haberdasher", &changed));
+        assert_ne!(hash, schema_hash("example.Milliner", &methods));
     }
 }
@@ -0,0 +1,144 @@
+//! Reading custom method options out of an encoded `FileDescriptorSet`.
+//!
+//! `prost` drops unknown fields (which is how extensions look to it) when decoding the descriptor
+//! set, so custom options are read directly from the encoded bytes instead.
+
+/// The type of a custom method option, which determines how its value is rendered in the
+/// generated code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionType {
+    /// `string` options, rendered as is.
+    String,
+    /// `bool` options, rendered as `true` or `false`.
+    Bool,
+    /// `int32`, `int64` and `enum` options, rendered as decimal numbers.
+    Int,
+    /// `uint32` and `uint64` options, rendered as decimal numbers.
+    UInt,
+}
+
+/// A custom method option to read from the proto definitions.
+#[derive(Debug, Clone)]
+pub(crate) struct MethodOption {
+    pub(crate) name: String,
+    pub(crate) field_number: u32,
+    pub(crate) option_type: OptionType,
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Fixed64,
+    Bytes(&'a [u8]),
+    Fixed32,
+}
+
+/// Find the configured custom options set on the methods of `service_fqn` (e.g.
+/// `service.haberdash.v1.HaberdasherAPI`), as `(method, option, value)` in declaration order.
+pub(crate) fn find_method_options(
+    descriptor_set: &[u8],
+    service_fqn: &str,
+    options: &[MethodOption],
+) -> Vec<(String, String, String)> {
+    let mut found = vec![];
+    for file in submessages(descriptor_set, 1) {
+        let package = strings(file, 2).next().unwrap_or_default();
+        for service in submessages(file, 6) {
+            let name = strings(service, 1).next().unwrap_or_default();
+            let fqn = if package.is_empty() {
+                name.to_string()
+            } else {
+                format!("{package}.{name}")
+            };
+            if fqn != service_fqn {
+                continue;
+            }
+            for method in submessages(service, 2) {
+                let method_name = strings(method, 1).next().unwrap_or_default();
+                for method_options in submessages(method, 4) {
+                    for (number, value) in fields(method_options) {
+                        let Some(option) = options.iter().find(|o| o.field_number == number) else {
+                            continue;
+                        };
+                        found.push((
+                            method_name.to_string(),
+                            option.name.clone(),
+                            render(option, value),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    found
+}
+
+fn render(option: &MethodOption, value: Value<'_>) -> String {
+    match (option.option_type, value) {
+        (OptionType::String, Value::Bytes(bytes)) => std::str::from_utf8(bytes)
+            .unwrap_or_else(|_| panic!("option {} is not valid UTF-8", option.name))
+            .to_string(),
+        (OptionType::Bool, Value::Varint(v)) => (v != 0).to_string(),
+        (OptionType::Int, Value::Varint(v)) => (v as i64).to_string(),
+        (OptionType::UInt, Value::Varint(v)) => v.to_string(),
+        (option_type, _) => panic!(
+            "option {} (field {}) is not encoded as {:?}",
+            option.name, option.field_number, option_type
+        ),
+    }
+}
+
+fn submessages(message: &[u8], number: u32) -> impl Iterator<Item = &[u8]> {
+    fields(message)
+        .into_iter()
+        .filter_map(move |(n, value)| match value {
+            Value::Bytes(bytes) if n == number => Some(bytes),
+            _ => None,
+        })
+}
+
+fn strings(message: &[u8], number: u32) -> impl Iterator<Item = &str> {
+    submessages(message, number).map(|bytes| std::str::from_utf8(bytes).unwrap_or_default())
+}
+
+/// Split an encoded protobuf message into its fields.
+fn fields(mut buf: &[u8]) -> Vec<(u32, Value<'_>)> {
+    let mut fields = vec![];
+    while !buf.is_empty() {
+        let key = read_varint(&mut buf);
+        let value = match key & 0x7 {
+            0 => Value::Varint(read_varint(&mut buf)),
+            1 => {
+                buf = &buf[8..];
+                Value::Fixed64
+            }
+            2 => {
+                let len = read_varint(&mut buf) as usize;
+                let (bytes, rest) = buf.split_at(len);
+                buf = rest;
+                Value::Bytes(bytes)
+            }
+            5 => {
+                buf = &buf[4..];
+                Value::Fixed32
+            }
+            wire_type => panic!("unsupported wire type {wire_type} in file descriptor set"),
+        };
+        fields.push(((key >> 3) as u32, value));
+    }
+    fields
+}
+
+fn read_varint(buf: &mut &[u8]) -> u64 {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf
+            .split_first()
+            .expect("truncated varint in file descriptor set");
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte < 0x80 {
+            return value;
+        }
+    }
+    panic!("invalid varint in file descriptor set")
+}
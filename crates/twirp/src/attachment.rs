@@ -0,0 +1,337 @@
+//! Binary attachments returned alongside an rpc response.
+//!
+//! **This is not part of the Twirp spec** and is only available with the `attachments` feature.
+//! Standard Twirp clients will not understand these responses, so only attach blobs to responses
+//! for callers that use [`Client::request_with_attachment`].
+//!
+//! A handler opts in by inserting an [`Attachment`] into its response extensions:
+//!
+//! ```
+//! # use twirp::attachment::Attachment;
+//! # fn handler(ctx: twirp::Context) {
+//! ctx.insert(Attachment::new(vec![0u8; 1024]));
+//! # }
+//! ```
+//!
+//! The response is then sent as `multipart/mixed` with two parts, identified by the `name`
+//! parameter of their `Content-Disposition` header:
+//!
+//! - `message`: the rpc response message, encoded as usual for the request's format.
+//! - `attachment`: the raw bytes of the attachment, as `application/octet-stream`.
+
+use std::time::Instant;
+
+use axum::body::{Body, Bytes};
+use axum::response::IntoResponse;
+use http_body_util::BodyExt;
+use hyper::{header, Response};
+use reqwest::header::CONTENT_TYPE;
+
+use crate::client::ClientTimings;
+use crate::headers::is_protobuf_content_type;
+use crate::{error, Client, ClientError, Result, TwirpErrorCode};
+
+const MULTIPART_MIXED: &str = "multipart/mixed";
+const MESSAGE_PART: &str = "message";
+const ATTACHMENT_PART: &str = "attachment";
+
+/// A binary blob to send after the response message, without embedding it in the protobuf.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attachment(pub Bytes);
+
+impl Attachment {
+    pub fn new(data: impl Into<Bytes>) -> Self {
+        Self(data.into())
+    }
+}
+
+/// Turn a successful response into a multipart response carrying `attachment`. Error responses,
+/// including those sent with a `200` status, are returned untouched.
+pub(crate) async fn attach(resp: Response<Body>, attachment: Attachment) -> Response<Body> {
+    if !resp.status().is_success() || resp.extensions().get::<TwirpErrorCode>().is_some() {
+        return resp;
+    }
+
+    let (mut parts, body) = resp.into_parts();
+    let message = match body.collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(err) => {
+            let mut twirp_err = error::internal("error writing attachment");
+            twirp_err.insert_meta("error".to_string(), err.to_string());
+            return twirp_err.into_response();
+        }
+    };
+    let message_type = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    let boundary = choose_boundary(&[&message, &attachment.0]);
+    let mut data = Vec::with_capacity(message.len() + attachment.0.len() + 256);
+    write_part(
+        &mut data,
+        &boundary,
+        "inline",
+        MESSAGE_PART,
+        &message_type,
+        &message,
+    );
+    write_part(
+        &mut data,
+        &boundary,
+        "attachment",
+        ATTACHMENT_PART,
+        "application/octet-stream",
+        &attachment.0,
+    );
+    data.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+
+    let content_type = format!("{MULTIPART_MIXED}; boundary={boundary}");
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        content_type
+            .parse()
+            .expect("boundary is a valid header value"),
+    );
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(data))
+}
+
+fn write_part(
+    data: &mut Vec<u8>,
+    boundary: &str,
+    disposition: &str,
+    name: &str,
+    content_type: &str,
+    content: &[u8],
+) {
+    data.extend_from_slice(
+        format!(
+            "--{boundary}\r\nContent-Disposition: {disposition}; name=\"{name}\"\r\nContent-Type: {content_type}\r\n\r\n"
+        )
+        .as_bytes(),
+    );
+    data.extend_from_slice(content);
+    data.extend_from_slice(b"\r\n");
+}
+
+/// Pick a boundary that does not occur in any of the parts.
+fn choose_boundary(contents: &[&[u8]]) -> String {
+    (0u64..)
+        .map(|n| format!("twirp-attachment-{n}"))
+        .find(|boundary| {
+            !contents
+                .iter()
+                .any(|content| find(content, boundary.as_bytes()).is_some())
+        })
+        .expect("some boundary is always unused")
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn boundary_from_content_type(content_type: &str) -> Option<&str> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case(MULTIPART_MIXED) {
+        return None;
+    }
+    params.split(';').find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+/// A part of a multipart body: its name, content type, and content.
+struct Part<'a> {
+    name: &'a str,
+    content_type: &'a str,
+    content: &'a [u8],
+}
+
+fn decode_multipart<'a>(data: &'a [u8], boundary: &str) -> Option<Vec<Part<'a>>> {
+    let delimiter = format!("--{boundary}");
+    let mut rest = data.strip_prefix(delimiter.as_bytes())?;
+    let delimiter = format!("\r\n--{boundary}");
+    let mut parts = vec![];
+    loop {
+        if rest.starts_with(b"--") {
+            return Some(parts);
+        }
+        rest = rest.strip_prefix(b"\r\n")?;
+        let end = find(rest, delimiter.as_bytes())?;
+        let (part, next) = rest.split_at(end);
+        rest = &next[delimiter.len()..];
+
+        let headers_end = find(part, b"\r\n\r\n")?;
+        let headers = std::str::from_utf8(&part[..headers_end]).ok()?;
+        let mut name = None;
+        let mut content_type = "";
+        for line in headers.split("\r\n") {
+            let (key, value) = line.split_once(':')?;
+            if key.trim().eq_ignore_ascii_case("content-disposition") {
+                name = value.split(';').find_map(|param| {
+                    let (key, value) = param.split_once('=')?;
+                    (key.trim() == "name").then(|| value.trim().trim_matches('"'))
+                });
+            } else if key.trim().eq_ignore_ascii_case("content-type") {
+                content_type = value.trim();
+            }
+        }
+        parts.push(Part {
+            name: name?,
+            content_type,
+            content: &part[headers_end + 4..],
+        });
+    }
+}
+
+impl Client {
+    /// Make an HTTP twirp request to an rpc that may return an [`Attachment`].
+    ///
+    /// Returns the response message and, if the server sent one, the attachment. Responses without
+    /// an attachment are handled exactly like [`Client::request`].
+    pub async fn request_with_attachment<I, O>(
+        &self,
+        path: &str,
+        body: I,
+    ) -> Result<(O, Option<Attachment>)>
+    where
+        I: prost::Message,
+        O: prost::Message + Default,
+    {
+        self.within_timeout(self.send_with_attachment(path, body))
+            .await
+    }
+
+    async fn send_with_attachment<I, O>(
+        &self,
+        path: &str,
+        body: I,
+    ) -> Result<(O, Option<Attachment>)>
+    where
+        I: prost::Message,
+        O: prost::Message + Default,
+    {
+        let mut timings = ClientTimings::new(Instant::now());
+        let (resp, path) = self.send(path, body, &mut timings).await?;
+        let boundary = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|ct| ct.to_str().ok())
+            .and_then(boundary_from_content_type)
+            .map(|boundary| boundary.to_string());
+        let boundary = match boundary {
+            Some(boundary) if resp.status().is_success() => boundary,
+            _ => return Ok((self.read_response(resp, path).await?, None)),
+        };
+
+        let data = resp.bytes().await?;
+        let parts = decode_multipart(&data, &boundary)
+            .ok_or_else(|| ClientError::MalformedResponse("invalid multipart body".to_string()))?;
+        let message = parts
+            .iter()
+            .find(|part| part.name == MESSAGE_PART)
+            .ok_or_else(|| ClientError::MalformedResponse("missing message part".to_string()))?;
+        if !is_protobuf_content_type(message.content_type.as_bytes()) {
+            return Err(ClientError::MalformedResponse(format!(
+                "unexpected message content-type: {}",
+                message.content_type
+            )));
+        }
+        let message = O::decode(message.content)?;
+        let attachment = parts
+            .iter()
+            .find(|part| part.name == ATTACHMENT_PART)
+            .map(|part| Attachment(data.slice_ref(part.content)));
+        Ok((message, attachment))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tower::Service;
+
+    use crate::details::TwirpRouterBuilder;
+    use crate::test::*;
+    use crate::Context;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_attachment_roundtrip() {
+        // Include the first boundary we'd try in the blob, to make sure it is avoided.
+        let blob = Bytes::from(
+            [b"twirp-attachment-0".as_slice(), &[0u8, 1, 2, 255][..]]
+                .concat()
+                .repeat(1024),
+        );
+        let expected = blob.clone();
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                move |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| {
+                    let blob = blob.clone();
+                    async move {
+                        ctx.insert(Attachment(blob));
+                        api.ping(ctx, req).await
+                    }
+                },
+            )
+            .build();
+        let mut router = axum::Router::new().nest("/twirp/test.TestAPI", twirp_routes);
+
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let content_type = resp.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let boundary = boundary_from_content_type(&content_type).unwrap();
+        assert_eq!(boundary, "twirp-attachment-1");
+
+        let data = resp.into_body().collect().await.unwrap().to_bytes();
+        let parts = decode_multipart(&data, boundary).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name, MESSAGE_PART);
+        assert_eq!(parts[0].content_type, "application/json");
+        let message: PingResponse = serde_json::from_slice(parts[0].content).unwrap();
+        assert_eq!(&message.name, "hi");
+        assert_eq!(parts[1].name, ATTACHMENT_PART);
+        assert_eq!(parts[1].content, &expected[..]);
+    }
+
+    #[tokio::test]
+    async fn test_errors_in_body() {
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                |_: Arc<TestApiServer>, ctx: Context, _: PingRequest| async move {
+                    ctx.insert(Attachment::new(vec![0u8; 16]));
+                    Err::<PingResponse, _>(crate::internal("no hats"))
+                },
+            )
+            .build();
+        let mut router = axum::Router::new()
+            .nest("/twirp/test.TestAPI", twirp_routes)
+            .layer(axum::Extension(
+                crate::server::ServerOptions::default().with_errors_in_body(true),
+            ));
+
+        // the error is sent as it is, though its status is 200
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Internal);
+        assert_eq!(err.msg, "no hats");
+    }
+}
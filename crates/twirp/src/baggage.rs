@@ -0,0 +1,283 @@
+//! Key-value baggage propagated from the client to the server (and on to the services it calls),
+//! in the [W3C `baggage`] header format: `key1=value1,key2=value2`.
+//!
+//! Attach baggage to the requests of a client with [`Client::with_baggage`], and enable reading
+//! it on the server with [`ServerOptions::with_baggage`]. Handlers then find it in the
+//! [`Context`], and can pass it on:
+//!
+//! ```ignore
+//! let baggage = ctx.get::<Baggage>().cloned().unwrap_or_default();
+//! let hat = self.hats_client.with_baggage(&baggage).make_hat(req).await?;
+//! ```
+//!
+//! [W3C `baggage`]: https://www.w3.org/TR/baggage/
+//! [`Client::with_baggage`]: crate::Client::with_baggage
+//! [`ServerOptions::with_baggage`]: crate::server::ServerOptions::with_baggage
+//! [`Context`]: crate::Context
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use http::HeaderMap;
+use thiserror::Error;
+
+use crate::headers::BAGGAGE;
+
+/// The most entries baggage can have, as in the W3C spec.
+pub const MAX_ENTRIES: usize = 180;
+
+/// The longest the encoded baggage can be, in bytes, as in the W3C spec.
+pub const MAX_LEN: usize = 8192;
+
+/// Why baggage couldn't be built or decoded.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BaggageError {
+    #[error("invalid baggage key: {0:?}")]
+    InvalidKey(String),
+    #[error("invalid baggage value for key {0:?}")]
+    InvalidValue(String),
+    #[error("baggage has more than {MAX_ENTRIES} entries")]
+    TooManyEntries,
+    #[error("baggage is longer than {MAX_LEN} bytes")]
+    TooLong,
+}
+
+/// Key-value pairs propagated along with requests. Keys are HTTP tokens (e.g. `tenant-id`),
+/// values any string.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Baggage(BTreeMap<String, String>);
+
+impl Baggage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an entry, replacing any entry with the same key. Fails, leaving the baggage as it was,
+    /// if the key isn't a valid token or the baggage would exceed [`MAX_ENTRIES`] or [`MAX_LEN`].
+    pub fn insert(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<(), BaggageError> {
+        let key = key.into();
+        if !is_token(&key) {
+            return Err(BaggageError::InvalidKey(key));
+        }
+        let previous = self.0.insert(key.clone(), value.into());
+        let result = self.check_limits();
+        if result.is_err() {
+            match previous {
+                Some(previous) => self.0.insert(key, previous),
+                None => self.0.remove(&key),
+            };
+        }
+        result
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.0.remove(key)
+    }
+
+    /// The entries, ordered by key.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Encode the baggage as a `baggage` header value, percent-encoding the values.
+    pub fn encode(&self) -> String {
+        let mut encoded = String::new();
+        for (key, value) in &self.0 {
+            if !encoded.is_empty() {
+                encoded.push(',');
+            }
+            encoded.push_str(key);
+            encoded.push('=');
+            for byte in value.bytes() {
+                if is_baggage_octet(byte) && byte != b'%' {
+                    encoded.push(char::from(byte));
+                } else {
+                    write!(encoded, "%{byte:02X}").expect("writing to a String can't fail");
+                }
+            }
+        }
+        encoded
+    }
+
+    /// Decode a `baggage` header value. Entry properties (`key=value;property`) are dropped.
+    pub fn decode(header: &str) -> Result<Self, BaggageError> {
+        if header.len() > MAX_LEN {
+            return Err(BaggageError::TooLong);
+        }
+        let mut baggage = Self::new();
+        for entry in header.split(',') {
+            let entry = entry.trim_matches([' ', '\t']);
+            if entry.is_empty() {
+                continue;
+            }
+            let (key, value) = entry.split_once('=').unwrap_or((entry, ""));
+            let key = key.trim_matches([' ', '\t']);
+            if !is_token(key) || !entry.contains('=') {
+                return Err(BaggageError::InvalidKey(key.to_string()));
+            }
+            let value = value.split(';').next().unwrap_or_default();
+            let value = percent_decode(value.trim_matches([' ', '\t']))
+                .ok_or_else(|| BaggageError::InvalidValue(key.to_string()))?;
+            baggage.0.insert(key.to_string(), value);
+            if baggage.0.len() > MAX_ENTRIES {
+                return Err(BaggageError::TooManyEntries);
+            }
+        }
+        Ok(baggage)
+    }
+
+    /// Decode the `baggage` headers of a request, if any. Repeated headers are combined.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, BaggageError> {
+        let mut values = headers.get_all(BAGGAGE).iter().peekable();
+        if values.peek().is_none() {
+            return Ok(None);
+        }
+        let mut combined = String::new();
+        for value in values {
+            let value = value
+                .to_str()
+                .map_err(|_| BaggageError::InvalidValue(String::new()))?;
+            if !combined.is_empty() {
+                combined.push(',');
+            }
+            combined.push_str(value);
+        }
+        Self::decode(&combined).map(Some)
+    }
+
+    fn check_limits(&self) -> Result<(), BaggageError> {
+        if self.0.len() > MAX_ENTRIES {
+            return Err(BaggageError::TooManyEntries);
+        }
+        if self.encode().len() > MAX_LEN {
+            return Err(BaggageError::TooLong);
+        }
+        Ok(())
+    }
+}
+
+/// An HTTP token (RFC 9110), the syntax of baggage keys.
+fn is_token(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// A byte allowed unencoded in baggage values.
+fn is_baggage_octet(byte: u8) -> bool {
+    matches!(byte, 0x21 | 0x23..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E)
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+        } else if is_baggage_octet(byte) {
+            decoded.push(byte);
+        } else {
+            return None;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let mut baggage = Baggage::new();
+        baggage.insert("tenant-id", "acme").unwrap();
+        baggage.insert("user", "Zoë, 50%; \"admin\"").unwrap();
+        let encoded = baggage.encode();
+        assert_eq!(
+            encoded,
+            "tenant-id=acme,user=Zo%C3%AB%2C%2050%25%3B%20%22admin%22"
+        );
+        assert_eq!(Baggage::decode(&encoded).unwrap(), baggage);
+
+        let decoded = Baggage::decode(" a = 1 ;prop=x , b=2,, c=").unwrap();
+        let entries: Vec<_> = decoded.iter().collect();
+        assert_eq!(entries, vec![("a", "1"), ("b", "2"), ("c", "")]);
+    }
+
+    #[test]
+    fn test_validation() {
+        let mut baggage = Baggage::new();
+        assert_eq!(
+            baggage.insert("not a token", "x"),
+            Err(BaggageError::InvalidKey("not a token".to_string()))
+        );
+        assert!(baggage.insert("big", "x".repeat(MAX_LEN)).is_err());
+        assert!(baggage.is_empty());
+
+        for i in 0..MAX_ENTRIES {
+            baggage.insert(format!("k{i}"), "v").unwrap();
+        }
+        assert_eq!(
+            baggage.insert("one-more", "v"),
+            Err(BaggageError::TooManyEntries)
+        );
+        assert_eq!(baggage.len(), MAX_ENTRIES);
+        // replacing an entry is still fine
+        baggage.insert("k0", "w").unwrap();
+
+        assert!(matches!(
+            Baggage::decode("novalue"),
+            Err(BaggageError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            Baggage::decode("a=%zz"),
+            Err(BaggageError::InvalidValue(_))
+        ));
+        assert!(matches!(
+            Baggage::decode("a=b c"),
+            Err(BaggageError::InvalidValue(_))
+        ));
+        let too_many: Vec<_> = (0..=MAX_ENTRIES).map(|i| format!("k{i}=v")).collect();
+        assert_eq!(
+            Baggage::decode(&too_many.join(",")),
+            Err(BaggageError::TooManyEntries)
+        );
+        assert_eq!(
+            Baggage::decode(&format!("a={}", "x".repeat(MAX_LEN))),
+            Err(BaggageError::TooLong)
+        );
+    }
+
+    #[test]
+    fn test_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Baggage::from_headers(&headers), Ok(None));
+        headers.append(BAGGAGE, "a=1".parse().unwrap());
+        headers.append(BAGGAGE, "b=2".parse().unwrap());
+        let baggage = Baggage::from_headers(&headers).unwrap().unwrap();
+        assert_eq!(baggage.get("a"), Some("1"));
+        assert_eq!(baggage.get("b"), Some("2"));
+    }
+}
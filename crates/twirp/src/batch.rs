@@ -0,0 +1,189 @@
+//! A convention for batch rpcs where some items may fail without failing the whole call.
+//!
+//! The response message carries the results of the items that succeeded, in request order, and
+//! the errors of the others, keyed by the index of their item in the request:
+//!
+//! ```proto
+//! message CreateHatsResponse {
+//!   repeated Hat hats = 1;
+//!   // Item index -> Twirp error JSON, see `twirp::batch`.
+//!   map<uint32, string> errors = 2;
+//! }
+//! ```
+//!
+//! [`BatchResults`] converts between per-item `Result`s and these two fields:
+//!
+//! ```
+//! use twirp::batch::BatchResults;
+//! # struct Hat;
+//! # fn make_hat(size: i32) -> Result<Hat, twirp::TwirpErrorResponse> { Ok(Hat) }
+//!
+//! # fn handler(sizes: Vec<i32>) {
+//! // On the server:
+//! let results: BatchResults<Hat> = sizes.into_iter().map(make_hat).collect();
+//! let (hats, errors) = results.into_parts();
+//!
+//! // On the client, with `hats` and `errors` from the response:
+//! let results = BatchResults::from_parts(hats, errors);
+//! # }
+//! ```
+//!
+//! When the call has to fail as a whole anyway (e.g. a transactional batch), the per-item
+//! errors can be attached to the call's error instead, with [`BatchResults::into_error`].
+
+use std::collections::HashMap;
+
+use crate::{internal, TwirpErrorResponse};
+
+/// The `meta` key [`BatchResults::into_error`] puts the per-item errors under.
+pub const BATCH_ERRORS_META: &str = "batch_errors";
+
+/// The outcome of each item of a batch, in request order.
+#[derive(Debug, PartialEq)]
+pub struct BatchResults<T> {
+    items: Vec<Result<T, TwirpErrorResponse>>,
+}
+
+impl<T> BatchResults<T> {
+    pub fn new(items: Vec<Result<T, TwirpErrorResponse>>) -> Self {
+        Self { items }
+    }
+
+    pub fn items(&self) -> &[Result<T, TwirpErrorResponse>] {
+        &self.items
+    }
+
+    pub fn into_items(self) -> Vec<Result<T, TwirpErrorResponse>> {
+        self.items
+    }
+
+    /// The number of items that failed.
+    pub fn failure_count(&self) -> usize {
+        self.items.iter().filter(|item| item.is_err()).count()
+    }
+
+    /// Split the results into the successful items, in order, and the encoded errors of the
+    /// others, keyed by item index, for the response message.
+    pub fn into_parts(self) -> (Vec<T>, HashMap<u32, String>) {
+        let mut successes = vec![];
+        let mut errors = HashMap::new();
+        for (index, item) in self.items.into_iter().enumerate() {
+            match item {
+                Ok(item) => successes.push(item),
+                Err(err) => {
+                    errors.insert(index as u32, encode(&err));
+                }
+            }
+        }
+        (successes, errors)
+    }
+
+    /// Rebuild the results from the fields of a response message made with
+    /// [`BatchResults::into_parts`]. Errors that can't be decoded become `internal` errors.
+    pub fn from_parts(successes: Vec<T>, errors: HashMap<u32, String>) -> Self {
+        let len = successes.len() + errors.len();
+        let mut successes = successes.into_iter();
+        let items = (0..len as u32)
+            .filter_map(|index| match errors.get(&index) {
+                Some(err) => Some(Err(decode(err))),
+                None => successes.next().map(Ok),
+            })
+            .collect();
+        Self { items }
+    }
+
+    /// Attach the per-item errors to `err`, under the [`BATCH_ERRORS_META`] key of its `meta`, as
+    /// a JSON object of item index to Twirp error.
+    pub fn into_error(self, mut err: TwirpErrorResponse) -> TwirpErrorResponse {
+        let (_, errors) = self.into_parts();
+        let errors: HashMap<u32, serde_json::Value> = errors
+            .into_iter()
+            .map(|(index, item_err)| {
+                let value = serde_json::from_str(&item_err).expect("encoded as JSON");
+                (index, value)
+            })
+            .collect();
+        let errors = serde_json::to_string(&errors).expect("JSON serialization should not fail");
+        err.insert_meta(BATCH_ERRORS_META.to_string(), errors);
+        err
+    }
+
+    /// Read the per-item errors attached to `err` by [`BatchResults::into_error`], keyed by item
+    /// index.
+    pub fn errors_from_meta(err: &TwirpErrorResponse) -> HashMap<u32, TwirpErrorResponse> {
+        err.meta
+            .get(BATCH_ERRORS_META)
+            .and_then(|errors| serde_json::from_str(errors).ok())
+            .unwrap_or_default()
+    }
+}
+
+impl<T> FromIterator<Result<T, TwirpErrorResponse>> for BatchResults<T> {
+    fn from_iter<I: IntoIterator<Item = Result<T, TwirpErrorResponse>>>(iter: I) -> Self {
+        Self::new(iter.into_iter().collect())
+    }
+}
+
+fn encode(err: &TwirpErrorResponse) -> String {
+    serde_json::to_string(err).expect("JSON serialization of an error should not fail")
+}
+
+fn decode(err: &str) -> TwirpErrorResponse {
+    serde_json::from_str(err).unwrap_or_else(|e| {
+        let mut twirp_err = internal("invalid batch item error");
+        twirp_err.insert_meta("error".to_string(), e.to_string());
+        twirp_err
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{invalid_argument, not_found, TwirpErrorCode};
+
+    use super::*;
+
+    fn mixed() -> BatchResults<&'static str> {
+        BatchResults::new(vec![
+            Ok("a"),
+            Err(invalid_argument("bad b")),
+            Ok("c"),
+            Err(not_found("no d")),
+        ])
+    }
+
+    #[test]
+    fn test_mixed_outcomes() {
+        let results = mixed();
+        assert_eq!(results.failure_count(), 2);
+
+        let (successes, errors) = results.into_parts();
+        assert_eq!(successes, vec!["a", "c"]);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            serde_json::from_str::<TwirpErrorResponse>(&errors[&1]).unwrap(),
+            invalid_argument("bad b")
+        );
+
+        assert_eq!(BatchResults::from_parts(successes, errors), mixed());
+
+        let results = BatchResults::from_parts(vec!["a"], HashMap::from([(1, "junk".to_string())]));
+        assert_eq!(results.items()[0], Ok("a"));
+        assert_eq!(
+            results.items()[1].as_ref().unwrap_err().code,
+            TwirpErrorCode::Internal
+        );
+    }
+
+    #[test]
+    fn test_into_error() {
+        let err = mixed().into_error(invalid_argument("some items failed"));
+        assert_eq!(err.code, TwirpErrorCode::InvalidArgument);
+        let errors = BatchResults::<()>::errors_from_meta(&err);
+        assert_eq!(
+            errors,
+            HashMap::from([(1, invalid_argument("bad b")), (3, not_found("no d"))])
+        );
+
+        assert!(BatchResults::<()>::errors_from_meta(&internal("boom")).is_empty());
+    }
+}
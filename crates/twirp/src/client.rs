@@ -1,14 +1,32 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::vec;
 
 use async_trait::async_trait;
-use reqwest::header::{InvalidHeaderValue, CONTENT_TYPE};
+#[cfg(feature = "compression")]
+use reqwest::header::CONTENT_ENCODING;
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, InvalidHeaderValue, AUTHORIZATION, CONTENT_TYPE, COOKIE,
+    PROXY_AUTHORIZATION, RETRY_AFTER, SET_COOKIE,
+};
 use reqwest::StatusCode;
 use thiserror::Error;
 use url::Url;
 
-use crate::headers::{CONTENT_TYPE_JSON, CONTENT_TYPE_PROTOBUF};
-use crate::{serialize_proto_message, GenericError, TwirpErrorResponse};
+use crate::baggage::Baggage;
+#[cfg(feature = "compression")]
+use crate::compression;
+use crate::headers::{
+    is_protobuf_content_type, BAGGAGE, CONTENT_TYPE_JSON, CONTENT_TYPE_PROTOBUF, ERROR_CODE,
+    TIMEOUT_MS,
+};
+use crate::info::{ServerInfo, SERVER_INFO_PATH};
+use crate::{serialize_proto_message, GenericError, TwirpErrorCode, TwirpErrorResponse};
+
+/// The conventional path prefix of Twirp routes, see [`ClientBuilder::path_prefix`].
+pub const DEFAULT_PATH_PREFIX: &str = "twirp";
 
 #[derive(Debug, Error)]
 #[non_exhaustive]
@@ -17,6 +35,8 @@ pub enum ClientError {
     InvalidHeader(#[from] InvalidHeaderValue),
     #[error("base_url must end in /, but got: {0}")]
     InvalidBaseUrl(Url),
+    #[error("base_url scheme must be {expected}, but got: {url}")]
+    UnsupportedScheme { url: Url, expected: &'static str },
     #[error(transparent)]
     InvalidUrl(#[from] url::ParseError),
     #[error(
@@ -36,8 +56,18 @@ pub enum ClientError {
     ProtoDecodeError(#[from] prost::DecodeError),
     #[error(transparent)]
     ReqwestError(#[from] reqwest::Error),
-    #[error("twirp error: {0:?}")]
-    TwirpError(TwirpErrorResponse),
+    /// The server responded with a Twirp error, with the HTTP `status` of its response.
+    #[error("twirp error: {error:?}")]
+    TwirpError {
+        status: StatusCode,
+        error: TwirpErrorResponse,
+    },
+    /// A single attempt took longer than the budget of an [`AttemptTimeout`] middleware.
+    #[error("request attempt timed out after {0:?}")]
+    AttemptTimedOut(Duration),
+    /// The whole request took longer than its timeout, see [`ClientBuilder::timeout`].
+    #[error("request timed out after {0:?}")]
+    Timeout(Duration),
 
     /// A generic error that can be used by custom middleware.
     #[error(transparent)]
@@ -46,39 +76,377 @@ pub enum ClientError {
 
 pub type Result<T, E = ClientError> = std::result::Result<T, E>;
 
+impl ClientError {
+    /// The error the server responded with, if it responded with a Twirp error.
+    pub fn twirp_error(&self) -> Option<&TwirpErrorResponse> {
+        match self {
+            ClientError::TwirpError { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+
+    /// The code of the error the server responded with, to branch on, e.g. to treat `not_found`
+    /// differently from `permission_denied`.
+    pub fn code(&self) -> Option<TwirpErrorCode> {
+        self.twirp_error().map(|err| err.code)
+    }
+
+    /// The meta of the error the server responded with.
+    pub fn meta(&self) -> Option<&HashMap<String, String>> {
+        self.twirp_error().map(|err| &err.meta)
+    }
+
+    /// The HTTP status of the error response, if there was one. This is the status the server
+    /// actually sent, which for Twirp errors isn't always the one of their code (e.g. `200 OK`
+    /// with [`ClientBuilder::errors_in_body`]).
+    pub fn http_status(&self) -> Option<StatusCode> {
+        match self {
+            ClientError::HttpError { status, .. } | ClientError::TwirpError { status, .. } => {
+                Some(*status)
+            }
+            _ => None,
+        }
+    }
+}
+
 pub struct ClientBuilder {
     base_url: Url,
-    http_client: reqwest::Client,
+    /// `None` to build a `reqwest::Client` with the socket options below.
+    http_client: Option<reqwest::Client>,
+    tcp_nodelay: bool,
+    tcp_keepalive: Option<Duration>,
     middleware: Vec<Box<dyn Middleware>>,
+    #[cfg(feature = "compression")]
+    compress_request_threshold: Option<usize>,
+    default_timeout_ms: Option<u64>,
+    timeout: Option<Duration>,
+    errors_in_body: bool,
+    path_prefix: Option<String>,
+    path_prefix_segments: Vec<String>,
+    https_only: bool,
+    title_case_headers: bool,
+    coalesce_headers: bool,
+    resolve_overrides: Vec<(String, IpAddr)>,
+    default_headers: HeaderMap,
+    negotiate: bool,
+    #[cfg(feature = "metrics")]
+    metrics: bool,
 }
 
 impl ClientBuilder {
     pub fn new(base_url: Url, http_client: reqwest::Client) -> Self {
+        Self {
+            http_client: Some(http_client),
+            ..Self::from_base_url(base_url)
+        }
+    }
+
+    /// Like [`ClientBuilder::new`], but the `reqwest::Client` is built along with the twirp
+    /// `Client`, so that socket options like [`ClientBuilder::tcp_nodelay`] can be applied to it.
+    pub fn from_base_url(base_url: Url) -> Self {
         Self {
             base_url,
             middleware: vec![],
-            http_client,
+            http_client: None,
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            #[cfg(feature = "compression")]
+            compress_request_threshold: None,
+            default_timeout_ms: None,
+            timeout: None,
+            errors_in_body: false,
+            path_prefix: None,
+            path_prefix_segments: vec![],
+            https_only: false,
+            title_case_headers: false,
+            coalesce_headers: false,
+            resolve_overrides: vec![],
+            default_headers: HeaderMap::new(),
+            negotiate: false,
+            #[cfg(feature = "metrics")]
+            metrics: false,
         }
     }
 
     /// Add middleware to the client that will be called on each request.
     /// Middlewares are invoked in the order they are added as part of the
     /// request cycle.
-    pub fn with<M>(self, middleware: M) -> Self
+    pub fn with<M>(mut self, middleware: M) -> Self
     where
         M: Middleware,
     {
-        let mut mw = self.middleware;
-        mw.push(Box::new(middleware));
-        Self {
-            base_url: self.base_url,
-            http_client: self.http_client,
-            middleware: mw,
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Gzip request bodies (and set `Content-Encoding: gzip`) when the serialized message is
+    /// larger than `threshold` bytes. Only enable this for servers that accept gzip encoded
+    /// requests, as the `twirp` server does.
+    #[cfg(feature = "compression")]
+    pub fn compress_request_threshold(mut self, threshold: usize) -> Self {
+        self.compress_request_threshold = Some(threshold);
+        self
+    }
+
+    /// Send a [`TIMEOUT_MS`] header with every request, asking the server to give up on the
+    /// request after `timeout_ms` milliseconds (a `twirp` server fails it with
+    /// `deadline_exceeded` once its handler takes that long, see
+    /// [`ServerOptions::with_timeouts`](crate::server::ServerOptions::with_timeouts)).
+    /// Middleware that sets the header itself overrides this default for that request.
+    pub fn default_timeout_ms(mut self, timeout_ms: u64) -> Self {
+        self.default_timeout_ms = Some(timeout_ms);
+        self
+    }
+
+    /// Fail requests that take longer than `timeout`, middleware and retries included, with
+    /// [`ClientError::Timeout`], and send the timeout to the server in the [`TIMEOUT_MS`] header
+    /// (in place of [`ClientBuilder::default_timeout_ms`]). Override it for some calls with
+    /// [`Client::with_timeout`].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Understand error responses sent with a `200 OK` status by a server using
+    /// [`ServerOptions::with_errors_in_body`](crate::server::ServerOptions::with_errors_in_body).
+    /// These are returned as [`ClientError::TwirpError`], like any other Twirp error.
+    pub fn errors_in_body(mut self, enabled: bool) -> Self {
+        self.errors_in_body = enabled;
+        self
+    }
+
+    /// Send requests under `prefix` (conventionally [`DEFAULT_PATH_PREFIX`], `twirp`), relative to
+    /// the base url, instead of taking the base url as the mount point of the Twirp routes. This
+    /// way the same client configuration can point at the root of different environments, and at
+    /// servers mounting their routes somewhere else:
+    ///
+    /// ```
+    /// # use twirp::url::Url;
+    /// # fn build(env_url: Url) -> twirp::Result<twirp::Client> {
+    /// // e.g. `https://hats.staging.example.com`, calling
+    /// // `https://hats.staging.example.com/twirp/example.Haberdasher/MakeHat`
+    /// twirp::ClientBuilder::from_base_url(env_url)
+    ///     .path_prefix(twirp::client::DEFAULT_PATH_PREFIX)
+    ///     .build()
+    /// # }
+    /// ```
+    ///
+    /// Leading and trailing slashes of `prefix` are ignored, and with a prefix the base url
+    /// doesn't need to end in `/`: `http://host/api` and `http://host/api/` both send requests
+    /// to `http://host/api/twirp/...`.
+    pub fn path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.path_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Insert `segments` into the path of every request, between the base url and the service
+    /// name, e.g. to call `/twirp/v2/example.Service/Method` for URL-based API versioning.
+    ///
+    /// Pair this with a server that mounts the versioned routes under the same prefix, side by
+    /// side with the other versions:
+    ///
+    /// ```
+    /// # fn build(v1_routes: twirp::Router, v2_routes: twirp::Router) -> twirp::Router {
+    /// twirp::Router::new()
+    ///     .nest("/twirp", v1_routes)
+    ///     .nest("/twirp/v2", v2_routes)
+    /// # }
+    /// ```
+    pub fn path_prefix_segments<I, S>(mut self, segments: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.path_prefix_segments = segments.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set `TCP_NODELAY` on connections, sending small requests immediately instead of batching
+    /// them (Nagle's algorithm). Enabled by default.
+    ///
+    /// Socket options only apply to a client made with [`ClientBuilder::from_base_url`]. When
+    /// passing your own `reqwest::Client`, set them with `reqwest::ClientBuilder` instead.
+    pub fn tcp_nodelay(mut self, enabled: bool) -> Self {
+        self.tcp_nodelay = enabled;
+        self
+    }
+
+    /// Send TCP keepalive probes on idle connections every `interval`, where the platform
+    /// supports it, so that dead connections in the pool are detected. Disabled by default.
+    ///
+    /// Like [`ClientBuilder::tcp_nodelay`], this only applies to a client made with
+    /// [`ClientBuilder::from_base_url`].
+    pub fn tcp_keepalive(mut self, interval: Duration) -> Self {
+        self.tcp_keepalive = Some(interval);
+        self
+    }
+
+    /// Send header names in title case (`Content-Type`) rather than lowercase over HTTP/1, for
+    /// servers that are strict about casing. Disabled by default.
+    ///
+    /// Like [`ClientBuilder::tcp_nodelay`], this only applies to a client made with
+    /// [`ClientBuilder::from_base_url`].
+    pub fn title_case_headers(mut self, enabled: bool) -> Self {
+        self.title_case_headers = enabled;
+        self
+    }
+
+    /// Connect to `ip` instead of resolving `host`, e.g. to reach a specific backend behind a
+    /// load balancer. Requests still carry `host` in their `Host` header and in TLS (SNI and
+    /// certificate verification), and use the port of the base url.
+    ///
+    /// Like [`ClientBuilder::tcp_nodelay`], this only applies to a client made with
+    /// [`ClientBuilder::from_base_url`].
+    pub fn resolve(mut self, host: impl Into<String>, ip: IpAddr) -> Self {
+        self.resolve_overrides.push((host.into(), ip));
+        self
+    }
+
+    /// Merge repeated request headers into a single header before sending, dropping duplicate
+    /// values and joining the rest with `, ` (`; ` for `Cookie`), for servers that don't accept
+    /// repeated headers. This applies after all middleware has run. Disabled by default.
+    pub fn coalesce_headers(mut self, enabled: bool) -> Self {
+        self.coalesce_headers = enabled;
+        self
+    }
+
+    /// Send `value` in the `name` header of every request, e.g. a `User-Agent`, an auth token or
+    /// a tenant id, in addition to any default headers set before.
+    ///
+    /// Default headers have the lowest precedence: headers set by the client itself (e.g.
+    /// `Content-Type`, and the `baggage` of [`Client::with_baggage`]) replace them, and so does
+    /// any middleware, which runs after the default headers are added.
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.default_headers.append(name, value);
+        self
+    }
+
+    /// Like [`ClientBuilder::with_header`], for every header of `headers`.
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        for (name, value) in &headers {
+            self.default_headers.append(name, value.clone());
         }
+        self
+    }
+
+    /// Adapt requests to the [`ServerInfo`] the server publishes at [`SERVER_INFO_PATH`], fetched
+    /// once before the first request (see [`Client::server_info`]): requests are sent with the
+    /// first protobuf content type the server lists, e.g. `application/x-protobuf` for older
+    /// servers. Servers without the endpoint, or that can't be reached, get the defaults, and
+    /// aren't asked again. Disabled by default.
+    pub fn negotiate(mut self, enabled: bool) -> Self {
+        self.negotiate = enabled;
+        self
+    }
+
+    /// Record metrics for every request with the [`metrics`] facade, labelled with the `service`
+    /// and `method` of the request, for whatever recorder the application installs:
+    ///
+    /// - `twirp_client_requests_total`: a counter of requests.
+    /// - `twirp_client_errors_total`: a counter of failed requests, also labelled with the Twirp
+    ///   error `code` (`deadline_exceeded` for [`ClientError::AttemptTimedOut`] and
+    ///   [`ClientError::Timeout`], and `unknown` for errors without a Twirp error code, e.g.
+    ///   connection errors).
+    /// - `twirp_client_request_duration_seconds`: a histogram of request latencies, including
+    ///   the middleware.
+    ///
+    /// Disabled by default.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(mut self, enabled: bool) -> Self {
+        self.metrics = enabled;
+        self
+    }
+
+    /// Only accept an `https` base url, so that a misconfigured `http` url is rejected by
+    /// [`ClientBuilder::build`] instead of sending requests in plain text. Disabled by default.
+    pub fn https_only(mut self, enabled: bool) -> Self {
+        self.https_only = enabled;
+        self
     }
 
+    /// Build the client, checking that the base url is an `http` or `https` url (only `https`
+    /// with [`ClientBuilder::https_only`]) ending in `/`, unless there is a
+    /// [path prefix](ClientBuilder::path_prefix). Such urls always have a host: `Url` refuses to
+    /// parse them without one.
     pub fn build(self) -> Result<Client> {
-        Client::new(self.base_url, self.http_client, self.middleware)
+        let expected = if self.https_only {
+            "https"
+        } else {
+            "http or https"
+        };
+        match self.base_url.scheme() {
+            "https" => {}
+            "http" if !self.https_only => {}
+            _ => {
+                return Err(ClientError::UnsupportedScheme {
+                    url: self.base_url,
+                    expected,
+                })
+            }
+        }
+        if self.path_prefix.is_none() && !self.base_url.path().ends_with('/') {
+            return Err(ClientError::InvalidBaseUrl(self.base_url));
+        }
+        let mut request_base_url = self.base_url.clone();
+        if self.path_prefix.is_some() || !self.path_prefix_segments.is_empty() {
+            let prefix = self
+                .path_prefix
+                .as_deref()
+                .unwrap_or_default()
+                .split('/')
+                .filter(|segment| !segment.is_empty());
+            request_base_url
+                .path_segments_mut()
+                .map_err(|()| ClientError::InvalidBaseUrl(self.base_url.clone()))?
+                .pop_if_empty()
+                .extend(prefix)
+                .extend(&self.path_prefix_segments)
+                .push("");
+        }
+        let http_client = match self.http_client {
+            Some(http_client) => http_client,
+            None => {
+                let mut builder = reqwest::Client::builder()
+                    .tcp_nodelay(self.tcp_nodelay)
+                    .tcp_keepalive(self.tcp_keepalive);
+                if self.title_case_headers {
+                    builder = builder.http1_title_case_headers();
+                }
+                for (host, ip) in &self.resolve_overrides {
+                    // Port 0 keeps the port of the url.
+                    builder = builder.resolve(host, SocketAddr::new(*ip, 0));
+                }
+                builder.build()?
+            }
+        };
+        let middleware_count = self.middleware.len();
+        let mut middlewares = self.middleware;
+        if self.coalesce_headers {
+            middlewares.push(Box::new(CoalesceHeaders));
+        }
+        Ok(Client {
+            http_client,
+            inner: Arc::new(ClientRef {
+                base_url: self.base_url,
+                request_base_url,
+                middlewares,
+                #[cfg(feature = "compression")]
+                compress_request_threshold: self.compress_request_threshold,
+                default_timeout_ms: self.default_timeout_ms,
+                timeout: self.timeout,
+                errors_in_body: self.errors_in_body,
+                middleware_count,
+                coalesce_headers: self.coalesce_headers,
+                default_headers: self.default_headers,
+                negotiate: self.negotiate,
+                server_info: tokio::sync::OnceCell::new(),
+                #[cfg(feature = "metrics")]
+                metrics: self.metrics,
+            }),
+            host: None,
+            baggage: None,
+            timeout: None,
+        })
     }
 }
 
@@ -92,11 +460,52 @@ pub struct Client {
     http_client: reqwest::Client,
     inner: Arc<ClientRef>,
     host: Option<String>,
+    baggage: Option<HeaderValue>,
+    /// Overrides the timeout of the [`ClientRef`], see [`Client::with_timeout`].
+    timeout: Option<Duration>,
 }
 
 struct ClientRef {
     base_url: Url,
+    /// The base url with the path prefix segments, if any.
+    request_base_url: Url,
     middlewares: Vec<Box<dyn Middleware>>,
+    #[cfg(feature = "compression")]
+    compress_request_threshold: Option<usize>,
+    default_timeout_ms: Option<u64>,
+    timeout: Option<Duration>,
+    errors_in_body: bool,
+    /// The number of middlewares added with [`ClientBuilder::with`].
+    middleware_count: usize,
+    coalesce_headers: bool,
+    /// Added to every request, unless it has a header of the same name.
+    default_headers: HeaderMap,
+    negotiate: bool,
+    /// The server info, once fetched. `None` if the server doesn't publish it.
+    server_info: tokio::sync::OnceCell<Option<ServerInfo>>,
+    #[cfg(feature = "metrics")]
+    metrics: bool,
+}
+
+/// The effective configuration of a [`Client`], see [`Client::config`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ClientConfig {
+    pub base_url: Url,
+    /// The url requests are made relative to: the base url with any
+    /// [path prefix segments](ClientBuilder::path_prefix_segments).
+    pub request_base_url: Url,
+    /// The host requests are sent to instead of the base url's, see [`Client::with_host`].
+    pub host: Option<String>,
+    pub default_timeout_ms: Option<u64>,
+    /// The timeout of requests, see [`ClientBuilder::timeout`] and [`Client::with_timeout`].
+    pub timeout: Option<Duration>,
+    #[cfg(feature = "compression")]
+    pub compress_request_threshold: Option<usize>,
+    pub errors_in_body: bool,
+    pub coalesce_headers: bool,
+    /// The number of middlewares added with [`ClientBuilder::with`].
+    pub middleware_count: usize,
 }
 
 impl std::fmt::Debug for Client {
@@ -119,18 +528,9 @@ impl Client {
         http_client: reqwest::Client,
         middlewares: Vec<Box<dyn Middleware>>,
     ) -> Result<Self> {
-        if base_url.path().ends_with('/') {
-            Ok(Client {
-                http_client,
-                inner: Arc::new(ClientRef {
-                    base_url,
-                    middlewares,
-                }),
-                host: None,
-            })
-        } else {
-            Err(ClientError::InvalidBaseUrl(base_url))
-        }
+        let mut builder = ClientBuilder::new(base_url, http_client);
+        builder.middleware = middlewares;
+        builder.build()
     }
 
     /// Creates a `twirp::Client` with the default `reqwest::ClientBuilder`.
@@ -138,13 +538,30 @@ impl Client {
     /// The underlying `reqwest::Client` holds a connection pool internally, so it is advised that
     /// you create one and **reuse** it.
     pub fn from_base_url(base_url: Url) -> Result<Self> {
-        Self::new(base_url, reqwest::Client::new(), vec![])
+        ClientBuilder::from_base_url(base_url).build()
     }
 
     pub fn base_url(&self) -> &Url {
         &self.inner.base_url
     }
 
+    /// The effective configuration of the client, e.g. to log it when diagnosing misconfigured
+    /// clients. Options of the underlying `reqwest::Client` are not included.
+    pub fn config(&self) -> ClientConfig {
+        ClientConfig {
+            base_url: self.inner.base_url.clone(),
+            request_base_url: self.inner.request_base_url.clone(),
+            host: self.host.clone(),
+            default_timeout_ms: self.inner.default_timeout_ms,
+            timeout: self.timeout(),
+            #[cfg(feature = "compression")]
+            compress_request_threshold: self.inner.compress_request_threshold,
+            errors_in_body: self.inner.errors_in_body,
+            coalesce_headers: self.inner.coalesce_headers,
+            middleware_count: self.inner.middleware_count,
+        }
+    }
+
     /// Creates a new `twirp::Client` with the same configuration as the current
     /// one, but with a different host in the base URL.
     pub fn with_host(&self, host: &str) -> Self {
@@ -152,47 +569,281 @@ impl Client {
             http_client: self.http_client.clone(),
             inner: self.inner.clone(),
             host: Some(host.to_string()),
+            baggage: self.baggage.clone(),
+            timeout: self.timeout,
+        }
+    }
+
+    /// Creates a new `twirp::Client` with the same configuration as the current one, sending
+    /// `baggage` in the `baggage` header of its requests (replacing any baggage set before), e.g.
+    /// to pass on the baggage of the request being handled. See [`crate::baggage`].
+    pub fn with_baggage(&self, baggage: &Baggage) -> Self {
+        let baggage = (!baggage.is_empty()).then(|| {
+            HeaderValue::from_str(&baggage.encode()).expect("encoded baggage is a valid header")
+        });
+        Self {
+            http_client: self.http_client.clone(),
+            inner: self.inner.clone(),
+            host: self.host.clone(),
+            baggage,
+            timeout: self.timeout,
+        }
+    }
+
+    /// Creates a new `twirp::Client` with the same configuration as the current one, but failing
+    /// requests that take longer than `timeout`, in place of [`ClientBuilder::timeout`].
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Self {
+            http_client: self.http_client.clone(),
+            inner: self.inner.clone(),
+            host: self.host.clone(),
+            baggage: self.baggage.clone(),
+            timeout: Some(timeout),
+        }
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout.or(self.inner.timeout)
+    }
+
+    /// The [`ServerInfo`] the server publishes at [`SERVER_INFO_PATH`] (on the host of the base
+    /// url), or `None` if it doesn't. It is fetched on first use and cached for the lifetime of the
+    /// client, and its copies. The fetch is sent with the client's default headers (see
+    /// [`ClientBuilder::with_header`]), but not through its middleware. A failed fetch is
+    /// returned as an error by the call that made it,
+    /// and cached as `None`, so that an unreachable or broken endpoint doesn't cost every request
+    /// another round trip.
+    pub async fn server_info(&self) -> Result<Option<ServerInfo>> {
+        let mut fetch_err = None;
+        let info = self
+            .inner
+            .server_info
+            .get_or_init(|| async {
+                self.fetch_server_info().await.unwrap_or_else(|err| {
+                    fetch_err = Some(err);
+                    None
+                })
+            })
+            .await;
+        match fetch_err {
+            Some(err) => Err(err),
+            None => Ok(info.clone()),
+        }
+    }
+
+    async fn fetch_server_info(&self) -> Result<Option<ServerInfo>> {
+        let mut url = self.inner.base_url.join(SERVER_INFO_PATH)?;
+        if let Some(host) = &self.host {
+            url.set_host(Some(host))?
+        };
+        let req = self
+            .http_client
+            .get(url)
+            .headers(self.inner.default_headers.clone());
+        let resp = self.within_timeout(async { Ok(req.send().await?) }).await?;
+        if !resp.status().is_success() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&resp.bytes().await?)?))
+    }
+
+    /// The content type to send requests with, see [`ClientBuilder::negotiate`].
+    async fn request_content_type(&self) -> HeaderValue {
+        let default = HeaderValue::from_bytes(CONTENT_TYPE_PROTOBUF).expect("a valid content type");
+        if !self.inner.negotiate {
+            return default;
+        }
+        let Ok(Some(info)) = self.server_info().await else {
+            return default;
+        };
+        info.content_types
+            .iter()
+            .find(|ct| is_protobuf_content_type(ct.as_bytes()))
+            .and_then(|ct| HeaderValue::from_str(ct).ok())
+            .unwrap_or(default)
+    }
+
+    /// Run `call`, failing with [`ClientError::Timeout`] if it takes longer than the timeout.
+    pub(crate) async fn within_timeout<T>(
+        &self,
+        call: impl std::future::Future<Output = Result<T>>,
+    ) -> Result<T> {
+        match self.timeout() {
+            Some(timeout) => tokio::time::timeout(timeout, call)
+                .await
+                .unwrap_or(Err(ClientError::Timeout(timeout))),
+            None => call.await,
         }
     }
 
     /// Make an HTTP twirp request.
+    ///
+    /// The returned future is cancellation-safe: dropping it before it resolves aborts the
+    /// in-flight HTTP request (closing its connection) and stops any remaining middleware, rather
+    /// than letting the current attempt run to completion in the background.
     pub async fn request<I, O>(&self, path: &str, body: I) -> Result<O>
     where
         I: prost::Message,
         O: prost::Message + Default,
     {
-        let mut url = self.inner.base_url.join(path)?;
+        let (resp, _) = self.request_with_timings(path, body).await?;
+        Ok(resp)
+    }
+
+    /// Like [`Client::request`], but also returning the [`ClientTimings`] of the request, to tell
+    /// serialization overhead apart from network time.
+    pub async fn request_with_timings<I, O>(
+        &self,
+        path: &str,
+        body: I,
+    ) -> Result<(O, ClientTimings)>
+    where
+        I: prost::Message,
+        O: prost::Message + Default,
+    {
+        let mut timings = ClientTimings::new(Instant::now());
+        let resp = self.call(path, body, &mut timings).await?;
+        Ok((resp.message, timings))
+    }
+
+    /// Like [`Client::request`], but also returning the status and headers of the HTTP response,
+    /// e.g. to read rate limit counters or request ids set by the server.
+    pub async fn request_with_response<I, O>(
+        &self,
+        path: &str,
+        body: I,
+    ) -> Result<ClientResponse<O>>
+    where
+        I: prost::Message,
+        O: prost::Message + Default,
+    {
+        let mut timings = ClientTimings::new(Instant::now());
+        self.call(path, body, &mut timings).await
+    }
+
+    async fn call<I, O>(
+        &self,
+        path: &str,
+        body: I,
+        timings: &mut ClientTimings,
+    ) -> Result<ClientResponse<O>>
+    where
+        I: prost::Message,
+        O: prost::Message + Default,
+    {
+        let result = self
+            .within_timeout(async {
+                let (resp, path) = self.send(path, body, timings).await?;
+                let status = resp.status();
+                let headers = resp.headers().clone();
+                let message = self.read_response(resp, path).await?;
+                timings.set_deserialized();
+                Ok(ClientResponse {
+                    message,
+                    status,
+                    headers,
+                })
+            })
+            .await;
+        #[cfg(feature = "metrics")]
+        if self.inner.metrics {
+            record_metrics(path, result.as_ref().err(), timings.total_duration());
+        }
+        result
+    }
+
+    /// Send a twirp request through the middleware, returning the raw response along with the
+    /// path it was sent to.
+    pub(crate) async fn send<I>(
+        &self,
+        path: &str,
+        body: I,
+        timings: &mut ClientTimings,
+    ) -> Result<(reqwest::Response, String)>
+    where
+        I: prost::Message,
+    {
+        let mut url = self.inner.request_base_url.join(path)?;
         if let Some(host) = &self.host {
             url.set_host(Some(host))?
         };
         let path = url.path().to_string();
-        let req = self
+        let body = serialize_proto_message(body);
+        #[cfg(feature = "compression")]
+        let (body, gzipped) = match self.inner.compress_request_threshold {
+            Some(threshold) if body.len() > threshold => (compression::gzip(&body), true),
+            _ => (body, false),
+        };
+        // Fetching the server info on first use is part of the round trip.
+        timings.set_serialized();
+        let content_type = self.request_content_type().await;
+        let mut req = self
             .http_client
             .post(url)
-            .header(CONTENT_TYPE, CONTENT_TYPE_PROTOBUF)
-            .body(serialize_proto_message(body))
-            .build()?;
+            .header(CONTENT_TYPE, content_type);
+        if let Some(baggage) = &self.baggage {
+            req = req.header(BAGGAGE, baggage.clone());
+        }
+        #[cfg(feature = "compression")]
+        if gzipped {
+            req = req.header(CONTENT_ENCODING, compression::GZIP);
+        }
+        let timeout_ms = match self.timeout() {
+            Some(timeout) => Some(u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX)),
+            None => self.inner.default_timeout_ms,
+        };
+        if let Some(timeout_ms) = timeout_ms {
+            req = req.header(TIMEOUT_MS, timeout_ms);
+        }
+        let mut req = req.body(body).build()?;
+        for name in self.inner.default_headers.keys() {
+            if !req.headers().contains_key(name) {
+                for value in self.inner.default_headers.get_all(name) {
+                    req.headers_mut().append(name, value.clone());
+                }
+            }
+        }
 
         // Create and execute the middleware handlers
-        let next = Next::new(&self.http_client, &self.inner.middlewares);
+        let next = Next::new(&self.http_client, &self.inner.middlewares, *timings);
         let resp = next.run(req).await?;
+        timings.set_response_received();
+        Ok((resp, path))
+    }
 
+    /// Decode a twirp response into either the response message or an error.
+    pub(crate) async fn read_response<O>(&self, resp: reqwest::Response, path: String) -> Result<O>
+    where
+        O: prost::Message + Default,
+    {
         // These have to be extracted because reading the body consumes `Response`.
         let status = resp.status();
         let content_type = resp.headers().get(CONTENT_TYPE).cloned();
+        let error_in_body = self.inner.errors_in_body && resp.headers().contains_key(ERROR_CODE);
 
         // TODO: Include more info in the error cases: request path, content-type, etc.
         match (status, content_type) {
-            (status, Some(ct)) if status.is_success() && ct.as_bytes() == CONTENT_TYPE_PROTOBUF => {
+            (status, Some(ct))
+                if status.is_success() && error_in_body && ct.as_bytes() == CONTENT_TYPE_JSON =>
+            {
+                Err(ClientError::TwirpError {
+                    status,
+                    error: serde_json::from_slice(&resp.bytes().await?)?,
+                })
+            }
+            (status, Some(ct))
+                if status.is_success() && is_protobuf_content_type(ct.as_bytes()) =>
+            {
                 O::decode(resp.bytes().await?).map_err(|e| e.into())
             }
             (status, Some(ct))
                 if (status.is_client_error() || status.is_server_error())
                     && ct.as_bytes() == CONTENT_TYPE_JSON =>
             {
-                Err(ClientError::TwirpError(serde_json::from_slice(
-                    &resp.bytes().await?,
-                )?))
+                Err(ClientError::TwirpError {
+                    status,
+                    error: serde_json::from_slice(&resp.bytes().await?)?,
+                })
             }
             (status, ct) => Err(ClientError::HttpError {
                 status,
@@ -206,6 +857,45 @@ impl Client {
     }
 }
 
+/// A successful response, see [`Client::request_with_response`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ClientResponse<O> {
+    /// The decoded response message.
+    pub message: O,
+    /// The status of the HTTP response.
+    pub status: StatusCode,
+    /// The headers of the HTTP response.
+    pub headers: HeaderMap,
+}
+
+/// Record the metrics of a request to `path` (e.g. `example.Haberdasher/MakeHat`), see
+/// [`ClientBuilder::metrics`].
+#[cfg(feature = "metrics")]
+fn record_metrics(path: &str, err: Option<&ClientError>, elapsed: Duration) {
+    let (service, method) = path
+        .trim_start_matches('/')
+        .rsplit_once('/')
+        .unwrap_or(("", path));
+    let labels = [
+        ("service", service.to_string()),
+        ("method", method.to_string()),
+    ];
+    metrics::counter!("twirp_client_requests_total", &labels).increment(1);
+    metrics::histogram!("twirp_client_request_duration_seconds", &labels)
+        .record(elapsed.as_secs_f64());
+    if let Some(err) = err {
+        let code = match err {
+            ClientError::TwirpError { error, .. } => error.code.twirp_code(),
+            ClientError::AttemptTimedOut(_) | ClientError::Timeout(_) => "deadline_exceeded",
+            _ => "unknown",
+        };
+        let [service, method] = labels;
+        let labels = [service, method, ("code", code.to_string())];
+        metrics::counter!("twirp_client_errors_total", &labels).increment(1);
+    }
+}
+
 // This concept of reqwest middleware is taken pretty much directly from:
 // https://github.com/TrueLayer/reqwest-middleware, but simplified for the
 // specific needs of this twirp client.
@@ -227,22 +917,102 @@ where
     }
 }
 
+/// Contains timing information associated with a client request, the counterpart of the server's
+/// [`Timings`](crate::server::Timings). See [`Client::request_with_timings`] and
+/// [`Next::timings`].
+#[derive(Debug, Clone, Copy)]
+pub struct ClientTimings {
+    // When the request started.
+    start: Instant,
+    // When the request was serialized.
+    serialized: Option<Instant>,
+    // When the response headers were received, after all middleware.
+    response_received: Option<Instant>,
+    // When the response body was read and deserialized.
+    deserialized: Option<Instant>,
+}
+
+impl ClientTimings {
+    pub(crate) fn new(start: Instant) -> Self {
+        Self {
+            start,
+            serialized: None,
+            response_received: None,
+            deserialized: None,
+        }
+    }
+
+    fn set_serialized(&mut self) {
+        self.serialized = Some(Instant::now());
+    }
+
+    fn set_response_received(&mut self) {
+        self.response_received = Some(Instant::now());
+    }
+
+    fn set_deserialized(&mut self) {
+        self.deserialized = Some(Instant::now());
+    }
+
+    /// How long it took to build the request, including serialization and compression.
+    pub fn serialized(&self) -> Option<Duration> {
+        self.serialized.map(|x| x - self.start)
+    }
+
+    /// The round trip through the middleware and the network, up to the response headers. This
+    /// includes fetching the server info for [`ClientBuilder::negotiate`], on first use.
+    pub fn round_trip(&self) -> Option<Duration> {
+        match (self.response_received, self.serialized) {
+            (Some(received), Some(serialized)) => Some(received - serialized),
+            _ => None,
+        }
+    }
+
+    /// How long it took to read and deserialize the response body.
+    pub fn deserialized(&self) -> Option<Duration> {
+        match (self.deserialized, self.response_received) {
+            (Some(deserialized), Some(received)) => Some(deserialized - received),
+            _ => None,
+        }
+    }
+
+    /// The total duration since the request started.
+    pub fn total_duration(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
 #[derive(Clone)]
 pub struct Next<'a> {
     client: &'a reqwest::Client,
     middlewares: &'a [Box<dyn Middleware>],
+    timings: ClientTimings,
 }
 
 pub type BoxFuture<'a, T> = std::pin::Pin<Box<dyn std::future::Future<Output = T> + Send + 'a>>;
 
 impl<'a> Next<'a> {
-    pub(crate) fn new(client: &'a reqwest::Client, middlewares: &'a [Box<dyn Middleware>]) -> Self {
+    pub(crate) fn new(
+        client: &'a reqwest::Client,
+        middlewares: &'a [Box<dyn Middleware>],
+        timings: ClientTimings,
+    ) -> Self {
         Next {
             client,
             middlewares,
+            timings,
         }
     }
 
+    /// The timings of the request so far: when it started and how long it took to serialize. A
+    /// middleware can time the round trip by timing [`Next::run`].
+    pub fn timings(&self) -> ClientTimings {
+        self.timings
+    }
+
+    /// Run the remaining middleware and then send the request.
+    ///
+    /// Dropping the returned future cancels the request wherever it currently is in the chain.
     pub fn run(mut self, req: reqwest::Request) -> BoxFuture<'a, Result<reqwest::Response>> {
         if let Some((current, rest)) = self.middlewares.split_first() {
             self.middlewares = rest;
@@ -253,48 +1023,507 @@ impl<'a> Next<'a> {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use reqwest::{Request, Response};
+/// Middleware that fails a request with [`ClientError::AttemptTimedOut`] when it takes longer than
+/// `budget` to get a response, to fail fast on stalled connections.
+///
+/// The budget covers the rest of the middleware chain and the request up to the response headers.
+/// It applies to each attempt separately, as opposed to the deadline of the whole call, so add it
+/// after any middleware that retries requests:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use twirp::client::AttemptTimeout;
+/// # fn build(base_url: twirp::url::Url) -> twirp::Result<twirp::Client> {
+/// twirp::ClientBuilder::from_base_url(base_url)
+///     // .with(retries)
+///     .with(AttemptTimeout::new(Duration::from_millis(250)))
+///     .build()
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct AttemptTimeout {
+    budget: Duration,
+}
 
-    use crate::test::*;
+impl AttemptTimeout {
+    pub fn new(budget: Duration) -> Self {
+        Self { budget }
+    }
+}
 
-    use super::*;
+#[async_trait]
+impl Middleware for AttemptTimeout {
+    async fn handle(&self, req: reqwest::Request, next: Next<'_>) -> Result<reqwest::Response> {
+        tokio::time::timeout(self.budget, next.run(req))
+            .await
+            .unwrap_or(Err(ClientError::AttemptTimedOut(self.budget)))
+    }
+}
 
-    struct AssertRouting {
-        expected_url: &'static str,
+/// Middleware that retries requests that failed without being processed: connection errors,
+/// attempts that timed out (see [`AttemptTimeout`]), and `unavailable` and `resource_exhausted`
+/// Twirp errors. Any other error, e.g. `invalid_argument`, is returned immediately.
+///
+/// Only use it for idempotent rpcs: an `unavailable` error or a timed out attempt doesn't
+/// guarantee that the server didn't act on the request.
+///
+/// Between attempts it waits for the delay of the server's `Retry-After` header (in seconds), or
+/// else of its [`Backoff`] policy, [`ExponentialBackoff`] by default:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use twirp::client::{AttemptTimeout, ExponentialBackoff, RetryMiddleware};
+/// # fn build(base_url: twirp::url::Url) -> twirp::Result<twirp::Client> {
+/// twirp::ClientBuilder::from_base_url(base_url)
+///     .with(
+///         RetryMiddleware::new(3).with_backoff(ExponentialBackoff::new(
+///             Duration::from_millis(50),
+///             Duration::from_secs(2),
+///         )),
+///     )
+///     .with(AttemptTimeout::new(Duration::from_millis(250)))
+///     .build()
+/// # }
+/// ```
+///
+/// Requests with a streaming body can't be retried, and are sent just once.
+pub struct RetryMiddleware {
+    max_attempts: u32,
+    backoff: Box<dyn Backoff>,
+}
+
+impl RetryMiddleware {
+    /// Retry requests until they have been attempted `max_attempts` times (including the first).
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            backoff: Box::new(ExponentialBackoff::default()),
+        }
     }
 
-    #[async_trait]
-    impl Middleware for AssertRouting {
-        async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response> {
-            assert_eq!(self.expected_url, &req.url().to_string());
-            next.run(req).await
+    /// Wait between attempts according to `backoff`.
+    pub fn with_backoff<B>(mut self, backoff: B) -> Self
+    where
+        B: Backoff,
+    {
+        self.backoff = Box::new(backoff);
+        self
+    }
+}
+
+#[async_trait]
+impl Middleware for RetryMiddleware {
+    async fn handle(&self, mut req: reqwest::Request, next: Next<'_>) -> Result<reqwest::Response> {
+        let mut attempt = 1;
+        loop {
+            let retry = match req.try_clone() {
+                Some(retry) if attempt < self.max_attempts => retry,
+                _ => return next.run(req).await,
+            };
+            let result = next.clone().run(req).await;
+            let delay = match &result {
+                Ok(resp) if is_retryable_response(resp) => {
+                    retry_after(resp.headers()).unwrap_or_else(|| self.backoff.delay(attempt))
+                }
+                Err(err) if is_retryable_error(err) => self.backoff.delay(attempt),
+                _ => return result,
+            };
+            drop(result);
+            tokio::time::sleep(delay).await;
+            req = retry;
+            attempt += 1;
         }
     }
+}
 
-    #[tokio::test]
-    async fn test_base_url() {
-        let url = Url::parse("http://localhost:3001/twirp/").unwrap();
-        assert!(Client::from_base_url(url).is_ok());
-        let url = Url::parse("http://localhost:3001/twirp").unwrap();
-        assert_eq!(
-            Client::from_base_url(url).unwrap_err().to_string(),
-            "base_url must end in /, but got: http://localhost:3001/twirp",
-        );
+fn is_retryable_response(resp: &reqwest::Response) -> bool {
+    match resp.headers().get(ERROR_CODE) {
+        Some(code) => code == "unavailable" || code == "resource_exhausted",
+        None => matches!(
+            resp.status(),
+            StatusCode::SERVICE_UNAVAILABLE | StatusCode::TOO_MANY_REQUESTS
+        ),
     }
+}
 
-    #[tokio::test]
-    async fn test_routes() {
-        let base_url = Url::parse("http://localhost:3001/twirp/").unwrap();
+fn is_retryable_error(err: &ClientError) -> bool {
+    match err {
+        ClientError::ReqwestError(err) => err.is_connect(),
+        ClientError::AttemptTimedOut(_) => true,
+        _ => false,
+    }
+}
 
-        let client = ClientBuilder::new(base_url, reqwest::Client::new())
-            .with(AssertRouting {
-                expected_url: "http://localhost:3001/twirp/test.TestAPI/Ping",
-            })
-            .build()
-            .unwrap();
-        assert!(client
+/// The delay of a `Retry-After` header in seconds. HTTP dates aren't supported.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let seconds = headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+/// How long [`RetryMiddleware`] waits before retrying a request.
+pub trait Backoff: 'static + Send + Sync {
+    /// The delay before the `retry`th retry, counting from 1.
+    fn delay(&self, retry: u32) -> Duration;
+}
+
+impl<F> Backoff for F
+where
+    F: 'static + Send + Sync + Fn(u32) -> Duration,
+{
+    fn delay(&self, retry: u32) -> Duration {
+        (self)(retry)
+    }
+}
+
+/// Exponential backoff with jitter: the delay doubles with every retry, starting from `initial`
+/// and up to `max`, and is then randomly reduced by up to half so that clients that failed at
+/// the same time don't all retry at the same time.
+#[derive(Debug, Clone, Copy)]
+pub struct ExponentialBackoff {
+    initial: Duration,
+    max: Duration,
+}
+
+impl ExponentialBackoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max }
+    }
+}
+
+impl Default for ExponentialBackoff {
+    /// 100ms, doubling up to 10s.
+    fn default() -> Self {
+        Self::new(Duration::from_millis(100), Duration::from_secs(10))
+    }
+}
+
+impl Backoff for ExponentialBackoff {
+    fn delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        let delay = self.initial.saturating_mul(factor).min(self.max);
+        delay.mul_f64(1.0 - random_fraction() / 2.0)
+    }
+}
+
+/// A random number in `[0, 1)`, from the randomly seeded hasher of the standard library.
+fn random_fraction() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// How much [`LoggingMiddleware`] logs about each request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum LogVerbosity {
+    /// The method path, the content type and the response status.
+    #[default]
+    Summary,
+    /// Also the request and response headers.
+    Headers,
+    /// Also the request and response bodies, with JSON pretty-printed. The client sends protobuf
+    /// and Twirp servers answer in kind, so in practice only error responses are JSON; protobuf
+    /// bodies are logged as their size. Responses are read into memory to log them, so only use
+    /// this for debugging.
+    Bodies,
+}
+
+/// Middleware that logs the requests and responses going over the wire, for debugging:
+///
+/// ```
+/// # use twirp::client::{LogVerbosity, LoggingMiddleware};
+/// # fn build(base_url: twirp::url::Url) -> twirp::Result<twirp::Client> {
+/// twirp::ClientBuilder::from_base_url(base_url)
+///     .with(
+///         LoggingMiddleware::new(|message| eprintln!("{message}"))
+///             .with_verbosity(LogVerbosity::Headers)
+///             .with_redacted_header(twirp::reqwest::header::HeaderName::from_static("x-api-key")),
+///     )
+///     .build()
+/// # }
+/// ```
+///
+/// The values of the `authorization`, `proxy-authorization`, `cookie` and `set-cookie` headers,
+/// and of any added with [`LoggingMiddleware::with_redacted_header`], are never logged.
+pub struct LoggingMiddleware {
+    verbosity: LogVerbosity,
+    redacted_headers: Vec<HeaderName>,
+    sink: Box<dyn Fn(&str) + Send + Sync>,
+}
+
+impl LoggingMiddleware {
+    /// Pass each request and each response to `sink` as one message, e.g. to log it with the
+    /// application's logger (like `|message| tracing::debug!("{message}")`).
+    pub fn new<F>(sink: F) -> Self
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        Self {
+            verbosity: LogVerbosity::default(),
+            redacted_headers: vec![AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE, SET_COOKIE],
+            sink: Box::new(sink),
+        }
+    }
+
+    /// Log as much as `verbosity`, [`LogVerbosity::Summary`] by default.
+    pub fn with_verbosity(mut self, verbosity: LogVerbosity) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
+
+    /// Replace the value of the `name` header with `<redacted>` in the logs.
+    pub fn with_redacted_header(mut self, name: HeaderName) -> Self {
+        self.redacted_headers.push(name);
+        self
+    }
+
+    /// The headers, one per line, unless the verbosity leaves them out.
+    fn write_headers(&self, message: &mut String, headers: &HeaderMap) {
+        use std::fmt::Write;
+
+        if self.verbosity < LogVerbosity::Headers {
+            return;
+        }
+        for (name, value) in headers {
+            let value = if self.redacted_headers.contains(name) {
+                "<redacted>"
+            } else {
+                value.to_str().unwrap_or("<binary>")
+            };
+            let _ = write!(message, "\n{name}: {value}");
+        }
+    }
+
+    /// The body after a blank line, unless the verbosity leaves it out.
+    fn write_body(&self, message: &mut String, headers: &HeaderMap, body: Option<&[u8]>) {
+        if self.verbosity < LogVerbosity::Bodies {
+            return;
+        }
+        let is_json = headers
+            .get(CONTENT_TYPE)
+            .is_some_and(|ct| ct.as_bytes().starts_with(CONTENT_TYPE_JSON));
+        let body = match body {
+            Some(body) if is_json => match serde_json::from_slice::<serde_json::Value>(body) {
+                Ok(json) => serde_json::to_string_pretty(&json).unwrap_or_default(),
+                Err(_) => String::from_utf8_lossy(body).into_owned(),
+            },
+            Some(body) => format!("<{} bytes>", body.len()),
+            None => "<streaming body>".to_string(),
+        };
+        message.push_str("\n\n");
+        message.push_str(&body);
+    }
+}
+
+#[async_trait]
+impl Middleware for LoggingMiddleware {
+    async fn handle(&self, req: reqwest::Request, next: Next<'_>) -> Result<reqwest::Response> {
+        let path = req.url().path().to_string();
+        let mut message = format!(
+            "--> {} {path} ({})",
+            req.method(),
+            content_type(req.headers())
+        );
+        self.write_headers(&mut message, req.headers());
+        let body = req.body().and_then(|body| body.as_bytes());
+        self.write_body(&mut message, req.headers(), body);
+        (self.sink)(&message);
+
+        let resp = match next.run(req).await {
+            Ok(resp) => resp,
+            Err(err) => {
+                (self.sink)(&format!("<-- {path} failed: {err}"));
+                return Err(err);
+            }
+        };
+        let mut message = format!(
+            "<-- {} {path} ({})",
+            resp.status(),
+            content_type(resp.headers())
+        );
+        self.write_headers(&mut message, resp.headers());
+        if self.verbosity < LogVerbosity::Bodies {
+            (self.sink)(&message);
+            return Ok(resp);
+        }
+        // Read the body to log it, and hand on a response with the same body.
+        let status = resp.status();
+        let version = resp.version();
+        let headers = resp.headers().clone();
+        let body = resp.bytes().await?;
+        self.write_body(&mut message, &headers, Some(&body));
+        (self.sink)(&message);
+        let mut resp = http::Response::new(body);
+        *resp.status_mut() = status;
+        *resp.version_mut() = version;
+        *resp.headers_mut() = headers;
+        Ok(resp.into())
+    }
+}
+
+fn content_type(headers: &HeaderMap) -> &str {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .unwrap_or("no content type")
+}
+
+/// Merges repeated request headers, see [`ClientBuilder::coalesce_headers`].
+struct CoalesceHeaders;
+
+#[async_trait]
+impl Middleware for CoalesceHeaders {
+    async fn handle(&self, mut req: reqwest::Request, next: Next<'_>) -> Result<reqwest::Response> {
+        coalesce_headers(req.headers_mut());
+        next.run(req).await
+    }
+}
+
+fn coalesce_headers(headers: &mut HeaderMap) {
+    let repeated: Vec<HeaderName> = headers
+        .keys()
+        .filter(|name| headers.get_all(*name).iter().nth(1).is_some())
+        .cloned()
+        .collect();
+    for name in repeated {
+        let separator: &[u8] = if name == COOKIE { b"; " } else { b", " };
+        let mut values: Vec<&HeaderValue> = vec![];
+        for value in headers.get_all(&name) {
+            if !values.contains(&value) {
+                values.push(value);
+            }
+        }
+        let joined = values
+            .iter()
+            .map(|value| value.as_bytes())
+            .collect::<Vec<_>>()
+            .join(separator);
+        let value = HeaderValue::from_bytes(&joined).expect("joined header values are valid");
+        headers.insert(name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::{Request, Response};
+
+    use crate::details::TwirpRouterBuilder;
+    use crate::test::*;
+    use crate::Context;
+
+    use super::*;
+
+    struct AssertRouting {
+        expected_url: &'static str,
+    }
+
+    #[async_trait]
+    impl Middleware for AssertRouting {
+        async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response> {
+            assert_eq!(self.expected_url, &req.url().to_string());
+            next.run(req).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_base_url() {
+        let url = Url::parse("http://localhost:3001/twirp/").unwrap();
+        assert!(Client::from_base_url(url).is_ok());
+        let url = Url::parse("http://localhost:3001/twirp").unwrap();
+        assert_eq!(
+            Client::from_base_url(url).unwrap_err().to_string(),
+            "base_url must end in /, but got: http://localhost:3001/twirp",
+        );
+    }
+
+    #[tokio::test]
+    async fn test_path_prefix() {
+        for (base_url, prefix) in [
+            ("http://localhost:3001", "twirp"),
+            ("http://localhost:3001/", "/twirp/"),
+            ("http://localhost:3001/api", "twirp"),
+            ("http://localhost:3001/api/", "twirp"),
+            ("http://localhost:3001/api/twirp/", ""),
+        ] {
+            let expected = if base_url.contains("/api") {
+                "http://localhost:3001/api/twirp/test.TestAPI/Ping"
+            } else {
+                "http://localhost:3001/twirp/test.TestAPI/Ping"
+            };
+            let client = ClientBuilder::from_base_url(Url::parse(base_url).unwrap())
+                .path_prefix(prefix)
+                .with(AssertRouting {
+                    expected_url: expected,
+                })
+                .build()
+                .unwrap();
+            let _ = client
+                .ping(PingRequest {
+                    name: "hi".to_string(),
+                })
+                .await;
+        }
+
+        // along with versioned segments
+        let client = ClientBuilder::from_base_url(Url::parse("http://localhost:3001").unwrap())
+            .path_prefix(DEFAULT_PATH_PREFIX)
+            .path_prefix_segments(["v2"])
+            .build()
+            .unwrap();
+        assert_eq!(
+            client.config().request_base_url.as_str(),
+            "http://localhost:3001/twirp/v2/"
+        );
+    }
+
+    #[test]
+    fn test_base_url_validation() {
+        let build = |url: &str, https_only: bool| {
+            ClientBuilder::from_base_url(Url::parse(url).unwrap())
+                .https_only(https_only)
+                .build()
+        };
+        assert!(build("http://localhost:3001/twirp/", false).is_ok());
+        assert!(build("https://example.com/", false).is_ok());
+        assert!(build("https://example.com/twirp/", true).is_ok());
+
+        assert_eq!(
+            build("http://example.com/twirp/", true)
+                .unwrap_err()
+                .to_string(),
+            "base_url scheme must be https, but got: http://example.com/twirp/",
+        );
+        assert_eq!(
+            build("ftp://example.com/twirp/", false)
+                .unwrap_err()
+                .to_string(),
+            "base_url scheme must be http or https, but got: ftp://example.com/twirp/",
+        );
+        assert!(matches!(
+            build("unix:/run/api.sock/", false),
+            Err(ClientError::UnsupportedScheme { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_routes() {
+        let base_url = Url::parse("http://localhost:3001/twirp/").unwrap();
+
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .with(AssertRouting {
+                expected_url: "http://localhost:3001/twirp/test.TestAPI/Ping",
+            })
+            .build()
+            .unwrap();
+        assert!(client
             .ping(PingRequest {
                 name: "hi".to_string(),
             })
@@ -316,4 +1545,1286 @@ mod tests {
         assert_eq!(&resp.name, "hi");
         h.abort()
     }
+
+    struct AssertHeader {
+        name: &'static str,
+        expected: Option<&'static str>,
+    }
+
+    #[async_trait]
+    impl Middleware for AssertHeader {
+        async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response> {
+            let value = req.headers().get(self.name).map(|v| v.to_str().unwrap());
+            assert_eq!(self.expected, value);
+            next.run(req).await
+        }
+    }
+
+    /// Sets the `name` header of requests to `value`.
+    struct SetHeader {
+        name: &'static str,
+        value: &'static str,
+    }
+
+    #[async_trait]
+    impl Middleware for SetHeader {
+        async fn handle(&self, mut req: Request, next: Next<'_>) -> Result<Response> {
+            req.headers_mut()
+                .insert(self.name, HeaderValue::from_static(self.value));
+            next.run(req).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_default_headers() {
+        let base_url = Url::parse("http://localhost:3001/twirp/").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tenant", HeaderValue::from_static("hats"));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        headers.insert(BAGGAGE, HeaderValue::from_static("default=1"));
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .with_header(
+                reqwest::header::USER_AGENT,
+                HeaderValue::from_static("hat-client/1.0"),
+            )
+            .with_headers(headers)
+            .with_header(
+                HeaderName::from_static("x-token"),
+                HeaderValue::from_static("default"),
+            )
+            .with(AssertHeader {
+                name: "user-agent",
+                expected: Some("hat-client/1.0"),
+            })
+            .with(AssertHeader {
+                name: "x-tenant",
+                expected: Some("hats"),
+            })
+            // the client's own headers win
+            .with(AssertHeader {
+                name: "content-type",
+                expected: Some("application/protobuf"),
+            })
+            .with(AssertHeader {
+                name: BAGGAGE,
+                expected: Some("user=alice"),
+            })
+            // and so does middleware
+            .with(AssertHeader {
+                name: "x-token",
+                expected: Some("default"),
+            })
+            .with(SetHeader {
+                name: "x-token",
+                value: "override",
+            })
+            .with(AssertHeader {
+                name: "x-token",
+                expected: Some("override"),
+            })
+            .build()
+            .unwrap();
+        let mut baggage = Baggage::default();
+        baggage.insert("user", "alice").unwrap();
+        let client = client.with_baggage(&baggage);
+        assert!(client
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .is_err()); // expected connection refused error.
+    }
+
+    #[tokio::test]
+    async fn test_logging_middleware() {
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = tcp_listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(tcp_listener, test_api_router()).await });
+        let base_url = Url::parse(&format!("http://{addr}/twirp/")).unwrap();
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        let client = |verbosity: LogVerbosity| {
+            let messages = Arc::new(std::sync::Mutex::new(vec![]));
+            let sink = messages.clone();
+            let client = ClientBuilder::new(base_url.clone(), reqwest::Client::new())
+                .with_header(
+                    reqwest::header::AUTHORIZATION,
+                    HeaderValue::from_static("Bearer secret"),
+                )
+                .with(
+                    LoggingMiddleware::new(move |message| {
+                        sink.lock().unwrap().push(message.to_string())
+                    })
+                    .with_verbosity(verbosity),
+                )
+                .build()
+                .unwrap();
+            (client, messages)
+        };
+
+        let (summary, messages) = client(LogVerbosity::Summary);
+        assert_eq!(&summary.ping(req.clone()).await.unwrap().name, "hi");
+        assert_eq!(
+            *messages.lock().unwrap(),
+            [
+                "--> POST /twirp/test.TestAPI/Ping (application/protobuf)",
+                "<-- 200 OK /twirp/test.TestAPI/Ping (application/protobuf)",
+            ]
+        );
+
+        let (headers, messages) = client(LogVerbosity::Headers);
+        assert_eq!(&headers.ping(req.clone()).await.unwrap().name, "hi");
+        {
+            let messages = messages.lock().unwrap();
+            assert!(
+                messages[0].contains("\nauthorization: <redacted>"),
+                "{}",
+                messages[0]
+            );
+            assert!(!messages[0].contains("secret"));
+            assert!(messages[1].contains("\ncontent-type: application/protobuf"));
+        }
+
+        let (bodies, messages) = client(LogVerbosity::Bodies);
+        assert_eq!(&bodies.ping(req.clone()).await.unwrap().name, "hi");
+        assert!(bodies.boom(req).await.is_err());
+        let messages = messages.lock().unwrap();
+        assert!(messages[0].ends_with("\n\n<4 bytes>"), "{}", messages[0]);
+        assert!(messages[1].ends_with("\n\n<4 bytes>"), "{}", messages[1]);
+        // the error is pretty-printed, and still read by the client
+        assert!(messages[3].starts_with("<-- 500 Internal Server Error /twirp/test.TestAPI/Boom"));
+        assert!(
+            messages[3].contains("\n\n{\n  \"code\": \"internal\""),
+            "{}",
+            messages[3]
+        );
+        h.abort()
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_compress_request_threshold() {
+        let h = run_test_server(3003).await;
+        let base_url = Url::parse("http://localhost:3003/twirp/").unwrap();
+        let name = "a".repeat(1024);
+
+        let client = ClientBuilder::new(base_url.clone(), reqwest::Client::new())
+            .compress_request_threshold(64)
+            .with(AssertHeader {
+                name: "content-encoding",
+                expected: Some("gzip"),
+            })
+            .build()
+            .unwrap();
+        let resp = client
+            .ping(PingRequest { name: name.clone() })
+            .await
+            .unwrap();
+        assert_eq!(resp.name, name);
+
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .compress_request_threshold(64)
+            .with(AssertHeader {
+                name: "content-encoding",
+                expected: None,
+            })
+            .build()
+            .unwrap();
+        let resp = client
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(&resp.name, "hi");
+        h.abort()
+    }
+
+    #[tokio::test]
+    async fn test_default_timeout() {
+        let base_url = Url::parse("http://localhost:3001/twirp/").unwrap();
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .default_timeout_ms(1500)
+            .with(AssertHeader {
+                name: TIMEOUT_MS,
+                expected: Some("1500"),
+            })
+            .build()
+            .unwrap();
+        assert!(client
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .is_err()); // expected connection refused error.
+    }
+
+    /// Records the timeout header of requests, and never responds.
+    struct Hang(Arc<std::sync::Mutex<Vec<String>>>);
+
+    #[async_trait]
+    impl Middleware for Hang {
+        async fn handle(&self, req: Request, _: Next<'_>) -> Result<Response> {
+            let timeout_ms = req.headers()[TIMEOUT_MS].to_str().unwrap().to_string();
+            self.0.lock().unwrap().push(timeout_ms);
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let seen = Arc::new(std::sync::Mutex::new(vec![]));
+        let base_url = Url::parse("http://localhost:3001/twirp/").unwrap();
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .default_timeout_ms(1500)
+            .timeout(Duration::from_millis(50))
+            .with(Hang(seen.clone()))
+            .build()
+            .unwrap();
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        match client.ping(req.clone()).await {
+            Err(ClientError::Timeout(timeout)) => assert_eq!(timeout, Duration::from_millis(50)),
+            other => panic!("unexpected result: {other:?}"),
+        }
+
+        // overridden for a call
+        let quick = client.with_timeout(Duration::from_millis(20));
+        assert_eq!(quick.config().timeout, Some(Duration::from_millis(20)));
+        match quick.ping(req).await {
+            Err(ClientError::Timeout(timeout)) => assert_eq!(timeout, Duration::from_millis(20)),
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert_eq!(*seen.lock().unwrap(), ["50", "20"]);
+    }
+
+    /// Records the content type of requests, and responds successfully without sending anything.
+    struct RecordContentType(Arc<std::sync::Mutex<Vec<String>>>);
+
+    #[async_trait]
+    impl Middleware for RecordContentType {
+        async fn handle(&self, req: Request, _: Next<'_>) -> Result<Response> {
+            let content_type = req.headers()[CONTENT_TYPE].to_str().unwrap().to_string();
+            self.0.lock().unwrap().push(content_type);
+            let resp = http::Response::builder()
+                .header(CONTENT_TYPE, CONTENT_TYPE_PROTOBUF)
+                .body(serialize_proto_message(PingResponse {
+                    name: "hi".to_string(),
+                }))
+                .unwrap();
+            Ok(resp.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_negotiate() {
+        let info = ServerInfo::new("1.0.0").with_content_types(["application/x-protobuf"]);
+        let app = axum::Router::new().route(SERVER_INFO_PATH, info.clone().handler());
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = tcp_listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(tcp_listener, app).await });
+        let base_url = Url::parse(&format!("http://{addr}/twirp/")).unwrap();
+
+        let seen = Arc::new(std::sync::Mutex::new(vec![]));
+        let client = ClientBuilder::new(base_url.clone(), reqwest::Client::new())
+            .negotiate(true)
+            .with(RecordContentType(seen.clone()))
+            .build()
+            .unwrap();
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        client.ping(req.clone()).await.unwrap();
+        client.ping(req.clone()).await.unwrap();
+        assert_eq!(client.server_info().await.unwrap(), Some(info));
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["application/x-protobuf", "application/x-protobuf"]
+        );
+
+        // without negotiation, the server info isn't used
+        seen.lock().unwrap().clear();
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .with(RecordContentType(seen.clone()))
+            .build()
+            .unwrap();
+        client.ping(req.clone()).await.unwrap();
+        assert_eq!(*seen.lock().unwrap(), ["application/protobuf"]);
+        h.abort();
+
+        // servers with a broken endpoint get the default, and the failure is cached
+        let fetches = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let app = test_api_router().route(
+            SERVER_INFO_PATH,
+            axum::routing::get(move || {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                async { "not json" }
+            }),
+        );
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = tcp_listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(tcp_listener, app).await });
+        seen.lock().unwrap().clear();
+        let client = ClientBuilder::new(
+            Url::parse(&format!("http://{addr}/twirp/")).unwrap(),
+            reqwest::Client::new(),
+        )
+        .negotiate(true)
+        .with(RecordContentType(seen.clone()))
+        .build()
+        .unwrap();
+        client.ping(req.clone()).await.unwrap();
+        client.ping(req).await.unwrap();
+        assert_eq!(client.server_info().await.unwrap(), None);
+        assert_eq!(fetches.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["application/protobuf", "application/protobuf"]
+        );
+        h.abort();
+
+        // so is a server that can't be reached, after returning the error once
+        let addr = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind")
+            .local_addr()
+            .unwrap();
+        let client = ClientBuilder::new(
+            Url::parse(&format!("http://{addr}/twirp/")).unwrap(),
+            reqwest::Client::new(),
+        )
+        .negotiate(true)
+        .build()
+        .unwrap();
+        assert!(client.server_info().await.is_err());
+        assert_eq!(client.server_info().await.unwrap(), None);
+    }
+
+    /// Records the timings passed to the middleware, and the round trip it measures itself.
+    struct RecordTimings(Arc<std::sync::Mutex<Vec<(ClientTimings, Duration)>>>);
+
+    #[async_trait]
+    impl Middleware for RecordTimings {
+        async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response> {
+            let timings = next.timings();
+            let start = Instant::now();
+            let resp = next.run(req).await;
+            self.0.lock().unwrap().push((timings, start.elapsed()));
+            resp
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timings() {
+        let seen = Arc::new(std::sync::Mutex::new(vec![]));
+        let client = ClientBuilder::from_base_url(Url::parse("http://localhost/twirp/").unwrap())
+            .with(RecordTimings(seen.clone()))
+            .with(RecordContentType(Arc::default()))
+            .build()
+            .unwrap();
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        let (resp, timings): (PingResponse, _) = client
+            .request_with_timings("test.TestAPI/Ping", req)
+            .await
+            .unwrap();
+        assert_eq!(resp.name, "hi");
+        assert!(timings.serialized().is_some());
+        assert!(timings.round_trip().is_some());
+        assert!(timings.deserialized().is_some());
+        assert!(timings.total_duration() >= timings.round_trip().unwrap());
+
+        let seen = seen.lock().unwrap();
+        let (middleware_timings, elapsed) = seen[0];
+        assert_eq!(middleware_timings.serialized(), timings.serialized());
+        assert!(middleware_timings.round_trip().is_none());
+        assert!(elapsed <= timings.round_trip().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_timings_negotiate() {
+        let info = ServerInfo::new("1.0.0");
+        let app = test_api_router().route(
+            SERVER_INFO_PATH,
+            axum::routing::get(move || async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                axum::Json(info)
+            }),
+        );
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = tcp_listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(tcp_listener, app).await });
+        let client = ClientBuilder::new(
+            Url::parse(&format!("http://{addr}/twirp/")).unwrap(),
+            reqwest::Client::new(),
+        )
+        .negotiate(true)
+        .build()
+        .unwrap();
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        let (_, timings): (PingResponse, _) = client
+            .request_with_timings("test.TestAPI/Ping", req)
+            .await
+            .unwrap();
+        // fetching the server info isn't part of serializing the request
+        assert!(timings.serialized().unwrap() < Duration::from_millis(100));
+        assert!(timings.round_trip().unwrap() >= Duration::from_millis(100));
+        h.abort();
+    }
+
+    #[tokio::test]
+    async fn test_request_with_response() {
+        let router = test_api_router().layer(axum::Extension(
+            crate::server::ServerOptions::default()
+                .with_request_id_header(HeaderName::from_static(crate::headers::REQUEST_ID)),
+        ));
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = tcp_listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(tcp_listener, router).await });
+        let base_url = Url::parse(&format!("http://{addr}/twirp/")).unwrap();
+        let client = Client::from_base_url(base_url).unwrap();
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+
+        let resp: ClientResponse<PingResponse> = client
+            .request_with_response("test.TestAPI/Ping", req.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status, StatusCode::OK);
+        // the test server echoes the request id it generated in the name, and in the header
+        let request_id = resp
+            .message
+            .name
+            .strip_prefix("hi-")
+            .expect("the name has the request id");
+        assert_eq!(resp.headers[crate::headers::REQUEST_ID], request_id);
+
+        // errors are returned as usual
+        let err = client
+            .request_with_response::<_, PingResponse>("test.TestAPI/Boom", req)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Some(TwirpErrorCode::Internal));
+        h.abort()
+    }
+
+    #[test]
+    fn test_error_accessors() {
+        let mut not_found = crate::not_found("no such hat");
+        not_found.insert_meta("size".to_string(), "12".to_string());
+        let err = ClientError::TwirpError {
+            status: StatusCode::NOT_FOUND,
+            error: not_found,
+        };
+        assert_eq!(err.code(), Some(TwirpErrorCode::NotFound));
+        assert_eq!(err.meta().unwrap()["size"], "12");
+        assert_eq!(err.twirp_error().unwrap().msg, "no such hat");
+        assert_eq!(err.http_status(), Some(StatusCode::NOT_FOUND));
+
+        let err = ClientError::HttpError {
+            status: StatusCode::BAD_GATEWAY,
+            msg: "bad gateway".to_string(),
+            path: "/twirp/test.TestAPI/Ping".to_string(),
+            content_type: "text/plain".to_string(),
+        };
+        assert_eq!(err.code(), None);
+        assert_eq!(err.meta(), None);
+        assert_eq!(err.http_status(), Some(StatusCode::BAD_GATEWAY));
+
+        let err = ClientError::Timeout(Duration::from_secs(1));
+        assert_eq!(err.twirp_error(), None);
+        assert_eq!(err.http_status(), None);
+    }
+
+    #[tokio::test]
+    async fn test_errors_in_body() {
+        let router = test_api_router().layer(axum::Extension(
+            crate::server::ServerOptions::default().with_errors_in_body(true),
+        ));
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = tcp_listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(tcp_listener, router).await });
+        let base_url = Url::parse(&format!("http://{addr}/twirp/")).unwrap();
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+
+        // without the option, an error with a 200 status isn't a valid response
+        let client = Client::from_base_url(base_url.clone()).unwrap();
+        match client.boom(req.clone()).await {
+            Err(ClientError::HttpError { status, .. }) => assert_eq!(status, StatusCode::OK),
+            other => panic!("unexpected result: {other:?}"),
+        }
+
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .errors_in_body(true)
+            .build()
+            .unwrap();
+        match client.boom(req.clone()).await {
+            Err(err @ ClientError::TwirpError { .. }) => {
+                assert_eq!(err.twirp_error(), Some(&crate::error::internal("boom!")));
+                // the status is the one actually sent, not the one of the error code
+                assert_eq!(err.http_status(), Some(StatusCode::OK));
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert_eq!(&client.ping(req).await.unwrap().name, "hi");
+        h.abort()
+    }
+
+    #[tokio::test]
+    async fn test_protobuf_content_type_variants() {
+        let router = test_api_router().layer(axum::Extension(
+            crate::server::ServerOptions::default().with_protobuf_content_type(
+                reqwest::header::HeaderValue::from_static("application/x-protobuf"),
+            ),
+        ));
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = tcp_listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(tcp_listener, router).await });
+
+        let client =
+            Client::from_base_url(Url::parse(&format!("http://{addr}/twirp/")).unwrap()).unwrap();
+        let resp = client
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(&resp.name, "hi");
+        h.abort()
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_with_server() {
+        async fn require_auth(
+            req: axum::extract::Request,
+            next: axum::middleware::Next,
+        ) -> axum::response::Response {
+            use axum::response::IntoResponse;
+
+            match req.headers().get(reqwest::header::AUTHORIZATION) {
+                Some(auth) if auth == "Bearer hat" => next.run(req).await,
+                _ => StatusCode::UNAUTHORIZED.into_response(),
+            }
+        }
+
+        let info = ServerInfo::new("1.0.0").with_content_types(["application/x-protobuf"]);
+        let app = test_api_router()
+            .route(SERVER_INFO_PATH, info.clone().handler())
+            .layer(axum::middleware::from_fn(require_auth));
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = tcp_listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(tcp_listener, app).await });
+
+        let seen = Arc::new(std::sync::Mutex::new(vec![]));
+        let client = ClientBuilder::new(
+            Url::parse(&format!("http://{addr}/twirp/")).unwrap(),
+            reqwest::Client::new(),
+        )
+        .negotiate(true)
+        .with_header(
+            reqwest::header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer hat"),
+        )
+        .with(RecordContentType(seen.clone()))
+        .build()
+        .unwrap();
+        let resp = client
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(&resp.name, "hi");
+        assert_eq!(client.server_info().await.unwrap(), Some(info));
+        assert_eq!(*seen.lock().unwrap(), ["application/x-protobuf"]);
+        h.abort()
+    }
+
+    #[tokio::test]
+    async fn test_protobuf_content_type_variant_requests() {
+        let router = test_api_router().layer(axum::Extension(
+            crate::server::ServerOptions::default().with_protobuf_content_type(
+                reqwest::header::HeaderValue::from_static("application/x-protobuf"),
+            ),
+        ));
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = tcp_listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(tcp_listener, router).await });
+
+        let body = serialize_proto_message(PingRequest {
+            name: "hi".to_string(),
+        });
+        for content_type in [
+            "application/x-protobuf",
+            "Application/X-Protobuf",
+            "application/vnd.google.protobuf",
+            "application/protobuf; proto=test.PingRequest",
+        ] {
+            let resp = reqwest::Client::new()
+                .post(format!("http://{addr}/twirp/test.TestAPI/Ping"))
+                .header(CONTENT_TYPE, content_type)
+                .body(body.clone())
+                .send()
+                .await
+                .unwrap();
+            assert!(resp.status().is_success(), "{content_type}: {:?}", resp);
+            assert_eq!(resp.headers()[CONTENT_TYPE], "application/x-protobuf");
+            let resp: PingResponse = prost::Message::decode(resp.bytes().await.unwrap()).unwrap();
+            assert_eq!(&resp.name, "hi", "{content_type}");
+        }
+        h.abort()
+    }
+
+    #[test]
+    fn test_config() {
+        let base_url = Url::parse("http://localhost:3001/twirp/").unwrap();
+        let builder = ClientBuilder::from_base_url(base_url.clone());
+        #[cfg(feature = "compression")]
+        let builder = builder.compress_request_threshold(1024);
+        let client = builder
+            .default_timeout_ms(1500)
+            .path_prefix_segments(["v2"])
+            .coalesce_headers(true)
+            .with(AttemptTimeout::new(Duration::from_secs(1)))
+            .build()
+            .unwrap();
+        assert_eq!(
+            client.config(),
+            ClientConfig {
+                base_url: base_url.clone(),
+                request_base_url: Url::parse("http://localhost:3001/twirp/v2/").unwrap(),
+                host: None,
+                default_timeout_ms: Some(1500),
+                timeout: None,
+                #[cfg(feature = "compression")]
+                compress_request_threshold: Some(1024),
+                errors_in_body: false,
+                coalesce_headers: true,
+                middleware_count: 1,
+            }
+        );
+        assert_eq!(
+            client.with_host("example.com").config().host.as_deref(),
+            Some("example.com")
+        );
+
+        let config = Client::from_base_url(base_url).unwrap().config();
+        assert_eq!(config.default_timeout_ms, None);
+        assert_eq!(config.middleware_count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_attempt_timeout() {
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                    if req.name == "slow" {
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                    }
+                    api.ping(ctx, req).await
+                },
+            )
+            .build();
+        let router = axum::Router::new().nest("/twirp/test.TestAPI", twirp_routes);
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = tcp_listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(tcp_listener, router).await });
+
+        let base_url = Url::parse(&format!("http://{addr}/twirp/")).unwrap();
+        let budget = Duration::from_millis(100);
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .with(AttemptTimeout::new(budget))
+            .build()
+            .unwrap();
+        let resp = client
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(&resp.name, "hi");
+
+        let started = std::time::Instant::now();
+        match client
+            .ping(PingRequest {
+                name: "slow".to_string(),
+            })
+            .await
+        {
+            Err(ClientError::AttemptTimedOut(timeout)) => assert_eq!(timeout, budget),
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+        h.abort()
+    }
+
+    /// Fails the first attempts of a request with `errors`, then responds successfully, without
+    /// sending anything.
+    struct Flaky {
+        errors: Vec<TwirpErrorResponse>,
+        retry_after: Option<&'static str>,
+        attempts: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl Flaky {
+        fn new(errors: Vec<TwirpErrorResponse>) -> Self {
+            Self {
+                errors,
+                retry_after: None,
+                attempts: Arc::default(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Middleware for Flaky {
+        async fn handle(&self, _: Request, _: Next<'_>) -> Result<Response> {
+            let attempt = self
+                .attempts
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let resp = match self.errors.get(attempt) {
+                Some(err) => {
+                    let mut resp = http::Response::builder()
+                        .status(err.code.http_status_code())
+                        .header(CONTENT_TYPE, CONTENT_TYPE_JSON);
+                    if let Some(retry_after) = self.retry_after {
+                        resp = resp.header(RETRY_AFTER, retry_after);
+                    }
+                    resp.body(serde_json::to_vec(err).unwrap()).unwrap()
+                }
+                None => http::Response::builder()
+                    .header(CONTENT_TYPE, CONTENT_TYPE_PROTOBUF)
+                    .body(serialize_proto_message(PingResponse {
+                        name: "hi".to_string(),
+                    }))
+                    .unwrap(),
+            };
+            Ok(resp.into())
+        }
+    }
+
+    fn retrying_client(retries: RetryMiddleware, flaky: Flaky) -> Client {
+        ClientBuilder::from_base_url(Url::parse("http://localhost/twirp/").unwrap())
+            .with(retries)
+            .with(flaky)
+            .build()
+            .unwrap()
+    }
+
+    fn ping_hi() -> PingRequest {
+        PingRequest {
+            name: "hi".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let no_backoff = |_| Duration::ZERO;
+
+        // retried until it succeeds
+        let flaky = Flaky::new(vec![
+            crate::unavailable("down"),
+            crate::resource_exhausted("busy"),
+        ]);
+        let attempts = flaky.attempts.clone();
+        let client = retrying_client(RetryMiddleware::new(3).with_backoff(no_backoff), flaky);
+        assert_eq!(client.ping(ping_hi()).await.unwrap().name, "hi");
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 3);
+
+        // up to the max attempts
+        let flaky = Flaky::new((0..5).map(|_| crate::unavailable("down")).collect());
+        let attempts = flaky.attempts.clone();
+        let client = retrying_client(RetryMiddleware::new(3).with_backoff(no_backoff), flaky);
+        match client.ping(ping_hi()).await {
+            Err(ClientError::TwirpError { error, .. }) => {
+                assert_eq!(error.code, TwirpErrorCode::Unavailable)
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 3);
+
+        // but not on other errors
+        let flaky = Flaky::new(vec![crate::invalid_argument("bad hat")]);
+        let attempts = flaky.attempts.clone();
+        let client = retrying_client(RetryMiddleware::new(3).with_backoff(no_backoff), flaky);
+        match client.ping(ping_hi()).await {
+            Err(ClientError::TwirpError { error, .. }) => {
+                assert_eq!(error.code, TwirpErrorCode::InvalidArgument)
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after() {
+        let backoffs = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let counted = backoffs.clone();
+        let backoff = move |_| {
+            counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Duration::ZERO
+        };
+        let mut flaky = Flaky::new(vec![crate::unavailable("down")]);
+        flaky.retry_after = Some("1");
+        let client = retrying_client(RetryMiddleware::new(2).with_backoff(backoff), flaky);
+
+        let started = std::time::Instant::now();
+        client.ping(ping_hi()).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(backoffs.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    struct CountAttempts(Arc<std::sync::atomic::AtomicUsize>);
+
+    #[async_trait]
+    impl Middleware for CountAttempts {
+        async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            next.run(req).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_connection_error() {
+        // a port nothing listens on
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let client =
+            ClientBuilder::from_base_url(Url::parse(&format!("http://{addr}/twirp/")).unwrap())
+                .with(RetryMiddleware::new(3).with_backoff(|_| Duration::from_millis(1)))
+                .with(CountAttempts(attempts.clone()))
+                .build()
+                .unwrap();
+        match client.ping(ping_hi()).await {
+            Err(ClientError::ReqwestError(err)) => assert!(err.is_connect(), "{err:?}"),
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_exponential_backoff() {
+        let backoff = ExponentialBackoff::new(Duration::from_millis(100), Duration::from_secs(1));
+        for (retry, max) in [
+            (1, 100),
+            (2, 200),
+            (3, 400),
+            (4, 800),
+            (5, 1000),
+            (50, 1000),
+        ] {
+            let max = Duration::from_millis(max);
+            let delay = backoff.delay(retry);
+            assert!(delay <= max && delay >= max / 2, "{retry}: {delay:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_path_prefix_segments() {
+        let v2_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                    api.ping(ctx, req).await
+                },
+            )
+            .build();
+        // only the versioned routes are served
+        let router = axum::Router::new().nest("/twirp/v2/test.TestAPI", v2_routes);
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = tcp_listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(tcp_listener, router).await });
+
+        let base_url = Url::parse(&format!("http://{addr}/twirp/")).unwrap();
+        let client = ClientBuilder::new(base_url.clone(), reqwest::Client::new())
+            .path_prefix_segments(["v2"])
+            .build()
+            .unwrap();
+        assert_eq!(client.base_url(), &base_url);
+        let resp = client
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(&resp.name, "hi");
+        h.abort()
+    }
+
+    #[tokio::test]
+    async fn test_socket_options() {
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = tcp_listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(tcp_listener, test_api_router()).await });
+
+        // The options can't be observed from here, but they must not break the connection
+        let client =
+            ClientBuilder::from_base_url(Url::parse(&format!("http://{addr}/twirp/")).unwrap())
+                .tcp_nodelay(false)
+                .tcp_keepalive(Duration::from_secs(30))
+                .build()
+                .unwrap();
+        let resp = client
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(&resp.name, "hi");
+        h.abort()
+    }
+
+    struct AppendHeaders(&'static [(&'static str, &'static str)]);
+
+    #[async_trait]
+    impl Middleware for AppendHeaders {
+        async fn handle(&self, mut req: Request, next: Next<'_>) -> Result<Response> {
+            for (name, value) in self.0 {
+                req.headers_mut()
+                    .append(*name, HeaderValue::from_static(value));
+            }
+            next.run(req).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_normalized_headers() {
+        use std::io::{Read, Write};
+
+        // A bare HTTP/1 server, to see the request head exactly as sent.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut head = vec![];
+            let mut buf = [0u8; 1024];
+            while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                head.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-type: application/protobuf\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8(head).unwrap()
+        });
+
+        let client =
+            ClientBuilder::from_base_url(Url::parse(&format!("http://{addr}/twirp/")).unwrap())
+                .title_case_headers(true)
+                .coalesce_headers(true)
+                .with(AppendHeaders(&[
+                    ("x-tag", "a"),
+                    ("x-tag", "b"),
+                    ("x-tag", "a"),
+                    ("cookie", "c=1"),
+                    ("cookie", "d=2"),
+                ]))
+                .build()
+                .unwrap();
+        client
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let head = server.join().unwrap();
+        assert!(
+            head.contains("\r\nContent-Type: application/protobuf\r\n"),
+            "{head}"
+        );
+        assert!(head.contains("\r\nX-Tag: a, b\r\n"), "{head}");
+        assert!(head.contains("\r\nCookie: c=1; d=2\r\n"), "{head}");
+        assert_eq!(head.matches("X-Tag").count(), 1, "{head}");
+    }
+
+    #[tokio::test]
+    async fn test_baggage() {
+        // echoes the baggage it receives in the response
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                |_: Arc<TestApiServer>, ctx: Context, _: PingRequest| async move {
+                    let name = ctx.get::<Baggage>().map(Baggage::encode);
+                    Ok::<_, TwirpErrorResponse>(PingResponse {
+                        name: name.unwrap_or_default(),
+                    })
+                },
+            )
+            .build();
+        let router = axum::Router::new()
+            .nest("/twirp/test.TestAPI", twirp_routes)
+            .layer(axum::Extension(
+                crate::server::ServerOptions::default().with_baggage(true),
+            ));
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = tcp_listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(tcp_listener, router).await });
+
+        let client =
+            Client::from_base_url(Url::parse(&format!("http://{addr}/twirp/")).unwrap()).unwrap();
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        let resp = client.ping(req.clone()).await.unwrap();
+        assert_eq!(resp.name, "");
+
+        let mut baggage = Baggage::new();
+        baggage.insert("tenant-id", "acme").unwrap();
+        baggage.insert("origin", "web, eu").unwrap();
+        let resp = client
+            .with_baggage(&baggage)
+            .ping(req.clone())
+            .await
+            .unwrap();
+        assert_eq!(Baggage::decode(&resp.name).unwrap(), baggage);
+
+        // invalid baggage is rejected
+        let invalid = client.with_baggage(&Baggage::new());
+        let invalid = Client {
+            baggage: Some(HeaderValue::from_static("not a token=1")),
+            ..invalid
+        };
+        let err = invalid.ping(req).await.unwrap_err();
+        let ClientError::TwirpError { error, .. } = err else {
+            panic!("unexpected error: {err:?}");
+        };
+        assert_eq!(error.code, TwirpErrorCode::Malformed);
+        h.abort()
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut head = vec![];
+            let mut buf = [0u8; 1024];
+            while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                head.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-type: application/protobuf\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8(head).unwrap()
+        });
+
+        // `.test` names never resolve, so the request can only get through the override.
+        let base_url = Url::parse(&format!("http://backend.twirp.test:{port}/twirp/")).unwrap();
+        let client = ClientBuilder::from_base_url(base_url)
+            .resolve("backend.twirp.test", IpAddr::from([127, 0, 0, 1]))
+            .build()
+            .unwrap();
+        client
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let head = server.join().unwrap().to_lowercase();
+        assert!(
+            head.contains(&format!("\r\nhost: backend.twirp.test:{port}\r\n")),
+            "{head}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dropped_request_is_aborted() {
+        // The sender is held by the handler for as long as the server is still working on the
+        // request, so the receiver resolves once the server abandons it.
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let tx = Arc::new(std::sync::Mutex::new(Some(tx)));
+        let router = axum::Router::new().route(
+            "/twirp/test.TestAPI/Ping",
+            axum::routing::post(move || {
+                let tx = tx.clone();
+                async move {
+                    let _guard = tx.lock().expect("mutex poisoned").take();
+                    std::future::pending::<()>().await
+                }
+            }),
+        );
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = tcp_listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(tcp_listener, router).await });
+
+        let base_url = Url::parse(&format!("http://{addr}/twirp/")).unwrap();
+        let client = Client::from_base_url(base_url).unwrap();
+        let call = client.ping(PingRequest {
+            name: "hi".to_string(),
+        });
+        // Drop the call while the server is still handling it.
+        assert!(tokio::time::timeout(Duration::from_millis(200), call)
+            .await
+            .is_err());
+
+        tokio::time::timeout(Duration::from_secs(5), rx)
+            .await
+            .expect("in-flight request was not aborted")
+            .expect_err("handler should not have completed");
+        h.abort()
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_metrics() {
+        use metrics::{Counter, CounterFn, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata};
+        use metrics::{SharedString, Unit};
+
+        type Log = Arc<std::sync::Mutex<Vec<(String, Vec<(String, String)>, f64)>>>;
+
+        /// Records every update of a metric, with its labels.
+        struct Recorded(Key, Log);
+
+        impl Recorded {
+            fn push(&self, value: f64) {
+                let labels = self
+                    .0
+                    .labels()
+                    .map(|label| (label.key().to_string(), label.value().to_string()))
+                    .collect();
+                self.1
+                    .lock()
+                    .unwrap()
+                    .push((self.0.name().to_string(), labels, value));
+            }
+        }
+
+        impl CounterFn for Recorded {
+            fn increment(&self, value: u64) {
+                self.push(value as f64)
+            }
+
+            fn absolute(&self, value: u64) {
+                self.push(value as f64)
+            }
+        }
+
+        impl HistogramFn for Recorded {
+            fn record(&self, value: f64) {
+                self.push(value)
+            }
+        }
+
+        struct TestRecorder(Log);
+
+        impl metrics::Recorder for TestRecorder {
+            fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+            fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+                Counter::from_arc(Arc::new(Recorded(key.clone(), self.0.clone())))
+            }
+
+            fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+                Gauge::noop()
+            }
+
+            fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+                Histogram::from_arc(Arc::new(Recorded(key.clone(), self.0.clone())))
+            }
+        }
+
+        // No other test enables metrics, so they are the only ones recorded.
+        let log = Log::default();
+        metrics::set_global_recorder(TestRecorder(log.clone())).unwrap();
+
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = tcp_listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(tcp_listener, test_api_router()).await });
+        let client =
+            ClientBuilder::from_base_url(Url::parse(&format!("http://{addr}/twirp/")).unwrap())
+                .metrics(true)
+                .build()
+                .unwrap();
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        client.ping(req.clone()).await.unwrap();
+        client.boom(req).await.unwrap_err();
+        h.abort();
+
+        let labels = |method: &str, code: Option<&str>| {
+            let mut labels = vec![
+                ("service".to_string(), "test.TestAPI".to_string()),
+                ("method".to_string(), method.to_string()),
+            ];
+            if let Some(code) = code {
+                labels.push(("code".to_string(), code.to_string()));
+            }
+            labels
+        };
+        let log = log.lock().unwrap();
+        let counts: Vec<_> = log
+            .iter()
+            .filter(|(name, _, _)| name.ends_with("_total"))
+            .map(|(name, labels, value)| (name.as_str(), labels.clone(), *value))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                ("twirp_client_requests_total", labels("Ping", None), 1.0),
+                ("twirp_client_requests_total", labels("Boom", None), 1.0),
+                (
+                    "twirp_client_errors_total",
+                    labels("Boom", Some("internal")),
+                    1.0
+                ),
+            ]
+        );
+        let latencies = log
+            .iter()
+            .filter(|(name, _, _)| name == "twirp_client_request_duration_seconds")
+            .count();
+        assert_eq!(latencies, 2);
+    }
 }
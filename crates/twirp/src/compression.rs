@@ -0,0 +1,72 @@
+//! Gzip support for request and response bodies.
+
+use std::io::{Read, Write};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use http::header::ACCEPT_ENCODING;
+use http::HeaderMap;
+
+pub(crate) const GZIP: &str = "gzip";
+
+pub(crate) fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(data)
+        .expect("writing to a Vec can't fail");
+    encoder.finish().expect("writing to a Vec can't fail")
+}
+
+/// Whether the `Accept-Encoding` headers of a request allow a gzip encoded response.
+pub(crate) fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let rejected = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            });
+            (name.eq_ignore_ascii_case(GZIP) || name == "*") && !rejected
+        })
+}
+
+/// Decompress `data`, returning `None` if it decompresses to more than `max_len` bytes.
+pub(crate) fn gunzip(data: &[u8], max_len: usize) -> std::io::Result<Option<Vec<u8>>> {
+    let mut decoded = Vec::new();
+    let limit = u64::try_from(max_len).unwrap_or(u64::MAX).saturating_add(1);
+    GzDecoder::new(data).take(limit).read_to_end(&mut decoded)?;
+    Ok((decoded.len() <= max_len).then_some(decoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_accepts_gzip() {
+        let accepts = |values: &[&'static str]| {
+            let mut headers = HeaderMap::new();
+            for value in values {
+                headers.append(ACCEPT_ENCODING, HeaderValue::from_static(value));
+            }
+            accepts_gzip(&headers)
+        };
+        assert!(!accepts(&[]));
+        assert!(accepts(&["gzip"]));
+        assert!(accepts(&["br", "deflate, GZIP;q=0.5"]));
+        assert!(accepts(&["*"]));
+        assert!(!accepts(&["identity"]));
+        assert!(!accepts(&["gzip;q=0"]));
+        assert!(!accepts(&["gzips"]));
+    }
+}
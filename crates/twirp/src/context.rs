@@ -1,6 +1,9 @@
-use std::sync::{Arc, Mutex};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 
-use http::Extensions;
+use http::header::{HeaderName, HeaderValue, SET_COOKIE};
+use http::{Extensions, HeaderMap};
 
 /// Context allows passing information between twirp rpc handlers and http middleware by providing
 /// access to extensions on the `http::Request` and `http::Response`.
@@ -29,14 +32,173 @@ impl Context {
         self.extensions.get::<T>()
     }
 
+    /// The id of the request, if there is a [`RequestId`] extension, e.g. from
+    /// [`ServerOptions::with_request_id_header`](crate::server::ServerOptions::with_request_id_header).
+    pub fn request_id(&self) -> Option<&str> {
+        self.get::<RequestId>().map(|id| id.0.as_str())
+    }
+
     /// Insert a response extension.
     pub fn insert<T>(&self, val: T) -> Option<T>
     where
         T: Clone + Send + Sync + 'static,
     {
-        self.resp_extensions
-            .lock()
-            .expect("mutex poisoned")
-            .insert(val)
+        lock_extensions(&self.resp_extensions).insert(val)
+    }
+
+    /// Set a header on the response, replacing any value set before. `Set-Cookie` can't be
+    /// combined into a single value, so its values are appended instead, and every cookie set is
+    /// sent in a header of its own.
+    pub fn set_response_header(&self, name: HeaderName, value: HeaderValue) {
+        let mut extensions = lock_extensions(&self.resp_extensions);
+        let headers = extensions.get_or_insert_default::<ResponseHeaders>();
+        if name == SET_COOKIE {
+            headers.0.append(name, value);
+        } else {
+            headers.0.insert(name, value);
+        }
+    }
+
+    /// Add a header to the response, keeping any values set before.
+    pub fn append_response_header(&self, name: HeaderName, value: HeaderValue) {
+        let mut extensions = lock_extensions(&self.resp_extensions);
+        let headers = extensions.get_or_insert_default::<ResponseHeaders>();
+        headers.0.append(name, value);
+    }
+
+    /// Let the other tasks on this worker run if the request has used up its yield budget, see
+    /// [`ServerOptions::with_yield_budget`](crate::server::ServerOptions::with_yield_budget).
+    /// Call this in loops that do a lot of work without awaiting anything that yields on its own.
+    /// Without a yield budget, this consumes a unit of tokio's cooperative budget instead.
+    pub async fn yield_point(&self) {
+        match self.get::<YieldBudget>() {
+            Some(budget) if budget.consume() => tokio::task::yield_now().await,
+            Some(_) => {}
+            None => tokio::task::consume_budget().await,
+        }
+    }
+
+    /// The `twirp` span of this request, with the rpc `method` and `request_id` (when request ids
+    /// are enabled) as fields. The handler already runs inside it, so this is only needed to
+    /// attach the span to work spawned from the handler. A disabled span outside of requests.
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> tracing::Span {
+        self.extensions
+            .get::<tracing::Span>()
+            .cloned()
+            .unwrap_or_else(tracing::Span::none)
+    }
+}
+
+/// Lock response extensions, recovering them if the mutex is poisoned.
+///
+/// A panic while the extensions are locked (e.g. in an extension's `Clone` impl) poisons the
+/// mutex. The extensions map itself is still consistent, so rather than panicking on every later
+/// access to it, the poison is cleared and the extensions are used as they are.
+pub(crate) fn lock_extensions(extensions: &Mutex<Extensions>) -> MutexGuard<'_, Extensions> {
+    extensions.lock().unwrap_or_else(|poisoned| {
+        extensions.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+/// The number of [`Context::yield_point`] calls a handler gets before it yields.
+#[derive(Clone, Debug)]
+pub(crate) struct YieldBudget {
+    every: u32,
+    remaining: Arc<AtomicU32>,
+}
+
+impl YieldBudget {
+    pub(crate) fn new(every: u32) -> Self {
+        Self {
+            every,
+            remaining: Arc::new(AtomicU32::new(every)),
+        }
+    }
+
+    /// Use up one unit of the budget, returning whether it ran out (and was refilled).
+    fn consume(&self) -> bool {
+        let remaining = self.remaining.load(Ordering::Relaxed);
+        if remaining > 1 {
+            self.remaining.store(remaining - 1, Ordering::Relaxed);
+            false
+        } else {
+            self.remaining.store(self.every, Ordering::Relaxed);
+            true
+        }
+    }
+}
+
+/// The headers set with [`Context::set_response_header`], added to the response by the server.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ResponseHeaders(pub(crate) HeaderMap);
+
+/// The id of a request, used to correlate it across services and logs.
+///
+/// Insert this into the request extensions (e.g. from a middleware that reads an `x-request-id`
+/// header) to make it available through [`RequestContext::request_id`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RequestId(pub String);
+
+/// The name of the rpc method being handled, e.g. `MakeHat`.
+///
+/// This is inserted into the request extensions by the router for every request routed to a
+/// Twirp method.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RpcMethod(pub Arc<str>);
+
+/// A typed view of the request information most handlers need from a [`Context`].
+///
+/// `P` is the type of the authenticated principal your middleware inserts into the request
+/// extensions, if any.
+///
+/// ```
+/// use twirp::context::RequestContext;
+///
+/// #[derive(Clone)]
+/// struct User(String);
+///
+/// fn handle(ctx: &twirp::Context) {
+///     let req = RequestContext::<User>::new(ctx);
+///     if let (Some(id), Some(User(name))) = (req.request_id(), req.principal()) {
+///         println!("{id}: {:?} called by {name}", req.method());
+///     }
+/// }
+/// ```
+pub struct RequestContext<'a, P = ()> {
+    ctx: &'a Context,
+    principal: PhantomData<fn() -> P>,
+}
+
+impl<'a, P> RequestContext<'a, P>
+where
+    P: Clone + Send + Sync + 'static,
+{
+    pub fn new(ctx: &'a Context) -> Self {
+        Self {
+            ctx,
+            principal: PhantomData,
+        }
+    }
+
+    /// The id of the request, if there is a [`RequestId`] extension.
+    pub fn request_id(&self) -> Option<&'a str> {
+        self.ctx.request_id()
+    }
+
+    /// The name of the rpc method being handled.
+    pub fn method(&self) -> Option<&'a str> {
+        self.ctx.get::<RpcMethod>().map(|method| method.0.as_ref())
+    }
+
+    /// The authenticated principal, if one was inserted into the request extensions.
+    pub fn principal(&self) -> Option<&'a P> {
+        self.ctx.get::<P>()
+    }
+
+    /// The underlying [`Context`], for anything else.
+    pub fn context(&self) -> &'a Context {
+        self.ctx
     }
 }
@@ -0,0 +1,39 @@
+//! Static descriptions of Twirp services, for introspection tooling.
+//!
+//! `twirp-build` generates a `SERVICE_DESCRIPTOR` constant per service when configured with
+//! `with_service_descriptor(true)`. The names and paths follow gRPC's conventions, so the same
+//! tooling can handle both Twirp and gRPC services.
+
+/// A service, as declared in its proto file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServiceDescriptor {
+    /// The proto name of the service, e.g. `HaberdasherAPI`.
+    pub name: &'static str,
+    /// The proto package, e.g. `service.haberdash.v1`.
+    pub package: &'static str,
+    /// The fully qualified name, e.g. `service.haberdash.v1.HaberdasherAPI`.
+    pub full_name: &'static str,
+    pub methods: &'static [MethodDescriptor],
+}
+
+/// An rpc method of a [`ServiceDescriptor`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MethodDescriptor {
+    /// The proto name of the method, e.g. `MakeHat`.
+    pub name: &'static str,
+    /// The path of the method relative to the Twirp prefix, as in gRPC, e.g.
+    /// `/service.haberdash.v1.HaberdasherAPI/MakeHat`.
+    pub path: &'static str,
+    /// The fully qualified proto name of the input message, e.g.
+    /// `.service.haberdash.v1.MakeHatRequest`.
+    pub input_type: &'static str,
+    /// The fully qualified proto name of the output message.
+    pub output_type: &'static str,
+}
+
+impl ServiceDescriptor {
+    /// Look up a method by its proto name.
+    pub fn method(&self, name: &str) -> Option<&'static MethodDescriptor> {
+        self.methods.iter().find(|m| m.name == name)
+    }
+}
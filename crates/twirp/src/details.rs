@@ -1,10 +1,13 @@
 //! Undocumented features that are public for use in generated code (see `twirp-build`).
 
 use std::future::Future;
+use std::sync::Arc;
 
-use axum::extract::{Request, State};
+use axum::extract::Request;
+use axum::routing::{MethodFilter, MethodRouter};
 use axum::Router;
 
+use crate::context::RpcMethod;
 use crate::{server, Context, IntoTwirpResponse};
 
 /// Builder object used by generated code to build a Twirp service.
@@ -35,18 +38,14 @@ where
     where
         F: Fn(S, Context, Req) -> Fut + Clone + Sync + Send + 'static,
         Fut: Future<Output = Result<Res, Err>> + Send,
-        Req: prost::Message + Default + serde::de::DeserializeOwned,
+        Req: prost::Message + Default + serde::de::DeserializeOwned + 'static,
         Res: prost::Message + serde::Serialize,
         Err: IntoTwirpResponse,
     {
+        let handler = method_handler(self.service.clone(), url, f);
         TwirpRouterBuilder {
             service: self.service,
-            router: self.router.route(
-                url,
-                axum::routing::post(move |State(api): State<S>, req: Request| async move {
-                    server::handle_request(api, req, f).await
-                }),
-            ),
+            router: self.router.route(url, handler),
         }
     }
 
@@ -57,3 +56,32 @@ where
             .with_state(self.service)
     }
 }
+
+/// Build the handler for a single `rpc`, for generated code that mounts methods individually.
+///
+/// `url` is the method's path relative to the service, like `/MakeHat`. The service is captured by
+/// the handler rather than passed as axum state, so the returned `MethodRouter` can be added to a
+/// router with any state, and given its own layers.
+pub fn method_handler<S, St, F, Fut, Req, Res, Err>(service: S, url: &str, f: F) -> MethodRouter<St>
+where
+    S: Clone + Send + Sync + 'static,
+    St: Clone + Send + Sync + 'static,
+    F: Fn(S, Context, Req) -> Fut + Clone + Sync + Send + 'static,
+    Fut: Future<Output = Result<Res, Err>> + Send,
+    Req: prost::Message + Default + serde::de::DeserializeOwned + 'static,
+    Res: prost::Message + serde::Serialize,
+    Err: IntoTwirpResponse,
+{
+    let method = RpcMethod(Arc::from(url.trim_start_matches('/')));
+    // `GET` is only accepted for the methods allowed by `ServerOptions::with_get_methods`.
+    let methods = MethodFilter::POST.or(MethodFilter::GET);
+    let fallback_method = method.clone();
+    axum::routing::on(methods, move |mut req: Request| async move {
+        req.extensions_mut().insert(method);
+        server::handle_request(service, req, f).await
+    })
+    .fallback(move |mut req: Request| async move {
+        req.extensions_mut().insert(fallback_method);
+        server::method_not_allowed(req).await
+    })
+}
@@ -0,0 +1,137 @@
+//! Rejecting JSON requests with duplicate keys.
+//!
+//! serde_json keeps the last of duplicate keys of a map field, so `{"labels":{"a":"1","a":"2"}}`
+//! decodes as if it were `{"labels":{"a":"2"}}` (a duplicated message field is rejected by the
+//! derived `Deserialize`, but without saying why). That can hide client bugs, or let a request
+//! mean one thing to a proxy that keeps the first key and another to the service, so hardened
+//! endpoints may reject them instead.
+
+use std::collections::HashSet;
+
+use crate::{malformed, TwirpErrorResponse};
+
+/// Check that no object of a JSON request has the same key twice.
+pub(crate) fn check_json(bytes: &[u8]) -> Result<(), TwirpErrorResponse> {
+    let mut duplicate = None;
+    let checker = KeyChecker {
+        path: &mut vec![],
+        duplicate: &mut duplicate,
+    };
+    let mut deserializer = serde_json::Deserializer::from_slice(bytes);
+    // Malformed requests are left for the decoder to reject.
+    let _ = serde::de::DeserializeSeed::deserialize(checker, &mut deserializer);
+    match duplicate {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Visits a JSON value, keeping track of the path to it. Parsing is aborted at the first
+/// duplicate key, which is kept in `duplicate`.
+struct KeyChecker<'a> {
+    path: &'a mut Vec<String>,
+    duplicate: &'a mut Option<TwirpErrorResponse>,
+}
+
+impl KeyChecker<'_> {
+    fn nested(&mut self) -> KeyChecker<'_> {
+        KeyChecker {
+            path: self.path,
+            duplicate: self.duplicate,
+        }
+    }
+}
+
+impl<'de> serde::de::DeserializeSeed<'de> for KeyChecker<'_> {
+    type Value = ();
+
+    fn deserialize<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> serde::de::Visitor<'de> for KeyChecker<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("any JSON value")
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_i64<E>(self, _: i64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_u64<E>(self, _: u64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_unit<E>(self) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(mut self, mut seq: A) -> Result<(), A::Error> {
+        for index in 0.. {
+            self.path.push(index.to_string());
+            let item = seq.next_element_seed(self.nested())?;
+            self.path.pop();
+            if item.is_none() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_map<A: serde::de::MapAccess<'de>>(mut self, mut map: A) -> Result<(), A::Error> {
+        let mut keys = HashSet::new();
+        while let Some(name) = map.next_key::<String>()? {
+            self.path.push(name.clone());
+            if !keys.insert(name) {
+                *self.duplicate = Some(duplicate_key(self.path));
+                return Err(serde::de::Error::custom("duplicate key"));
+            }
+            map.next_value_seed(self.nested())?;
+            self.path.pop();
+        }
+        Ok(())
+    }
+}
+
+fn duplicate_key(path: &[String]) -> TwirpErrorResponse {
+    let mut err = malformed("duplicate key in JSON request");
+    err.insert_meta("reason".to_string(), "duplicate_key".to_string());
+    err.insert_meta("key".to_string(), path.join("."));
+    err
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_keys() {
+        assert!(check_json(br#"{"name":"a","names":["a","a"],"inner":{"name":"b"}}"#).is_ok());
+
+        let err = check_json(br#"{"name":"a","name":"b"}"#).unwrap_err();
+        assert_eq!(err.code, crate::TwirpErrorCode::Malformed);
+        assert_eq!(err.meta["reason"], "duplicate_key");
+        assert_eq!(err.meta["key"], "name");
+
+        let err = check_json(br#"{"inners":[{},{"count":1,"count":1}]}"#).unwrap_err();
+        assert_eq!(err.meta["key"], "inners.1.count");
+
+        // malformed requests are left to the decoder
+        assert!(check_json(br#"{"name":"#).is_ok());
+    }
+}
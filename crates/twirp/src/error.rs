@@ -1,6 +1,6 @@
 //! Implement [Twirp](https://twitchtv.github.io/twirp/) error responses
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use axum::body::Body;
 use axum::response::IntoResponse;
@@ -158,6 +158,45 @@ twirp_error_codes! {
     (Dataloss, StatusCode::INTERNAL_SERVER_ERROR, dataloss);
 }
 
+/// The prefix of the meta keys holding field violations, see [`invalid_argument_fields`].
+pub const FIELD_VIOLATION_META_PREFIX: &str = "field:";
+
+/// An `invalid_argument` error for the fields of a request that failed validation, given as
+/// pairs of the field (e.g. `hat.inches`) and why it is invalid.
+///
+/// Each violation is written to `meta` as `"field:<field>": "<reason>"`, and read back with
+/// [`TwirpErrorResponse::field_violations`]:
+///
+/// ```
+/// let err = twirp::invalid_argument_fields([("inches", "must be positive")]);
+/// assert_eq!(err.msg, "invalid fields: inches");
+/// assert_eq!(err.meta["field:inches"], "must be positive");
+/// assert_eq!(err.field_violations()["inches"], "must be positive");
+/// ```
+pub fn invalid_argument_fields<F, R>(
+    violations: impl IntoIterator<Item = (F, R)>,
+) -> TwirpErrorResponse
+where
+    F: Into<String>,
+    R: Into<String>,
+{
+    let mut fields = vec![];
+    let mut meta = HashMap::new();
+    for (field, reason) in violations {
+        let field = field.into();
+        meta.insert(
+            format!("{FIELD_VIOLATION_META_PREFIX}{field}"),
+            reason.into(),
+        );
+        fields.push(field);
+    }
+    TwirpErrorResponse {
+        code: TwirpErrorCode::InvalidArgument,
+        msg: format!("invalid fields: {}", fields.join(", ")),
+        meta,
+    }
+}
+
 impl Serialize for TwirpErrorCode {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -169,6 +208,7 @@ impl Serialize for TwirpErrorCode {
 
 // Twirp error responses are always JSON
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(from = "WireErrorResponse")]
 pub struct TwirpErrorResponse {
     pub code: TwirpErrorCode,
     pub msg: String,
@@ -177,18 +217,158 @@ pub struct TwirpErrorResponse {
     pub meta: HashMap<String, String>,
 }
 
+/// Where the `meta` of an error response is written in its JSON body.
+///
+/// Both encodings are accepted when reading error responses, so clients understand either.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorDetailsEncoding {
+    /// A `meta` object of strings, as in the Twirp spec: `"meta": {"key": "value"}`.
+    #[default]
+    Meta,
+    /// A non-standard `details` array of key-value objects, sorted by key, as some Twirp
+    /// implementations use: `"details": [{"key": "key", "value": "value"}]`.
+    Details,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ErrorDetail {
+    key: String,
+    value: String,
+}
+
+/// The JSON form of an error response, with either encoding of its details.
+#[derive(Serialize, Deserialize)]
+struct WireErrorResponse {
+    code: TwirpErrorCode,
+    msg: String,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    meta: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    details: Vec<ErrorDetail>,
+}
+
+impl From<WireErrorResponse> for TwirpErrorResponse {
+    fn from(wire: WireErrorResponse) -> Self {
+        let mut meta = wire.meta;
+        meta.extend(
+            wire.details
+                .into_iter()
+                .map(|detail| (detail.key, detail.value)),
+        );
+        TwirpErrorResponse {
+            code: wire.code,
+            msg: wire.msg,
+            meta,
+        }
+    }
+}
+
 impl TwirpErrorResponse {
     pub fn insert_meta(&mut self, key: String, value: String) -> Option<String> {
         self.meta.insert(key, value)
     }
 
+    /// The field violations of an error made with [`invalid_argument_fields`], by field. Empty
+    /// for other errors.
+    pub fn field_violations(&self) -> BTreeMap<&str, &str> {
+        self.meta
+            .iter()
+            .filter_map(|(key, reason)| {
+                let field = key.strip_prefix(FIELD_VIOLATION_META_PREFIX)?;
+                Some((field, reason.as_str()))
+            })
+            .collect()
+    }
+
     pub fn into_axum_body(self) -> Body {
-        let json =
-            serde_json::to_string(&self).expect("JSON serialization of an error should not fail");
+        self.into_axum_body_with(ErrorDetailsEncoding::Meta)
+    }
+
+    /// Like [`TwirpErrorResponse::into_axum_body`], writing `meta` with the given encoding.
+    pub fn into_axum_body_with(self, encoding: ErrorDetailsEncoding) -> Body {
+        let json = match encoding {
+            ErrorDetailsEncoding::Meta => serde_json::to_string(&self),
+            ErrorDetailsEncoding::Details => {
+                let mut details: Vec<_> = self
+                    .meta
+                    .into_iter()
+                    .map(|(key, value)| ErrorDetail { key, value })
+                    .collect();
+                details.sort_by(|a, b| a.key.cmp(&b.key));
+                serde_json::to_string(&WireErrorResponse {
+                    code: self.code,
+                    msg: self.msg,
+                    meta: HashMap::new(),
+                    details,
+                })
+            }
+        }
+        .expect("JSON serialization of an error should not fail");
         Body::new(json)
     }
 }
 
+/// The Twirp error for an HTTP error response without a Twirp error body, e.g. from a proxy or
+/// a generic middleware, based on its status the same way as other Twirp clients do: e.g. `429`
+/// and `503` become `unavailable`, and `404` `bad_route`. The error has
+/// `http_error_from_intermediary` set in its meta, along with the `status_code`.
+pub fn from_http_status(status: StatusCode) -> TwirpErrorResponse {
+    let code = match status {
+        StatusCode::UNAUTHORIZED => TwirpErrorCode::Unauthenticated,
+        StatusCode::FORBIDDEN => TwirpErrorCode::PermissionDenied,
+        StatusCode::NOT_FOUND => TwirpErrorCode::BadRoute,
+        StatusCode::TOO_MANY_REQUESTS
+        | StatusCode::BAD_GATEWAY
+        | StatusCode::SERVICE_UNAVAILABLE
+        | StatusCode::GATEWAY_TIMEOUT => TwirpErrorCode::Unavailable,
+        status if status.is_redirection() || status == StatusCode::BAD_REQUEST => {
+            TwirpErrorCode::Internal
+        }
+        _ => TwirpErrorCode::Unknown,
+    };
+    let mut err = TwirpErrorResponse {
+        code,
+        msg: format!("error from intermediary with HTTP status code {status}"),
+        meta: HashMap::new(),
+    };
+    err.insert_meta(
+        "http_error_from_intermediary".to_string(),
+        "true".to_string(),
+    );
+    err.insert_meta("status_code".to_string(), status.as_u16().to_string());
+    err
+}
+
+/// Decode the body of an error response received over HTTP, e.g. to assert on it in tests.
+///
+/// Twirp error bodies are always JSON, whatever the format of the request was. Anything else
+/// (including JSON that isn't a Twirp error) came from an intermediary such as a proxy or load
+/// balancer, and is turned into an error based on the HTTP status with [`from_http_status`], with
+/// the `body` in its meta too.
+///
+/// Decoding protobuf error envelopes is out of scope: the Twirp spec doesn't define one, so a
+/// protobuf body is handled like any other non-JSON body.
+pub fn from_response_body(
+    status: StatusCode,
+    content_type: Option<&str>,
+    body: &[u8],
+) -> TwirpErrorResponse {
+    let is_json = content_type
+        .is_some_and(|ct| ct.split(';').next().unwrap_or_default().trim() == "application/json");
+    if is_json {
+        if let Ok(err) = serde_json::from_slice(body) {
+            return err;
+        }
+    }
+    let mut err = from_http_status(status);
+    err.insert_meta(
+        "body".to_string(),
+        String::from_utf8_lossy(body).into_owned(),
+    );
+    err
+}
+
 impl IntoTwirpResponse for TwirpErrorResponse {
     fn into_twirp_response(self) -> Response<TwirpErrorResponse> {
         let mut headers = HeaderMap::new();
@@ -198,7 +378,10 @@ impl IntoTwirpResponse for TwirpErrorResponse {
         );
 
         let code = self.code.http_status_code();
-        (code, headers).into_response().map(|_| self)
+        let mut resp = (code, headers).into_response();
+        // Let middleware (e.g. access logs and metrics) see which error was returned.
+        resp.extensions_mut().insert(self.code);
+        resp.map(|_| self)
     }
 }
 
@@ -208,6 +391,59 @@ impl IntoResponse for TwirpErrorResponse {
     }
 }
 
+/// Convert a gRPC status into the equivalent Twirp error, for use where a Twirp service fronts
+/// (or is migrating from) gRPC services.
+///
+/// The gRPC code is mapped to the Twirp code with the same meaning and the message is kept. The
+/// original code is added to `meta` as `grpc_code`, along with the binary status details (if
+/// any) as standard base64 in `grpc_details`.
+#[cfg(feature = "tonic")]
+impl From<tonic::Status> for TwirpErrorResponse {
+    fn from(status: tonic::Status) -> Self {
+        use base64::Engine;
+        use tonic::Code;
+
+        let code = match status.code() {
+            Code::Cancelled => TwirpErrorCode::Canceled,
+            Code::InvalidArgument => TwirpErrorCode::InvalidArgument,
+            Code::DeadlineExceeded => TwirpErrorCode::DeadlineExceeded,
+            Code::NotFound => TwirpErrorCode::NotFound,
+            Code::AlreadyExists => TwirpErrorCode::AlreadyExists,
+            Code::PermissionDenied => TwirpErrorCode::PermissionDenied,
+            Code::ResourceExhausted => TwirpErrorCode::ResourceExhausted,
+            Code::FailedPrecondition => TwirpErrorCode::FailedPrecondition,
+            Code::Aborted => TwirpErrorCode::Aborted,
+            Code::OutOfRange => TwirpErrorCode::OutOfRange,
+            Code::Unimplemented => TwirpErrorCode::Unimplemented,
+            Code::Internal => TwirpErrorCode::Internal,
+            Code::Unavailable => TwirpErrorCode::Unavailable,
+            Code::DataLoss => TwirpErrorCode::Dataloss,
+            Code::Unauthenticated => TwirpErrorCode::Unauthenticated,
+            // An `Ok` status isn't an error, so there is nothing more specific to map it to.
+            Code::Ok | Code::Unknown => TwirpErrorCode::Unknown,
+        };
+        let mut err = TwirpErrorResponse {
+            code,
+            msg: status.message().to_string(),
+            meta: Default::default(),
+        };
+        err.insert_meta("grpc_code".to_string(), (status.code() as i32).to_string());
+        if !status.details().is_empty() {
+            let details = base64::engine::general_purpose::STANDARD.encode(status.details());
+            err.insert_meta("grpc_details".to_string(), details);
+        }
+        err
+    }
+}
+
+/// Lets handlers return a `tonic::Status` as their error type.
+#[cfg(feature = "tonic")]
+impl IntoTwirpResponse for tonic::Status {
+    fn into_twirp_response(self) -> Response<TwirpErrorResponse> {
+        TwirpErrorResponse::from(self).into_twirp_response()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::{TwirpErrorCode, TwirpErrorResponse};
@@ -260,4 +496,122 @@ mod test {
         let result = serde_json::from_str(&result).unwrap();
         assert_eq!(response, result);
     }
+
+    #[test]
+    fn twirp_error_details_encoding() {
+        let mut err = crate::invalid_argument("bad");
+        err.insert_meta("field".to_string(), "name".to_string());
+        err.insert_meta("another".to_string(), "x".to_string());
+
+        let details = r#"{"code":"invalid_argument","msg":"bad","details":[{"key":"another","value":"x"},{"key":"field","value":"name"}]}"#;
+        let decoded: TwirpErrorResponse = serde_json::from_str(details).unwrap();
+        assert_eq!(decoded, err);
+
+        let meta = serde_json::to_string(&err).unwrap();
+        assert!(meta.contains(r#""meta":{"#));
+        assert!(!meta.contains("details"));
+        let decoded: TwirpErrorResponse = serde_json::from_str(&meta).unwrap();
+        assert_eq!(decoded, err);
+    }
+
+    #[test]
+    fn twirp_error_from_response_body() {
+        use hyper::StatusCode;
+
+        use super::from_response_body;
+
+        let mut err = crate::not_found("no hat");
+        err.insert_meta("size".to_string(), "12".to_string());
+        let body = serde_json::to_vec(&err).unwrap();
+        for content_type in ["application/json", "application/json; charset=utf-8"] {
+            let decoded = from_response_body(StatusCode::NOT_FOUND, Some(content_type), &body);
+            assert_eq!(decoded, err);
+        }
+
+        // any non-JSON error body came from an intermediary
+        let decoded = from_response_body(StatusCode::NOT_FOUND, Some("text/html"), b"<h1>404</h1>");
+        assert_eq!(decoded.code, TwirpErrorCode::BadRoute);
+        assert_eq!(decoded.meta["body"], "<h1>404</h1>");
+
+        // JSON that isn't a twirp error
+        let decoded = from_response_body(StatusCode::BAD_GATEWAY, Some("application/json"), b"{}");
+        assert_eq!(decoded.code, TwirpErrorCode::Unavailable);
+        let decoded = from_response_body(StatusCode::IM_A_TEAPOT, None, b"");
+        assert_eq!(decoded.code, TwirpErrorCode::Unknown);
+    }
+
+    #[test]
+    fn twirp_error_from_protobuf_response_body() {
+        use hyper::StatusCode;
+
+        use super::from_response_body;
+
+        // there's no protobuf error envelope, so the body is kept as is
+        let decoded = from_response_body(
+            StatusCode::SERVICE_UNAVAILABLE,
+            Some("application/protobuf"),
+            b"\x0a\x02hi",
+        );
+        assert_eq!(decoded.code, TwirpErrorCode::Unavailable);
+        assert_eq!(decoded.meta["http_error_from_intermediary"], "true");
+        assert_eq!(decoded.meta["status_code"], "503");
+        assert_eq!(decoded.meta["body"], "\n\u{2}hi");
+    }
+
+    #[test]
+    fn field_violations() {
+        let err = crate::invalid_argument_fields([
+            ("hat.inches", "must be positive".to_string()),
+            ("hat.color", "must not be empty".to_string()),
+        ]);
+        assert_eq!(err.code, TwirpErrorCode::InvalidArgument);
+        assert_eq!(err.msg, "invalid fields: hat.inches, hat.color");
+
+        let json = serde_json::to_string(&err).unwrap();
+        let received: TwirpErrorResponse = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            received.field_violations().into_iter().collect::<Vec<_>>(),
+            [
+                ("hat.color", "must not be empty"),
+                ("hat.inches", "must be positive")
+            ]
+        );
+
+        // also from the `details` encoding
+        let received: TwirpErrorResponse = serde_json::from_str(
+            r#"{"code":"invalid_argument","msg":"invalid fields: size","details":[{"key":"field:size","value":"too big"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(received.field_violations()["size"], "too big");
+
+        assert!(crate::internal("boom").field_violations().is_empty());
+    }
+
+    #[cfg(feature = "tonic")]
+    #[test]
+    fn tonic_status_conversion() {
+        use tonic::{Code, Status};
+
+        for (grpc, twirp) in [
+            (Code::Cancelled, TwirpErrorCode::Canceled),
+            (Code::Unknown, TwirpErrorCode::Unknown),
+            (Code::InvalidArgument, TwirpErrorCode::InvalidArgument),
+            (Code::NotFound, TwirpErrorCode::NotFound),
+            (Code::PermissionDenied, TwirpErrorCode::PermissionDenied),
+            (Code::Unauthenticated, TwirpErrorCode::Unauthenticated),
+            (Code::DataLoss, TwirpErrorCode::Dataloss),
+            (Code::Unavailable, TwirpErrorCode::Unavailable),
+        ] {
+            let err = TwirpErrorResponse::from(Status::new(grpc, "oops"));
+            assert_eq!(err.code, twirp);
+            assert_eq!(err.msg, "oops");
+            assert_eq!(err.meta["grpc_code"], (grpc as i32).to_string());
+            assert!(!err.meta.contains_key("grpc_details"));
+        }
+
+        let status = Status::with_details(Code::Internal, "boom", vec![1, 2, 3].into());
+        let err = TwirpErrorResponse::from(status);
+        assert_eq!(err.code, TwirpErrorCode::Internal);
+        assert_eq!(err.meta["grpc_details"], "AQID");
+    }
 }
@@ -0,0 +1,374 @@
+//! Entity tags for conditional requests.
+//!
+//! A handler tags its response by inserting an [`ETag`] into its response extensions:
+//!
+//! ```
+//! # use twirp::etag::ETag;
+//! # fn handler(ctx: twirp::Context) {
+//! ctx.insert(ETag::strong("v42"));
+//! # }
+//! ```
+//!
+//! The tag is sent in the `ETag` response header. If the request's `If-None-Match` header matches
+//! it (using the weak comparison of [RFC 9110], so `W/"v42"` matches `"v42"`, and `*` matches any
+//! tag), the response is replaced by an empty `304 Not Modified`, letting the caller reuse the
+//! response it already has. Responses without an `ETag`, and error responses, are never
+//! replaced.
+//!
+//! [RFC 9110]: https://www.rfc-editor.org/rfc/rfc9110#section-13.1.2
+
+use axum::body::Body;
+use hyper::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG};
+use hyper::{Response, StatusCode};
+
+use crate::TwirpErrorCode;
+
+/// An entity tag identifying a version of a response.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ETag {
+    tag: String,
+    weak: bool,
+}
+
+impl ETag {
+    /// A strong tag, for responses that are byte-for-byte identical whenever the tag is.
+    pub fn strong(tag: impl Into<String>) -> Self {
+        Self {
+            tag: tag.into(),
+            weak: false,
+        }
+    }
+
+    /// A weak tag, for responses that are semantically equivalent whenever the tag is.
+    pub fn weak(tag: impl Into<String>) -> Self {
+        Self {
+            tag: tag.into(),
+            weak: true,
+        }
+    }
+
+    /// The opaque tag, without quotes.
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn is_weak(&self) -> bool {
+        self.weak
+    }
+
+    /// Weak comparison: the tags are equal, whether either is weak or not.
+    pub fn weak_eq(&self, other: &ETag) -> bool {
+        self.tag == other.tag
+    }
+
+    /// Strong comparison: neither tag is weak, and the tags are equal.
+    pub fn strong_eq(&self, other: &ETag) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// The tag as a header value, like `"v42"` or `W/"v42"`. `None` if the tag contains a quote
+    /// or characters that aren't allowed in headers.
+    pub fn to_header_value(&self) -> Option<HeaderValue> {
+        if self.tag.contains('"') {
+            return None;
+        }
+        let prefix = if self.weak { "W/" } else { "" };
+        HeaderValue::from_str(&format!("{prefix}\"{}\"", self.tag)).ok()
+    }
+
+    /// The tag of another representation of the same response, like its gzip encoding.
+    #[cfg(feature = "compression")]
+    pub(crate) fn with_suffix(&self, suffix: &str) -> ETag {
+        ETag {
+            tag: format!("{}{suffix}", self.tag),
+            weak: self.weak,
+        }
+    }
+}
+
+/// Parse a list of entity tags, like the value of an `If-None-Match` header. Returns `None` for
+/// the `*` wildcard, and skips malformed entries.
+fn parse_list(value: &str) -> Option<Vec<ETag>> {
+    if value.trim() == "*" {
+        return None;
+    }
+    let mut tags = vec![];
+    let mut rest = value;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        if rest.is_empty() {
+            return Some(tags);
+        }
+        let (weak, quoted) = match rest.strip_prefix("W/") {
+            Some(quoted) => (true, quoted),
+            None => (false, rest),
+        };
+        let parsed = quoted
+            .strip_prefix('"')
+            .and_then(|quoted| quoted.split_once('"'));
+        match parsed {
+            Some((tag, after)) => {
+                tags.push(ETag {
+                    tag: tag.to_string(),
+                    weak,
+                });
+                rest = after;
+            }
+            // Skip to the next entry.
+            None => rest = rest.split_once(',').map_or("", |(_, after)| after),
+        }
+    }
+}
+
+/// Whether the `If-None-Match` header values match `etag`. `false` if there are none.
+pub(crate) fn if_none_match(values: &[HeaderValue], etag: &ETag) -> bool {
+    values
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| match parse_list(value) {
+            None => true,
+            Some(tags) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        })
+}
+
+/// Set the `ETag` header of a successful response, replacing it with `304 Not Modified` if
+/// the request's `If-None-Match` header values match. Errors, including those sent with a `200`
+/// status (see `ServerOptions::with_errors_in_body`), are returned untouched.
+pub(crate) fn apply(
+    resp: Response<Body>,
+    etag: &ETag,
+    request_tags: &[HeaderValue],
+) -> Response<Body> {
+    if !resp.status().is_success() || resp.extensions().get::<TwirpErrorCode>().is_some() {
+        return resp;
+    }
+    let Some(value) = etag.to_header_value() else {
+        return resp;
+    };
+    let mut resp = if if_none_match(request_tags, etag) {
+        let (mut parts, _) = resp.into_parts();
+        parts.status = StatusCode::NOT_MODIFIED;
+        // Caches update the stored response with the other headers (e.g. `Vary`), but there
+        // is no body for these to describe.
+        for name in [CONTENT_TYPE, CONTENT_LENGTH, CONTENT_ENCODING] {
+            parts.headers.remove(name);
+        }
+        Response::from_parts(parts, Body::empty())
+    } else {
+        resp
+    };
+    resp.headers_mut().insert(ETAG, value);
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use http_body_util::BodyExt;
+    use hyper::header::IF_NONE_MATCH;
+    use tower::Service;
+
+    use crate::details::TwirpRouterBuilder;
+    use crate::test::*;
+    use crate::Context;
+
+    use super::*;
+
+    fn headers(if_none_match: &[&'static str]) -> Vec<HeaderValue> {
+        if_none_match
+            .iter()
+            .map(|value| HeaderValue::from_static(value))
+            .collect()
+    }
+
+    #[test]
+    fn test_comparison() {
+        let strong = ETag::strong("v1");
+        let weak = ETag::weak("v1");
+        assert!(strong.weak_eq(&weak));
+        assert!(strong.strong_eq(&strong));
+        assert!(!strong.strong_eq(&weak));
+        assert!(!weak.strong_eq(&weak));
+        assert_eq!(strong.to_header_value().unwrap(), "\"v1\"");
+        assert_eq!(weak.to_header_value().unwrap(), "W/\"v1\"");
+        assert!(ETag::strong("a\"b").to_header_value().is_none());
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let strong = ETag::strong("v1");
+        let weak = ETag::weak("v1");
+
+        // If-None-Match uses the weak comparison
+        for etag in [&strong, &weak] {
+            assert!(if_none_match(&headers(&["\"v1\""]), etag));
+            assert!(if_none_match(&headers(&["W/\"v1\""]), etag));
+            assert!(if_none_match(&headers(&["\"v0\", W/\"v1\""]), etag));
+            assert!(if_none_match(&headers(&["\"v0\"", "\"v1\""]), etag));
+            assert!(if_none_match(&headers(&["*"]), etag));
+            assert!(!if_none_match(&headers(&[]), etag));
+            assert!(!if_none_match(&headers(&["\"v0\", \"v2\""]), etag));
+            assert!(!if_none_match(&headers(&["v1"]), etag));
+        }
+
+        // commas may appear inside tags, and malformed entries are skipped
+        assert!(if_none_match(&headers(&["\"a,b\""]), &ETag::strong("a,b")));
+        assert!(if_none_match(&headers(&["junk, \"v1\""]), &strong));
+    }
+
+    #[tokio::test]
+    async fn test_not_modified() {
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                    ctx.insert(ETag::weak(format!("{}-1", req.name)));
+                    api.ping(ctx, req).await
+                },
+            )
+            .build();
+        let mut router = axum::Router::new().nest("/twirp/test.TestAPI", twirp_routes);
+
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[ETAG], "W/\"hi-1\"");
+
+        for if_none_match in ["W/\"hi-1\"", "\"hi-1\"", "\"hi-0\", W/\"hi-1\"", "*"] {
+            let mut req = gen_ping_request("hi");
+            req.headers_mut()
+                .insert(IF_NONE_MATCH, HeaderValue::from_static(if_none_match));
+            let resp = router.call(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::NOT_MODIFIED, "{if_none_match}");
+            assert_eq!(resp.headers()[ETAG], "W/\"hi-1\"");
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            assert!(body.is_empty());
+        }
+
+        let mut req = gen_ping_request("hi");
+        req.headers_mut()
+            .insert(IF_NONE_MATCH, HeaderValue::from_static("\"hi-0\""));
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(&data.name, "hi");
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_gzip_tag() {
+        use hyper::header::{ACCEPT_ENCODING, CONTENT_ENCODING, VARY};
+
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                    ctx.insert(ETag::strong("v1"));
+                    api.ping(ctx, req).await
+                },
+            )
+            .build();
+        let mut router = axum::Router::new()
+            .nest("/twirp/test.TestAPI", twirp_routes)
+            .layer(axum::Extension(
+                crate::server::ServerOptions::default().with_compress_response_threshold(0),
+            ));
+        let gen_req = |if_none_match: &'static str| {
+            let mut req = gen_ping_request("hi");
+            req.headers_mut()
+                .insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+            req.headers_mut()
+                .insert(IF_NONE_MATCH, HeaderValue::from_static(if_none_match));
+            req
+        };
+
+        // the gzip encoded response has its own tag
+        let resp = router.call(gen_req("\"v1\"")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(resp.headers()[ETAG], "\"v1-gzip\"");
+
+        let resp = router.call(gen_req("\"v1-gzip\"")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers()[ETAG], "\"v1-gzip\"");
+        assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+        let vary: Vec<_> = resp.headers().get_all(VARY).iter().collect();
+        assert_eq!(vary, ["accept", "accept-encoding"]);
+
+        // the identity encoded one keeps the handler's tag
+        let mut req = gen_ping_request("hi");
+        req.headers_mut()
+            .insert(IF_NONE_MATCH, HeaderValue::from_static("\"v1-gzip\""));
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[ETAG], "\"v1\"");
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_gzip_tag_under_threshold() {
+        use hyper::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
+
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                    ctx.insert(ETag::strong("v1"));
+                    api.ping(ctx, req).await
+                },
+            )
+            .build();
+        let mut router = axum::Router::new()
+            .nest("/twirp/test.TestAPI", twirp_routes)
+            .layer(axum::Extension(
+                crate::server::ServerOptions::default().with_compress_response_threshold(1024),
+            ));
+
+        // the small response is sent uncompressed, so it keeps the handler's tag
+        let mut req = gen_ping_request("hi");
+        req.headers_mut()
+            .insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(resp.headers()[ETAG], "\"v1\"");
+
+        let mut req = gen_ping_request("hi");
+        req.headers_mut()
+            .insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        req.headers_mut()
+            .insert(IF_NONE_MATCH, HeaderValue::from_static("\"v1\""));
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers()[ETAG], "\"v1\"");
+    }
+
+    #[tokio::test]
+    async fn test_errors_in_body() {
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                |_: Arc<TestApiServer>, ctx: Context, _: PingRequest| async move {
+                    ctx.insert(ETag::strong("v1"));
+                    Err::<PingResponse, _>(crate::internal("no hats"))
+                },
+            )
+            .build();
+        let mut router = axum::Router::new()
+            .nest("/twirp/test.TestAPI", twirp_routes)
+            .layer(axum::Extension(
+                crate::server::ServerOptions::default().with_errors_in_body(true),
+            ));
+
+        // the error is sent as it is, though its status is 200
+        let mut req = gen_ping_request("hi");
+        req.headers_mut()
+            .insert(IF_NONE_MATCH, HeaderValue::from_static("\"v1\""));
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!resp.headers().contains_key(ETAG));
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Internal);
+        assert_eq!(err.msg, "no hats");
+    }
+}
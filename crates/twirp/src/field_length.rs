@@ -0,0 +1,246 @@
+//! Limits on the length of individual fields of request messages.
+//!
+//! prost doesn't limit field lengths, and the request types aren't introspectable, so the limit is
+//! checked on the encoded request instead: on every string of a JSON request, and on every
+//! length-delimited field of a protobuf request. The protobuf wire format doesn't tell strings
+//! and bytes apart from embedded messages and packed repeated fields, so any length-delimited
+//! field over the limit is rejected, embedded messages included. When it is a well-formed message,
+//! the error names the innermost field over the limit.
+
+use crate::{invalid_argument, TwirpErrorResponse};
+
+/// How deep embedded messages are followed, the same as prost's default recursion limit.
+const MAX_DEPTH: usize = 100;
+
+const VARINT: u64 = 0;
+const FIXED64: u64 = 1;
+const LENGTH_DELIMITED: u64 = 2;
+const FIXED32: u64 = 5;
+
+/// Check the fields of a protobuf encoded message.
+pub(crate) fn check_protobuf(bytes: &[u8], max_len: usize) -> Result<(), TwirpErrorResponse> {
+    // Malformed messages are left for the decoder to reject.
+    check_message(bytes, max_len, &mut vec![], 0)?;
+    Ok(())
+}
+
+/// Check the strings of a JSON request.
+pub(crate) fn check_json(
+    value: &serde_json::Value,
+    max_len: usize,
+) -> Result<(), TwirpErrorResponse> {
+    check_json_value(value, max_len, &mut vec![])
+}
+
+/// Count the fields of a protobuf encoded message, including those of its embedded messages.
+/// Every element of a non-packed repeated field counts, while a packed repeated field counts once
+/// as its elements can't be told apart from bytes. As with the length check, a length-delimited
+/// field is taken to be an embedded message whenever it is a well-formed one.
+pub(crate) fn count_fields(bytes: &[u8]) -> usize {
+    count_message_fields(bytes, 0).unwrap_or_default()
+}
+
+/// The fields of `bytes`, or `None` if it isn't a well-formed message.
+fn count_message_fields(mut bytes: &[u8], depth: usize) -> Option<usize> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    let mut count = 0;
+    while !bytes.is_empty() {
+        let key = read_varint(&mut bytes)?;
+        if key >> 3 == 0 {
+            return None;
+        }
+        let skip = match key & 0x7 {
+            VARINT => {
+                read_varint(&mut bytes)?;
+                0
+            }
+            FIXED64 => 8,
+            FIXED32 => 4,
+            LENGTH_DELIMITED => {
+                let len = usize::try_from(read_varint(&mut bytes)?).ok()?;
+                let value = bytes.get(..len)?;
+                count += count_message_fields(value, depth + 1).unwrap_or_default();
+                len
+            }
+            _ => return None,
+        };
+        bytes = bytes.get(skip..)?;
+        count += 1;
+    }
+    Some(count)
+}
+
+/// Walk the fields of `bytes`, returning `Ok(false)` if it isn't a well-formed message.
+fn check_message(
+    mut bytes: &[u8],
+    max_len: usize,
+    path: &mut Vec<String>,
+    depth: usize,
+) -> Result<bool, TwirpErrorResponse> {
+    if depth > MAX_DEPTH {
+        return Ok(false);
+    }
+    while !bytes.is_empty() {
+        let Some(key) = read_varint(&mut bytes) else {
+            return Ok(false);
+        };
+        let (field, wire_type) = (key >> 3, key & 0x7);
+        if field == 0 {
+            return Ok(false);
+        }
+        let skip = match wire_type {
+            VARINT => match read_varint(&mut bytes) {
+                Some(_) => 0,
+                None => return Ok(false),
+            },
+            FIXED64 => 8,
+            FIXED32 => 4,
+            LENGTH_DELIMITED => {
+                let Some(len) = read_varint(&mut bytes).and_then(|len| usize::try_from(len).ok())
+                else {
+                    return Ok(false);
+                };
+                let Some(value) = bytes.get(..len) else {
+                    return Ok(false);
+                };
+                if len > max_len {
+                    path.push(field.to_string());
+                    check_message(value, max_len, path, depth + 1)?;
+                    return Err(too_long(path, max_len));
+                }
+                len
+            }
+            // Groups are deprecated, and not supported by prost either.
+            _ => return Ok(false),
+        };
+        let Some(rest) = bytes.get(skip..) else {
+            return Ok(false);
+        };
+        bytes = rest;
+    }
+    Ok(true)
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for (i, byte) in bytes.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            *bytes = &bytes[i + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn check_json_value(
+    value: &serde_json::Value,
+    max_len: usize,
+    path: &mut Vec<String>,
+) -> Result<(), TwirpErrorResponse> {
+    match value {
+        serde_json::Value::String(s) if s.len() > max_len => Err(too_long(path, max_len)),
+        serde_json::Value::Array(items) => {
+            for (index, item) in items.iter().enumerate() {
+                path.push(index.to_string());
+                check_json_value(item, max_len, path)?;
+                path.pop();
+            }
+            Ok(())
+        }
+        serde_json::Value::Object(fields) => {
+            for (name, field) in fields {
+                path.push(name.clone());
+                check_json_value(field, max_len, path)?;
+                path.pop();
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn too_long(path: &[String], max_len: usize) -> TwirpErrorResponse {
+    let mut err = invalid_argument("field exceeds the maximum length");
+    err.insert_meta("field".to_string(), path.join("."));
+    err.insert_meta("max_length".to_string(), max_len.to_string());
+    err
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::*;
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Inner {
+        #[prost(string, tag = "1")]
+        name: String,
+        #[prost(bytes = "vec", tag = "2")]
+        data: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Outer {
+        #[prost(message, repeated, tag = "3")]
+        inners: Vec<Inner>,
+        #[prost(uint64, tag = "4")]
+        count: u64,
+    }
+
+    fn outer(name: &str, data: &[u8]) -> Outer {
+        Outer {
+            inners: vec![Inner {
+                name: name.to_string(),
+                data: data.to_vec(),
+            }],
+            count: 300,
+        }
+    }
+
+    #[test]
+    fn test_protobuf() {
+        let ok = outer("hat", &[1; 8]).encode_to_vec();
+        assert!(check_protobuf(&ok, 16).is_ok());
+
+        let err = check_protobuf(&outer("fedora", &[1; 9]).encode_to_vec(), 8).unwrap_err();
+        assert_eq!(err.meta["field"], "3.2");
+        assert_eq!(err.meta["max_length"], "8");
+        let err = check_protobuf(&outer("stovepipe", &[]).encode_to_vec(), 8).unwrap_err();
+        assert_eq!(err.meta["field"], "3.1");
+
+        // the embedded message is over the limit, even though its fields aren't
+        let err = check_protobuf(&ok, 8).unwrap_err();
+        assert_eq!(err.meta["field"], "3");
+
+        // and so is a string that happens to be a well-formed message
+        let nested = outer(&"\n\u{1}a".repeat(4), &[]).encode_to_vec();
+        let err = check_protobuf(&nested, 8).unwrap_err();
+        assert_eq!(err.meta["field"], "3.1");
+
+        // malformed messages are left to the decoder
+        assert!(check_protobuf(&ok[..ok.len() - 1], 16).is_ok());
+    }
+
+    #[test]
+    fn test_count_fields() {
+        // `inners`, its `name` and `data`, and `count`
+        assert_eq!(count_fields(&outer("hat", &[1, 2]).encode_to_vec()), 4);
+        let mut two = outer("hat", &[]);
+        two.inners.push(Inner::default());
+        // unset fields aren't encoded
+        assert_eq!(count_fields(&two.encode_to_vec()), 4);
+        assert_eq!(count_fields(&Outer::default().encode_to_vec()), 0);
+    }
+
+    #[test]
+    fn test_json() {
+        let json = serde_json::json!({"inners": [{"name": "hat", "data": "AQID"}], "count": 300});
+        assert!(check_json(&json, 4).is_ok());
+        let err = check_json(&json, 3).unwrap_err();
+        assert_eq!(err.meta["field"], "inners.0.data");
+    }
+}
@@ -1,2 +1,44 @@
+//! Headers used by the Twirp client and server.
+
 pub(crate) const CONTENT_TYPE_PROTOBUF: &[u8] = b"application/protobuf";
 pub(crate) const CONTENT_TYPE_JSON: &[u8] = b"application/json";
+/// Content types other toolchains use for protobuf, accepted by the client in responses and by
+/// the server in requests.
+const CONTENT_TYPE_PROTOBUF_VARIANTS: &[&[u8]] = &[
+    CONTENT_TYPE_PROTOBUF,
+    b"application/x-protobuf",
+    b"application/x-google-protobuf",
+    b"application/vnd.google.protobuf",
+];
+/// Non-standard content type for a length-delimited (varint length prefixed) protobuf request.
+pub(crate) const CONTENT_TYPE_PROTOBUF_DELIMITED: &[u8] = b"application/protobuf-delimited";
+
+/// Non-standard header carrying the number of milliseconds the client is willing to wait for a
+/// response, set by
+/// [`ClientBuilder::default_timeout_ms`](crate::ClientBuilder::default_timeout_ms). The server
+/// applies it to the handler like its own timeouts.
+pub const TIMEOUT_MS: &str = "twirp-timeout-ms";
+
+/// Non-standard header set on error responses sent with a `200 OK` status, holding the Twirp
+/// error code. See
+/// [`ServerOptions::with_errors_in_body`](crate::server::ServerOptions::with_errors_in_body).
+pub const ERROR_CODE: &str = "twirp-error-code";
+
+/// The conventional request id header, e.g. for
+/// [`ServerOptions::with_request_id_header`](crate::server::ServerOptions::with_request_id_header).
+pub const REQUEST_ID: &str = "x-request-id";
+
+/// The [W3C `baggage`](https://www.w3.org/TR/baggage/) header, see [`crate::baggage`].
+pub const BAGGAGE: &str = "baggage";
+
+/// Whether `content_type` is one of the common protobuf content types, ignoring any parameters.
+pub(crate) fn is_protobuf_content_type(content_type: &[u8]) -> bool {
+    let mime = content_type
+        .split(|&b| b == b';')
+        .next()
+        .unwrap_or_default()
+        .trim_ascii();
+    CONTENT_TYPE_PROTOBUF_VARIANTS
+        .iter()
+        .any(|variant| mime.eq_ignore_ascii_case(variant))
+}
@@ -0,0 +1,215 @@
+//! Readiness checks for the dependencies of a service.
+//!
+//! Register an async check per dependency (database, cache, downstream services, ...) with a
+//! [`HealthRegistry`], and mount its [`handler`](HealthRegistry::handler) next to the Twirp
+//! routes:
+//!
+//! ```
+//! use twirp::health::HealthRegistry;
+//!
+//! # fn build(twirp_routes: twirp::Router) -> twirp::Router {
+//! let health = HealthRegistry::new().register("database", || async {
+//!     // e.g. run `SELECT 1`
+//!     Ok::<_, String>(())
+//! });
+//! twirp::Router::new()
+//!     .nest("/twirp", twirp_routes)
+//!     .route("/readyz", health.handler())
+//! # }
+//! ```
+//!
+//! The endpoint runs every check concurrently and responds `200 OK` when they all pass, or
+//! `503 Service Unavailable` otherwise, with a JSON body describing each check:
+//!
+//! ```json
+//! {"status":"unavailable","checks":{"database":{"status":"ok"},"cache":{"status":"error","error":"timed out"}}}
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use axum::response::{IntoResponse, Response};
+use axum::routing::MethodRouter;
+use axum::Json;
+use futures::future::{join_all, BoxFuture};
+use futures::{Future, FutureExt};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
+
+type Check = Arc<dyn Fn() -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+
+/// A set of named dependency health checks, aggregated by a readiness endpoint.
+#[derive(Clone)]
+pub struct HealthRegistry {
+    checks: Vec<(String, Check)>,
+    timeout: Duration,
+}
+
+impl Default for HealthRegistry {
+    fn default() -> Self {
+        Self {
+            checks: vec![],
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Debug for HealthRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HealthRegistry")
+            .field(
+                "checks",
+                &self.checks.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            )
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl HealthRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long each check may run before it is considered failed. Defaults to 5 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Register the check `name`. It passes if it returns `Ok` within the timeout.
+    pub fn register<F, Fut, E>(mut self, name: impl Into<String>, check: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: ToString,
+    {
+        let check: Check = Arc::new(move || {
+            check()
+                .map(|res| res.map_err(|err| err.to_string()))
+                .boxed()
+        });
+        self.checks.push((name.into(), check));
+        self
+    }
+
+    /// Run all the checks concurrently.
+    pub async fn check(&self) -> HealthReport {
+        let results = join_all(self.checks.iter().map(|(name, check)| async move {
+            let status = match tokio::time::timeout(self.timeout, check()).await {
+                Ok(Ok(())) => CheckStatus::Ok,
+                Ok(Err(error)) => CheckStatus::Error { error },
+                Err(_) => CheckStatus::Error {
+                    error: "timed out".to_string(),
+                },
+            };
+            (name.clone(), status)
+        }))
+        .await;
+        let checks: BTreeMap<_, _> = results.into_iter().collect();
+        let status = if checks.values().all(|s| *s == CheckStatus::Ok) {
+            Status::Ok
+        } else {
+            Status::Unavailable
+        };
+        HealthReport { status, checks }
+    }
+
+    /// An axum handler for a readiness endpoint, responding with the [`HealthReport`] as JSON.
+    pub fn handler<S>(self) -> MethodRouter<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        axum::routing::get(move || async move { self.check().await.into_response() })
+    }
+}
+
+/// The aggregated result of a [`HealthRegistry`]'s checks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub status: Status,
+    pub checks: BTreeMap<String, CheckStatus>,
+}
+
+/// Whether all the checks passed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    Unavailable,
+}
+
+/// The result of a single check.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum CheckStatus {
+    Ok,
+    Error { error: String },
+}
+
+impl IntoResponse for HealthReport {
+    fn into_response(self) -> Response {
+        let status = match self.status {
+            Status::Ok => StatusCode::OK,
+            Status::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use http_body_util::BodyExt;
+    use hyper::Request;
+    use tower::Service;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let health = HealthRegistry::new()
+            .with_timeout(Duration::from_millis(50))
+            .register("database", || async { Ok::<_, String>(()) });
+        let mut router = axum::Router::new().route("/readyz", health.clone().handler());
+        let req = || Request::get("/readyz").body(Body::empty()).unwrap();
+
+        let resp = router.call(req()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let health = health
+            .register("cache", || async { Err("connection refused") })
+            .register("search", || async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Ok::<_, String>(())
+            });
+        let mut router = axum::Router::new().route("/readyz", health.handler());
+        let resp = router.call(req()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let report: HealthReport = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            report,
+            HealthReport {
+                status: Status::Unavailable,
+                checks: BTreeMap::from([
+                    (
+                        "cache".to_string(),
+                        CheckStatus::Error {
+                            error: "connection refused".to_string()
+                        }
+                    ),
+                    ("database".to_string(), CheckStatus::Ok),
+                    (
+                        "search".to_string(),
+                        CheckStatus::Error {
+                            error: "timed out".to_string()
+                        }
+                    ),
+                ]),
+            }
+        );
+    }
+}
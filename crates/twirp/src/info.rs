@@ -0,0 +1,154 @@
+//! An opt-in endpoint describing the running server, for operations tooling.
+//!
+//! Nothing is served unless the handler is mounted, conventionally at [`SERVER_INFO_PATH`]. Since
+//! the handler is an ordinary `MethodRouter`, it can be put behind the same authentication layer
+//! as the rest of the service:
+//!
+//! ```
+//! use twirp::info::{ServerInfo, SERVER_INFO_PATH};
+//!
+//! # fn build(twirp_routes: twirp::Router) -> twirp::Router {
+//! let info = ServerInfo::new(env!("CARGO_PKG_VERSION")).with_build("3f2a1c9");
+//! twirp::Router::new()
+//!     .nest("/twirp", twirp_routes)
+//!     .route(SERVER_INFO_PATH, info.handler())
+//! # }
+//! ```
+//!
+//! The endpoint responds to `GET` with the [`ServerInfo`] as JSON. Clients can read it with
+//! [`Client::server_info`](crate::Client::server_info), and adapt their requests to it with
+//! [`ClientBuilder::negotiate`](crate::ClientBuilder::negotiate).
+
+use std::collections::BTreeMap;
+
+use axum::routing::MethodRouter;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+
+/// The path reserved for the server info endpoint.
+pub const SERVER_INFO_PATH: &str = "/__twirp/serverinfo";
+
+/// The Twirp protocol versions this crate implements.
+const PROTOCOLS: &[&str] = &["v7"];
+
+/// The request content types the `twirp` server accepts.
+const CONTENT_TYPES: &[&str] = &["application/json", "application/protobuf"];
+
+/// Version and build information about a server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<String>,
+    /// The Twirp protocol versions the server supports.
+    pub protocols: Vec<String>,
+    /// The request content types the server accepts, in order of preference. Empty if the server
+    /// doesn't say.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub content_types: Vec<String>,
+    /// Any other information to report, e.g. the deployment region.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra: BTreeMap<String, String>,
+}
+
+impl ServerInfo {
+    pub fn new(version: impl Into<String>) -> Self {
+        Self {
+            version: version.into(),
+            build: None,
+            protocols: PROTOCOLS.iter().map(|p| p.to_string()).collect(),
+            content_types: CONTENT_TYPES.iter().map(|ct| ct.to_string()).collect(),
+            extra: BTreeMap::new(),
+        }
+    }
+
+    /// The build identifier, e.g. the commit hash.
+    pub fn with_build(mut self, build: impl Into<String>) -> Self {
+        self.build = Some(build.into());
+        self
+    }
+
+    /// The request content types the server accepts, e.g. when a proxy in front of it only
+    /// accepts some of them.
+    pub fn with_content_types<I, C>(mut self, content_types: I) -> Self
+    where
+        I: IntoIterator<Item = C>,
+        C: Into<String>,
+    {
+        self.content_types = content_types.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Add a field to `extra`.
+    pub fn with_extra(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.extra.insert(key.into(), value.into());
+        self
+    }
+
+    /// An axum handler responding with this info as JSON.
+    pub fn handler<S>(self) -> MethodRouter<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        axum::routing::get(move || async move { Json(self) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::extract::Request;
+    use axum::middleware::{self, Next};
+    use axum::response::{IntoResponse, Response};
+    use http_body_util::BodyExt;
+    use hyper::StatusCode;
+    use tower::Service;
+
+    use super::*;
+
+    async fn require_token(req: Request, next: Next) -> Response {
+        match req.headers().get("authorization") {
+            Some(token) if token == "Bearer ops" => next.run(req).await,
+            _ => StatusCode::UNAUTHORIZED.into_response(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_info() {
+        let info = ServerInfo::new("1.2.3")
+            .with_build("abc123")
+            .with_extra("region", "eu-west-1");
+        let mut router = axum::Router::new().route(
+            SERVER_INFO_PATH,
+            info.clone()
+                .handler()
+                .layer(middleware::from_fn(require_token)),
+        );
+        let req = |token: Option<&'static str>| {
+            let mut req = Request::get(SERVER_INFO_PATH);
+            if let Some(token) = token {
+                req = req.header("authorization", token);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+
+        let resp = router.call(req(None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = router.call(req(Some("Bearer ops"))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "version": "1.2.3",
+                "build": "abc123",
+                "protocols": ["v7"],
+                "content_types": ["application/json", "application/protobuf"],
+                "extra": {"region": "eu-west-1"},
+            })
+        );
+        assert_eq!(serde_json::from_value::<ServerInfo>(json).unwrap(), info);
+    }
+}
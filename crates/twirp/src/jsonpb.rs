@@ -0,0 +1,654 @@
+//! Helpers for encoding messages as proto3 JSON with `serde`.
+//!
+//! Messages are (de)serialized with their `serde` implementations, usually derived through
+//! `prost-build`'s `type_attribute`, which stays the cheapest option when both ends are Rust.
+//! Where the derived encoding differs from the [proto3 JSON mapping], which the Go and Python
+//! Twirp implementations use, the messages and fields can opt in to it:
+//!
+//! ```ignore
+//! prost_build::Config::new()
+//!     .type_attribute(".", "#[derive(serde::Serialize,serde::Deserialize)]")
+//!     // lowerCamelCase field names
+//!     .type_attribute(".", r#"#[serde(rename_all = "camelCase")]"#)
+//!     .field_attribute("Measurement.id", r#"#[serde(with = "twirp::jsonpb::int64")]"#)
+//!     .field_attribute("Measurement.value", r#"#[serde(with = "twirp::jsonpb::float")]"#)
+//!     .field_attribute("Measurement.samples", r#"#[serde(with = "twirp::jsonpb::float::repeated")]"#)
+//!     .field_attribute("Measurement.raw", r#"#[serde(with = "twirp::jsonpb::bytes")]"#)
+//! ```
+//!
+//! `twirp_build::configure_json_pb` adds these attributes based on the types of the fields.
+//!
+//! With `rename_all`, requests using the original proto field names (which proto3 JSON parsers
+//! also accept) are no longer understood, unless the fields are given an `alias` as well.
+//!
+//! `google.protobuf.Timestamp` and `google.protobuf.Duration` are written as RFC 3339 strings by
+//! the types of `prost-wkt-types`, see the `example` crate. Enums are still written as numbers
+//! rather than by their value names, as prost enum fields are plain `i32`s.
+//!
+//! [proto3 JSON mapping]: https://protobuf.dev/programming-guides/proto3/#json
+
+/// `float` and `double` fields.
+///
+/// The special values are encoded as the strings `"NaN"`, `"Infinity"` and `"-Infinity"`, instead
+/// of failing to serialize (or becoming `null`, depending on the serializer). Decoding accepts
+/// them, as well as numbers and numbers in strings (e.g. `"1.5"`). Values out of the range of the
+/// field's type are rejected.
+pub mod float {
+    use std::fmt;
+    use std::marker::PhantomData;
+
+    use serde::de::{self, Deserializer, Visitor};
+    use serde::Serializer;
+
+    const NAN: &str = "NaN";
+    const INFINITY: &str = "Infinity";
+    const NEG_INFINITY: &str = "-Infinity";
+
+    /// `f32` or `f64`.
+    pub trait Float: Copy + private::Sealed {
+        #[doc(hidden)]
+        fn to_f64(self) -> f64;
+        #[doc(hidden)]
+        fn from_f64(value: f64) -> Option<Self>;
+        #[doc(hidden)]
+        fn serialize_finite<S: Serializer>(self, serializer: S) -> Result<S::Ok, S::Error>;
+    }
+
+    impl Float for f32 {
+        fn to_f64(self) -> f64 {
+            self as f64
+        }
+
+        fn from_f64(value: f64) -> Option<Self> {
+            let narrowed = value as f32;
+            (narrowed.is_finite() || !value.is_finite()).then_some(narrowed)
+        }
+
+        fn serialize_finite<S: Serializer>(self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_f32(self)
+        }
+    }
+
+    impl Float for f64 {
+        fn to_f64(self) -> f64 {
+            self
+        }
+
+        fn from_f64(value: f64) -> Option<Self> {
+            Some(value)
+        }
+
+        fn serialize_finite<S: Serializer>(self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_f64(self)
+        }
+    }
+
+    mod private {
+        pub trait Sealed {}
+        impl Sealed for f32 {}
+        impl Sealed for f64 {}
+    }
+
+    pub fn serialize<T: Float, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        let wide = value.to_f64();
+        if wide.is_nan() {
+            serializer.serialize_str(NAN)
+        } else if wide == f64::INFINITY {
+            serializer.serialize_str(INFINITY)
+        } else if wide == f64::NEG_INFINITY {
+            serializer.serialize_str(NEG_INFINITY)
+        } else {
+            value.serialize_finite(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, T: Float, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        deserializer.deserialize_any(FloatVisitor(PhantomData))
+    }
+
+    struct FloatVisitor<T>(PhantomData<T>);
+
+    impl<T: Float> FloatVisitor<T> {
+        fn narrow<E: de::Error>(value: f64) -> Result<T, E> {
+            T::from_f64(value).ok_or_else(|| E::custom(format!("{value} is out of range")))
+        }
+    }
+
+    impl<T: Float> Visitor<'_> for FloatVisitor<T> {
+        type Value = T;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a number, \"NaN\", \"Infinity\" or \"-Infinity\"")
+        }
+
+        fn visit_f64<E: de::Error>(self, value: f64) -> Result<T, E> {
+            Self::narrow(value)
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<T, E> {
+            Self::narrow(value as f64)
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<T, E> {
+            Self::narrow(value as f64)
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<T, E> {
+            let parsed = match value {
+                NAN => f64::NAN,
+                INFINITY => f64::INFINITY,
+                NEG_INFINITY => f64::NEG_INFINITY,
+                // `f64::from_str` also accepts spellings like "inf", which proto3 JSON doesn't.
+                _ => match value.parse::<f64>() {
+                    Ok(parsed) if parsed.is_finite() => parsed,
+                    _ => return Err(E::invalid_value(de::Unexpected::Str(value), &self)),
+                },
+            };
+            Self::narrow(parsed)
+        }
+    }
+
+    /// `repeated float` and `repeated double` fields.
+    pub mod repeated {
+        use serde::de::Deserializer;
+        use serde::ser::{SerializeSeq, Serializer};
+        use serde::Deserialize;
+
+        use super::Float;
+
+        struct Item<T>(T);
+
+        impl<T: Float> serde::Serialize for Item<T> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                super::serialize(&self.0, serializer)
+            }
+        }
+
+        impl<'de, T: Float> Deserialize<'de> for Item<T> {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                super::deserialize(deserializer).map(Item)
+            }
+        }
+
+        pub fn serialize<T: Float, S: Serializer>(
+            values: &[T],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            let mut seq = serializer.serialize_seq(Some(values.len()))?;
+            for value in values {
+                seq.serialize_element(&Item(*value))?;
+            }
+            seq.end()
+        }
+
+        pub fn deserialize<'de, T: Float, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<T>, D::Error> {
+            let items = Vec::<Item<T>>::deserialize(deserializer)?;
+            Ok(items.into_iter().map(|Item(value)| value).collect())
+        }
+    }
+}
+
+/// `int64`, `uint64`, `sint64`, `fixed64` and `sfixed64` fields.
+///
+/// These are encoded as strings (e.g. `"9007199254740993"`), which JavaScript clients can read
+/// without losing precision. Decoding accepts them, as well as numbers.
+pub mod int64 {
+    use std::fmt;
+    use std::marker::PhantomData;
+    use std::str::FromStr;
+
+    use serde::de::{self, Deserializer, Visitor};
+    use serde::Serializer;
+
+    /// `i64` or `u64`.
+    pub trait Int64:
+        Copy + fmt::Display + FromStr + TryFrom<i64> + TryFrom<u64> + private::Sealed
+    {
+    }
+
+    impl Int64 for i64 {}
+    impl Int64 for u64 {}
+
+    mod private {
+        pub trait Sealed {}
+        impl Sealed for i64 {}
+        impl Sealed for u64 {}
+    }
+
+    pub fn serialize<T: Int64, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T: Int64, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        deserializer.deserialize_any(Int64Visitor(PhantomData))
+    }
+
+    struct Int64Visitor<T>(PhantomData<T>);
+
+    impl<T: Int64> Visitor<'_> for Int64Visitor<T> {
+        type Value = T;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("an integer, or an integer in a string")
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<T, E> {
+            T::try_from(value).map_err(|_| E::custom(format!("{value} is out of range")))
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<T, E> {
+            T::try_from(value).map_err(|_| E::custom(format!("{value} is out of range")))
+        }
+
+        fn visit_f64<E: de::Error>(self, value: f64) -> Result<T, E> {
+            // proto3 JSON allows integers written with an exponent, like `1e3`.
+            if value.fract() != 0.0 || value < -(2f64.powi(63)) || value >= 2f64.powi(64) {
+                return Err(E::invalid_value(de::Unexpected::Float(value), &self));
+            }
+            if value < 0.0 {
+                self.visit_i64(value as i64)
+            } else {
+                self.visit_u64(value as u64)
+            }
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<T, E> {
+            value
+                .parse()
+                .map_err(|_| E::invalid_value(de::Unexpected::Str(value), &self))
+        }
+    }
+
+    /// `repeated` 64-bit integer fields.
+    pub mod repeated {
+        use serde::de::Deserializer;
+        use serde::ser::{SerializeSeq, Serializer};
+        use serde::Deserialize;
+
+        use super::Int64;
+
+        struct Item<T>(T);
+
+        impl<T: Int64> serde::Serialize for Item<T> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                super::serialize(&self.0, serializer)
+            }
+        }
+
+        impl<'de, T: Int64> Deserialize<'de> for Item<T> {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                super::deserialize(deserializer).map(Item)
+            }
+        }
+
+        pub fn serialize<T: Int64, S: Serializer>(
+            values: &[T],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            let mut seq = serializer.serialize_seq(Some(values.len()))?;
+            for value in values {
+                seq.serialize_element(&Item(*value))?;
+            }
+            seq.end()
+        }
+
+        pub fn deserialize<'de, T: Int64, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<T>, D::Error> {
+            let items = Vec::<Item<T>>::deserialize(deserializer)?;
+            Ok(items.into_iter().map(|Item(value)| value).collect())
+        }
+    }
+}
+
+/// `bytes` fields.
+///
+/// These are encoded as standard base64 with padding, instead of an array of numbers. Decoding
+/// accepts both standard and URL-safe base64, with or without padding.
+pub mod bytes {
+    use std::fmt;
+
+    use base64::alphabet;
+    use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig, STANDARD};
+    use base64::engine::DecodePaddingMode;
+    use base64::Engine;
+    use serde::de::{self, Deserializer, Visitor};
+    use serde::Serializer;
+
+    const CONFIG: GeneralPurposeConfig =
+        GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent);
+    const STANDARD_DECODER: GeneralPurpose = GeneralPurpose::new(&alphabet::STANDARD, CONFIG);
+    const URL_SAFE_DECODER: GeneralPurpose = GeneralPurpose::new(&alphabet::URL_SAFE, CONFIG);
+
+    pub fn serialize<S: Serializer>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(value))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_str(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl Visitor<'_> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a base64 encoded string")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Vec<u8>, E> {
+            let decoder = if value.contains(['-', '_']) {
+                URL_SAFE_DECODER
+            } else {
+                STANDARD_DECODER
+            };
+            decoder
+                .decode(value)
+                .map_err(|_| E::invalid_value(de::Unexpected::Str(value), &self))
+        }
+    }
+
+    /// `repeated bytes` fields.
+    pub mod repeated {
+        use serde::de::Deserializer;
+        use serde::ser::{SerializeSeq, Serializer};
+        use serde::Deserialize;
+
+        struct ItemRef<'a>(&'a [u8]);
+
+        impl serde::Serialize for ItemRef<'_> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                super::serialize(self.0, serializer)
+            }
+        }
+
+        struct Item(Vec<u8>);
+
+        impl<'de> Deserialize<'de> for Item {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                super::deserialize(deserializer).map(Item)
+            }
+        }
+
+        pub fn serialize<S: Serializer>(
+            values: &[Vec<u8>],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            let mut seq = serializer.serialize_seq(Some(values.len()))?;
+            for value in values {
+                seq.serialize_element(&ItemRef(value))?;
+            }
+            seq.end()
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<Vec<u8>>, D::Error> {
+            let items = Vec::<Item>::deserialize(deserializer)?;
+            Ok(items.into_iter().map(|Item(value)| value).collect())
+        }
+    }
+}
+
+/// The largest integer a JavaScript number (an IEEE 754 double) represents exactly.
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+/// Replace the integers of `value` that JavaScript can't represent exactly with strings, the way
+/// proto3 JSON encodes 64-bit integers. Smaller integers stay numbers, since the type of the field
+/// they belong to isn't known at this point.
+pub(crate) fn large_integers_to_strings(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Number(n) => {
+            let large = match (n.as_u64(), n.as_i64()) {
+                (Some(n), _) => n > MAX_SAFE_INTEGER,
+                (None, Some(n)) => n.unsigned_abs() > MAX_SAFE_INTEGER,
+                _ => false,
+            };
+            if large {
+                *value = serde_json::Value::String(n.to_string());
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(large_integers_to_strings),
+        serde_json::Value::Object(fields) => {
+            fields.values_mut().for_each(large_integers_to_strings)
+        }
+        _ => {}
+    }
+}
+
+/// Sort the keys of every object in `value`, whether or not `serde_json` preserves insertion
+/// order.
+pub(crate) fn sort_keys(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Array(items) => items.iter_mut().for_each(sort_keys),
+        serde_json::Value::Object(fields) => {
+            let mut sorted: Vec<_> = std::mem::take(fields).into_iter().collect();
+            sorted.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (key, mut field) in sorted {
+                sort_keys(&mut field);
+                fields.insert(key, field);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Measurement {
+        #[serde(with = "super::float")]
+        single: f32,
+        #[serde(with = "super::float")]
+        double: f64,
+        #[serde(with = "super::float::repeated")]
+        samples: Vec<f64>,
+    }
+
+    fn measurement(value: f64) -> Measurement {
+        Measurement {
+            single: value as f32,
+            double: value,
+            samples: vec![1.5, value],
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Account {
+        #[serde(with = "super::int64")]
+        account_id: i64,
+        #[serde(with = "super::int64")]
+        total_bytes: u64,
+        #[serde(with = "super::int64::repeated")]
+        recent_ids: Vec<i64>,
+    }
+
+    #[test]
+    fn test_int64() {
+        let account = Account {
+            account_id: -9007199254740993,
+            total_bytes: u64::MAX,
+            recent_ids: vec![1, i64::MIN],
+        };
+        let json = serde_json::to_value(&account).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "accountId": "-9007199254740993",
+                "totalBytes": "18446744073709551615",
+                "recentIds": ["1", "-9223372036854775808"],
+            })
+        );
+        assert_eq!(serde_json::from_value::<Account>(json).unwrap(), account);
+
+        let decoded: Account = serde_json::from_value(
+            json!({"accountId": 42, "totalBytes": "7", "recentIds": [1e3, "-2"]}),
+        )
+        .unwrap();
+        assert_eq!(
+            decoded,
+            Account {
+                account_id: 42,
+                total_bytes: 7,
+                recent_ids: vec![1000, -2],
+            }
+        );
+
+        // unsigned integers with an exponent can be over i64::MAX
+        let decoded: Account = serde_json::from_value(
+            json!({"accountId": -9.2e18, "totalBytes": 1e19, "recentIds": []}),
+        )
+        .unwrap();
+        assert_eq!(decoded.account_id, -9_200_000_000_000_000_000);
+        assert_eq!(decoded.total_bytes, 10_000_000_000_000_000_000);
+
+        for invalid in [
+            json!({"accountId": 1.5, "totalBytes": 0, "recentIds": []}),
+            json!({"accountId": "1.0", "totalBytes": 0, "recentIds": []}),
+            json!({"accountId": 0, "totalBytes": -1, "recentIds": []}),
+            json!({"accountId": 0, "totalBytes": "-1", "recentIds": []}),
+            json!({"accountId": u64::MAX, "totalBytes": 0, "recentIds": []}),
+            json!({"accountId": 1e19, "totalBytes": 0, "recentIds": []}),
+            json!({"accountId": 0, "totalBytes": 2e19, "recentIds": []}),
+            json!({"accountId": 0, "totalBytes": -1e3, "recentIds": []}),
+            json!({"accountId": 0, "totalBytes": 0, "recentIds": [null]}),
+        ] {
+            assert!(
+                serde_json::from_value::<Account>(invalid.clone()).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Blob {
+        #[serde(with = "super::bytes")]
+        data: Vec<u8>,
+        #[serde(with = "super::bytes::repeated")]
+        chunks: Vec<Vec<u8>>,
+    }
+
+    #[test]
+    fn test_bytes() {
+        let blob = Blob {
+            data: vec![0xfb, 0xff],
+            chunks: vec![b"hat".to_vec(), vec![]],
+        };
+        let json = serde_json::to_value(&blob).unwrap();
+        assert_eq!(json, json!({"data": "+/8=", "chunks": ["aGF0", ""]}));
+        assert_eq!(serde_json::from_value::<Blob>(json).unwrap(), blob);
+
+        // URL-safe and unpadded base64 is accepted too
+        let decoded: Blob =
+            serde_json::from_value(json!({"data": "-_8", "chunks": ["aGF0"]})).unwrap();
+        assert_eq!(decoded.data, vec![0xfb, 0xff]);
+
+        for invalid in [
+            json!({"data": "not base64!", "chunks": []}),
+            json!({"data": [1, 2], "chunks": []}),
+        ] {
+            assert!(
+                serde_json::from_value::<Blob>(invalid.clone()).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_large_integers_to_strings() {
+        let max_safe = super::MAX_SAFE_INTEGER as i64;
+        let mut value = json!({
+            "small": 42,
+            "safe": max_safe,
+            "large": max_safe + 1,
+            "negative": -max_safe - 1,
+            "unsigned": u64::MAX,
+            "float": 1e300,
+            "nested": [{"id": i64::MIN}],
+        });
+        super::large_integers_to_strings(&mut value);
+        assert_eq!(
+            value,
+            json!({
+                "small": 42,
+                "safe": max_safe,
+                "large": "9007199254740992",
+                "negative": "-9007199254740992",
+                "unsigned": "18446744073709551615",
+                "float": 1e300,
+                "nested": [{"id": "-9223372036854775808"}],
+            })
+        );
+    }
+
+    #[test]
+    fn test_special_values_round_trip() {
+        for (value, encoded) in [
+            (f64::NAN, "NaN"),
+            (f64::INFINITY, "Infinity"),
+            (f64::NEG_INFINITY, "-Infinity"),
+        ] {
+            let json = serde_json::to_value(measurement(value)).unwrap();
+            assert_eq!(
+                json,
+                json!({"single": encoded, "double": encoded, "samples": [1.5, encoded]})
+            );
+
+            let decoded: Measurement = serde_json::from_value(json).unwrap();
+            for decoded in [decoded.single as f64, decoded.double, decoded.samples[1]] {
+                if value.is_nan() {
+                    assert!(decoded.is_nan(), "{encoded}");
+                } else {
+                    assert_eq!(decoded, value, "{encoded}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_finite_values() {
+        let json = serde_json::to_value(measurement(0.1)).unwrap();
+        assert_eq!(
+            json,
+            json!({"single": 0.1f32, "double": 0.1, "samples": [1.5, 0.1]})
+        );
+        assert_eq!(
+            serde_json::to_string(&measurement(0.1)).unwrap(),
+            r#"{"single":0.1,"double":0.1,"samples":[1.5,0.1]}"#
+        );
+
+        let decoded: Measurement =
+            serde_json::from_value(json!({"single": 2, "double": "1e3", "samples": ["-0.5"]}))
+                .unwrap();
+        assert_eq!(decoded.single, 2.0);
+        assert_eq!(decoded.double, 1000.0);
+        assert_eq!(decoded.samples, vec![-0.5]);
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        for invalid in [
+            json!({"single": "nan", "double": 0, "samples": []}),
+            json!({"single": 0, "double": "inf", "samples": []}),
+            json!({"single": 0, "double": 0, "samples": ["Infinite"]}),
+            json!({"single": 1e39, "double": 0, "samples": []}),
+            json!({"single": 0, "double": null, "samples": []}),
+        ] {
+            assert!(
+                serde_json::from_value::<Measurement>(invalid.clone()).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+}
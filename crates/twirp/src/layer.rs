@@ -0,0 +1,198 @@
+//! Making the error responses of generic tower middleware Twirp errors.
+//!
+//! Third-party layers (e.g. for authentication or rate limiting) reject requests with bare HTTP
+//! responses, like an empty `401 Unauthorized`, that Twirp clients can only make sense of by
+//! guessing from the status. Wrap them with a [`TwirpErrorLayer`] to turn such responses into
+//! Twirp errors, with [`from_http_status`](crate::error::from_http_status):
+//!
+//! ```
+//! use twirp::axum::extract::Request;
+//! use twirp::axum::http::StatusCode;
+//! use twirp::axum::middleware::{self, Next};
+//! use twirp::axum::response::{IntoResponse, Response};
+//! use twirp::layer::TwirpErrorLayer;
+//!
+//! async fn auth(req: Request, next: Next) -> Response {
+//!     if req.headers().contains_key("authorization") {
+//!         next.run(req).await
+//!     } else {
+//!         StatusCode::UNAUTHORIZED.into_response()
+//!     }
+//! }
+//!
+//! # fn build(twirp_routes: twirp::Router) -> twirp::Router {
+//! twirp_routes
+//!     .layer(middleware::from_fn(auth))
+//!     // outside of the layers whose responses to convert
+//!     .layer(TwirpErrorLayer)
+//! # }
+//! ```
+
+use std::task::{Context, Poll};
+
+use axum::body::Body;
+use axum::response::IntoResponse;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hyper::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::Response;
+use tower::{Layer, Service};
+
+use crate::{error, TwirpErrorCode};
+
+/// A layer replacing the error responses (`4xx` and `5xx`) of the services it wraps with Twirp
+/// errors, unless they already are. Other headers of the responses, e.g. `WWW-Authenticate`, are
+/// kept.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TwirpErrorLayer;
+
+impl<S> Layer<S> for TwirpErrorLayer {
+    type Service = TwirpErrorService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TwirpErrorService { inner }
+    }
+}
+
+/// The service of a [`TwirpErrorLayer`].
+#[derive(Debug, Clone)]
+pub struct TwirpErrorService<S> {
+    inner: S,
+}
+
+impl<S, Req> Service<Req> for TwirpErrorService<S>
+where
+    S: Service<Req, Response = Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response<Body>, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        let resp = self.inner.call(req);
+        async move { Ok(into_twirp_error(resp.await?)) }.boxed()
+    }
+}
+
+/// Replace `resp` with the Twirp error for its status, if it is an error response but not a Twirp
+/// error. Twirp error responses are recognized by the [`TwirpErrorCode`] the server sets in the
+/// extensions of every error it writes.
+fn into_twirp_error(resp: Response<Body>) -> Response<Body> {
+    let status = resp.status();
+    let is_error = status.is_client_error() || status.is_server_error();
+    if !is_error || resp.extensions().get::<TwirpErrorCode>().is_some() {
+        return resp;
+    }
+    let (mut parts, _) = resp.into_parts();
+    let twirp_resp = error::from_http_status(status).into_response();
+    for name in [CONTENT_TYPE, CONTENT_LENGTH, CONTENT_ENCODING] {
+        parts.headers.remove(name);
+    }
+    let (twirp_parts, body) = twirp_resp.into_parts();
+    parts.status = twirp_parts.status;
+    parts.headers.extend(twirp_parts.headers);
+    parts.extensions.extend(twirp_parts.extensions);
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::middleware::{self, Next};
+    use hyper::header::WWW_AUTHENTICATE;
+    use hyper::{Request, StatusCode};
+    use tower::Service;
+
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::details::TwirpRouterBuilder;
+    use crate::test::*;
+    use crate::{IntoTwirpResponse, TwirpErrorResponse};
+
+    /// Rejects requests without an `authorization` header with a bare `401`.
+    async fn auth(req: Request<Body>, next: Next) -> Response<Body> {
+        if req.headers().contains_key("authorization") {
+            return next.run(req).await;
+        }
+        (
+            StatusCode::UNAUTHORIZED,
+            [(WWW_AUTHENTICATE, "Bearer")],
+            "who are you?",
+        )
+            .into_response()
+    }
+
+    #[tokio::test]
+    async fn test_twirp_error_layer() {
+        let mut router = test_api_router()
+            .layer(middleware::from_fn(auth))
+            .layer(TwirpErrorLayer);
+
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(resp.headers()[WWW_AUTHENTICATE], "Bearer");
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(
+            resp.extensions().get::<TwirpErrorCode>(),
+            Some(&TwirpErrorCode::Unauthenticated)
+        );
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Unauthenticated);
+        assert_eq!(err.meta["status_code"], "401");
+
+        // Twirp errors and successful responses are left as they are
+        let mut req = Request::post("/twirp/test.TestAPI/Boom")
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+        req.headers_mut()
+            .insert("authorization", "Bearer hats".parse().unwrap());
+        let resp = router.call(req).await.unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Internal);
+        assert_eq!(err.msg, "boom!");
+
+        let mut req = gen_ping_request("hi");
+        req.headers_mut()
+            .insert("authorization", "Bearer hats".parse().unwrap());
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+    }
+    #[tokio::test]
+    async fn test_twirp_error_layer_custom_error() {
+        /// An error building its own response, without a `TwirpErrorCode` extension.
+        struct NoSuchHat;
+
+        impl IntoTwirpResponse for NoSuchHat {
+            fn into_twirp_response(self) -> Response<TwirpErrorResponse> {
+                Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(error::not_found("no such hat"))
+                    .unwrap()
+            }
+        }
+
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                |_: Arc<TestApiServer>, _: crate::Context, _: PingRequest| async move {
+                    Err::<PingResponse, _>(NoSuchHat)
+                },
+            )
+            .build();
+        let mut router = axum::Router::new()
+            .nest("/twirp/test.TestAPI", twirp_routes)
+            .layer(TwirpErrorLayer);
+
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::NotFound);
+        assert_eq!(err.msg, "no such hat");
+    }
+}
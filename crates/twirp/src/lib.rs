@@ -1,8 +1,26 @@
+#[cfg(feature = "attachments")]
+pub mod attachment;
+pub mod baggage;
+pub mod batch;
 pub mod client;
+#[cfg(feature = "compression")]
+mod compression;
 pub mod context;
+pub mod descriptor;
+mod duplicate_keys;
 pub mod error;
+pub mod etag;
+mod field_length;
 pub mod headers;
+pub mod health;
+pub mod info;
+pub mod jsonpb;
+pub mod layer;
+pub mod proxy;
+pub mod serve;
 pub mod server;
+#[cfg(feature = "status-details")]
+pub mod status;
 
 #[cfg(any(test, feature = "test-support"))]
 pub mod test;
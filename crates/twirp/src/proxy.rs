@@ -0,0 +1,213 @@
+//! Forwarding requests for methods this server doesn't implement to an upstream Twirp server, for
+//! gateways, or to move the methods of a service to another server one at a time.
+//!
+//! Use the [`handler`](UpstreamProxy::handler) of an [`UpstreamProxy`] as the fallback of a
+//! service's router, in place of `bad_route`, to forward requests for the methods it lacks:
+//!
+//! ```
+//! use twirp::proxy::UpstreamProxy;
+//! use twirp::url::Url;
+//!
+//! # fn build(haberdasher_routes: twirp::Router) -> twirp::Router {
+//! let upstream = UpstreamProxy::new(Url::parse("http://legacy-hats:3000").unwrap());
+//! let twirp_routes = twirp::Router::new().nest(
+//!     "/example.Haberdasher",
+//!     haberdasher_routes.fallback(upstream.handler()),
+//! );
+//! twirp::Router::new().nest("/twirp", twirp_routes)
+//! # }
+//! ```
+//!
+//! Or use it as the fallback of the whole app to forward requests for entire services.
+
+use axum::body::{Body, Bytes};
+use axum::extract::OriginalUri;
+use axum::response::IntoResponse;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use http_body_util::{BodyExt, Limited};
+use hyper::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use hyper::{Request, Response};
+use url::Url;
+
+use crate::server::{is_body_too_large, ServerOptions, DEFAULT_MAX_REQUEST_SIZE};
+use crate::{error, TwirpErrorResponse};
+
+/// Forwards requests, as they are, to an upstream server.
+#[derive(Debug, Clone)]
+pub struct UpstreamProxy {
+    upstream: Url,
+    http_client: reqwest::Client,
+}
+
+impl UpstreamProxy {
+    /// Forward requests to the same path (the full path, before any nesting of routers) on
+    /// `upstream`, e.g. `http://legacy-hats:3000`.
+    pub fn new(upstream: Url) -> Self {
+        Self {
+            upstream,
+            http_client: reqwest::Client::new(),
+        }
+    }
+
+    /// Send the forwarded requests with `http_client`, e.g. to configure its timeouts.
+    pub fn with_http_client(mut self, http_client: reqwest::Client) -> Self {
+        self.http_client = http_client;
+        self
+    }
+
+    /// The axum handler forwarding requests, for `Router::fallback`.
+    pub fn handler(
+        &self,
+    ) -> impl Fn(Request<Body>) -> BoxFuture<'static, Response<Body>> + Clone + Send + Sync + 'static
+    {
+        let proxy = self.clone();
+        move |req| {
+            let proxy = proxy.clone();
+            async move { proxy.forward(req).await }.boxed()
+        }
+    }
+
+    /// Forward `req` with its body, `Content-Type` and `Content-Encoding`, and respond with the
+    /// status, body, `Content-Type` and `Content-Encoding` of the upstream's response.
+    ///
+    /// The body is limited to [`ServerOptions::with_max_request_size`], and an upstream that
+    /// can't be reached is an `unavailable` error.
+    pub async fn forward(&self, req: Request<Body>) -> Response<Body> {
+        match self.try_forward(req).await {
+            Ok(resp) => resp,
+            Err(err) => err.into_response(),
+        }
+    }
+
+    async fn try_forward(&self, req: Request<Body>) -> Result<Response<Body>, TwirpErrorResponse> {
+        let uri = match req.extensions().get::<OriginalUri>() {
+            Some(OriginalUri(uri)) => uri.clone(),
+            None => req.uri().clone(),
+        };
+        let mut url = self.upstream.clone();
+        url.set_path(uri.path());
+        url.set_query(uri.query());
+
+        let max_size = req
+            .extensions()
+            .get::<ServerOptions>()
+            .map_or(DEFAULT_MAX_REQUEST_SIZE, ServerOptions::max_request_size);
+        let (parts, body) = req.into_parts();
+        let body = match Limited::new(body, max_size).collect().await {
+            Ok(collected) => collected.to_bytes(),
+            Err(err) if is_body_too_large(&*err) => {
+                let mut twirp_err = error::resource_exhausted("request body too large");
+                twirp_err.insert_meta("reason".to_string(), "body_too_large".to_string());
+                return Err(twirp_err);
+            }
+            Err(err) => {
+                let mut twirp_err = error::malformed("error reading request body");
+                twirp_err.insert_meta("error".to_string(), err.to_string());
+                return Err(twirp_err);
+            }
+        };
+
+        let mut upstream_req = self.http_client.request(parts.method, url);
+        for name in [CONTENT_TYPE, CONTENT_ENCODING] {
+            if let Some(value) = parts.headers.get(&name) {
+                upstream_req = upstream_req.header(name, value);
+            }
+        }
+        let upstream_resp = upstream_req
+            .body(body)
+            .send()
+            .await
+            .map_err(upstream_error)?;
+
+        let mut resp = Response::builder().status(upstream_resp.status());
+        for name in [CONTENT_TYPE, CONTENT_ENCODING] {
+            if let Some(value) = upstream_resp.headers().get(&name) {
+                resp = resp.header(name, value);
+            }
+        }
+        let body: Bytes = upstream_resp.bytes().await.map_err(upstream_error)?;
+        Ok(resp
+            .body(Body::from(body))
+            .expect("status and headers come from a valid response"))
+    }
+}
+
+fn upstream_error(err: reqwest::Error) -> TwirpErrorResponse {
+    let mut twirp_err = error::unavailable("error forwarding request upstream");
+    twirp_err.insert_meta("error".to_string(), err.to_string());
+    twirp_err
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tower::Service;
+
+    use super::*;
+    use crate::details::TwirpRouterBuilder;
+    use crate::test::*;
+    use crate::{Context, TwirpErrorCode};
+
+    /// The test API with only `Boom`, forwarding `Ping` to `upstream`.
+    fn partial_router(upstream: Url) -> axum::Router {
+        let routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Boom",
+                |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                    api.boom(ctx, req).await
+                },
+            )
+            .build()
+            .fallback(UpstreamProxy::new(upstream).handler());
+        axum::Router::new().nest("/twirp/test.TestAPI", routes)
+    }
+
+    #[tokio::test]
+    async fn test_forward() {
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = tcp_listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(tcp_listener, test_api_router()).await });
+        let mut router = partial_router(Url::parse(&format!("http://{addr}")).unwrap());
+
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/json");
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(&data.name, "hi");
+
+        // implemented here
+        let req = Request::post("/twirp/test.TestAPI/Boom")
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.msg, "boom!");
+
+        // unknown upstream too
+        let req = Request::post("/twirp/test.TestAPI/Nope")
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), hyper::StatusCode::NOT_FOUND);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::BadRoute);
+        h.abort()
+    }
+
+    #[tokio::test]
+    async fn test_upstream_unavailable() {
+        // a port nothing listens on
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut router = partial_router(Url::parse(&format!("http://{addr}")).unwrap());
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Unavailable);
+    }
+}
@@ -0,0 +1,380 @@
+//! Serving a twirp router with tuned connection settings.
+//!
+//! [`axum::serve`] is enough for most services, but it doesn't expose hyper's buffer sizes or
+//! HTTP/2 settings. Large payload workloads and high fan-in clients can tune them with [`serve`]:
+//!
+//! ```
+//! use twirp::serve::{serve, ServeOptions};
+//!
+//! # async fn run(app: twirp::Router) -> std::io::Result<()> {
+//! let listener = tokio::net::TcpListener::bind("127.0.0.1:3000").await?;
+//! let options = ServeOptions::default().with_max_buf_size(4 * 1024 * 1024);
+//! serve(listener, app, options).await
+//! # }
+//! ```
+//!
+//! Larger buffers trade memory per connection for fewer reads and writes of large messages, so
+//! raise them only as far as the payloads need. The same goes for HTTP/2 flow control windows,
+//! while more concurrent streams let a single client connection multiplex more requests.
+//!
+//! [`serve_with_graceful_shutdown`] also stops the server once a signal completes, letting the
+//! requests in flight finish.
+
+use std::future::Future;
+use std::io;
+use std::pin::pin;
+use std::time::Duration;
+
+use axum::Router;
+use futures::future::{select, Either};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+/// The default of [`ServeOptions::with_max_buf_size`], hyper's default.
+pub const DEFAULT_MAX_BUF_SIZE: usize = 8192 + 4096 * 100;
+
+/// The default of [`ServeOptions::with_http2_max_send_buf_size`], hyper's default.
+pub const DEFAULT_HTTP2_MAX_SEND_BUF_SIZE: usize = 400 * 1024;
+
+/// The default of [`ServeOptions::with_http2_max_concurrent_streams`], hyper's default.
+pub const DEFAULT_HTTP2_MAX_CONCURRENT_STREAMS: u32 = 200;
+
+/// The default of [`ServeOptions::with_http2_initial_stream_window_size`], hyper's default.
+pub const DEFAULT_HTTP2_INITIAL_STREAM_WINDOW_SIZE: u32 = 1024 * 1024;
+
+/// The default of [`ServeOptions::with_http2_initial_connection_window_size`], hyper's default.
+pub const DEFAULT_HTTP2_INITIAL_CONNECTION_WINDOW_SIZE: u32 = 1024 * 1024;
+
+/// The smallest buffer size hyper accepts for HTTP/1 connections.
+const MIN_MAX_BUF_SIZE: usize = 8 * 1024;
+
+/// Connection settings for [`serve`]. hyper's settings are left alone unless overridden.
+#[derive(Debug, Clone, Default)]
+pub struct ServeOptions {
+    max_buf_size: Option<usize>,
+    http2_max_send_buf_size: Option<usize>,
+    http2_max_concurrent_streams: Option<u32>,
+    http2_initial_stream_window_size: Option<u32>,
+    http2_initial_connection_window_size: Option<u32>,
+}
+
+impl ServeOptions {
+    /// The maximum size of the read and write buffers of HTTP/1 connections, in bytes. Defaults
+    /// to [`DEFAULT_MAX_BUF_SIZE`] (408 KiB).
+    ///
+    /// # Panics
+    ///
+    /// If `size` is less than 8 KiB, the smallest buffer hyper accepts.
+    pub fn with_max_buf_size(mut self, size: usize) -> Self {
+        assert!(
+            size >= MIN_MAX_BUF_SIZE,
+            "the max buffer size must be at least {MIN_MAX_BUF_SIZE} bytes"
+        );
+        self.max_buf_size = Some(size);
+        self
+    }
+
+    /// The maximum size of the write buffer of each HTTP/2 stream, in bytes. Defaults to
+    /// [`DEFAULT_HTTP2_MAX_SEND_BUF_SIZE`] (400 KiB).
+    pub fn with_http2_max_send_buf_size(mut self, size: usize) -> Self {
+        self.http2_max_send_buf_size = Some(size);
+        self
+    }
+
+    /// The maximum number of concurrent streams (i.e. requests) a client may open on each HTTP/2
+    /// connection. Defaults to [`DEFAULT_HTTP2_MAX_CONCURRENT_STREAMS`] (200).
+    pub fn with_http2_max_concurrent_streams(mut self, max: u32) -> Self {
+        self.http2_max_concurrent_streams = Some(max);
+        self
+    }
+
+    /// The initial flow control window of each HTTP/2 stream, in bytes: how much of a request a
+    /// client may send before the server reads it. Defaults to
+    /// [`DEFAULT_HTTP2_INITIAL_STREAM_WINDOW_SIZE`] (1 MiB).
+    pub fn with_http2_initial_stream_window_size(mut self, size: u32) -> Self {
+        self.http2_initial_stream_window_size = Some(size);
+        self
+    }
+
+    /// The initial flow control window of each HTTP/2 connection, shared by its streams, in
+    /// bytes. Defaults to [`DEFAULT_HTTP2_INITIAL_CONNECTION_WINDOW_SIZE`] (1 MiB).
+    pub fn with_http2_initial_connection_window_size(mut self, size: u32) -> Self {
+        self.http2_initial_connection_window_size = Some(size);
+        self
+    }
+
+    pub fn max_buf_size(&self) -> usize {
+        self.max_buf_size.unwrap_or(DEFAULT_MAX_BUF_SIZE)
+    }
+
+    pub fn http2_max_send_buf_size(&self) -> usize {
+        self.http2_max_send_buf_size
+            .unwrap_or(DEFAULT_HTTP2_MAX_SEND_BUF_SIZE)
+    }
+
+    pub fn http2_max_concurrent_streams(&self) -> u32 {
+        self.http2_max_concurrent_streams
+            .unwrap_or(DEFAULT_HTTP2_MAX_CONCURRENT_STREAMS)
+    }
+
+    pub fn http2_initial_stream_window_size(&self) -> u32 {
+        self.http2_initial_stream_window_size
+            .unwrap_or(DEFAULT_HTTP2_INITIAL_STREAM_WINDOW_SIZE)
+    }
+
+    pub fn http2_initial_connection_window_size(&self) -> u32 {
+        self.http2_initial_connection_window_size
+            .unwrap_or(DEFAULT_HTTP2_INITIAL_CONNECTION_WINDOW_SIZE)
+    }
+}
+
+/// Serve `router` on `listener` over HTTP/1 and HTTP/2, with the connection settings of
+/// `options`. Like [`axum::serve`], this never returns.
+pub async fn serve(listener: TcpListener, router: Router, options: ServeOptions) -> io::Result<()> {
+    serve_with_graceful_shutdown(listener, router, options, std::future::pending()).await
+}
+
+/// Like [`serve`], but stop accepting connections once `signal` completes, and return once the
+/// requests in flight are handled, as [`axum::serve`] does with `with_graceful_shutdown`. Idle
+/// connections are closed right away.
+///
+/// ```
+/// use tokio::sync::oneshot;
+/// use twirp::serve::{serve_with_graceful_shutdown, ServeOptions};
+///
+/// # async fn run(app: twirp::Router, shutdown: oneshot::Receiver<()>) -> std::io::Result<()> {
+/// let listener = tokio::net::TcpListener::bind("127.0.0.1:3000").await?;
+/// let signal = async {
+///     let _ = shutdown.await;
+/// };
+/// serve_with_graceful_shutdown(listener, app, ServeOptions::default(), signal).await
+/// # }
+/// ```
+pub async fn serve_with_graceful_shutdown<F>(
+    listener: TcpListener,
+    router: Router,
+    options: ServeOptions,
+    signal: F,
+) -> io::Result<()>
+where
+    F: Future<Output = ()>,
+{
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    if let Some(size) = options.max_buf_size {
+        builder.http1().max_buf_size(size);
+    }
+    let mut http2 = builder.http2();
+    if let Some(size) = options.http2_max_send_buf_size {
+        http2.max_send_buf_size(size);
+    }
+    if let Some(max) = options.http2_max_concurrent_streams {
+        http2.max_concurrent_streams(max);
+    }
+    if let Some(size) = options.http2_initial_stream_window_size {
+        http2.initial_stream_window_size(size);
+    }
+    if let Some(size) = options.http2_initial_connection_window_size {
+        http2.initial_connection_window_size(size);
+    }
+    // Connections watch `shutdown_tx` to know when to shut down, and hold `close_rx` until they
+    // are closed.
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let (close_tx, close_rx) = watch::channel(());
+    let mut signal = pin!(signal);
+    loop {
+        let stream = match select(signal.as_mut(), pin!(accept(&listener))).await {
+            Either::Left(((), _)) => break,
+            Either::Right((stream, _)) => stream,
+        };
+        let _ = stream.set_nodelay(true);
+        let service = TowerToHyperService::new(router.clone());
+        let builder = builder.clone();
+        let mut shutdown_rx = shutdown_rx.clone();
+        let close_rx = close_rx.clone();
+        tokio::spawn(async move {
+            let mut conn =
+                pin!(builder.serve_connection_with_upgrades(TokioIo::new(stream), service));
+            // Errors of a single connection (e.g. the client going away) don't stop the server.
+            if let Either::Right(_) = select(conn.as_mut(), pin!(shutdown_rx.changed())).await {
+                conn.as_mut().graceful_shutdown();
+                let _ = conn.await;
+            }
+            drop(close_rx);
+        });
+    }
+    drop(listener);
+    drop(close_rx);
+    let _ = shutdown_tx.send(());
+    close_tx.closed().await;
+    Ok(())
+}
+
+/// Accept the next connection. Errors of a single connection are skipped, while others (e.g. too
+/// many open files) are retried after a second rather than spinning, like [`axum::serve`].
+async fn accept(listener: &TcpListener) -> TcpStream {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => return stream,
+            Err(err) if is_connection_error(&err) => continue,
+            Err(_) => tokio::time::sleep(Duration::from_secs(1)).await,
+        }
+    }
+}
+
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::*;
+    use crate::test::*;
+    use crate::{Client, ClientBuilder};
+
+    #[test]
+    fn test_default_options() {
+        let options = ServeOptions::default();
+        assert_eq!(options.max_buf_size(), DEFAULT_MAX_BUF_SIZE);
+        assert_eq!(
+            options.http2_max_send_buf_size(),
+            DEFAULT_HTTP2_MAX_SEND_BUF_SIZE
+        );
+        assert_eq!(
+            options.http2_max_concurrent_streams(),
+            DEFAULT_HTTP2_MAX_CONCURRENT_STREAMS
+        );
+        assert_eq!(
+            options.http2_initial_stream_window_size(),
+            DEFAULT_HTTP2_INITIAL_STREAM_WINDOW_SIZE
+        );
+        assert_eq!(
+            options.http2_initial_connection_window_size(),
+            DEFAULT_HTTP2_INITIAL_CONNECTION_WINDOW_SIZE
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "at least")]
+    fn test_max_buf_size_too_small() {
+        ServeOptions::default().with_max_buf_size(1024);
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = tcp_listener.local_addr().unwrap();
+        let options = ServeOptions::default()
+            .with_max_buf_size(64 * 1024)
+            .with_http2_max_send_buf_size(64 * 1024);
+        let h = tokio::spawn(serve(tcp_listener, test_api_router(), options));
+
+        let client: Client =
+            ClientBuilder::from_base_url(Url::parse(&format!("http://{addr}/twirp/")).unwrap())
+                .build()
+                .unwrap();
+        // larger than the buffers
+        let name = "hat".repeat(100_000);
+        let resp = client
+            .ping(PingRequest { name: name.clone() })
+            .await
+            .unwrap();
+        assert_eq!(resp.name, name);
+        h.abort();
+    }
+
+    #[tokio::test]
+    async fn test_serve_http2() {
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = tcp_listener.local_addr().unwrap();
+        let options = ServeOptions::default()
+            .with_http2_max_concurrent_streams(4)
+            .with_http2_initial_stream_window_size(64 * 1024)
+            .with_http2_initial_connection_window_size(256 * 1024);
+        let h = tokio::spawn(serve(tcp_listener, test_api_router(), options));
+
+        let http_client = reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .build()
+            .unwrap();
+        let resp = http_client
+            .post(format!("http://{addr}/twirp/test.TestAPI/Ping"))
+            .header("content-type", "application/json")
+            .body(r#"{"name":"hi"}"#)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.version(), http::Version::HTTP_2);
+        assert!(resp.status().is_success(), "{:?}", resp);
+
+        // more requests than streams, some larger than the windows, on the one connection
+        let client = Client::new(
+            Url::parse(&format!("http://{addr}/twirp/")).unwrap(),
+            http_client,
+            vec![],
+        )
+        .unwrap();
+        let pings = (0..16).map(|i| {
+            let name = format!("hat {i}").repeat(i * 10_000);
+            let client = client.clone();
+            async move {
+                let resp = client
+                    .ping(PingRequest { name: name.clone() })
+                    .await
+                    .unwrap();
+                assert_eq!(resp.name, name);
+            }
+        });
+        futures::future::join_all(pings).await;
+        h.abort();
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = tcp_listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let signal = async {
+            let _ = rx.await;
+        };
+        let h = tokio::spawn(serve_with_graceful_shutdown(
+            tcp_listener,
+            test_api_router(),
+            ServeOptions::default(),
+            signal,
+        ));
+
+        let client: Client =
+            ClientBuilder::from_base_url(Url::parse(&format!("http://{addr}/twirp/")).unwrap())
+                .build()
+                .unwrap();
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        assert_eq!(client.ping(req.clone()).await.unwrap().name, "hi");
+
+        // the client's idle keep-alive connection doesn't hold up the shutdown
+        tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), h)
+            .await
+            .expect("the server shut down")
+            .unwrap()
+            .unwrap();
+        assert!(client.ping(req).await.is_err());
+    }
+}
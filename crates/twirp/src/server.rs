@@ -3,54 +3,327 @@
 //! There is not much to see in the documentation here. This API is meant to be used with
 //! `twirp-build`. See <https://github.com/github/twirp-rs#usage> for details and an example.
 
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
-use axum::body::Body;
+use axum::body::{Body, Bytes};
 use axum::response::IntoResponse;
+use base64::Engine;
 use futures::Future;
 use http::Extensions;
-use http_body_util::BodyExt;
-use hyper::{header, Request, Response};
+use http_body_util::{BodyExt, Limited};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{header, HeaderMap, Method, Request, Response, StatusCode};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use tokio::time::{Duration, Instant};
 
-use crate::headers::{CONTENT_TYPE_JSON, CONTENT_TYPE_PROTOBUF};
-use crate::{error, serialize_proto_message, Context, GenericError, IntoTwirpResponse};
+use crate::baggage::Baggage;
+#[cfg(feature = "compression")]
+use crate::compression;
+use crate::context::{lock_extensions, RequestId, ResponseHeaders, RpcMethod, YieldBudget};
+use crate::headers::{
+    is_protobuf_content_type, CONTENT_TYPE_JSON, CONTENT_TYPE_PROTOBUF,
+    CONTENT_TYPE_PROTOBUF_DELIMITED, ERROR_CODE, TIMEOUT_MS,
+};
+use crate::{
+    duplicate_keys, error, field_length, serialize_proto_message, Context, ErrorDetailsEncoding,
+    GenericError, IntoTwirpResponse, TwirpErrorCode, TwirpErrorResponse,
+};
 
 // TODO: Properly implement JsonPb (de)serialization as it is slightly different
-// than standard JSON.
-#[derive(Debug, Clone, Copy, Default)]
-enum BodyFormat {
+// than standard JSON (enums still aren't written by name, see `crate::jsonpb`).
+/// The wire format of a Twirp request, and of its response unless the client asked for the other
+/// one (see [`BodyFormat::from_accept`]). Responses are sent with `Vary: accept` accordingly.
+///
+/// Middleware runs before the request body is parsed, so it can use
+/// [`BodyFormat::from_content_type`] to find out which format the handler will see.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BodyFormat {
+    /// JSON, the format used when the `Content-Type` is anything other than protobuf. Messages
+    /// are (de)serialized with `serde`, see [`crate::jsonpb`] for the proto3 JSON mapping.
     #[default]
     JsonPb,
+    /// Binary protobuf (`application/protobuf`).
     Pb,
 }
 
 impl BodyFormat {
-    fn from_content_type(req: &Request<Body>) -> BodyFormat {
-        match req
-            .headers()
-            .get(header::CONTENT_TYPE)
-            .map(|x| x.as_bytes())
-        {
-            Some(CONTENT_TYPE_PROTOBUF) => BodyFormat::Pb,
+    /// Determine the format of a request from its `Content-Type` header, or for a `GET` request
+    /// (see [`ServerOptions::with_get_methods`]) from its `base64` query parameter.
+    pub fn from_content_type<B>(req: &Request<B>) -> BodyFormat {
+        if req.method() == Method::GET {
+            if GetMessage::from_query(req.uri().query()).base64 {
+                return BodyFormat::Pb;
+            }
+            return BodyFormat::JsonPb;
+        }
+        Self::from_headers(req.headers())
+    }
+
+    /// The name of the format in error metadata.
+    fn name(self) -> &'static str {
+        match self {
+            BodyFormat::JsonPb => "json",
+            BodyFormat::Pb => "protobuf",
+        }
+    }
+
+    /// Determine the format of a request from its headers.
+    pub fn from_headers(headers: &HeaderMap) -> BodyFormat {
+        match headers.get(header::CONTENT_TYPE).map(|x| x.as_bytes()) {
+            Some(ct) if is_protobuf_content_type(ct) => BodyFormat::Pb,
             _ => BodyFormat::JsonPb,
         }
     }
+
+    /// The response format a client asked for with its `Accept` header: the JSON or protobuf
+    /// media type with the highest quality, the first one listed on a tie. `None` if it names
+    /// neither, e.g. `*/*`, so that the response is in the format of the request.
+    pub fn from_accept(headers: &HeaderMap) -> Option<BodyFormat> {
+        let mut best: Option<(BodyFormat, f32)> = None;
+        for value in headers.get_all(header::ACCEPT) {
+            for media_range in value.as_bytes().split(|&b| b == b',') {
+                let mut params = media_range.split(|&b| b == b';');
+                let media_type = params.next().unwrap_or_default().trim_ascii();
+                let format = if media_type.eq_ignore_ascii_case(CONTENT_TYPE_JSON) {
+                    BodyFormat::JsonPb
+                } else if is_protobuf_content_type(media_type) {
+                    BodyFormat::Pb
+                } else {
+                    continue;
+                };
+                let quality = params
+                    .filter_map(|param| {
+                        let param = std::str::from_utf8(param).ok()?.trim();
+                        param.strip_prefix("q=")?.parse::<f32>().ok()
+                    })
+                    .next()
+                    .unwrap_or(1.0);
+                match best {
+                    Some((_, best_quality)) if best_quality >= quality => {}
+                    _ if quality > 0.0 => best = Some((format, quality)),
+                    _ => {}
+                }
+            }
+        }
+        best.map(|(format, _)| format)
+    }
+}
+
+/// The request message of a `GET` request, in its query: `?message=<JSON>`, or
+/// `?message=<base64url protobuf>&base64=1`.
+#[derive(Debug, Default)]
+struct GetMessage {
+    message: String,
+    base64: bool,
+}
+
+impl GetMessage {
+    fn from_query(query: Option<&str>) -> Self {
+        let mut get = Self::default();
+        for (name, value) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            match &*name {
+                "message" => get.message = value.into_owned(),
+                "base64" => get.base64 = value == "1" || value == "true",
+                _ => {}
+            }
+        }
+        get
+    }
+
+    /// The encoded message, empty for a message with every field unset.
+    fn decode(&self) -> Result<Vec<u8>, GenericError> {
+        if !self.base64 {
+            return Ok(self.message.clone().into_bytes());
+        }
+        let message = self.message.trim_end_matches('=');
+        Ok(base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(message)?)
+    }
 }
 
+/// The largest request body accepted unless configured otherwise with
+/// [`ServerOptions::with_max_request_size`], 5 MiB.
+pub const DEFAULT_MAX_REQUEST_SIZE: usize = 5 * 1024 * 1024;
+
 /// Entry point used in code generated by `twirp-build`.
 pub(crate) async fn handle_request<S, F, Fut, Req, Resp, Err>(
     service: S,
-    req: Request<Body>,
+    mut req: Request<Body>,
+    f: F,
+) -> Response<Body>
+where
+    F: FnOnce(S, Context, Req) -> Fut + Clone + Sync + Send + 'static,
+    Fut: Future<Output = Result<Resp, Err>> + Send,
+    Req: prost::Message + Default + serde::de::DeserializeOwned + 'static,
+    Resp: prost::Message + serde::Serialize,
+    Err: IntoTwirpResponse,
+{
+    let options = req
+        .extensions()
+        .get::<ServerOptions>()
+        .cloned()
+        .unwrap_or_default();
+    let request_id = options.request_id(&mut req);
+    let method = req.extensions().get::<RpcMethod>().cloned();
+    let audit = options.audit_hook.as_ref().map(|_| AuditEvent::start(&req));
+    let observed = options
+        .observer
+        .as_ref()
+        .map(|_| ObservedRequest::start(&req));
+
+    #[cfg(feature = "tracing")]
+    let mut resp = {
+        use tracing::Instrument;
+
+        let span = request_span(&req);
+        req.extensions_mut().insert(span.clone());
+        let resp = handle(service, req, f, &options)
+            .instrument(span.clone())
+            .await;
+        record_response(&span, &resp);
+        resp
+    };
+    #[cfg(not(feature = "tracing"))]
+    let mut resp = handle(service, req, f, &options).await;
+    if let Some((name, id)) = request_id.filter(|_| !options.hide_request_id) {
+        resp.headers_mut().insert(name, id);
+    }
+    if let Some(method) = method {
+        resp.extensions_mut().insert(method);
+    }
+    if let (Some(Callback(hook)), Some(mut event)) = (&options.audit_hook, audit) {
+        event.code = resp.extensions().get::<TwirpErrorCode>().copied();
+        hook(&event);
+    }
+    if let (Some(Callback(observer)), Some(observed)) = (&options.observer, observed) {
+        // Requests that failed early (e.g. failing to parse) don't have their timings in the
+        // response, so only their total duration is known.
+        let timings = resp
+            .extensions()
+            .get::<Timings>()
+            .copied()
+            .unwrap_or(observed.timings);
+        let code = resp.extensions().get::<TwirpErrorCode>().copied();
+        observer.on_response(&observed.method, code, &timings);
+    }
+    resp
+}
+
+/// What a [`ServerObserver`] needs to know about a request, captured before handling it.
+struct ObservedRequest {
+    method: String,
+    timings: Timings,
+}
+
+impl ObservedRequest {
+    fn start<B>(req: &Request<B>) -> Self {
+        let method = match req.extensions().get::<RpcMethod>() {
+            Some(_) => original_path(req).to_string(),
+            None => UNKNOWN_METHOD.to_string(),
+        };
+        let timings = req
+            .extensions()
+            .get::<Timings>()
+            .copied()
+            .unwrap_or_else(|| Timings::new(Instant::now()));
+        Self { method, timings }
+    }
+}
+
+/// The full path of a request, also for routers nested in others.
+fn original_path<B>(req: &Request<B>) -> &str {
+    match req.extensions().get::<axum::extract::OriginalUri>() {
+        Some(axum::extract::OriginalUri(uri)) => uri.path(),
+        None => req.uri().path(),
+    }
+}
+
+/// The path of the rpc a request is for relative to the Twirp prefix, like
+/// `example.Haberdasher/MakeHat`: the last two segments of its full path.
+fn rpc_path<B>(req: &Request<B>) -> Option<&str> {
+    let path = original_path(req);
+    let service_end = path.rfind('/')?;
+    let start = path[..service_end].rfind('/').map_or(0, |i| i + 1);
+    Some(&path[start..])
+}
+
+/// The timeout the client asked for in the [`TIMEOUT_MS`] header, if any. Invalid values are
+/// ignored.
+fn requested_timeout(headers: &HeaderMap) -> Option<Duration> {
+    let timeout_ms = headers.get(TIMEOUT_MS)?.to_str().ok()?.parse().ok()?;
+    Some(Duration::from_millis(timeout_ms))
+}
+
+/// The span a request is handled in, see [`Context::span`].
+///
+/// The `code` (`ok` or the Twirp error code) and the [`Timings`] of the request are recorded once
+/// the response is ready, see [`record_response`].
+#[cfg(feature = "tracing")]
+fn request_span<B>(req: &Request<B>) -> tracing::Span {
+    use tracing::field::Empty;
+
+    let method = req.extensions().get::<RpcMethod>().map(|m| &*m.0);
+    let request_id = req.extensions().get::<RequestId>().map(|id| id.0.as_str());
+    tracing::info_span!(
+        "twirp",
+        method = method.unwrap_or_default(),
+        path = original_path(req),
+        request_id = request_id,
+        code = Empty,
+        received = Empty,
+        parsed = Empty,
+        handled = Empty,
+        written = Empty,
+        total = Empty,
+    )
+}
+
+/// Record the outcome of a request in its span, with an event for errors.
+#[cfg(feature = "tracing")]
+fn record_response(span: &tracing::Span, resp: &Response<Body>) {
+    use tracing::field::debug;
+
+    let code = resp.extensions().get::<TwirpErrorCode>();
+    span.record("code", code.map_or("ok", |code| code.twirp_code()));
+    if let Some(timings) = resp.extensions().get::<Timings>() {
+        let durations = [
+            ("received", timings.received()),
+            ("parsed", timings.parsed()),
+            ("handled", timings.response_handled()),
+            ("written", timings.response_written()),
+        ];
+        for (field, duration) in durations {
+            if let Some(duration) = duration {
+                span.record(field, debug(duration));
+            }
+        }
+        span.record("total", debug(timings.total_duration()));
+    }
+    if let Some(code) = code {
+        span.in_scope(|| {
+            tracing::warn!(
+                code = code.twirp_code(),
+                status = resp.status().as_u16(),
+                "twirp error"
+            )
+        });
+    }
+}
+
+async fn handle<S, F, Fut, Req, Resp, Err>(
+    service: S,
+    mut req: Request<Body>,
     f: F,
+    options: &ServerOptions,
 ) -> Response<Body>
 where
     F: FnOnce(S, Context, Req) -> Fut + Clone + Sync + Send + 'static,
     Fut: Future<Output = Result<Resp, Err>> + Send,
-    Req: prost::Message + Default + serde::de::DeserializeOwned,
+    Req: prost::Message + Default + serde::de::DeserializeOwned + 'static,
     Resp: prost::Message + serde::Serialize,
     Err: IntoTwirpResponse,
 {
@@ -60,176 +333,1594 @@ where
         .copied()
         .unwrap_or_else(|| Timings::new(Instant::now()));
 
-    let (req, exts, resp_fmt) = match parse_request(req, &mut timings).await {
+    let if_none_match: Vec<_> = req
+        .headers()
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .cloned()
+        .collect();
+    if let Some(max) = options.max_header_count {
+        let count = req.headers().len();
+        if count > max {
+            let mut twirp_err = error::malformed("too many headers");
+            twirp_err.insert_meta("header_count".to_string(), count.to_string());
+            twirp_err.insert_meta("max_header_count".to_string(), max.to_string());
+            return options.error_response(twirp_err);
+        }
+    }
+    if options.baggage {
+        match Baggage::from_headers(req.headers()) {
+            Ok(Some(baggage)) => {
+                req.extensions_mut().insert(baggage);
+            }
+            Ok(None) => {}
+            Err(err) => {
+                let mut twirp_err = error::malformed("invalid baggage header");
+                twirp_err.insert_meta("error".to_string(), err.to_string());
+                return options.error_response(twirp_err);
+            }
+        }
+    }
+    // Held until the response is ready.
+    let _permit = match options
+        .acquire_concurrency_permit(rpc_path(&req), req.extensions().get::<RpcMethod>())
+    {
+        Ok(permit) => permit,
+        Err(twirp_err) => return options.error_response(twirp_err),
+    };
+    #[cfg(feature = "compression")]
+    let gzip_response =
+        options.compress_response_threshold.is_some() && compression::accepts_gzip(req.headers());
+    let headers_only = options
+        .probe_header
+        .as_ref()
+        .is_some_and(|name| req.headers().contains_key(name));
+    let rpc_method = req.extensions().get::<RpcMethod>().cloned();
+    // The client's deadline applies too, but can't extend the server's own timeouts.
+    let timeout = match (
+        options.timeout(rpc_path(&req), rpc_method.as_ref()),
+        requested_timeout(req.headers()),
+    ) {
+        (Some(timeout), Some(requested)) => Some(timeout.min(requested)),
+        (timeout, requested) => timeout.or(requested),
+    };
+    let parsed = parse_request(req, &mut timings, options);
+    let parsed = match options.parse_timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, parsed).await {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                let mut twirp_err = error::deadline_exceeded("request not received in time");
+                twirp_err.insert_meta("reason".to_string(), "parse_timeout".to_string());
+                twirp_err.insert_meta("timeout_ms".to_string(), timeout.as_millis().to_string());
+                return options.error_response(twirp_err);
+            }
+        },
+        None => parsed.await,
+    };
+    let (req, mut exts, resp_fmt) = match parsed {
         Ok(pair) => pair,
+        Err(err) if is_body_too_large(&*err) => {
+            let mut twirp_err = error::resource_exhausted("request body too large");
+            twirp_err.insert_meta("reason".to_string(), "body_too_large".to_string());
+            let mut resp = options.error_response(twirp_err);
+            if !options.errors_in_body {
+                *resp.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+            }
+            resp.extensions_mut().insert(RequestError(Arc::from(err)));
+            return resp;
+        }
+        #[cfg(feature = "compression")]
+        Err(err) if err.is::<DecompressedBodyTooLarge>() => {
+            let max_size = err
+                .downcast_ref::<DecompressedBodyTooLarge>()
+                .expect("checked above")
+                .max_size;
+            let mut twirp_err = error::resource_exhausted("decompressed request body too large");
+            twirp_err.insert_meta(
+                "reason".to_string(),
+                "decompressed_body_too_large".to_string(),
+            );
+            twirp_err.insert_meta("max_decompressed_size".to_string(), max_size.to_string());
+            let mut resp = options.error_response(twirp_err);
+            if !options.errors_in_body {
+                *resp.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+            }
+            resp.extensions_mut().insert(RequestError(Arc::from(err)));
+            return resp;
+        }
+        Err(err) if err.is::<Rejected>() => {
+            let Rejected(twirp_err) = *err.downcast().expect("checked above");
+            return options.error_response(twirp_err);
+        }
+        Err(err) if err.is::<MethodNotAllowed>() => {
+            return options.method_not_allowed(&Method::GET, false);
+        }
         Err(err) => {
-            // TODO: Capture original error in the response extensions. E.g.:
-            // resp_exts
-            //     .lock()
-            //     .expect("mutex poisoned")
-            //     .insert(RequestError(err));
-            let mut twirp_err = error::malformed("bad request");
-            twirp_err.insert_meta("error".to_string(), err.to_string());
-            return twirp_err.into_response();
+            let mut resp = options.error_response(malformed_request(&*err));
+            resp.extensions_mut().insert(RequestError(Arc::from(err)));
+            return resp;
         }
     };
 
     let resp_exts = Arc::new(Mutex::new(Extensions::new()));
+    let stats = (options.request_stats || options.request_stats_check.is_some())
+        .then(|| RequestStats::of(&req));
+    if let Some(stats) = stats {
+        if let Some(Callback(check)) = &options.request_stats_check {
+            if let Err(twirp_err) = check(&stats) {
+                let mut resp = options.error_response(twirp_err);
+                resp.extensions_mut().insert(stats);
+                return resp;
+            }
+        }
+        exts.insert(stats);
+    }
+    if let Some(every) = options.yield_budget {
+        exts.insert(YieldBudget::new(every));
+    }
     let ctx = Context::new(exts, resp_exts.clone());
-    let res = f(service, ctx, req).await;
+    let res = f(service, ctx, req);
+    let res = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, res).await {
+            Ok(res) => res,
+            Err(_) => {
+                let mut twirp_err = error::deadline_exceeded("handler timed out");
+                twirp_err.insert_meta("reason".to_string(), "handler_timeout".to_string());
+                twirp_err.insert_meta("timeout_ms".to_string(), timeout.as_millis().to_string());
+                return options.error_response(twirp_err);
+            }
+        },
+        None => res.await,
+    };
     timings.set_response_handled();
 
-    let mut resp = match write_response(res, resp_fmt) {
+    let mut resp = match write_response(res, resp_fmt, headers_only, options) {
         Ok(resp) => resp,
         Err(err) => {
-            // TODO: Capture original error in the response extensions.
-            let mut twirp_err = error::unknown("error serializing response");
-            twirp_err.insert_meta("error".to_string(), err.to_string());
-            return twirp_err.into_response();
+            let err = SerializationError {
+                format: resp_fmt,
+                source: err,
+            };
+            if let Some(Callback(hook)) = &options.serialization_error_hook {
+                hook(&err);
+            }
+            let mut twirp_err = error::internal("error serializing response");
+            twirp_err.insert_meta("reason".to_string(), "serialization_failed".to_string());
+            twirp_err.insert_meta("format".to_string(), err.format.name().to_string());
+            twirp_err.insert_meta("error".to_string(), err.source.to_string());
+            let mut resp = options.error_response(twirp_err);
+            resp.extensions_mut().insert(RequestError(Arc::new(err)));
+            return resp;
         }
     };
+    let headers = lock_extensions(&resp_exts).remove::<ResponseHeaders>();
+    if let Some(ResponseHeaders(headers)) = headers {
+        // Headers set by the handler take precedence over the ones set by the server.
+        for name in headers.keys() {
+            resp.headers_mut().remove(name);
+        }
+        for (name, value) in &headers {
+            resp.headers_mut().append(name, value.clone());
+        }
+    }
+    #[cfg(feature = "attachments")]
+    let attachment = lock_extensions(&resp_exts).remove::<crate::attachment::Attachment>();
+    #[cfg(feature = "attachments")]
+    if let Some(attachment) = attachment {
+        resp = crate::attachment::attach(resp, attachment).await;
+    }
+    // The format of the response depends on `Accept`, see `BodyFormat::from_accept`.
+    resp.headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept"));
+    #[cfg(feature = "compression")]
+    let mut gzipped = false;
+    #[cfg(feature = "compression")]
+    if let Some(threshold) = options.compress_response_threshold {
+        // The response depends on `Accept-Encoding` whether or not it ends up compressed.
+        resp.headers_mut()
+            .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        if gzip_response && !resp.headers().contains_key(header::CONTENT_ENCODING) {
+            resp = gzip_response_body(resp, threshold, options).await;
+            gzipped = resp.headers().contains_key(header::CONTENT_ENCODING);
+        }
+    }
+    let etag = lock_extensions(&resp_exts)
+        .get::<crate::etag::ETag>()
+        .cloned();
+    // A gzip encoded response is a different representation, so it gets a different tag. Small
+    // responses under the threshold are sent as they are, and keep the handler's tag.
+    #[cfg(feature = "compression")]
+    let etag = etag.map(|etag| match gzipped {
+        true => etag.with_suffix("-gzip"),
+        false => etag,
+    });
+    if let Some(etag) = etag {
+        resp = crate::etag::apply(resp, &etag, &if_none_match);
+    }
     timings.set_response_written();
 
     resp.extensions_mut()
-        .extend(resp_exts.lock().expect("mutex poisoned").clone());
+        .extend(lock_extensions(&resp_exts).clone());
     resp.extensions_mut().insert(timings);
+    if let Some(stats) = stats {
+        resp.extensions_mut().insert(stats);
+    }
     resp
 }
 
 async fn parse_request<T>(
     req: Request<Body>,
     timings: &mut Timings,
+    options: &ServerOptions,
+) -> Result<(T, Extensions, BodyFormat), GenericError>
+where
+    T: prost::Message + Default + DeserializeOwned + 'static,
+{
+    // The response is in the format the client accepts, or else that of the request.
+    let accept = BodyFormat::from_accept(req.headers());
+    if req.method() == Method::GET {
+        return parse_get_request(req, timings, options)
+            .map(|(request, exts, format)| (request, exts, accept.unwrap_or(format)));
+    }
+    let delimited = options.length_delimited_protobuf
+        && req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .is_some_and(|ct| ct.as_bytes() == CONTENT_TYPE_PROTOBUF_DELIMITED);
+    let format = if delimited {
+        BodyFormat::Pb
+    } else {
+        BodyFormat::from_content_type(&req)
+    };
+    let content_type = req.headers().get(header::CONTENT_TYPE);
+    options
+        .check_content_type(content_type.map(HeaderValue::as_bytes))
+        .map_err(Rejected)?;
+    options.check_format(format).map_err(Rejected)?;
+    let max_size = options.max_request_size();
+    let (parts, body) = req.into_parts();
+    let content_length = parts
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<u64>().ok());
+    if content_length.is_some_and(|len| len > max_size as u64) {
+        return Err(BodyTooLarge.into());
+    }
+    let identity = parts
+        .headers
+        .get(header::CONTENT_ENCODING)
+        .is_none_or(|encoding| encoding == "identity");
+    if options.streaming_json_decode
+        && format == BodyFormat::JsonPb
+        && identity
+        && options.request_inspector.is_none()
+        && !options.reject_duplicate_json_keys
+        && options.max_field_length.is_none()
+    {
+        let request = decode_json_streaming(body, max_size, timings).await?;
+        timings.set_parsed();
+        return Ok((request, parts.extensions, accept.unwrap_or(format)));
+    }
+    // The body is read frame by frame, so requests without a `Content-Length` (e.g. using
+    // `Transfer-Encoding: chunked`) are handled the same as any other, and reading stops as soon
+    // as the limit is exceeded.
+    let bytes = Limited::new(body, max_size).collect().await?.to_bytes();
+    let bytes = match parts.headers.get(header::CONTENT_ENCODING) {
+        None => bytes,
+        Some(encoding) if encoding == "identity" => bytes,
+        #[cfg(feature = "compression")]
+        Some(encoding) if encoding == compression::GZIP => {
+            // Decompression stops as soon as the limit is exceeded.
+            let max_size = options.max_decompressed_size();
+            compression::gunzip(&bytes, max_size)?
+                .ok_or(DecompressedBodyTooLarge { max_size })?
+                .into()
+        }
+        Some(encoding) => {
+            return Err(format!("unsupported content-encoding: {:?}", encoding).into());
+        }
+    };
+    timings.set_received();
+    let request = decode_body(&bytes, format, delimited, options)?;
+    timings.set_parsed();
+    Ok((request, parts.extensions, accept.unwrap_or(format)))
+}
+
+/// The number of body chunks queued for the decoder of
+/// [`ServerOptions::with_streaming_json_decode`].
+const STREAMING_JSON_DECODE_CHUNKS: usize = 4;
+
+/// Decode a JSON request on a blocking thread while its body is being received, see
+/// [`ServerOptions::with_streaming_json_decode`].
+async fn decode_json_streaming<T>(
+    body: Body,
+    max_size: usize,
+    timings: &mut Timings,
+) -> Result<T, GenericError>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    let (tx, rx) = tokio::sync::mpsc::channel(STREAMING_JSON_DECODE_CHUNKS);
+    let decoded = tokio::task::spawn_blocking(move || {
+        let reader = std::io::BufReader::new(ChunkReader {
+            chunks: rx,
+            chunk: Bytes::new(),
+        });
+        serde_json::from_reader(reader)
+    });
+    let mut body = Limited::new(body, max_size);
+    while let Some(frame) = body.frame().await {
+        // Dropping the sender on error ends the body early for the decoder too.
+        let Ok(data) = frame?.into_data() else {
+            continue;
+        };
+        if tx.send(data).await.is_err() {
+            // The decoder gave up on a malformed request, its error is returned below.
+            break;
+        }
+    }
+    drop(tx);
+    timings.set_received();
+    Ok(decoded.await??)
+}
+
+/// Reads the body chunks of [`decode_json_streaming`], blocking until they are received.
+struct ChunkReader {
+    chunks: tokio::sync::mpsc::Receiver<Bytes>,
+    chunk: Bytes,
+}
+
+impl std::io::Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => self.chunk = chunk,
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk.split_to(len));
+        Ok(len)
+    }
+}
+
+/// Parse a `GET` request for a method allowed by [`ServerOptions::with_get_methods`].
+fn parse_get_request<T>(
+    req: Request<Body>,
+    timings: &mut Timings,
+    options: &ServerOptions,
 ) -> Result<(T, Extensions, BodyFormat), GenericError>
 where
     T: prost::Message + Default + DeserializeOwned,
 {
+    if !options.allows_get(rpc_path(&req), req.extensions().get::<RpcMethod>()) {
+        return Err(MethodNotAllowed.into());
+    }
     let format = BodyFormat::from_content_type(&req);
-    let (parts, body) = req.into_parts();
-    let bytes = body.collect().await?.to_bytes();
+    options.check_format(format).map_err(Rejected)?;
+    let get = GetMessage::from_query(req.uri().query());
+    if get.message.len() > options.max_request_size() {
+        return Err(BodyTooLarge.into());
+    }
+    let bytes = get.decode()?;
     timings.set_received();
+    let request = decode_body(&bytes, format, false, options)?;
+    timings.set_parsed();
+    Ok((request, req.into_parts().0.extensions, format))
+}
+
+/// Decode a request message from its (uncompressed) body and `Content-Type`, the same way the
+/// server does for HTTP requests: picking the format from the content type, and applying the
+/// request [`ServerOptions`] such as [`ServerOptions::with_request_inspector`] and
+/// [`ServerOptions::with_max_field_length`].
+///
+/// This is for serving Twirp over other transports, e.g. a message queue, where requests don't
+/// come as HTTP requests. Errors are the ones the server would respond with, usually `malformed`.
+pub fn decode_request<T>(
+    content_type: Option<&str>,
+    body: &[u8],
+    options: &ServerOptions,
+) -> Result<T, TwirpErrorResponse>
+where
+    T: prost::Message + Default + DeserializeOwned,
+{
+    let content_type = content_type.map(str::as_bytes);
+    let delimited =
+        options.length_delimited_protobuf && content_type == Some(CONTENT_TYPE_PROTOBUF_DELIMITED);
+    let format = match content_type {
+        Some(ct) if is_protobuf_content_type(ct) => BodyFormat::Pb,
+        _ if delimited => BodyFormat::Pb,
+        _ => BodyFormat::JsonPb,
+    };
+    options.check_content_type(content_type)?;
+    options.check_format(format)?;
+    decode_body(body, format, delimited, options).map_err(|err| match err.downcast::<Rejected>() {
+        Ok(rejected) => rejected.0,
+        Err(err) => malformed_request(&*err),
+    })
+}
+
+fn decode_body<T>(
+    bytes: &[u8],
+    format: BodyFormat,
+    delimited: bool,
+    options: &ServerOptions,
+) -> Result<T, GenericError>
+where
+    T: prost::Message + Default + DeserializeOwned,
+{
+    if let Some(Callback(inspect)) = &options.request_inspector {
+        inspect(bytes).map_err(Rejected)?;
+    }
+    if format == BodyFormat::JsonPb && options.reject_duplicate_json_keys {
+        duplicate_keys::check_json(bytes).map_err(Rejected)?;
+    }
     let request = match format {
-        BodyFormat::Pb => T::decode(&bytes[..])?,
-        BodyFormat::JsonPb => serde_json::from_slice(&bytes)?,
+        BodyFormat::Pb => {
+            if let Some(max_len) = options.max_field_length {
+                // The length prefix is checked by the decoder.
+                let mut message = bytes;
+                if delimited {
+                    prost::encoding::decode_varint(&mut message)?;
+                }
+                field_length::check_protobuf(message, max_len).map_err(Rejected)?;
+            }
+            if delimited {
+                let mut rest = bytes;
+                let request = T::decode_length_delimited(&mut rest)?;
+                if options.strict_protobuf_framing && !rest.is_empty() {
+                    return Err(format!("{} trailing bytes after the message", rest.len()).into());
+                }
+                request
+            } else {
+                T::decode(bytes)?
+            }
+        }
+        BodyFormat::JsonPb => match options.max_field_length {
+            Some(max_len) => {
+                let value: serde_json::Value = serde_json::from_slice(bytes)?;
+                field_length::check_json(&value, max_len).map_err(Rejected)?;
+                serde_json::from_value(value)?
+            }
+            None => serde_json::from_slice(bytes)?,
+        },
     };
-    timings.set_parsed();
-    Ok((request, parts.extensions, format))
+    Ok(request)
 }
 
+fn malformed_request(err: &(dyn std::error::Error + 'static)) -> TwirpErrorResponse {
+    let mut twirp_err = error::malformed("bad request");
+    twirp_err.insert_meta("error".to_string(), err.to_string());
+    twirp_err
+}
+
+/// Whether reading the request body failed because it exceeded a size limit, either
+/// [`ServerOptions::with_max_request_size`] or one set with e.g.
+/// `tower_http::limit::RequestBodyLimitLayer`.
+pub(crate) fn is_body_too_large(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if err.is::<http_body_util::LengthLimitError>() || err.is::<BodyTooLarge>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Encode the response of a handler. With `headers_only`, a successful response is not encoded at
+/// all, see [`ServerOptions::with_probe_header`].
 fn write_response<T, Err>(
     response: Result<T, Err>,
     response_format: BodyFormat,
+    headers_only: bool,
+    options: &ServerOptions,
 ) -> Result<Response<Body>, GenericError>
 where
     T: prost::Message + Serialize,
     Err: IntoTwirpResponse,
 {
     let res = match response {
+        Ok(_) if headers_only => {
+            let content_type = match response_format {
+                BodyFormat::Pb => options
+                    .protobuf_content_type
+                    .as_ref()
+                    .map_or(CONTENT_TYPE_PROTOBUF, |ct| ct.as_bytes()),
+                BodyFormat::JsonPb => CONTENT_TYPE_JSON,
+            };
+            Response::builder()
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::empty())?
+        }
         Ok(response) => match response_format {
-            BodyFormat::Pb => Response::builder()
-                .header(header::CONTENT_TYPE, CONTENT_TYPE_PROTOBUF)
-                .body(Body::from(serialize_proto_message(response)))?,
+            BodyFormat::Pb => {
+                let data = serialize_proto_message(response);
+                if let Some(Callback(inspect)) = &options.response_inspector {
+                    inspect(&data);
+                }
+                Response::builder()
+                    .header(
+                        header::CONTENT_TYPE,
+                        options
+                            .protobuf_content_type
+                            .as_ref()
+                            .map_or(CONTENT_TYPE_PROTOBUF, |ct| ct.as_bytes()),
+                    )
+                    .body(Body::from(data))?
+            }
             BodyFormat::JsonPb => {
-                let data = serde_json::to_string(&response)?;
+                let data = if options.json_large_integers_as_strings || options.sorted_json_keys {
+                    let mut value = serde_json::to_value(&response)?;
+                    if options.json_large_integers_as_strings {
+                        crate::jsonpb::large_integers_to_strings(&mut value);
+                    }
+                    if options.sorted_json_keys {
+                        crate::jsonpb::sort_keys(&mut value);
+                    }
+                    serde_json::to_string(&value)?
+                } else {
+                    serde_json::to_string(&response)?
+                };
+                if let Some(Callback(inspect)) = &options.response_inspector {
+                    inspect(data.as_bytes());
+                }
                 Response::builder()
                     .header(header::CONTENT_TYPE, CONTENT_TYPE_JSON)
                     .body(Body::from(data))?
             }
         },
-        Err(err) => err.into_twirp_response().map(|err| err.into_axum_body()),
+        Err(err) => options.error_response(err),
     };
     Ok(res)
 }
 
-/// Axum handler function that returns 404 Not Found with a Twirp JSON payload.
-///
-/// `axum::Router`'s default fallback handler returns a 404 Not Found with no body content.
-/// Use this fallback instead for full Twirp compliance.
+/// Gzip the body of a successful response if it is larger than `threshold` bytes.
+#[cfg(feature = "compression")]
+async fn gzip_response_body(
+    resp: Response<Body>,
+    threshold: usize,
+    options: &ServerOptions,
+) -> Response<Body> {
+    if !resp.status().is_success() || resp.headers().contains_key(header::CONTENT_ENCODING) {
+        return resp;
+    }
+    let (mut parts, body) = resp.into_parts();
+    let data = match body.collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(err) => {
+            let mut twirp_err = error::internal("error compressing response");
+            twirp_err.insert_meta("error".to_string(), err.to_string());
+            let mut resp = options.error_response(twirp_err);
+            resp.headers_mut()
+                .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+            return resp;
+        }
+    };
+    if data.len() <= threshold {
+        return Response::from_parts(parts, Body::from(data));
+    }
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(compression::GZIP),
+    );
+    Response::from_parts(parts, Body::from(compression::gzip(&data)))
+}
+
+/// Options that control how the server handles Twirp requests.
 ///
-/// # Usage
+/// The server looks these up in the request extensions, so they can be applied to a whole router
+/// (or to any part of it) with an [`axum::Extension`] layer:
 ///
 /// ```
-/// use axum::Router;
+/// use twirp::axum::Extension;
+/// use twirp::server::ServerOptions;
 ///
-/// # fn build_app(twirp_routes: Router) -> Router {
-/// let app = Router::new()
-///     .nest("/twirp", twirp_routes)
-///     .fallback(twirp::server::not_found_handler);
-/// # app }
+/// # fn build(twirp_routes: twirp::Router) -> twirp::Router {
+/// twirp_routes.layer(Extension(ServerOptions::default().with_compact_errors(true)))
+/// # }
 /// ```
-pub async fn not_found_handler() -> Response<Body> {
-    error::bad_route("not found").into_response()
+///
+/// Requests without a `ServerOptions` extension are handled with the defaults.
+#[derive(Debug, Clone, Default)]
+pub struct ServerOptions {
+    compact_errors: bool,
+    request_id_header: Option<HeaderName>,
+    request_id_generator: Option<Callback<dyn Fn() -> String + Send + Sync>>,
+    hide_request_id: bool,
+    baggage: bool,
+    length_delimited_protobuf: bool,
+    yield_budget: Option<u32>,
+    errors_in_body: bool,
+    protobuf_content_type: Option<HeaderValue>,
+    error_details_encoding: ErrorDetailsEncoding,
+    request_inspector: Option<Callback<RequestInspector>>,
+    response_inspector: Option<Callback<ResponseInspector>>,
+    max_field_length: Option<usize>,
+    max_request_size: Option<usize>,
+    #[cfg(feature = "compression")]
+    max_decompressed_size: Option<usize>,
+    #[cfg(feature = "compression")]
+    compress_response_threshold: Option<usize>,
+    required_format: Option<BodyFormat>,
+    streaming_json_decode: bool,
+    reject_duplicate_json_keys: bool,
+    max_header_count: Option<usize>,
+    strict_protobuf_framing: bool,
+    json_large_integers_as_strings: bool,
+    sorted_json_keys: bool,
+    probe_header: Option<HeaderName>,
+    serialization_error_hook: Option<Callback<SerializationErrorHook>>,
+    audit_hook: Option<Callback<AuditHook>>,
+    observer: Option<Callback<dyn ServerObserver>>,
+    timeouts: Option<Timeouts>,
+    parse_timeout: Option<Duration>,
+    get_methods: Option<Arc<HashSet<String>>>,
+    allow_header: bool,
+    strict_content_type: bool,
+    request_stats: bool,
+    request_stats_check: Option<Callback<RequestStatsCheck>>,
+    concurrency_limits: Option<Arc<HashMap<String, ConcurrencyLimit>>>,
 }
 
-/// Contains timing information associated with a request.
-/// To access the timings in a given request, use the [extensions](Request::extensions)
-/// method and specialize to `Timings` appropriately.
-#[derive(Debug, Clone, Copy)]
-pub struct Timings {
-    // When the request started.
-    start: Instant,
-    // When the request was received (headers and body).
-    request_received: Option<Instant>,
-    // When the request body was parsed.
-    request_parsed: Option<Instant>,
-    // When the response handler returned.
-    response_handled: Option<Instant>,
-    // When the response was written.
-    response_written: Option<Instant>,
-}
+type RequestInspector = dyn Fn(&[u8]) -> Result<(), TwirpErrorResponse> + Send + Sync;
+type ResponseInspector = dyn Fn(&[u8]) + Send + Sync;
+type RequestStatsCheck = dyn Fn(&RequestStats) -> Result<(), TwirpErrorResponse> + Send + Sync;
+type SerializationErrorHook = dyn Fn(&SerializationError) + Send + Sync;
+type AuditHook = dyn Fn(&AuditEvent) + Send + Sync;
 
-impl Timings {
-    #[allow(clippy::new_without_default)]
-    pub fn new(start: Instant) -> Self {
-        Self {
-            start,
-            request_received: None,
-            request_parsed: None,
-            response_handled: None,
-            response_written: None,
-        }
+impl ServerOptions {
+    /// Omit the `meta` map from error responses, keeping only `code` and `msg`. This keeps error
+    /// payloads small and avoids leaking internal details in production. Disabled by default.
+    pub fn with_compact_errors(mut self, enabled: bool) -> Self {
+        self.compact_errors = enabled;
+        self
     }
 
-    fn set_received(&mut self) {
-        self.request_received = Some(Instant::now());
+    /// Make sure every request has an id. The id is read from the `header` request header
+    /// (conventionally [`REQUEST_ID`](crate::headers::REQUEST_ID), `x-request-id`), or generated
+    /// if the header is missing. Either way, handlers can get it with [`Context::request_id`],
+    /// and it is echoed back in the same header of the response (see
+    /// [`ServerOptions::with_request_id_echo`]). Disabled by default.
+    ///
+    /// ```
+    /// # use twirp::server::ServerOptions;
+    /// use twirp::headers::REQUEST_ID;
+    /// use twirp::reqwest::header::HeaderName;
+    ///
+    /// let options =
+    ///     ServerOptions::default().with_request_id_header(HeaderName::from_static(REQUEST_ID));
+    /// ```
+    pub fn with_request_id_header(mut self, header: HeaderName) -> Self {
+        self.request_id_header = Some(header);
+        self
     }
 
-    fn set_parsed(&mut self) {
-        self.request_parsed = Some(Instant::now());
+    /// Read the `baggage` header of requests into a [`Baggage`] request extension, for handlers
+    /// to get from the [`Context`] (and pass on with [`Client::with_baggage`]). Requests with an
+    /// invalid or oversized `baggage` header are rejected with `malformed`. Disabled by default.
+    ///
+    /// [`Client::with_baggage`]: crate::Client::with_baggage
+    pub fn with_baggage(mut self, enabled: bool) -> Self {
+        self.baggage = enabled;
+        self
     }
 
-    fn set_response_handled(&mut self) {
-        self.response_handled = Some(Instant::now());
+    /// Whether the request id is echoed back in the response, see
+    /// [`ServerOptions::with_request_id_header`]. Disable it to keep correlation ids from leaking
+    /// to clients, e.g. on public endpoints; handlers still get the id either way. Enabled by
+    /// default.
+    pub fn with_request_id_echo(mut self, enabled: bool) -> Self {
+        self.hide_request_id = !enabled;
+        self
     }
 
-    fn set_response_written(&mut self) {
-        self.response_written = Some(Instant::now());
+    /// Generate request ids with `generator` instead of the default random UUIDs (v4), e.g. to
+    /// match an existing ULID or snowflake scheme. Only takes effect with
+    /// [`ServerOptions::with_request_id_header`], and only for requests that don't already carry
+    /// an id. The generated ids must be valid header values; requests get no id otherwise.
+    pub fn with_request_id_generator<F>(mut self, generator: F) -> Self
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        self.request_id_generator = Some(Callback(Arc::new(generator)));
+        self
     }
 
-    pub fn received(&self) -> Option<Duration> {
-        self.request_received.map(|x| x - self.start)
+    /// Make handlers yield to the tokio scheduler after every `yield_points` calls to
+    /// [`Context::yield_point`], so that a handler busy with a long computation can't monopolize
+    /// its worker thread and starve the other requests on it.
+    ///
+    /// This trades some latency (and scheduling overhead) in the busy handler for fairness to
+    /// the other requests. Handlers can only be preempted at their yield points: the work
+    /// between two of them still blocks the worker, and heavy synchronous work belongs in
+    /// `tokio::task::spawn_blocking`. Disabled by default, in which case yield points only
+    /// consume tokio's cooperative budget.
+    pub fn with_yield_budget(mut self, yield_points: u32) -> Self {
+        self.yield_budget = Some(yield_points.max(1));
+        self
     }
 
-    pub fn parsed(&self) -> Option<Duration> {
-        match (self.request_parsed, self.request_received) {
-            (Some(parsed), Some(received)) => Some(parsed - received),
-            _ => None,
-        }
+    /// Accept length-delimited protobuf requests, i.e. a message prefixed with its varint encoded
+    /// length, sent with the non-standard `application/protobuf-delimited` content type. Some
+    /// gateways send requests this way. Responses are always bare protobuf messages. Disabled by
+    /// default.
+    pub fn with_length_delimited_protobuf(mut self, enabled: bool) -> Self {
+        self.length_delimited_protobuf = enabled;
+        self
     }
 
-    pub fn response_handled(&self) -> Option<Duration> {
-        match (self.response_handled, self.request_parsed) {
-            (Some(handled), Some(parsed)) => Some(handled - parsed),
-            _ => None,
-        }
+    /// Reject requests with more than `max` header entries (counting every value of a repeated
+    /// header) with `malformed`, before reading their body. This defends against header floods
+    /// that stay under the total header size limit of the HTTP server. Unlimited by default.
+    pub fn with_max_header_count(mut self, max: usize) -> Self {
+        self.max_header_count = Some(max);
+        self
     }
 
-    pub fn response_written(&self) -> Option<Duration> {
-        match (self.response_written, self.response_handled) {
-            (Some(written), Some(handled)) => Some(written - handled),
-            (Some(written), None) => {
-                if let Some(parsed) = self.request_parsed {
-                    Some(written - parsed)
-                } else {
+    /// Reject length-delimited protobuf requests (see
+    /// [`ServerOptions::with_length_delimited_protobuf`]) with bytes left over after the message,
+    /// with a `malformed` error. This catches clients with framing bugs, e.g. ones that send a
+    /// stale length prefix. Disabled by default, in which case the bytes after the message are
+    /// ignored.
+    ///
+    /// A bare protobuf request has no framing: the whole body is the message, so any trailing
+    /// bytes are decoded as more fields of it, regardless of this option. Invalid ones make the
+    /// request `malformed`, and well-formed ones are treated like any unknown field.
+    pub fn with_strict_protobuf_framing(mut self, enabled: bool) -> Self {
+        self.strict_protobuf_framing = enabled;
+        self
+    }
+
+    /// Fail requests whose handler takes longer than `default` with `deadline_exceeded`, or
+    /// longer than the timeout in `overrides` for the method, keyed by method name (e.g.
+    /// `MakeHat`) or by the method's path relative to the Twirp prefix (e.g.
+    /// `example.Haberdasher/MakeHat`, as in the `paths` module generated by `twirp-build`), which
+    /// takes precedence. The handler future is dropped when it times out, and the error has the
+    /// `reason` `handler_timeout` in its meta. Receiving and parsing the request doesn't count
+    /// towards these, see [`ServerOptions::with_parse_timeout`]. No timeout by default.
+    ///
+    /// A shorter timeout sent by the client in the [`TIMEOUT_MS`](crate::headers::TIMEOUT_MS)
+    /// header is applied the same way, with or without these.
+    ///
+    /// `twirp-build` can generate the overrides from a method option in the proto definitions,
+    /// as `TIMEOUTS` in the `paths` module (see its `ServiceGenerator::with_timeout_option`).
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use twirp::server::ServerOptions;
+    /// let options = ServerOptions::default().with_timeouts(
+    ///     Duration::from_secs(1),
+    ///     [
+    ///         ("ExportHats", Duration::from_secs(30)),
+    ///         ("example.Inventory/ExportHats", Duration::from_secs(60)),
+    ///     ],
+    /// );
+    /// ```
+    pub fn with_timeouts<'a, I>(mut self, default: Duration, overrides: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a str, Duration)>,
+    {
+        self.timeouts = Some(Timeouts {
+            default,
+            overrides: Arc::new(
+                overrides
+                    .into_iter()
+                    .map(|(method, timeout)| (method.to_string(), timeout))
+                    .collect(),
+            ),
+        });
+        self
+    }
+
+    /// Also accept `GET` requests for `methods`, with the request message in the query: as JSON
+    /// in `?message=`, or as base64url encoded protobuf with `?message=...&base64=1`. The response
+    /// is in the same format. Methods are given by name (e.g. `GetHat`, for the methods of that
+    /// name of every service) or by path relative to the Twirp prefix (e.g.
+    /// `example.Haberdasher/GetHat`). Only enable this for methods without side effects, e.g. so
+    /// that responses can be cached or the methods called from a browser. Other methods reject
+    /// `GET` requests with `bad_route`.
+    ///
+    /// `twirp-build` generates the paths of the methods marked with
+    /// `option idempotency_level = NO_SIDE_EFFECTS;` as `NO_SIDE_EFFECTS` in the `paths` module of
+    /// each service:
+    ///
+    /// ```
+    /// # use twirp::server::ServerOptions;
+    /// # mod haberdasher_paths {
+    /// #     pub const NO_SIDE_EFFECTS: [&str; 1] = ["example.Haberdasher/GetHat"];
+    /// # }
+    /// let options = ServerOptions::default().with_get_methods(haberdasher_paths::NO_SIDE_EFFECTS);
+    /// ```
+    pub fn with_get_methods<I, M>(mut self, methods: I) -> Self
+    where
+        I: IntoIterator<Item = M>,
+        M: Into<String>,
+    {
+        self.get_methods = Some(Arc::new(methods.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    /// Add an `Allow` header listing the accepted HTTP methods (`POST`, and `GET` for the methods
+    /// of [`ServerOptions::with_get_methods`]) to the `bad_route` errors of requests for an rpc
+    /// with any other HTTP method, as HTTP suggests. Disabled by default.
+    pub fn with_allow_header(mut self, enabled: bool) -> Self {
+        self.allow_header = enabled;
+        self
+    }
+
+    /// Limit how many requests for a method are handled at once, to protect expensive methods.
+    /// Limits are keyed by method name (e.g. `ExportHats`, shared by the methods of that name of
+    /// every service) or by the method's path relative to the Twirp prefix (e.g.
+    /// `example.Inventory/ExportHats`), which takes precedence, as with
+    /// [`ServerOptions::with_timeouts`]. Requests over the limit fail immediately with
+    /// `resource_exhausted`, before their body is read. Other methods aren't limited, and
+    /// [`ServerOptions::in_flight`] tells how many requests a limited method is handling.
+    ///
+    /// ```
+    /// # use twirp::server::ServerOptions;
+    /// let options =
+    ///     ServerOptions::default().with_concurrency_limits([("example.Inventory/ExportHats", 5)]);
+    /// ```
+    pub fn with_concurrency_limits<I, M>(mut self, limits: I) -> Self
+    where
+        I: IntoIterator<Item = (M, usize)>,
+        M: Into<String>,
+    {
+        self.concurrency_limits = Some(Arc::new(
+            limits
+                .into_iter()
+                .map(|(method, max)| {
+                    let limit = ConcurrencyLimit {
+                        max,
+                        semaphore: Arc::new(tokio::sync::Semaphore::new(max)),
+                    };
+                    (method.into(), limit)
+                })
+                .collect(),
+        ));
+        self
+    }
+
+    /// Send error responses with a `200 OK` status, and the Twirp error code in the non-standard
+    /// [`ERROR_CODE`](crate::headers::ERROR_CODE) header. The body is the usual Twirp error JSON.
+    ///
+    /// **This is not part of the Twirp spec.** It's meant for browsers and SDKs that treat any
+    /// non-2xx status as fatal; the `twirp` client understands it when configured with
+    /// [`ClientBuilder::errors_in_body`](crate::ClientBuilder::errors_in_body). Disabled by
+    /// default, so errors use their proper HTTP status.
+    pub fn with_errors_in_body(mut self, enabled: bool) -> Self {
+        self.errors_in_body = enabled;
+        self
+    }
+
+    /// The `Content-Type` of protobuf responses, for toolchains that expect a non-standard one
+    /// like `application/x-protobuf`. Defaults to the spec's `application/protobuf`. Requests
+    /// are accepted with any of the common protobuf content types either way.
+    pub fn with_protobuf_content_type(mut self, content_type: HeaderValue) -> Self {
+        self.protobuf_content_type = Some(content_type);
+        self
+    }
+
+    /// Where to write the `meta` of error responses. Defaults to the spec's
+    /// [`ErrorDetailsEncoding::Meta`]; use [`ErrorDetailsEncoding::Details`] for clients that
+    /// expect a `details` array instead.
+    pub fn with_error_details_encoding(mut self, encoding: ErrorDetailsEncoding) -> Self {
+        self.error_details_encoding = encoding;
+        self
+    }
+
+    /// Pass the raw bytes of every request body to `inspector`, e.g. to apply web application
+    /// firewall rules. The bytes are inspected after decompression and before decoding; returning
+    /// an error rejects the request with that error, without calling the handler.
+    ///
+    /// Inspection is skipped entirely when no inspector is set, which is the default.
+    pub fn with_request_inspector<F>(mut self, inspector: F) -> Self
+    where
+        F: Fn(&[u8]) -> Result<(), TwirpErrorResponse> + Send + Sync + 'static,
+    {
+        self.request_inspector = Some(Callback(Arc::new(inspector)));
+        self
+    }
+
+    /// Reject requests with a string or bytes field longer than `max_len` bytes with
+    /// `invalid_argument`, naming the field in the error's `field` meta (by field number for
+    /// protobuf requests, by name for JSON ones). Unlimited by default.
+    ///
+    /// The limit is checked on the encoded request, before decoding it: on base64 encoded bytes
+    /// for JSON requests, and on every length-delimited field for protobuf requests, as strings
+    /// can't be told apart from embedded messages and packed repeated fields there. So for
+    /// protobuf requests it also applies to embedded messages, and the limit should be above the
+    /// encoded size of the largest one.
+    pub fn with_max_field_length(mut self, max_len: usize) -> Self {
+        self.max_field_length = Some(max_len);
+        self
+    }
+
+    /// Reject requests with a body larger than `max_size` bytes with `resource_exhausted` (and a
+    /// `413 Payload Too Large` status). Requests with a larger `Content-Length` are rejected
+    /// without reading their body, others as soon as the limit is exceeded. Unless set with
+    /// [`ServerOptions::with_max_decompressed_size`], the limit also applies to the body after
+    /// decompressing it. Defaults to [`DEFAULT_MAX_REQUEST_SIZE`].
+    pub fn with_max_request_size(mut self, max_size: usize) -> Self {
+        self.max_request_size = Some(max_size);
+        self
+    }
+
+    /// Reject compressed requests whose body decompresses to more than `max_size` bytes, to bound
+    /// the memory a small compressed body (a "compression bomb") can take. Decompression stops
+    /// as soon as the limit is exceeded, and the request is rejected with `resource_exhausted`
+    /// (and a `413 Payload Too Large` status), with `decompressed_body_too_large` as the
+    /// `reason` in its meta. Defaults to [`ServerOptions::with_max_request_size`].
+    #[cfg(feature = "compression")]
+    pub fn with_max_decompressed_size(mut self, max_size: usize) -> Self {
+        self.max_decompressed_size = Some(max_size);
+        self
+    }
+
+    /// Gzip response bodies (and set `Content-Encoding: gzip`) larger than `threshold` bytes,
+    /// for clients that accept gzip encoded responses (`Accept-Encoding: gzip`), in either
+    /// format. Responses are sent with `Vary: accept-encoding`, and an [`ETag`] set by the
+    /// handler gets a `-gzip` suffix for those clients, so caches keep the two encodings apart.
+    /// Disabled by default. Gzip encoded requests are always accepted.
+    ///
+    /// [`ETag`]: crate::etag::ETag
+    #[cfg(feature = "compression")]
+    pub fn with_compress_response_threshold(mut self, threshold: usize) -> Self {
+        self.compress_response_threshold = Some(threshold);
+        self
+    }
+
+    /// Only accept requests with a `Content-Type` of `application/json` (with any parameters,
+    /// e.g. `charset=utf-8`) or `application/protobuf`, rejecting anything else (including a
+    /// missing content type) with `bad_route` naming the content type, rather than parsing it as
+    /// JSON. Disabled by default.
+    pub fn with_strict_content_type(mut self, enabled: bool) -> Self {
+        self.strict_content_type = enabled;
+        self
+    }
+
+    /// Compute the [`RequestStats`] of every request after decoding it. This costs encoding the
+    /// request message again. Disabled by default.
+    ///
+    /// The stats are only handed to the handler and to the middleware after it; use
+    /// [`ServerOptions::with_request_stats_check`] to reject requests before the handler runs.
+    pub fn with_request_stats(mut self, enabled: bool) -> Self {
+        self.request_stats = enabled;
+        self
+    }
+
+    /// Pass the [`RequestStats`] of every request to `check` after decoding it (which enables
+    /// [`ServerOptions::with_request_stats`]); returning an error rejects the request with that
+    /// error, without calling the handler. The error response has the stats in its extensions
+    /// too.
+    ///
+    /// ```
+    /// # use twirp::server::ServerOptions;
+    /// let options = ServerOptions::default().with_request_stats_check(|stats| {
+    ///     if stats.fields > 10_000 {
+    ///         return Err(twirp::invalid_argument("too many fields"));
+    ///     }
+    ///     Ok(())
+    /// });
+    /// ```
+    pub fn with_request_stats_check<F>(mut self, check: F) -> Self
+    where
+        F: Fn(&RequestStats) -> Result<(), TwirpErrorResponse> + Send + Sync + 'static,
+    {
+        self.request_stats_check = Some(Callback(Arc::new(check)));
+        self
+    }
+
+    /// Only accept requests in `format`, rejecting requests in the other format with `malformed`
+    /// before reading their body. Both formats are accepted by default.
+    pub fn with_required_format(mut self, format: BodyFormat) -> Self {
+        self.required_format = Some(format);
+        self
+    }
+
+    /// Decode JSON requests while their body is being received, on a blocking thread fed with
+    /// the body's chunks, rather than reading the whole body first. This cuts the peak memory of
+    /// large requests (e.g. with a long repeated field) to the decoded message and a few chunks
+    /// of the body, at the cost of a hand-off to `tokio::task::spawn_blocking` per request.
+    /// Disabled by default.
+    ///
+    /// Requests that need their whole body anyway are still read in full first: gzip encoded
+    /// ones, and those checked by [`ServerOptions::with_request_inspector`],
+    /// [`ServerOptions::with_reject_duplicate_json_keys`] or
+    /// [`ServerOptions::with_max_field_length`].
+    pub fn with_streaming_json_decode(mut self, enabled: bool) -> Self {
+        self.streaming_json_decode = enabled;
+        self
+    }
+
+    /// Reject JSON requests in which an object has the same key twice with `malformed` (and meta
+    /// `reason: duplicate_key`), rather than keeping the last value of a map as serde_json does,
+    /// for hardened endpoints. Disabled by default. This costs a second parse of JSON requests.
+    pub fn with_reject_duplicate_json_keys(mut self, enabled: bool) -> Self {
+        self.reject_duplicate_json_keys = enabled;
+        self
+    }
+
+    /// Write the integers of JSON responses that JavaScript can't represent exactly (beyond
+    /// ±2^53 - 1) as strings, like proto3 JSON does for 64-bit integers, so JavaScript clients
+    /// don't silently lose precision. Smaller integers are still written as numbers. Disabled by
+    /// default.
+    ///
+    /// This is a stopgap for the derived `serde` encoding of messages, which writes every integer
+    /// as a number. Clients have to accept 64-bit integers as strings, as proto3 JSON parsers do.
+    pub fn with_json_large_integers_as_strings(mut self, enabled: bool) -> Self {
+        self.json_large_integers_as_strings = enabled;
+        self
+    }
+
+    /// Sort the keys of the objects in JSON responses, so that responses are byte-for-byte
+    /// deterministic, e.g. for snapshot tests or caching. Proto `map` fields are `HashMap`s, which
+    /// are otherwise written in an arbitrary order. The fields of messages are sorted by name too,
+    /// as the two can't be told apart once encoded. Disabled by default, which is faster.
+    pub fn with_sorted_json_keys(mut self, enabled: bool) -> Self {
+        self.sorted_json_keys = enabled;
+        self
+    }
+
+    /// Skip encoding the response of requests that have the `header` header, e.g. from health
+    /// probes that call a real method but only look at the status. Successful responses then
+    /// have the `Content-Type` of the response but an empty body; error responses are sent as
+    /// usual. The handler still runs. Disabled by default.
+    pub fn with_probe_header(mut self, header: HeaderName) -> Self {
+        self.probe_header = Some(header);
+        self
+    }
+
+    /// Call `hook` when a handler's response can't be serialized, e.g. to log it as the bug it
+    /// usually is. The client gets an `internal` error either way, with `serialization_failed` as
+    /// the `reason` in its meta, and the `format` (`json` or `protobuf`) that failed. Unset by
+    /// default.
+    pub fn with_serialization_error_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&SerializationError) + Send + Sync + 'static,
+    {
+        self.serialization_error_hook = Some(Callback(Arc::new(hook)));
+        self
+    }
+
+    /// Call `hook` with an [`AuditEvent`] for every rpc once its response is ready, whether it
+    /// succeeded or failed, e.g. to write an audit log. The hook runs on the request's task, so
+    /// hand slow sinks the event through a channel. Unset by default.
+    pub fn with_audit_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&AuditEvent) + Send + Sync + 'static,
+    {
+        self.audit_hook = Some(Callback(Arc::new(hook)));
+        self
+    }
+
+    /// Report the outcome and [`Timings`] of every rpc to `observer` once its response is ready,
+    /// e.g. to record request counts and latencies in a metrics registry. Unset by default.
+    pub fn with_observer<O: ServerObserver>(mut self, observer: O) -> Self {
+        self.observer = Some(Callback(Arc::new(observer)));
+        self
+    }
+
+    /// Pass the raw bytes of every successful response body to `inspector`, after encoding and
+    /// before sending. Error responses are not inspected. Unset by default.
+    pub fn with_response_inspector<F>(mut self, inspector: F) -> Self
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        self.response_inspector = Some(Callback(Arc::new(inspector)));
+        self
+    }
+
+    /// Attach a [`RequestId`] to `req` if request ids are enabled, returning the header to echo
+    /// it back in.
+    fn request_id<B>(&self, req: &mut Request<B>) -> Option<(HeaderName, HeaderValue)> {
+        let name = self.request_id_header.clone()?;
+        let id = match req.headers().get(&name).and_then(|v| v.to_str().ok()) {
+            Some(id) => id.to_string(),
+            None => match &self.request_id_generator {
+                Some(Callback(generate)) => generate(),
+                None => uuid::Uuid::new_v4().to_string(),
+            },
+        };
+        let value = HeaderValue::from_str(&id).ok()?;
+        req.extensions_mut().insert(RequestId(id));
+        Some((name, value))
+    }
+
+    /// The number of requests being handled under the limit keyed by `method` (a method name or
+    /// path), if there is one, see [`ServerOptions::with_concurrency_limits`]. The options share
+    /// the counts with their clones, so this can be read from a copy of the options installed on
+    /// the router.
+    pub fn in_flight(&self, method: &str) -> Option<usize> {
+        let limit = self.concurrency_limits.as_ref()?.get(method)?;
+        Some(limit.max - limit.semaphore.available_permits())
+    }
+
+    /// Fail requests whose body isn't received and parsed within `timeout` with
+    /// `deadline_exceeded`, with the `reason` `parse_timeout` in its meta. This bounds how long
+    /// a client trickling its request body (e.g. a slowloris attack) can hold on to a
+    /// connection, without cutting into the time of the handler. No timeout by default.
+    pub fn with_parse_timeout(mut self, timeout: Duration) -> Self {
+        self.parse_timeout = Some(timeout);
+        self
+    }
+
+    /// The timeout of the handler of the rpc at `path` (see [`rpc_path`]) named `method`, see
+    /// [`ServerOptions::with_timeouts`].
+    fn timeout(&self, path: Option<&str>, method: Option<&RpcMethod>) -> Option<Duration> {
+        let timeouts = self.timeouts.as_ref()?;
+        let path_timeout = path.and_then(|path| timeouts.overrides.get(path));
+        let method_timeout = method.and_then(|method| timeouts.overrides.get(&*method.0));
+        Some(*path_timeout.or(method_timeout).unwrap_or(&timeouts.default))
+    }
+
+    /// Whether the rpc at `path` named `method` accepts `GET` requests, see
+    /// [`ServerOptions::with_get_methods`].
+    fn allows_get(&self, path: Option<&str>, method: Option<&RpcMethod>) -> bool {
+        let Some(methods) = &self.get_methods else {
+            return false;
+        };
+        path.is_some_and(|path| methods.contains(path))
+            || method.is_some_and(|method| methods.contains(&*method.0))
+    }
+
+    /// The `bad_route` error for a request with an HTTP method its rpc doesn't accept, see
+    /// [`ServerOptions::with_allow_header`].
+    fn method_not_allowed(&self, method: &Method, allows_get: bool) -> Response<Body> {
+        let (allowed, allow) = if allows_get {
+            ("GET and POST are", "GET, POST")
+        } else {
+            ("POST is", "POST")
+        };
+        let twirp_err = error::bad_route(format!(
+            "unsupported method {method} (only {allowed} allowed)"
+        ));
+        let mut resp = self.error_response(twirp_err);
+        if self.allow_header {
+            resp.headers_mut()
+                .insert(header::ALLOW, HeaderValue::from_static(allow));
+        }
+        resp
+    }
+
+    /// Take a permit to handle a request for the rpc at `path` named `method`, if its
+    /// concurrency is limited, see [`ServerOptions::with_concurrency_limits`].
+    fn acquire_concurrency_permit(
+        &self,
+        path: Option<&str>,
+        method: Option<&RpcMethod>,
+    ) -> Result<Option<tokio::sync::OwnedSemaphorePermit>, TwirpErrorResponse> {
+        let limit = self.concurrency_limits.as_ref().and_then(|limits| {
+            let path_limit = path.and_then(|path| limits.get(path));
+            path_limit.or_else(|| limits.get(&*method?.0))
+        });
+        let Some(limit) = limit else {
+            return Ok(None);
+        };
+        match limit.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Ok(Some(permit)),
+            Err(_) => {
+                let mut twirp_err = error::resource_exhausted("too many concurrent requests");
+                twirp_err.insert_meta("reason".to_string(), "concurrency_limit".to_string());
+                twirp_err.insert_meta("max_concurrency".to_string(), limit.max.to_string());
+                Err(twirp_err)
+            }
+        }
+    }
+
+    pub(crate) fn max_request_size(&self) -> usize {
+        self.max_request_size.unwrap_or(DEFAULT_MAX_REQUEST_SIZE)
+    }
+
+    #[cfg(feature = "compression")]
+    fn max_decompressed_size(&self) -> usize {
+        self.max_decompressed_size
+            .unwrap_or_else(|| self.max_request_size())
+    }
+
+    /// Reject requests whose content type isn't JSON or protobuf, with
+    /// [`ServerOptions::with_strict_content_type`].
+    fn check_content_type(&self, content_type: Option<&[u8]>) -> Result<(), TwirpErrorResponse> {
+        if !self.strict_content_type {
+            return Ok(());
+        }
+        let mime = content_type
+            .and_then(|ct| ct.split(|&b| b == b';').next())
+            .map(<[u8]>::trim_ascii);
+        let supported = match mime {
+            Some(mime) if is_protobuf_content_type(mime) => true,
+            Some(CONTENT_TYPE_PROTOBUF_DELIMITED) => self.length_delimited_protobuf,
+            Some(mime) => mime.eq_ignore_ascii_case(CONTENT_TYPE_JSON),
+            None => false,
+        };
+        if supported {
+            return Ok(());
+        }
+        let mut twirp_err = error::bad_route(match content_type {
+            Some(_) => "unsupported content type",
+            None => "missing content type",
+        });
+        let content_type = String::from_utf8_lossy(content_type.unwrap_or_default());
+        twirp_err.insert_meta("content_type".to_string(), content_type.into_owned());
+        Err(twirp_err)
+    }
+
+    /// Reject requests in a format other than the one set with
+    /// [`ServerOptions::with_required_format`].
+    fn check_format(&self, format: BodyFormat) -> Result<(), TwirpErrorResponse> {
+        match self.required_format {
+            Some(required) if required != format => {
+                let mut twirp_err = error::malformed("unsupported request format");
+                twirp_err.insert_meta("format".to_string(), format.name().to_string());
+                twirp_err.insert_meta("required_format".to_string(), required.name().to_string());
+                Err(twirp_err)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn error_response<E>(&self, err: E) -> Response<Body>
+    where
+        E: IntoTwirpResponse,
+    {
+        let mut resp = err.into_twirp_response();
+        // Also for errors that build their own response, so that middleware (e.g.
+        // `TwirpErrorLayer`) can tell every error the server writes apart.
+        let code = resp.body().code;
+        resp.extensions_mut().insert(code);
+        if self.errors_in_body {
+            let code = HeaderValue::from_static(resp.body().code.twirp_code());
+            *resp.status_mut() = StatusCode::OK;
+            resp.headers_mut().insert(ERROR_CODE, code);
+        }
+        resp.map(|mut err: TwirpErrorResponse| {
+            if self.compact_errors {
+                err.meta.clear();
+            }
+            err.into_axum_body_with(self.error_details_encoding)
+        })
+    }
+}
+
+/// A user-provided callback in [`ServerOptions`].
+struct Callback<F: ?Sized>(Arc<F>);
+
+impl<F: ?Sized> Clone for Callback<F> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<F: ?Sized> Debug for Callback<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Callback")
+    }
+}
+
+/// A handler's response that couldn't be serialized, see
+/// [`ServerOptions::with_serialization_error_hook`].
+#[derive(Debug)]
+pub struct SerializationError {
+    format: BodyFormat,
+    source: GenericError,
+}
+
+impl SerializationError {
+    /// The format the response was being serialized to.
+    pub fn format(&self) -> BodyFormat {
+        self.format
+    }
+}
+
+impl std::fmt::Display for SerializationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "failed to serialize {} response: {}",
+            self.format.name(),
+            self.source
+        )
+    }
+}
+
+impl std::error::Error for SerializationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+/// The error a request failed with when its body couldn't be read or decoded, or its response
+/// couldn't be serialized (as a [`SerializationError`]). It is added to the extensions of the
+/// error response, for middleware to log, while the client only gets a generic Twirp error.
+#[derive(Debug, Clone)]
+pub struct RequestError(pub Arc<dyn std::error::Error + Send + Sync>);
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&*self.0, f)
+    }
+}
+
+#[derive(Debug)]
+struct ConcurrencyLimit {
+    max: usize,
+    semaphore: Arc<tokio::sync::Semaphore>,
+}
+
+#[derive(Debug, Clone)]
+struct Timeouts {
+    default: Duration,
+    overrides: Arc<HashMap<String, Duration>>,
+}
+
+/// A request body over [`ServerOptions::with_max_request_size`].
+#[derive(Debug)]
+struct BodyTooLarge;
+
+impl std::fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("request body too large")
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+/// A compressed request body over [`ServerOptions::with_max_decompressed_size`] once
+/// decompressed.
+#[cfg(feature = "compression")]
+#[derive(Debug)]
+struct DecompressedBodyTooLarge {
+    max_size: usize,
+}
+
+#[cfg(feature = "compression")]
+impl std::fmt::Display for DecompressedBodyTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "decompressed request body over {} bytes", self.max_size)
+    }
+}
+
+#[cfg(feature = "compression")]
+impl std::error::Error for DecompressedBodyTooLarge {}
+
+/// A `GET` request for a method not allowed by [`ServerOptions::with_get_methods`].
+#[derive(Debug)]
+struct MethodNotAllowed;
+
+impl std::fmt::Display for MethodNotAllowed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("method not allowed")
+    }
+}
+
+impl std::error::Error for MethodNotAllowed {}
+
+/// A request rejected by the [`ServerOptions::with_request_inspector`] callback, or for
+/// exceeding [`ServerOptions::with_max_field_length`].
+#[derive(Debug)]
+struct Rejected(TwirpErrorResponse);
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "request rejected: {}", self.0.msg)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// Axum handler function that returns 404 Not Found with a Twirp JSON payload.
+///
+/// `axum::Router`'s default fallback handler returns a 404 Not Found with no body content.
+/// Use this fallback instead for full Twirp compliance.
+///
+/// Like every Twirp error response, the response has its
+/// [`TwirpErrorCode`](crate::TwirpErrorCode) (here `bad_route`) in its extensions, so access
+/// logging and metrics middleware can still record requests for unknown routes, along with the
+/// path they attempted.
+///
+/// # Usage
+///
+/// ```
+/// use axum::Router;
+///
+/// # fn build_app(twirp_routes: Router) -> Router {
+/// let app = Router::new()
+///     .nest("/twirp", twirp_routes)
+///     .fallback(twirp::server::not_found_handler);
+/// # app }
+/// ```
+pub async fn not_found_handler() -> Response<Body> {
+    error::bad_route("not found").into_response()
+}
+
+/// Fallback for requests routed to an rpc with an HTTP method other than `POST` or `GET`,
+/// responding with `bad_route`.
+pub(crate) async fn method_not_allowed(req: Request<Body>) -> Response<Body> {
+    let options = req
+        .extensions()
+        .get::<ServerOptions>()
+        .cloned()
+        .unwrap_or_default();
+    let allows_get = options.allows_get(rpc_path(&req), req.extensions().get::<RpcMethod>());
+    options.method_not_allowed(req.method(), allows_get)
+}
+
+/// The method label passed to a [`ServerObserver`] for requests not routed to an rpc.
+pub const UNKNOWN_METHOD: &str = "unknown";
+
+/// Observes the outcome of every rpc, see [`ServerOptions::with_observer`].
+///
+/// ```
+/// use std::sync::atomic::{AtomicU64, Ordering};
+///
+/// use twirp::server::{ServerObserver, Timings};
+/// use twirp::TwirpErrorCode;
+///
+/// #[derive(Default)]
+/// struct ErrorCounter(AtomicU64);
+///
+/// impl ServerObserver for ErrorCounter {
+///     fn on_response(&self, _method: &str, code: Option<TwirpErrorCode>, _timings: &Timings) {
+///         if code.is_some() {
+///             self.0.fetch_add(1, Ordering::Relaxed);
+///         }
+///     }
+/// }
+///
+/// let options = twirp::server::ServerOptions::default().with_observer(ErrorCounter::default());
+/// ```
+pub trait ServerObserver: Send + Sync + 'static {
+    /// Called once the response to a request is ready, also when it failed before reaching the
+    /// handler (e.g. failing to parse) or while serializing its response.
+    ///
+    /// `method` is the path of the rpc, like `/twirp/example.Haberdasher/MakeHat`, or
+    /// [`UNKNOWN_METHOD`]. `code` is the error the rpc failed with, `None` if it succeeded.
+    fn on_response(&self, method: &str, code: Option<TwirpErrorCode>, timings: &Timings);
+}
+
+/// An rpc that was handled, for [`ServerOptions::with_audit_hook`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct AuditEvent {
+    /// The name of the rpc method, e.g. `MakeHat`.
+    pub method: Option<String>,
+    /// The full path of the request, e.g. `/twirp/example.Haberdasher/MakeHat`.
+    pub path: String,
+    /// The id of the request, see [`ServerOptions::with_request_id_header`].
+    pub request_id: Option<String>,
+    /// The error code the rpc failed with, `None` if it succeeded.
+    pub code: Option<TwirpErrorCode>,
+    /// When the request started being handled.
+    pub timestamp: SystemTime,
+    extensions: Extensions,
+}
+
+impl AuditEvent {
+    fn start<B>(req: &Request<B>) -> Self {
+        let path = original_path(req).to_string();
+        Self {
+            method: req
+                .extensions()
+                .get::<RpcMethod>()
+                .map(|method| method.0.to_string()),
+            path,
+            request_id: req.extensions().get::<RequestId>().map(|id| id.0.clone()),
+            code: None,
+            timestamp: SystemTime::now(),
+            extensions: req.extensions().clone(),
+        }
+    }
+
+    /// The authenticated principal, if one was inserted into the request extensions, like
+    /// [`RequestContext::principal`](crate::context::RequestContext::principal).
+    pub fn principal<P>(&self) -> Option<&P>
+    where
+        P: Clone + Send + Sync + 'static,
+    {
+        self.extensions.get::<P>()
+    }
+}
+
+/// The size and number of fields of a decoded request message, to detect abusive requests, e.g.
+/// ones with huge repeated fields or deeply nested messages. Enabled with
+/// [`ServerOptions::with_request_stats`], and then found in the [`Context`] of the handler and in
+/// the response extensions, for middleware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RequestStats {
+    /// The size of the message encoded as protobuf, whatever format it was sent in.
+    pub size: usize,
+    /// The number of fields set in the message, counting every element of a repeated field and
+    /// the fields of embedded messages too. Packed repeated fields (of numbers) count once.
+    ///
+    /// This is an upper bound: the wire format doesn't tell strings and bytes apart from embedded
+    /// messages, so the content of a string or bytes field that happens to be a well-formed
+    /// message is counted as fields too, as for [`ServerOptions::with_max_field_length`].
+    pub fields: usize,
+}
+
+impl RequestStats {
+    fn of<T: prost::Message>(message: &T) -> Self {
+        let encoded = message.encode_to_vec();
+        Self {
+            size: encoded.len(),
+            fields: field_length::count_fields(&encoded),
+        }
+    }
+}
+
+/// Contains timing information associated with a request.
+/// To access the timings in a given request, use the [extensions](Request::extensions)
+/// method and specialize to `Timings` appropriately.
+#[derive(Debug, Clone, Copy)]
+pub struct Timings {
+    // When the request started.
+    start: Instant,
+    // When the request was received (headers and body).
+    request_received: Option<Instant>,
+    // When the request body was parsed.
+    request_parsed: Option<Instant>,
+    // When the response handler returned.
+    response_handled: Option<Instant>,
+    // When the response was written.
+    response_written: Option<Instant>,
+}
+
+impl Timings {
+    #[allow(clippy::new_without_default)]
+    pub fn new(start: Instant) -> Self {
+        Self {
+            start,
+            request_received: None,
+            request_parsed: None,
+            response_handled: None,
+            response_written: None,
+        }
+    }
+
+    fn set_received(&mut self) {
+        self.request_received = Some(Instant::now());
+    }
+
+    fn set_parsed(&mut self) {
+        self.request_parsed = Some(Instant::now());
+    }
+
+    fn set_response_handled(&mut self) {
+        self.response_handled = Some(Instant::now());
+    }
+
+    fn set_response_written(&mut self) {
+        self.response_written = Some(Instant::now());
+    }
+
+    pub fn received(&self) -> Option<Duration> {
+        self.request_received.map(|x| x - self.start)
+    }
+
+    pub fn parsed(&self) -> Option<Duration> {
+        match (self.request_parsed, self.request_received) {
+            (Some(parsed), Some(received)) => Some(parsed - received),
+            _ => None,
+        }
+    }
+
+    pub fn response_handled(&self) -> Option<Duration> {
+        match (self.response_handled, self.request_parsed) {
+            (Some(handled), Some(parsed)) => Some(handled - parsed),
+            _ => None,
+        }
+    }
+
+    pub fn response_written(&self) -> Option<Duration> {
+        match (self.response_written, self.response_handled) {
+            (Some(written), Some(handled)) => Some(written - handled),
+            (Some(written), None) => {
+                if let Some(parsed) = self.request_parsed {
+                    Some(written - parsed)
+                } else {
                     self.request_received.map(|received| written - received)
                 }
             }
@@ -241,80 +1932,1738 @@ impl Timings {
     pub fn total_duration(&self) -> Duration {
         self.start.elapsed()
     }
-}
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::details::TwirpRouterBuilder;
+    use crate::test::*;
+    use crate::TwirpErrorCode;
+
+    use axum::middleware::{self, Next};
+    use tower::Service;
+
+    fn timings() -> Timings {
+        Timings::new(Instant::now())
+    }
+
+    #[tokio::test]
+    async fn test_bad_route() {
+        let mut router = test_api_router();
+        let req = Request::get("/nothing")
+            .extension(timings())
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = router.call(req).await.unwrap();
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data, error::bad_route("not found"));
+    }
+
+    #[tokio::test]
+    async fn test_ping_success() {
+        let mut router = test_api_router();
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(&data.name, "hi");
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip() {
+        let router = test_api_router();
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        let resp = roundtrip(&router, "/twirp/test.TestAPI/Ping", req).await;
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert!(resp.extensions().get::<Timings>().is_some());
+        let data = resp.into_body().collect().await.unwrap().to_bytes();
+        let data = <PingResponse as prost::Message>::decode(data).unwrap();
+        assert_eq!(&data.name, "hi");
+    }
+
+    #[tokio::test]
+    async fn test_chunked_request() {
+        let mut router = test_api_router();
+        let data = serde_json::to_vec(&PingRequest {
+            name: "hi".to_string(),
+        })
+        .unwrap();
+        // A streamed body has no known length, just like a chunked request.
+        let chunks = data
+            .chunks(3)
+            .map(|chunk| Ok::<_, std::io::Error>(chunk.to_vec()))
+            .collect::<Vec<_>>();
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .header(header::TRANSFER_ENCODING, "chunked")
+            .extension(timings())
+            .body(Body::from_stream(futures::stream::iter(chunks)))
+            .unwrap();
+        assert!(req.headers().get(header::CONTENT_LENGTH).is_none());
+
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(&data.name, "hi");
+    }
+
+    #[tokio::test]
+    async fn test_length_delimited_request() {
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        let gen_req = || {
+            Request::post("/twirp/test.TestAPI/Ping")
+                .header(header::CONTENT_TYPE, "application/protobuf-delimited")
+                .extension(timings())
+                .body(Body::from(prost::Message::encode_length_delimited_to_vec(
+                    &req,
+                )))
+                .unwrap()
+        };
+
+        // not accepted by default
+        let mut router = test_api_router();
+        let resp = router.call(gen_req()).await.unwrap();
+        assert!(resp.status().is_client_error(), "{:?}", resp);
+
+        let mut router = test_api_router().layer(axum::Extension(
+            ServerOptions::default().with_length_delimited_protobuf(true),
+        ));
+        let resp = router.call(gen_req()).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/protobuf");
+        let data = resp.into_body().collect().await.unwrap().to_bytes();
+        let data = <PingResponse as prost::Message>::decode(data).unwrap();
+        assert_eq!(&data.name, "hi");
+    }
+
+    #[tokio::test]
+    async fn test_strict_protobuf_framing() {
+        let mut body = prost::Message::encode_length_delimited_to_vec(&PingRequest {
+            name: "hi".to_string(),
+        });
+        body.extend_from_slice(b"junk");
+        let gen_req = || {
+            Request::post("/twirp/test.TestAPI/Ping")
+                .header(header::CONTENT_TYPE, "application/protobuf-delimited")
+                .extension(timings())
+                .body(Body::from(body.clone()))
+                .unwrap()
+        };
+        let options = ServerOptions::default().with_length_delimited_protobuf(true);
+
+        // trailing bytes are ignored by default
+        let mut router = test_api_router().layer(axum::Extension(options.clone()));
+        let resp = router.call(gen_req()).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+
+        let mut router =
+            test_api_router().layer(axum::Extension(options.with_strict_protobuf_framing(true)));
+        let resp = router.call(gen_req()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Malformed);
+        assert_eq!(err.meta["error"], "4 trailing bytes after the message");
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_gzip_request() {
+        let mut router = test_api_router();
+        let data = serde_json::to_vec(&PingRequest {
+            name: "hi".to_string(),
+        })
+        .unwrap();
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .header(header::CONTENT_ENCODING, "gzip")
+            .extension(timings())
+            .body(Body::from(compression::gzip(&data)))
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(&data.name, "hi");
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_max_decompressed_size() {
+        // 1 MiB of the same letter compresses to about a kilobyte
+        let data = serde_json::to_vec(&PingRequest {
+            name: "a".repeat(1024 * 1024),
+        })
+        .unwrap();
+        let compressed = compression::gzip(&data);
+        assert!(compressed.len() < 16 * 1024);
+        let gzip_request = || {
+            Request::post("/twirp/test.TestAPI/Ping")
+                .header(header::CONTENT_ENCODING, "gzip")
+                .extension(timings())
+                .body(Body::from(compressed.clone()))
+                .unwrap()
+        };
+
+        let mut router = test_api_router().layer(axum::Extension(
+            ServerOptions::default()
+                .with_max_request_size(16 * 1024)
+                .with_max_decompressed_size(64 * 1024),
+        ));
+        let resp = router.call(gzip_request()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let RequestError(cause) = resp.extensions().get().cloned().unwrap();
+        assert!(cause.is::<DecompressedBodyTooLarge>(), "{cause}");
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::ResourceExhausted);
+        assert_eq!(err.meta["reason"], "decompressed_body_too_large");
+        assert_eq!(err.meta["max_decompressed_size"], "65536");
+
+        // the decompressed limit is independent of the request size limit
+        let mut router = test_api_router().layer(axum::Extension(
+            ServerOptions::default()
+                .with_max_request_size(16 * 1024)
+                .with_max_decompressed_size(2 * 1024 * 1024),
+        ));
+        let resp = router.call(gzip_request()).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+    }
+
+    #[tokio::test]
+    async fn test_body_too_large() {
+        let mut router = test_api_router();
+        let (parts, body) = gen_ping_request(&"hi".repeat(100)).into_parts();
+        let req = Request::from_parts(parts, Body::new(http_body_util::Limited::new(body, 64)));
+
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data.code, TwirpErrorCode::ResourceExhausted);
+        assert_eq!(data.meta["reason"], "body_too_large");
+
+        // bodies under the limit are unaffected
+        let (parts, body) = gen_ping_request("hi").into_parts();
+        let req = Request::from_parts(parts, Body::new(http_body_util::Limited::new(body, 64)));
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_compress_response() {
+        let router = test_api_router().layer(axum::Extension(
+            ServerOptions::default().with_compress_response_threshold(8),
+        ));
+        let gen_req = |name: &str, accept_encoding: Option<&'static str>| {
+            let mut req = gen_ping_request(name);
+            if let Some(accept_encoding) = accept_encoding {
+                req.headers_mut().insert(
+                    header::ACCEPT_ENCODING,
+                    HeaderValue::from_static(accept_encoding),
+                );
+            }
+            req
+        };
+
+        let resp = router
+            .clone()
+            .call(gen_req("fedora", Some("br, gzip")))
+            .await
+            .unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert_eq!(resp.headers()[header::CONTENT_ENCODING], "gzip");
+        let vary: Vec<_> = resp.headers().get_all(header::VARY).iter().collect();
+        assert_eq!(vary, ["accept", "accept-encoding"]);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let body = compression::gunzip(&body, usize::MAX).unwrap().unwrap();
+        let data: PingResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(&data.name, "fedora");
+
+        // not when the client doesn't accept it, though the response still varies on it
+        let resp = router.clone().call(gen_req("fedora", None)).await.unwrap();
+        assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+        let vary: Vec<_> = resp.headers().get_all(header::VARY).iter().collect();
+        assert_eq!(vary, ["accept", "accept-encoding"]);
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(&data.name, "fedora");
+
+        // nor for errors
+        let req = Request::post("/twirp/test.TestAPI/Boom")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .extension(timings())
+            .body(Body::from(r#"{"name":"fedora"}"#))
+            .unwrap();
+        let resp = router.clone().call(req).await.unwrap();
+        assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Internal);
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_invalid_gzip_request() {
+        let mut router = test_api_router();
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .header(header::CONTENT_ENCODING, "gzip")
+            .extension(timings())
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Malformed);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_json_keys() {
+        let gen_req = || {
+            Request::post("/twirp/test.TestAPI/Ping")
+                .extension(timings())
+                .body(Body::from(r#"{"name":"first","name":"second"}"#))
+                .unwrap()
+        };
+
+        // serde rejects a duplicated message field by default, but without saying why
+        let resp = test_api_router().call(gen_req()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Malformed);
+        assert!(!err.meta.contains_key("reason"));
+
+        let mut router = test_api_router().layer(axum::Extension(
+            ServerOptions::default().with_reject_duplicate_json_keys(true),
+        ));
+        let resp = router.call(gen_req()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Malformed);
+        assert_eq!(err.meta["reason"], "duplicate_key");
+        assert_eq!(err.meta["key"], "name");
+
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+    }
+
+    #[tokio::test]
+    async fn test_max_request_size() {
+        let router = test_api_router().layer(axum::Extension(
+            ServerOptions::default().with_max_request_size(64),
+        ));
+        let assert_too_large = |resp: Response<Body>| async move {
+            assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+            let data = read_err_body(resp.into_body()).await;
+            assert_eq!(data.code, TwirpErrorCode::ResourceExhausted);
+            assert_eq!(data.meta["reason"], "body_too_large");
+        };
+
+        // without a `Content-Length`, the body is read up to the limit
+        let resp = router
+            .clone()
+            .call(gen_ping_request(&"hi".repeat(100)))
+            .await
+            .unwrap();
+        assert_too_large(resp).await;
+
+        // a `Content-Length` over the limit is rejected up front, whatever the body
+        let mut req = gen_ping_request("hi");
+        req.headers_mut()
+            .insert(header::CONTENT_LENGTH, HeaderValue::from(1 << 30));
+        assert_too_large(router.clone().call(req).await.unwrap()).await;
+
+        // so is a small body decompressing to one over the limit, with its own reason
+        #[cfg(feature = "compression")]
+        {
+            let data = serde_json::to_vec(&PingRequest {
+                name: "hi".repeat(100),
+            })
+            .unwrap();
+            let req = Request::post("/twirp/test.TestAPI/Ping")
+                .header(header::CONTENT_ENCODING, "gzip")
+                .extension(timings())
+                .body(Body::from(compression::gzip(&data)))
+                .unwrap();
+            let resp = router.clone().call(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+            let data = read_err_body(resp.into_body()).await;
+            assert_eq!(data.meta["reason"], "decompressed_body_too_large");
+            assert_eq!(data.meta["max_decompressed_size"], "64");
+        }
+
+        let resp = router.clone().call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+    }
+
+    #[tokio::test]
+    async fn test_ping_invalid_request() {
+        let mut router = test_api_router();
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .extension(timings())
+            .body(Body::empty()) // not a valid request
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.status().is_client_error(), "{:?}", resp);
+        let data = read_err_body(resp.into_body()).await;
+
+        // TODO: I think malformed should return some info about what was wrong
+        // with the request, but we don't want to leak server errors that have
+        // other details.
+        let mut expected = error::malformed("bad request");
+        expected.insert_meta(
+            "error".to_string(),
+            "EOF while parsing a value at line 1 column 0".to_string(),
+        );
+        assert_eq!(data, expected);
+    }
+
+    #[tokio::test]
+    async fn test_compact_errors() {
+        let mut router = test_api_router().layer(axum::Extension(
+            ServerOptions::default().with_compact_errors(true),
+        ));
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .extension(timings())
+            .body(Body::empty()) // not a valid request
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.status().is_client_error(), "{:?}", resp);
+        // the cause is still available to middleware
+        let cause = resp.extensions().get::<RequestError>().unwrap();
+        assert_eq!(
+            cause.to_string(),
+            "EOF while parsing a value at line 1 column 0"
+        );
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data, error::malformed("bad request"));
+    }
+
+    #[tokio::test]
+    async fn test_boom() {
+        let mut router = test_api_router();
+        let req = serde_json::to_string(&PingRequest {
+            name: "hi".to_string(),
+        })
+        .unwrap();
+        let req = Request::post("/twirp/test.TestAPI/Boom")
+            .extension(timings())
+            .body(Body::from(req))
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.status().is_server_error(), "{:?}", resp);
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data, error::internal("boom!"));
+    }
+
+    #[tokio::test]
+    async fn test_errors_in_body() {
+        let gen_boom_request = || {
+            let req = serde_json::to_string(&PingRequest {
+                name: "hi".to_string(),
+            })
+            .unwrap();
+            Request::post("/twirp/test.TestAPI/Boom")
+                .extension(timings())
+                .body(Body::from(req))
+                .unwrap()
+        };
+
+        // by default errors use their HTTP status
+        let mut router = test_api_router();
+        let resp = router.call(gen_boom_request()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(resp.headers().get(ERROR_CODE).is_none());
+
+        let mut router = test_api_router().layer(axum::Extension(
+            ServerOptions::default().with_errors_in_body(true),
+        ));
+        let resp = router.call(gen_boom_request()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[ERROR_CODE], "internal");
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data, error::internal("boom!"));
+    }
+
+    #[tokio::test]
+    async fn test_protobuf_content_type() {
+        let resp = roundtrip(
+            &test_api_router(),
+            "/twirp/test.TestAPI/Ping",
+            PingRequest {
+                name: "hi".to_string(),
+            },
+        )
+        .await;
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/protobuf");
+
+        let router = test_api_router().layer(axum::Extension(
+            ServerOptions::default()
+                .with_protobuf_content_type(HeaderValue::from_static("application/x-protobuf")),
+        ));
+        let resp = roundtrip(
+            &router,
+            "/twirp/test.TestAPI/Ping",
+            PingRequest {
+                name: "hi".to_string(),
+            },
+        )
+        .await;
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "application/x-protobuf"
+        );
+    }
+
+    #[test]
+    fn test_poisoned_response_extensions() {
+        #[derive(Clone, Debug, PartialEq)]
+        struct Marker(u8);
+
+        let resp_exts = Arc::new(Mutex::new(Extensions::new()));
+        let ctx = Context::new(Extensions::new(), resp_exts.clone());
+        ctx.insert(Marker(1));
+
+        let poisoner = resp_exts.clone();
+        let res = std::thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("poison the response extensions");
+        })
+        .join();
+        assert!(res.is_err());
+        assert!(resp_exts.is_poisoned());
+
+        // the handler can still use the context, and the server can still read the extensions
+        assert_eq!(ctx.insert(Marker(2)), Some(Marker(1)));
+        assert_eq!(
+            lock_extensions(&resp_exts).get::<Marker>(),
+            Some(&Marker(2))
+        );
+        assert!(!resp_exts.is_poisoned());
+    }
+
+    #[tokio::test]
+    async fn test_error_details_encoding() {
+        let mut router = test_api_router();
+        let resp = router
+            .call(
+                Request::post("/twirp/test.TestAPI/Ping")
+                    .extension(timings())
+                    .body(Body::from("not json"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(json["meta"]["error"].is_string(), "{json}");
+        assert!(json.get("details").is_none());
+
+        let mut router = test_api_router().layer(axum::Extension(
+            ServerOptions::default().with_error_details_encoding(ErrorDetailsEncoding::Details),
+        ));
+        let resp = router
+            .call(
+                Request::post("/twirp/test.TestAPI/Ping")
+                    .extension(timings())
+                    .body(Body::from("not json"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(json.get("meta").is_none(), "{json}");
+        assert_eq!(json["details"][0]["key"], "error");
+
+        // and it reads back the same as the standard encoding
+        let err: TwirpErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(err.code, TwirpErrorCode::Malformed);
+        assert!(err.meta.contains_key("error"));
+    }
+
+    #[tokio::test]
+    async fn test_payload_inspectors() {
+        let responses = Arc::new(Mutex::new(vec![]));
+        let seen = responses.clone();
+        let mut router = test_api_router().layer(axum::Extension(
+            ServerOptions::default()
+                .with_request_inspector(|body| {
+                    if body.windows(6).any(|window| window == b"DROP T") {
+                        return Err(error::permission_denied("banned pattern"));
+                    }
+                    Ok(())
+                })
+                .with_response_inspector(move |body| {
+                    seen.lock()
+                        .unwrap()
+                        .push(String::from_utf8_lossy(body).into_owned())
+                }),
+        ));
+
+        let resp = router
+            .call(gen_ping_request("DROP TABLE hats"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data, error::permission_denied("banned pattern"));
+        assert!(responses.lock().unwrap().is_empty());
+
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert_eq!(*responses.lock().unwrap(), vec![r#"{"name":"hi"}"#]);
+    }
+
+    #[tokio::test]
+    async fn test_response_headers() {
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                    ctx.set_response_header(
+                        header::SET_COOKIE,
+                        HeaderValue::from_static("session=abc; HttpOnly"),
+                    );
+                    ctx.set_response_header(
+                        header::SET_COOKIE,
+                        HeaderValue::from_static("theme=dark"),
+                    );
+                    ctx.set_response_header(
+                        header::CACHE_CONTROL,
+                        HeaderValue::from_static("no-cache"),
+                    );
+                    ctx.set_response_header(
+                        header::CACHE_CONTROL,
+                        HeaderValue::from_static("no-store"),
+                    );
+                    api.ping(ctx, req).await
+                },
+            )
+            .build();
+        let mut router = axum::Router::new().nest("/twirp/test.TestAPI", twirp_routes);
+
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let cookies: Vec<_> = resp.headers().get_all(header::SET_COOKIE).iter().collect();
+        assert_eq!(cookies, vec!["session=abc; HttpOnly", "theme=dark"]);
+        let cache_control: Vec<_> = resp
+            .headers()
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .collect();
+        assert_eq!(cache_control, vec!["no-store"]);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+    }
+
+    #[tokio::test]
+    async fn test_yield_budget() {
+        // A handler that keeps a (current thread) worker busy until told to stop.
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let started_tx = Arc::new(Mutex::new(Some(started_tx)));
+        let spin_stop = stop.clone();
+        let spin_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Spin",
+                move |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| {
+                    let stop = spin_stop.clone();
+                    let started_tx = started_tx.lock().unwrap().take();
+                    async move {
+                        if let Some(started_tx) = started_tx {
+                            started_tx.send(()).unwrap();
+                        }
+                        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+                        let mut sum = 0u64;
+                        while !stop.load(Ordering::Relaxed) && std::time::Instant::now() < deadline
+                        {
+                            for i in 0..1000 {
+                                sum = std::hint::black_box(sum.wrapping_add(i));
+                            }
+                            ctx.yield_point().await;
+                        }
+                        api.ping(ctx, req).await
+                    }
+                },
+            )
+            .build();
+        let router = axum::Router::new()
+            .nest("/twirp/test.Spin", spin_routes)
+            .merge(test_api_router())
+            .layer(axum::Extension(
+                ServerOptions::default().with_yield_budget(16),
+            ));
+
+        let req = Request::post("/twirp/test.Spin/Spin")
+            .extension(timings())
+            .body(Body::from(r#"{"name":"spin"}"#))
+            .unwrap();
+        let spinning = tokio::spawn(router.clone().call(req));
+        // only gets to run when the spinning handler yields
+        started_rx.await.unwrap();
+
+        // other requests are served while the spinning handler is still running
+        let resp = router.clone().call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert!(!spinning.is_finished());
+        stop.store(true, Ordering::Relaxed);
+
+        let resp = spinning.await.unwrap().unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(&data.name, "spin");
+    }
+
+    #[tokio::test]
+    async fn test_json_large_integers_as_strings() {
+        #[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, prost::Message)]
+        struct Counter {
+            #[prost(int64, tag = "1")]
+            small: i64,
+            #[prost(int64, tag = "2")]
+            large: i64,
+        }
+
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                |_: Arc<TestApiServer>, _: Context, _: PingRequest| async move {
+                    Ok::<_, TwirpErrorResponse>(Counter {
+                        small: 42,
+                        large: i64::MAX,
+                    })
+                },
+            )
+            .build();
+        let router = axum::Router::new().nest("/twirp/test.TestAPI", twirp_routes);
+
+        let resp = router.clone().call(gen_ping_request("hi")).await.unwrap();
+        let body = read_string_body(resp.into_body()).await;
+        assert_eq!(body, r#"{"small":42,"large":9223372036854775807}"#);
+
+        let mut router = router.layer(axum::Extension(
+            ServerOptions::default().with_json_large_integers_as_strings(true),
+        ));
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let json: serde_json::Value = read_json_body(resp.into_body()).await;
+        assert_eq!(
+            json,
+            serde_json::json!({"small": 42, "large": "9223372036854775807"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sorted_json_keys() {
+        #[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, prost::Message)]
+        struct Inventory {
+            #[prost(string, tag = "1")]
+            store: String,
+            #[prost(map = "string, int32", tag = "2")]
+            hats: HashMap<String, i32>,
+        }
+
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                |_: Arc<TestApiServer>, _: Context, _: PingRequest| async move {
+                    let hats = ["trilby", "fedora", "stetson", "beret", "bowler", "cloche"]
+                        .into_iter()
+                        .map(|name| (name.to_string(), name.len() as i32))
+                        .collect();
+                    Ok::<_, TwirpErrorResponse>(Inventory {
+                        store: "main".to_string(),
+                        hats,
+                    })
+                },
+            )
+            .build();
+        let mut router = axum::Router::new()
+            .nest("/twirp/test.TestAPI", twirp_routes)
+            .layer(axum::Extension(
+                ServerOptions::default().with_sorted_json_keys(true),
+            ));
+
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let body = read_string_body(resp.into_body()).await;
+        assert_eq!(
+            body,
+            r#"{"hats":{"beret":5,"bowler":6,"cloche":6,"fedora":6,"stetson":7,"trilby":6},"store":"main"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serialization_error_hook() {
+        fn fail<S: serde::Serializer>(_: &str, _: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("unencodable name"))
+        }
+
+        #[derive(serde::Serialize, Clone, PartialEq, prost::Message)]
+        struct Unencodable {
+            #[prost(string, tag = "1")]
+            #[serde(serialize_with = "fail")]
+            name: String,
+        }
+
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                |_: Arc<TestApiServer>, _: Context, req: PingRequest| async move {
+                    Ok::<_, TwirpErrorResponse>(Unencodable { name: req.name })
+                },
+            )
+            .build();
+        let failures = Arc::new(Mutex::new(vec![]));
+        let seen = failures.clone();
+        let router = axum::Router::new()
+            .nest("/twirp/test.TestAPI", twirp_routes)
+            .layer(axum::Extension(
+                ServerOptions::default().with_serialization_error_hook(move |err| {
+                    seen.lock().unwrap().push((err.format(), err.to_string()))
+                }),
+            ));
+
+        let resp = router.clone().call(gen_ping_request("hi")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let RequestError(cause) = resp.extensions().get().cloned().unwrap();
+        assert!(cause.is::<SerializationError>());
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Internal);
+        assert_eq!(err.meta["reason"], "serialization_failed");
+        assert_eq!(err.meta["format"], "json");
+        assert_eq!(err.meta["error"], "unencodable name");
+        assert_eq!(
+            *failures.lock().unwrap(),
+            vec![(
+                BodyFormat::JsonPb,
+                "failed to serialize json response: unencodable name".to_string()
+            )]
+        );
+
+        // the same response encodes fine as protobuf
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        let resp = roundtrip(&router, "/twirp/test.TestAPI/Ping", req).await;
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert_eq!(failures.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_audit_hook() {
+        #[derive(Clone)]
+        struct User(&'static str);
+
+        async fn authenticate(mut req: Request<Body>, next: Next) -> Response<Body> {
+            req.extensions_mut().insert(User("alice"));
+            next.run(req).await
+        }
+
+        let events = Arc::new(Mutex::new(vec![]));
+        let seen = events.clone();
+        let options = ServerOptions::default()
+            .with_request_id_header(HeaderName::from_static("x-request-id"))
+            .with_audit_hook(move |event| seen.lock().unwrap().push(event.clone()));
+        let mut router = test_api_router()
+            .layer(middleware::from_fn(authenticate))
+            .layer(axum::Extension(options));
+
+        let before = SystemTime::now();
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .header("x-request-id", "abcd")
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let req = Request::post("/twirp/test.TestAPI/Boom")
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        let ping = &events[0];
+        assert_eq!(ping.method.as_deref(), Some("Ping"));
+        assert_eq!(ping.path, "/twirp/test.TestAPI/Ping");
+        assert_eq!(ping.request_id.as_deref(), Some("abcd"));
+        assert_eq!(ping.code, None);
+        assert_eq!(ping.principal::<User>().map(|user| user.0), Some("alice"));
+        assert!(ping.timestamp >= before);
+        let boom = &events[1];
+        assert_eq!(boom.method.as_deref(), Some("Boom"));
+        assert!(boom.request_id.is_some());
+        assert_eq!(boom.code, Some(TwirpErrorCode::Internal));
+    }
+
+    #[tokio::test]
+    async fn test_observer() {
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<(String, Option<TwirpErrorCode>, bool)>>);
+
+        impl ServerObserver for Arc<Recorder> {
+            fn on_response(&self, method: &str, code: Option<TwirpErrorCode>, timings: &Timings) {
+                let handled = timings.response_handled().is_some();
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push((method.to_string(), code, handled));
+            }
+        }
+
+        let recorder = Arc::new(Recorder::default());
+        let mut router = test_api_router().layer(axum::Extension(
+            ServerOptions::default().with_observer(recorder.clone()),
+        ));
+
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let req = Request::post("/twirp/test.TestAPI/Boom")
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from("not json"))
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![
+                ("/twirp/test.TestAPI/Ping".to_string(), None, true),
+                (
+                    "/twirp/test.TestAPI/Boom".to_string(),
+                    Some(TwirpErrorCode::Internal),
+                    true
+                ),
+                (
+                    "/twirp/test.TestAPI/Ping".to_string(),
+                    Some(TwirpErrorCode::Malformed),
+                    false
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_probe_header() {
+        let mut router = test_api_router().layer(axum::Extension(
+            ServerOptions::default().with_probe_header(HeaderName::from_static("x-probe")),
+        ));
+
+        let mut req = gen_ping_request("hi");
+        req.headers_mut()
+            .insert("x-probe", HeaderValue::from_static("1"));
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.is_empty());
+
+        // errors still have a body
+        let req = Request::post("/twirp/test.TestAPI/Boom")
+            .header("x-probe", "1")
+            .extension(timings())
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.status().is_server_error(), "{:?}", resp);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Internal);
+
+        // and requests without the header get the full response
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(&data.name, "hi");
+    }
+
+    // With paused time, the handler's sleep and the timeouts fire in order.
+    #[tokio::test(start_paused = true)]
+    async fn test_timeouts() {
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    api.ping(ctx, req).await
+                },
+            )
+            .build();
+        let router = axum::Router::new().nest("/twirp/test.TestAPI", twirp_routes);
+
+        // the default applies to every method
+        let mut slow = router.clone().layer(axum::Extension(
+            ServerOptions::default().with_timeouts(Duration::from_millis(50), []),
+        ));
+        let resp = slow.call(gen_ping_request("hi")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::REQUEST_TIMEOUT);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::DeadlineExceeded);
+        assert_eq!(err.meta["reason"], "handler_timeout");
+        assert_eq!(err.meta["timeout_ms"], "50");
+
+        // unless the method has its own
+        let mut fast =
+            router
+                .clone()
+                .layer(axum::Extension(ServerOptions::default().with_timeouts(
+                    Duration::from_millis(50),
+                    [("Ping", Duration::from_secs(1))],
+                )));
+        let resp = fast.call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(&data.name, "hi");
+
+        // keyed by its path, which wins over its name
+        let mut by_path = router.layer(axum::Extension(ServerOptions::default().with_timeouts(
+            Duration::from_secs(1),
+            [
+                ("Ping", Duration::from_secs(1)),
+                ("test.TestAPI/Ping", Duration::from_millis(50)),
+            ],
+        )));
+        let resp = by_path.call(gen_ping_request("hi")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::REQUEST_TIMEOUT);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.meta["timeout_ms"], "50");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_timeout() {
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    api.ping(ctx, req).await
+                },
+            )
+            .build();
+        let router = axum::Router::new().nest("/twirp/test.TestAPI", twirp_routes);
+        let gen_req = |timeout_ms: &str| {
+            let mut req = gen_ping_request("hi");
+            req.headers_mut()
+                .insert(TIMEOUT_MS, HeaderValue::from_str(timeout_ms).unwrap());
+            req
+        };
+
+        let resp = router.clone().call(gen_req("50")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::REQUEST_TIMEOUT);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::DeadlineExceeded);
+        assert_eq!(err.meta["timeout_ms"], "50");
+
+        // invalid values are ignored
+        let resp = router.clone().call(gen_req("soon")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+
+        // and the server's own timeout can't be extended
+        let mut capped = router.layer(axum::Extension(
+            ServerOptions::default().with_timeouts(Duration::from_millis(50), []),
+        ));
+        let resp = capped.call(gen_req("1000")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::REQUEST_TIMEOUT);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.meta["timeout_ms"], "50");
+    }
+
+    #[test]
+    fn test_rpc_path() {
+        let req = |uri: &str| Request::post(uri).body(()).unwrap();
+        assert_eq!(
+            rpc_path(&req("/twirp/example.Haberdasher/MakeHat")),
+            Some("example.Haberdasher/MakeHat")
+        );
+        assert_eq!(
+            rpc_path(&req("/example.Haberdasher/MakeHat")),
+            Some("example.Haberdasher/MakeHat")
+        );
+    }
 
-#[cfg(test)]
-mod tests {
+    // With paused time, the timers fire in order however slow the machine running the test.
+    #[tokio::test(start_paused = true)]
+    async fn test_parse_timeout() {
+        let slow_body = |delay: u64| {
+            let body = futures::stream::once(async move {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                Ok::<_, std::convert::Infallible>(r#"{"name":"hi"}"#)
+            });
+            Request::post("/twirp/test.TestAPI/Ping")
+                .extension(timings())
+                .body(Body::from_stream(body))
+                .unwrap()
+        };
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    api.ping(ctx, req).await
+                },
+            )
+            .build();
+        let mut router = axum::Router::new()
+            .nest("/twirp/test.TestAPI", twirp_routes)
+            .layer(axum::Extension(
+                ServerOptions::default()
+                    .with_parse_timeout(Duration::from_millis(100))
+                    .with_timeouts(Duration::from_millis(150), []),
+            ));
 
-    use super::*;
-    use crate::test::*;
+        // a trickling body times out while parsing
+        let resp = router.call(slow_body(300)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::REQUEST_TIMEOUT);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::DeadlineExceeded);
+        assert_eq!(err.meta["reason"], "parse_timeout");
+        assert_eq!(err.meta["timeout_ms"], "100");
 
-    use axum::middleware::{self, Next};
-    use tower::Service;
+        // while the handler has its own budget, so the two add up to more than either timeout
+        let resp = router.call(slow_body(80)).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(&data.name, "hi");
+    }
 
-    fn timings() -> Timings {
-        Timings::new(Instant::now())
+    #[test]
+    fn test_body_format_from_accept() {
+        let accept = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, HeaderValue::from_str(value).unwrap());
+            BodyFormat::from_accept(&headers)
+        };
+        assert_eq!(BodyFormat::from_accept(&HeaderMap::new()), None);
+        assert_eq!(accept("*/*"), None);
+        assert_eq!(accept("text/html, application/*"), None);
+        assert_eq!(accept("application/json"), Some(BodyFormat::JsonPb));
+        assert_eq!(accept("application/x-protobuf"), Some(BodyFormat::Pb));
+        assert_eq!(
+            accept("application/protobuf, application/json"),
+            Some(BodyFormat::Pb)
+        );
+        assert_eq!(
+            accept("application/json;q=0.5, application/protobuf;q=0.9, */*"),
+            Some(BodyFormat::Pb)
+        );
+        assert_eq!(accept("application/json; q=0, */*"), None);
     }
 
     #[tokio::test]
-    async fn test_bad_route() {
+    async fn test_accept_header() {
         let mut router = test_api_router();
-        let req = Request::get("/nothing")
-            .extension(timings())
-            .body(Body::empty())
+        let pb_request = |accept: Option<&str>| {
+            let body = serialize_proto_message(PingRequest {
+                name: "hi".to_string(),
+            });
+            let mut req = Request::post("/twirp/test.TestAPI/Ping")
+                .extension(timings())
+                .header(header::CONTENT_TYPE, "application/protobuf");
+            if let Some(accept) = accept {
+                req = req.header(header::ACCEPT, accept);
+            }
+            req.body(Body::from(body)).unwrap()
+        };
+
+        // a protobuf request gets a JSON response if it accepts one
+        let resp = router
+            .call(pb_request(Some("application/json")))
+            .await
             .unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(resp.headers()[header::VARY], "accept");
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(&data.name, "hi");
 
+        // and otherwise one in the format of the request, which still varies on it
+        for accept in [None, Some("*/*")] {
+            let resp = router.call(pb_request(accept)).await.unwrap();
+            assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/protobuf");
+            assert_eq!(resp.headers()[header::VARY], "accept");
+        }
+
+        // also the other way around
+        let mut req = gen_ping_request("hi");
+        req.headers_mut().insert(
+            header::ACCEPT,
+            HeaderValue::from_static("application/protobuf"),
+        );
         let resp = router.call(req).await.unwrap();
-        let data = read_err_body(resp.into_body()).await;
-        assert_eq!(data, error::bad_route("not found"));
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/protobuf");
     }
 
     #[tokio::test]
-    async fn test_ping_success() {
-        let mut router = test_api_router();
-        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+    async fn test_get_methods() {
+        let router = test_api_router().layer(axum::Extension(
+            ServerOptions::default().with_get_methods(["Ping"]),
+        ));
+        let get = |uri: &str| {
+            Request::get(uri)
+                .extension(timings())
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // JSON
+        let resp = router
+            .clone()
+            .call(get(
+                "/twirp/test.TestAPI/Ping?message=%7B%22name%22%3A%22hi%22%7D",
+            ))
+            .await
+            .unwrap();
         assert!(resp.status().is_success(), "{:?}", resp);
         let data: PingResponse = read_json_body(resp.into_body()).await;
         assert_eq!(&data.name, "hi");
+
+        // base64url protobuf
+        let message = prost::Message::encode_to_vec(&PingRequest {
+            name: "hi?/".to_string(),
+        });
+        let message = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(message);
+        let resp = router
+            .clone()
+            .call(get(&format!(
+                "/twirp/test.TestAPI/Ping?message={message}&base64=1"
+            )))
+            .await
+            .unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], CONTENT_TYPE_PROTOBUF);
+        let data = resp.into_body().collect().await.unwrap().to_bytes();
+        let data = <PingResponse as prost::Message>::decode(data).unwrap();
+        assert_eq!(&data.name, "hi?/");
+
+        let resp = router
+            .clone()
+            .call(get("/twirp/test.TestAPI/Ping?message=!!!&base64=1"))
+            .await
+            .unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Malformed);
+
+        // only for the methods allowed
+        let resp = router
+            .clone()
+            .call(get("/twirp/test.TestAPI/Boom?message=%7B%7D"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::BadRoute);
+
+        let resp = test_api_router()
+            .call(get("/twirp/test.TestAPI/Ping?message=%7B%7D"))
+            .await
+            .unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::BadRoute);
+
+        // methods can be given by path, to tell apart the methods of the same name of services
+        for (method, allowed) in [("test.TestAPI/Ping", true), ("other.TestAPI/Ping", false)] {
+            let resp = test_api_router()
+                .layer(axum::Extension(
+                    ServerOptions::default().with_get_methods([method]),
+                ))
+                .call(get("/twirp/test.TestAPI/Ping?message=%7B%7D"))
+                .await
+                .unwrap();
+            assert_eq!(resp.status().is_success(), allowed, "{method}");
+        }
     }
 
     #[tokio::test]
-    async fn test_ping_invalid_request() {
-        let mut router = test_api_router();
-        let req = Request::post("/twirp/test.TestAPI/Ping")
-            .extension(timings())
-            .body(Body::empty()) // not a valid request
+    async fn test_allow_header() {
+        let request = |method: Method, uri: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .extension(timings())
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // not by default
+        let resp = test_api_router()
+            .call(request(Method::GET, "/twirp/test.TestAPI/Ping"))
+            .await
             .unwrap();
-        let resp = router.call(req).await.unwrap();
-        assert!(resp.status().is_client_error(), "{:?}", resp);
-        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(!resp.headers().contains_key(header::ALLOW));
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::BadRoute);
+        assert_eq!(err.msg, "unsupported method GET (only POST is allowed)");
 
-        // TODO: I think malformed should return some info about what was wrong
-        // with the request, but we don't want to leak server errors that have
-        // other details.
-        let mut expected = error::malformed("bad request");
-        expected.insert_meta(
-            "error".to_string(),
-            "EOF while parsing a value at line 1 column 0".to_string(),
+        let router = test_api_router().layer(axum::Extension(
+            ServerOptions::default()
+                .with_allow_header(true)
+                .with_get_methods(["Ping"]),
+        ));
+        let resp = router
+            .clone()
+            .call(request(Method::GET, "/twirp/test.TestAPI/Boom"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers()[header::ALLOW], "POST");
+
+        // other methods are `bad_route` too
+        let resp = router
+            .clone()
+            .call(request(Method::DELETE, "/twirp/test.TestAPI/Ping"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers()[header::ALLOW], "GET, POST");
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::BadRoute);
+        assert_eq!(
+            err.msg,
+            "unsupported method DELETE (only GET and POST are allowed)"
         );
-        assert_eq!(data, expected);
     }
 
     #[tokio::test]
-    async fn test_boom() {
-        let mut router = test_api_router();
-        let req = serde_json::to_string(&PingRequest {
-            name: "hi".to_string(),
-        })
-        .unwrap();
+    async fn test_concurrency_limits() {
+        let release = Arc::new(tokio::sync::Notify::new());
+        let wait = release.clone();
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                move |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| {
+                    let wait = wait.clone();
+                    async move {
+                        wait.notified().await;
+                        api.ping(ctx, req).await
+                    }
+                },
+            )
+            .route(
+                "/Boom",
+                |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                    api.boom(ctx, req).await
+                },
+            )
+            .build();
+        let options = ServerOptions::default().with_concurrency_limits([("Ping", 1)]);
+        let router = axum::Router::new()
+            .nest("/twirp/test.TestAPI", twirp_routes)
+            .layer(axum::Extension(options.clone()));
+        assert_eq!(options.in_flight("Ping"), Some(0));
+        assert_eq!(options.in_flight("Boom"), None);
+
+        let mut first = router.clone();
+        let first = tokio::spawn(async move { first.call(gen_ping_request("first")).await });
+        while options.in_flight("Ping") != Some(1) {
+            tokio::task::yield_now().await;
+        }
+
+        // the method is saturated
+        let resp = router
+            .clone()
+            .call(gen_ping_request("second"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::ResourceExhausted);
+        assert_eq!(err.meta["max_concurrency"], "1");
+
+        // but other methods aren't limited
         let req = Request::post("/twirp/test.TestAPI/Boom")
             .extension(timings())
-            .body(Body::from(req))
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+        let resp = router.clone().call(req).await.unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Internal);
+
+        release.notify_one();
+        let resp = first.await.unwrap().unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert_eq!(options.in_flight("Ping"), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limits_by_path() {
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                    api.ping(ctx, req).await
+                },
+            )
+            .build();
+        // the same method name in another service has its own limit
+        let options = ServerOptions::default()
+            .with_concurrency_limits([("Ping", 1), ("test.TestAPI/Ping", 0)]);
+        let mut router = axum::Router::new()
+            .nest("/twirp/test.TestAPI", twirp_routes.clone())
+            .nest("/twirp/other.TestAPI", twirp_routes)
+            .layer(axum::Extension(options));
+
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.meta["max_concurrency"], "0");
+
+        let req = Request::post("/twirp/other.TestAPI/Ping")
+            .extension(timings())
+            .body(Body::from(r#"{"name":"hi"}"#))
             .unwrap();
         let resp = router.call(req).await.unwrap();
-        assert!(resp.status().is_server_error(), "{:?}", resp);
-        let data = read_err_body(resp.into_body()).await;
-        assert_eq!(data, error::internal("boom!"));
+        assert!(resp.status().is_success(), "{:?}", resp);
+    }
+
+    #[test]
+    fn test_decode_request() {
+        // e.g. messages received from a queue, with their content type as an attribute
+        let (tx, rx) = std::sync::mpsc::channel::<(&str, Vec<u8>)>();
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        tx.send(("application/protobuf", prost::Message::encode_to_vec(&req)))
+            .unwrap();
+        tx.send(("application/json", serde_json::to_vec(&req).unwrap()))
+            .unwrap();
+        drop(tx);
+        let options = ServerOptions::default();
+        for (content_type, body) in rx {
+            let decoded: PingRequest = decode_request(Some(content_type), &body, &options).unwrap();
+            assert_eq!(decoded, req, "{content_type}");
+        }
+
+        let err = decode_request::<PingRequest>(None, b"{", &options).unwrap_err();
+        assert_eq!(err.code, TwirpErrorCode::Malformed);
+        assert!(err.meta.contains_key("error"));
+
+        let options = options.with_max_field_length(1);
+        let err =
+            decode_request::<PingRequest>(Some("application/json"), br#"{"name":"hi"}"#, &options)
+                .unwrap_err();
+        assert_eq!(err.code, TwirpErrorCode::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_streaming_json_decode() {
+        #[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, prost::Message)]
+        struct Names {
+            #[prost(string, repeated, tag = "1")]
+            names: Vec<String>,
+        }
+
+        /// A body chunk, counting the chunks held at once.
+        struct Chunk {
+            data: Vec<u8>,
+            held: Arc<(AtomicU64, AtomicU64)>,
+        }
+
+        impl AsRef<[u8]> for Chunk {
+            fn as_ref(&self) -> &[u8] {
+                &self.data
+            }
+        }
+
+        impl Drop for Chunk {
+            fn drop(&mut self) {
+                self.held.0.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Count",
+                |_: Arc<TestApiServer>, _: Context, req: Names| async move {
+                    Ok::<_, TwirpErrorResponse>(PingResponse {
+                        name: req.names.len().to_string(),
+                    })
+                },
+            )
+            .build();
+        let router = axum::Router::new().nest("/twirp/test.TestAPI", twirp_routes);
+        // `{"names":["hat 0","hat 1",...]}` sent a name at a time, returning the peak number of
+        // chunks held by the server and its response
+        let count = |mut router: axum::Router, json: Option<&'static str>| async move {
+            let held = Arc::new((AtomicU64::new(0), AtomicU64::new(0)));
+            let chunks = std::iter::once("{\"names\":[".to_string())
+                .chain((0..10_000).map(|i| match i {
+                    0 => format!("\"hat {i}\""),
+                    _ => format!(",\"hat {i}\""),
+                }))
+                .chain([json.unwrap_or("]}").to_string()])
+                .map({
+                    let held = held.clone();
+                    move |data| {
+                        let now = held.0.fetch_add(1, Ordering::SeqCst) + 1;
+                        held.1.fetch_max(now, Ordering::SeqCst);
+                        let chunk = Chunk {
+                            data: data.into_bytes(),
+                            held: held.clone(),
+                        };
+                        Ok::<_, std::convert::Infallible>(Bytes::from_owner(chunk))
+                    }
+                });
+            let req = Request::post("/twirp/test.TestAPI/Count")
+                .extension(timings())
+                .body(Body::from_stream(futures::stream::iter(chunks)))
+                .unwrap();
+            let resp = router.call(req).await.unwrap();
+            (held.1.load(Ordering::SeqCst), resp)
+        };
+
+        // Best-effort: the body is read in full before it is decoded,
+        let (peak, resp) = count(router.clone(), None).await;
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(&data.name, "10000");
+        assert!(peak > 10_000, "{peak}");
+
+        // unless it is decoded while it is received.
+        let streaming = router.clone().layer(axum::Extension(
+            ServerOptions::default().with_streaming_json_decode(true),
+        ));
+        let (peak, resp) = count(streaming.clone(), None).await;
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(&data.name, "10000");
+        assert!(peak < 16, "{peak}");
+
+        let (_, resp) = count(streaming.clone(), Some("]")).await;
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Malformed);
+        let (_, resp) = count(streaming.clone(), Some("]} {}")).await;
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Malformed);
+
+        let limited = router.layer(axum::Extension(
+            ServerOptions::default()
+                .with_streaming_json_decode(true)
+                .with_max_request_size(1024),
+        ));
+        let (_, resp) = count(limited, None).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.meta["reason"], "body_too_large");
+    }
+
+    #[tokio::test]
+    async fn test_required_format() {
+        let router = test_api_router().layer(axum::Extension(
+            ServerOptions::default().with_required_format(BodyFormat::Pb),
+        ));
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        let resp = roundtrip(&router, "/twirp/test.TestAPI/Ping", req).await;
+        assert!(resp.status().is_success(), "{:?}", resp);
+
+        let resp = router.clone().call(gen_ping_request("hi")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Malformed);
+        assert_eq!(err.meta["format"], "json");
+        assert_eq!(err.meta["required_format"], "protobuf");
+
+        let options = ServerOptions::default().with_required_format(BodyFormat::JsonPb);
+        let err =
+            decode_request::<PingRequest>(Some("application/protobuf"), &[], &options).unwrap_err();
+        assert_eq!(err.meta["format"], "protobuf");
+    }
+
+    #[tokio::test]
+    async fn test_strict_content_type() {
+        let router = test_api_router().layer(axum::Extension(
+            ServerOptions::default().with_strict_content_type(true),
+        ));
+        let gen_req = |content_type: Option<&'static str>| {
+            let mut req = gen_ping_request("hi");
+            if let Some(content_type) = content_type {
+                req.headers_mut()
+                    .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+            }
+            req
+        };
+
+        let resp = roundtrip(&router, "/twirp/test.TestAPI/Ping", PingRequest::default()).await;
+        assert!(resp.status().is_success(), "{:?}", resp);
+        for content_type in ["application/json", "application/json; charset=utf-8"] {
+            let resp = router
+                .clone()
+                .call(gen_req(Some(content_type)))
+                .await
+                .unwrap();
+            assert!(resp.status().is_success(), "{content_type}: {:?}", resp);
+        }
+
+        let resp = router
+            .clone()
+            .call(gen_req(Some("text/plain")))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::BadRoute);
+        assert_eq!(err.meta["content_type"], "text/plain");
+
+        let resp = router.clone().call(gen_req(None)).await.unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::BadRoute);
+        assert_eq!(err.msg, "missing content type");
+
+        // lenient by default
+        let resp = test_api_router()
+            .call(gen_req(Some("text/plain")))
+            .await
+            .unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+
+        let options = ServerOptions::default().with_strict_content_type(true);
+        let err = decode_request::<PingRequest>(Some("text/json"), b"{}", &options).unwrap_err();
+        assert_eq!(err.code, TwirpErrorCode::BadRoute);
+    }
+
+    #[tokio::test]
+    async fn test_request_stats() {
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                    let stats = ctx.get::<RequestStats>().expect("stats are enabled");
+                    assert_eq!(stats.fields, 1);
+                    api.ping(ctx, req).await
+                },
+            )
+            .build();
+        let mut router = axum::Router::new()
+            .nest("/twirp/test.TestAPI", twirp_routes)
+            .layer(axum::Extension(
+                ServerOptions::default().with_request_stats(true),
+            ));
+
+        let resp = router.call(gen_ping_request("hat")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        // the tag and length of `name`, and its 3 bytes
+        assert_eq!(
+            resp.extensions().get::<RequestStats>(),
+            Some(&RequestStats { size: 5, fields: 1 })
+        );
+
+        let resp = test_api_router()
+            .call(gen_ping_request("hat"))
+            .await
+            .unwrap();
+        assert!(resp.extensions().get::<RequestStats>().is_none());
+
+        // a string that is a well-formed message counts its fields too
+        let resp = test_api_router()
+            .layer(axum::Extension(
+                ServerOptions::default().with_request_stats(true),
+            ))
+            .call(gen_ping_request("\n\u{1}a"))
+            .await
+            .unwrap();
+        assert_eq!(resp.extensions().get::<RequestStats>().unwrap().fields, 2);
+    }
+
+    #[tokio::test]
+    async fn test_request_stats_check() {
+        let handled = Arc::new(AtomicU64::new(0));
+        let counter = handled.clone();
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                move |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    async move { api.ping(ctx, req).await }
+                },
+            )
+            .build();
+        let mut router = axum::Router::new()
+            .nest("/twirp/test.TestAPI", twirp_routes)
+            .layer(axum::Extension(
+                ServerOptions::default().with_request_stats_check(|stats| {
+                    if stats.size > 8 {
+                        return Err(crate::invalid_argument("request too big"));
+                    }
+                    Ok(())
+                }),
+            ));
+
+        let resp = router.call(gen_ping_request("hat")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert!(resp.extensions().get::<RequestStats>().is_some());
+
+        let resp = router.call(gen_ping_request("fedora!")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(resp.extensions().get::<RequestStats>().unwrap().size, 9);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.msg, "request too big");
+        // the handler didn't run
+        assert_eq!(handled.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_max_header_count() {
+        let mut router = test_api_router().layer(axum::Extension(
+            ServerOptions::default().with_max_header_count(4),
+        ));
+        let gen_req = |count: usize| {
+            let mut req = gen_ping_request("hi");
+            for i in 0..count {
+                req.headers_mut()
+                    .append(HeaderName::from_static("x-flood"), HeaderValue::from(i));
+            }
+            req
+        };
+
+        let resp = router.call(gen_req(4)).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+
+        // repeated values count separately
+        let resp = router.call(gen_req(5)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Malformed);
+        assert_eq!(err.meta["header_count"], "5");
+    }
+
+    #[tokio::test]
+    async fn test_max_field_length() {
+        let router = test_api_router().layer(axum::Extension(
+            ServerOptions::default().with_max_field_length(8),
+        ));
+
+        let resp = router
+            .clone()
+            .call(gen_ping_request("fedora"))
+            .await
+            .unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+
+        let resp = router
+            .clone()
+            .call(gen_ping_request("stovepipe"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::InvalidArgument);
+        assert_eq!(err.meta["field"], "name");
+        assert_eq!(err.meta["max_length"], "8");
+
+        let req = PingRequest {
+            name: "stovepipe".to_string(),
+        };
+        let resp = roundtrip(&router, "/twirp/test.TestAPI/Ping", req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.meta["field"], "2");
     }
 
     #[tokio::test]
@@ -343,6 +3692,282 @@ mod tests {
         assert_eq!(&data.name, "hello-abcd");
     }
 
+    #[tokio::test]
+    async fn test_body_format_in_middleware() {
+        async fn format_middleware(request: Request<Body>, next: Next) -> Response<Body> {
+            let format = BodyFormat::from_content_type(&request);
+            let mut resp = next.run(request).await;
+            let format = match format {
+                BodyFormat::Pb => "pb",
+                BodyFormat::JsonPb => "json",
+            };
+            resp.headers_mut()
+                .insert("x-format", format.parse().unwrap());
+            resp
+        }
+        let router = test_api_router().layer(middleware::from_fn(format_middleware));
+
+        let resp = router.clone().call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert_eq!(resp.headers()["x-format"], "json");
+
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        let resp = roundtrip(&router, "/twirp/test.TestAPI/Ping", req).await;
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert_eq!(resp.headers()["x-format"], "pb");
+    }
+
+    #[tokio::test]
+    async fn test_generated_request_id() {
+        let mut router = test_api_router().layer(axum::Extension(
+            ServerOptions::default()
+                .with_request_id_header(HeaderName::from_static("x-request-id")),
+        ));
+
+        // an id is generated when the request doesn't have one
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let id = resp.headers()["x-request-id"].to_str().unwrap().to_string();
+        assert!(uuid::Uuid::parse_str(&id).is_ok(), "{id}");
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, format!("hi-{id}"));
+
+        // and the request's own id is used when it has one
+        let mut req = gen_ping_request("hi");
+        req.headers_mut()
+            .insert("x-request-id", HeaderValue::from_static("abcd"));
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert_eq!(resp.headers()["x-request-id"], "abcd");
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(&data.name, "hi-abcd");
+    }
+
+    #[tokio::test]
+    async fn test_request_id_echo() {
+        let mut router = test_api_router().layer(axum::Extension(
+            ServerOptions::default()
+                .with_request_id_header(HeaderName::from_static("x-request-id"))
+                .with_request_id_echo(false),
+        ));
+
+        for id in [None, Some("abcd")] {
+            let mut req = gen_ping_request("hi");
+            if let Some(id) = id {
+                req.headers_mut()
+                    .insert("x-request-id", HeaderValue::from_static(id));
+            }
+            let resp = router.call(req).await.unwrap();
+            assert!(resp.status().is_success(), "{:?}", resp);
+            assert!(resp.headers().get("x-request-id").is_none());
+            // the handler still gets the id
+            let data: PingResponse = read_json_body(resp.into_body()).await;
+            assert!(data.name.starts_with("hi-"), "{}", data.name);
+            if let Some(id) = id {
+                assert_eq!(data.name, format!("hi-{id}"));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_id_generator() {
+        let n = Arc::new(AtomicU64::new(0));
+        let counter = n.clone();
+        let mut router = test_api_router().layer(axum::Extension(
+            ServerOptions::default()
+                .with_request_id_header(HeaderName::from_static("x-request-id"))
+                .with_request_id_generator(move || {
+                    format!("req-{}", counter.fetch_add(1, Ordering::Relaxed))
+                }),
+        ));
+
+        for expected in ["req-0", "req-1"] {
+            let resp = router.call(gen_ping_request("hi")).await.unwrap();
+            assert!(resp.status().is_success(), "{:?}", resp);
+            assert_eq!(resp.headers()["x-request-id"], expected);
+            let data: PingResponse = read_json_body(resp.into_body()).await;
+            assert_eq!(data.name, format!("hi-{expected}"));
+        }
+
+        // ids sent by the caller are still used as is
+        let mut req = gen_ping_request("hi");
+        req.headers_mut()
+            .insert("x-request-id", HeaderValue::from_static("abcd"));
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.headers()["x-request-id"], "abcd");
+        assert_eq!(n.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_access_log_includes_bad_routes() {
+        type Log = Arc<Mutex<Vec<(String, Option<String>, Option<TwirpErrorCode>)>>>;
+        let log = Log::default();
+        let access_log = {
+            let log = log.clone();
+            move |request: Request<Body>, next: Next| {
+                let log = log.clone();
+                async move {
+                    let path = request.uri().path().to_string();
+                    let resp = next.run(request).await;
+                    let method = resp
+                        .extensions()
+                        .get::<RpcMethod>()
+                        .map(|m| m.0.to_string());
+                    let code = resp.extensions().get::<TwirpErrorCode>().copied();
+                    log.lock().unwrap().push((path, method, code));
+                    resp
+                }
+            }
+        };
+        let router = test_api_router().layer(middleware::from_fn(access_log));
+
+        router.clone().call(gen_ping_request("hi")).await.unwrap();
+        let req = Request::get("/nothing")
+            .extension(timings())
+            .body(Body::empty())
+            .unwrap();
+        router.clone().call(req).await.unwrap();
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                (
+                    "/twirp/test.TestAPI/Ping".to_string(),
+                    Some("Ping".to_string()),
+                    None
+                ),
+                ("/nothing".to_string(), None, Some(TwirpErrorCode::BadRoute)),
+            ]
+        );
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_request_span() {
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata};
+
+        /// Records the fields of every span, and the span every event happens in.
+        #[derive(Default)]
+        struct Recorder {
+            spans: Mutex<Vec<Vec<(String, String)>>>,
+            stack: Mutex<Vec<u64>>,
+            events: Mutex<Vec<Option<u64>>>,
+        }
+
+        struct Fields<'a>(&'a mut Vec<(String, String)>);
+
+        impl Visit for Fields<'_> {
+            fn record_str(&mut self, field: &Field, value: &str) {
+                self.0.push((field.name().to_string(), value.to_string()));
+            }
+
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                self.0
+                    .push((field.name().to_string(), format!("{value:?}")));
+            }
+        }
+
+        impl tracing::Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut fields = vec![("name".to_string(), span.metadata().name().to_string())];
+                span.record(&mut Fields(&mut fields));
+                let mut spans = self.spans.lock().unwrap();
+                spans.push(fields);
+                Id::from_u64(spans.len() as u64)
+            }
+
+            fn record(&self, span: &Id, values: &Record<'_>) {
+                let mut spans = self.spans.lock().unwrap();
+                let fields = &mut spans[span.into_u64() as usize - 1];
+                values.record(&mut Fields(fields));
+            }
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, _: &Event<'_>) {
+                let current = self.stack.lock().unwrap().last().copied();
+                self.events.lock().unwrap().push(current);
+            }
+
+            fn enter(&self, span: &Id) {
+                self.stack.lock().unwrap().push(span.into_u64());
+            }
+
+            fn exit(&self, _: &Id) {
+                self.stack.lock().unwrap().pop();
+            }
+        }
+
+        let recorder = Arc::new(Recorder::default());
+        let _guard = tracing::subscriber::set_default(recorder.clone());
+
+        let twirp_routes = TwirpRouterBuilder::new(Arc::new(TestApiServer))
+            .route(
+                "/Ping",
+                |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                    // the recorder doesn't track the current span, but this is the first one
+                    assert_eq!(ctx.span().id(), Some(Id::from_u64(1)));
+                    tracing::info!("handling ping");
+                    api.ping(ctx, req).await
+                },
+            )
+            .route(
+                "/Boom",
+                |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                    api.boom(ctx, req).await
+                },
+            )
+            .build();
+        let mut router = axum::Router::new()
+            .nest("/twirp/test.TestAPI", twirp_routes)
+            .layer(axum::Extension(
+                ServerOptions::default()
+                    .with_request_id_header(HeaderName::from_static("x-request-id")),
+            ));
+        let mut req = gen_ping_request("hi");
+        req.headers_mut()
+            .insert("x-request-id", HeaderValue::from_static("abcd"));
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+
+        let req = Request::post("/twirp/test.TestAPI/Boom")
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let spans = recorder.spans.lock().unwrap();
+        let field_names = |span: &[(String, String)]| -> Vec<String> {
+            span.iter().map(|(name, _)| name.clone()).collect()
+        };
+        assert_eq!(
+            spans[0][..4],
+            [
+                ("name".to_string(), "twirp".to_string()),
+                ("method".to_string(), "Ping".to_string()),
+                ("path".to_string(), "/twirp/test.TestAPI/Ping".to_string()),
+                ("request_id".to_string(), "abcd".to_string()),
+            ]
+        );
+        assert_eq!(
+            field_names(&spans[0][4..]),
+            ["code", "received", "parsed", "handled", "written", "total"]
+        );
+        assert_eq!(spans[0][4].1, "ok");
+        assert_eq!(spans[1][1], ("method".to_string(), "Boom".to_string()));
+        assert_eq!(spans[1][4], ("code".to_string(), "internal".to_string()));
+        // the handler's event happened in the request span, and so did the error's
+        assert_eq!(*recorder.events.lock().unwrap(), vec![Some(1), Some(2)]);
+    }
+
     async fn request_id_middleware(
         mut request: http::Request<Body>,
         next: Next,
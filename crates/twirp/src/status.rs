@@ -0,0 +1,403 @@
+//! Error details in the [`google.rpc.Status`] format, for services following the Google API
+//! error conventions.
+//!
+//! The details are attached to a Twirp error as a `google.rpc.Status` message, protobuf encoded
+//! and then standard base64 encoded into the `grpc_details` meta key. This is the same key (and
+//! encoding) that the conversion from `tonic::Status` uses for the status details of gRPC errors,
+//! so either end can be a gRPC service.
+//!
+//! ```
+//! use std::time::Duration;
+//! use twirp::status::{self, ErrorInfo, RetryInfo};
+//!
+//! let mut err = twirp::unavailable("the hat shop is closed");
+//! status::set_details(
+//!     &mut err,
+//!     &[
+//!         status::pack(&ErrorInfo::new("SHOP_CLOSED", "hats.example.com")),
+//!         status::pack(&RetryInfo::after(Duration::from_secs(60))),
+//!     ],
+//! );
+//!
+//! // and on the client
+//! let details = status::details(&err).unwrap().unwrap();
+//! let retry: RetryInfo = details.details[1].unpack().unwrap();
+//! assert_eq!(retry.delay(), Some(Duration::from_secs(60)));
+//! ```
+//!
+//! Or add details one at a time with [`TwirpErrorResponse::with_detail`], and read them back by
+//! type with [`TwirpErrorResponse::detail`]:
+//!
+//! ```
+//! use twirp::status::BadRequest;
+//!
+//! let mut err = twirp::invalid_argument("invalid hat");
+//! err.with_detail(BadRequest::new("inches", "must be positive"));
+//!
+//! // and on the client
+//! let bad_request: BadRequest = err.detail().unwrap();
+//! assert_eq!(bad_request.field_violations[0].field, "inches");
+//! ```
+//!
+//! [`google.rpc.Status`]: https://github.com/googleapis/googleapis/blob/master/google/rpc/status.proto
+
+use std::collections::HashMap;
+
+use base64::Engine;
+use prost::Message;
+
+use crate::{TwirpErrorCode, TwirpErrorResponse};
+
+/// The meta key holding the encoded [`Status`].
+pub const DETAILS_META_KEY: &str = "grpc_details";
+
+/// `google.rpc.Status`: an error code and message, with any number of detail messages.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Status {
+    /// The `google.rpc.Code` of the error.
+    #[prost(int32, tag = "1")]
+    pub code: i32,
+    #[prost(string, tag = "2")]
+    pub message: String,
+    #[prost(message, repeated, tag = "3")]
+    pub details: Vec<Any>,
+}
+
+/// `google.protobuf.Any`: a message of any type, identified by its type url.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Any {
+    #[prost(string, tag = "1")]
+    pub type_url: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub value: Vec<u8>,
+}
+
+impl Any {
+    /// Decode the message, if it is a `T`.
+    pub fn unpack<T: Detail>(&self) -> Option<T> {
+        if self.type_url != T::TYPE_URL {
+            return None;
+        }
+        T::decode(self.value.as_slice()).ok()
+    }
+}
+
+/// A detail message of a [`Status`], with the type url it is packed into an [`Any`] with.
+pub trait Detail: prost::Message + Default {
+    const TYPE_URL: &'static str;
+}
+
+/// Pack a detail message for [`set_details`].
+pub fn pack<T: Detail>(detail: &T) -> Any {
+    Any {
+        type_url: T::TYPE_URL.to_string(),
+        value: detail.encode_to_vec(),
+    }
+}
+
+/// `google.rpc.ErrorInfo`: the reason for the error, as a constant in the domain of the service
+/// that raised it, with structured metadata about it.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ErrorInfo {
+    #[prost(string, tag = "1")]
+    pub reason: String,
+    #[prost(string, tag = "2")]
+    pub domain: String,
+    #[prost(map = "string, string", tag = "3")]
+    pub metadata: HashMap<String, String>,
+}
+
+impl ErrorInfo {
+    pub fn new(reason: impl Into<String>, domain: impl Into<String>) -> Self {
+        Self {
+            reason: reason.into(),
+            domain: domain.into(),
+            metadata: HashMap::new(),
+        }
+    }
+
+    /// Add an entry to the metadata.
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+}
+
+impl Detail for ErrorInfo {
+    const TYPE_URL: &'static str = "type.googleapis.com/google.rpc.ErrorInfo";
+}
+
+/// `google.rpc.RetryInfo`: how long the client should wait before retrying.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RetryInfo {
+    #[prost(message, optional, tag = "1")]
+    pub retry_delay: Option<ProtoDuration>,
+}
+
+impl RetryInfo {
+    /// Retry after `delay`.
+    pub fn after(delay: std::time::Duration) -> Self {
+        Self {
+            retry_delay: Some(ProtoDuration {
+                seconds: i64::try_from(delay.as_secs()).unwrap_or(i64::MAX),
+                nanos: delay.subsec_nanos() as i32,
+            }),
+        }
+    }
+
+    /// The delay before retrying, if it is set and not negative.
+    pub fn delay(&self) -> Option<std::time::Duration> {
+        let delay = self.retry_delay.as_ref()?;
+        let seconds = u64::try_from(delay.seconds).ok()?;
+        let nanos = u32::try_from(delay.nanos).ok()?;
+        Some(std::time::Duration::new(seconds, nanos))
+    }
+}
+
+impl Detail for RetryInfo {
+    const TYPE_URL: &'static str = "type.googleapis.com/google.rpc.RetryInfo";
+}
+
+/// `google.protobuf.Duration`.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ProtoDuration {
+    #[prost(int64, tag = "1")]
+    pub seconds: i64,
+    #[prost(int32, tag = "2")]
+    pub nanos: i32,
+}
+
+/// `google.rpc.QuotaFailure`: the quotas that were exceeded.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct QuotaFailure {
+    #[prost(message, repeated, tag = "1")]
+    pub violations: Vec<QuotaViolation>,
+}
+
+impl QuotaFailure {
+    /// A failure for a single exceeded quota.
+    pub fn new(subject: impl Into<String>, description: impl Into<String>) -> Self {
+        Self {
+            violations: vec![QuotaViolation {
+                subject: subject.into(),
+                description: description.into(),
+            }],
+        }
+    }
+
+    /// Add another exceeded quota.
+    pub fn with_violation(
+        mut self,
+        subject: impl Into<String>,
+        description: impl Into<String>,
+    ) -> Self {
+        self.violations.push(QuotaViolation {
+            subject: subject.into(),
+            description: description.into(),
+        });
+        self
+    }
+}
+
+impl Detail for QuotaFailure {
+    const TYPE_URL: &'static str = "type.googleapis.com/google.rpc.QuotaFailure";
+}
+
+/// `google.rpc.QuotaFailure.Violation`: the subject whose quota was exceeded (e.g.
+/// `clientip:1.2.3.4` or `project:hats`), and which quota.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct QuotaViolation {
+    #[prost(string, tag = "1")]
+    pub subject: String,
+    #[prost(string, tag = "2")]
+    pub description: String,
+}
+
+/// `google.rpc.BadRequest`: the fields of the request that are invalid, and why.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BadRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub field_violations: Vec<FieldViolation>,
+}
+
+impl BadRequest {
+    /// A bad request with a single invalid field.
+    pub fn new(field: impl Into<String>, description: impl Into<String>) -> Self {
+        Self {
+            field_violations: vec![FieldViolation {
+                field: field.into(),
+                description: description.into(),
+            }],
+        }
+    }
+
+    /// Add another invalid field.
+    pub fn with_violation(
+        mut self,
+        field: impl Into<String>,
+        description: impl Into<String>,
+    ) -> Self {
+        self.field_violations.push(FieldViolation {
+            field: field.into(),
+            description: description.into(),
+        });
+        self
+    }
+}
+
+impl Detail for BadRequest {
+    const TYPE_URL: &'static str = "type.googleapis.com/google.rpc.BadRequest";
+}
+
+/// `google.rpc.BadRequest.FieldViolation`: the path of an invalid field (e.g. `hat.inches`), and
+/// why it is invalid.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FieldViolation {
+    #[prost(string, tag = "1")]
+    pub field: String,
+    #[prost(string, tag = "2")]
+    pub description: String,
+}
+
+impl TwirpErrorResponse {
+    /// Add `detail` to the [`Status`] attached to the error, after any details attached before.
+    /// Details that can't be decoded are replaced.
+    pub fn with_detail<M: Detail>(&mut self, detail: M) -> &mut Self {
+        let mut all = match details(self) {
+            Some(Ok(status)) => status.details,
+            _ => vec![],
+        };
+        all.push(pack(&detail));
+        set_details(self, &all);
+        self
+    }
+
+    /// The first detail of type `M` of the [`Status`] attached to the error, if any.
+    pub fn detail<M: Detail>(&self) -> Option<M> {
+        let status = details(self)?.ok()?;
+        status.details.iter().find_map(Any::unpack)
+    }
+}
+
+/// Attach `details` to `err` as a [`Status`] with the error's code and message, replacing any
+/// details attached before.
+pub fn set_details(err: &mut TwirpErrorResponse, details: &[Any]) {
+    let status = Status {
+        code: grpc_code(err.code),
+        message: err.msg.clone(),
+        details: details.to_vec(),
+    };
+    let encoded = base64::engine::general_purpose::STANDARD.encode(status.encode_to_vec());
+    err.insert_meta(DETAILS_META_KEY.to_string(), encoded);
+}
+
+/// The [`Status`] attached to `err`, if any, or an error if it can't be decoded.
+pub fn details(err: &TwirpErrorResponse) -> Option<Result<Status, crate::GenericError>> {
+    let encoded = err.meta.get(DETAILS_META_KEY)?;
+    let decode = || -> Result<Status, crate::GenericError> {
+        let bytes = base64::engine::general_purpose::STANDARD.decode(encoded)?;
+        Ok(Status::decode(bytes.as_slice())?)
+    };
+    Some(decode())
+}
+
+/// The `google.rpc.Code` with the same meaning as a Twirp error code.
+fn grpc_code(code: TwirpErrorCode) -> i32 {
+    match code {
+        TwirpErrorCode::Canceled => 1,
+        TwirpErrorCode::Unknown => 2,
+        TwirpErrorCode::InvalidArgument | TwirpErrorCode::Malformed => 3,
+        TwirpErrorCode::DeadlineExceeded => 4,
+        TwirpErrorCode::NotFound | TwirpErrorCode::BadRoute => 5,
+        TwirpErrorCode::AlreadyExists => 6,
+        TwirpErrorCode::PermissionDenied => 7,
+        TwirpErrorCode::ResourceExhausted => 8,
+        TwirpErrorCode::FailedPrecondition => 9,
+        TwirpErrorCode::Aborted => 10,
+        TwirpErrorCode::OutOfRange => 11,
+        TwirpErrorCode::Unimplemented => 12,
+        TwirpErrorCode::Internal => 13,
+        TwirpErrorCode::Unavailable => 14,
+        TwirpErrorCode::Dataloss => 15,
+        TwirpErrorCode::Unauthenticated => 16,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Attach `detail` to an error, send the error through its JSON body, and read it back.
+    fn round_trip<T: Detail + PartialEq>(detail: &T) -> Status {
+        let mut err = crate::resource_exhausted("slow down");
+        set_details(&mut err, &[pack(detail)]);
+        let json = serde_json::to_string(&err).unwrap();
+        let received: TwirpErrorResponse = serde_json::from_str(&json).unwrap();
+        let status = details(&received).unwrap().unwrap();
+        assert_eq!(status.code, 8);
+        assert_eq!(status.message, "slow down");
+        assert_eq!(status.details.len(), 1);
+        assert_eq!(status.details[0].unpack::<T>().as_ref(), Some(detail));
+        status
+    }
+
+    #[test]
+    fn test_error_info() {
+        let info = ErrorInfo::new("HAT_QUOTA", "hats.example.com").with_metadata("size", "12");
+        let status = round_trip(&info);
+        assert_eq!(
+            status.details[0].type_url,
+            "type.googleapis.com/google.rpc.ErrorInfo"
+        );
+        assert_eq!(status.details[0].unpack::<RetryInfo>(), None);
+    }
+
+    #[test]
+    fn test_retry_info() {
+        let retry = RetryInfo::after(Duration::from_millis(1500));
+        let status = round_trip(&retry);
+        let delay = status.details[0].unpack::<RetryInfo>().unwrap().delay();
+        assert_eq!(delay, Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn test_quota_failure() {
+        let quota = QuotaFailure::new("project:hats", "daily hat limit")
+            .with_violation("user:alice", "hourly hat limit");
+        round_trip(&quota);
+    }
+
+    #[test]
+    fn test_bad_request() {
+        let bad_request = BadRequest::new("hat.inches", "must be positive")
+            .with_violation("hat.color", "must not be empty");
+        round_trip(&bad_request);
+    }
+
+    #[test]
+    fn test_with_detail() {
+        let mut err = crate::invalid_argument("invalid hat");
+        assert_eq!(err.detail::<BadRequest>(), None);
+        err.with_detail(BadRequest::new("inches", "must be positive"))
+            .with_detail(ErrorInfo::new("INVALID_HAT", "hats.example.com"));
+
+        let json = serde_json::to_string(&err).unwrap();
+        let received: TwirpErrorResponse = serde_json::from_str(&json).unwrap();
+        let bad_request: BadRequest = received.detail().unwrap();
+        assert_eq!(bad_request.field_violations[0].field, "inches");
+        let info: ErrorInfo = received.detail().unwrap();
+        assert_eq!(info.reason, "INVALID_HAT");
+        assert_eq!(received.detail::<RetryInfo>(), None);
+        assert_eq!(details(&received).unwrap().unwrap().code, 3);
+    }
+
+    #[test]
+    fn test_missing_and_invalid_details() {
+        let mut err = crate::internal("boom");
+        assert!(details(&err).is_none());
+        err.insert_meta(DETAILS_META_KEY.to_string(), "not base64!".to_string());
+        assert!(details(&err).unwrap().is_err());
+    }
+}
@@ -6,14 +6,16 @@ use async_trait::async_trait;
 use axum::body::Body;
 use axum::Router;
 use http_body_util::BodyExt;
-use hyper::Request;
+use hyper::{header, Request, Response};
 use serde::de::DeserializeOwned;
 use tokio::task::JoinHandle;
 use tokio::time::Instant;
+use tower::Service;
 
 use crate::details::TwirpRouterBuilder;
+use crate::headers::CONTENT_TYPE_PROTOBUF;
 use crate::server::Timings;
-use crate::{error, Client, Context, Result, TwirpErrorResponse};
+use crate::{error, serialize_proto_message, Client, Context, Result, TwirpErrorResponse};
 
 pub async fn run_test_server(port: u16) -> JoinHandle<Result<(), std::io::Error>> {
     let router = test_api_router();
@@ -62,6 +64,40 @@ pub fn gen_ping_request(name: &str) -> Request<Body> {
         .expect("always a valid twirp request")
 }
 
+/// Send a protobuf encoded `request` to the rpc at `path` (e.g. `/twirp/test.TestAPI/Ping`) on
+/// `router`, entirely in memory, and return the response.
+///
+/// No network is involved, so this is handy for benchmarking serialization and routing in your
+/// handlers. The response carries [`Timings`] in its extensions. For example, with criterion:
+///
+/// ```ignore
+/// fn bench_ping(c: &mut criterion::Criterion) {
+///     let rt = tokio::runtime::Runtime::new().unwrap();
+///     let router = twirp::test::test_api_router();
+///     c.bench_function("ping", |b| {
+///         b.to_async(&rt).iter(|| {
+///             let req = PingRequest { name: "hi".to_string() };
+///             twirp::test::roundtrip(&router, "/twirp/test.TestAPI/Ping", req)
+///         })
+///     });
+/// }
+/// ```
+pub async fn roundtrip<T>(router: &Router, path: &str, request: T) -> Response<Body>
+where
+    T: prost::Message,
+{
+    let req = Request::post(path)
+        .header(header::CONTENT_TYPE, CONTENT_TYPE_PROTOBUF)
+        .extension(Timings::new(Instant::now()))
+        .body(Body::from(serialize_proto_message(request)))
+        .expect("always a valid twirp request");
+    router
+        .clone()
+        .call(req)
+        .await
+        .unwrap_or_else(|err| match err {})
+}
+
 pub async fn read_string_body(body: Body) -> String {
     let data = Vec::<u8>::from(body.collect().await.expect("invalid body").to_bytes());
     String::from_utf8(data).expect("non-utf8 body")
@@ -90,7 +126,7 @@ impl TestApi for TestApiServer {
         ctx: Context,
         req: PingRequest,
     ) -> Result<PingResponse, TwirpErrorResponse> {
-        if let Some(RequestId(rid)) = ctx.get::<RequestId>() {
+        if let Some(rid) = ctx.request_id() {
             Ok(PingResponse {
                 name: format!("{}-{}", req.name, rid),
             })
@@ -108,8 +144,7 @@ impl TestApi for TestApiServer {
     }
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug, Default)]
-pub struct RequestId(pub String);
+pub use crate::context::RequestId;
 
 // Small test twirp services (this would usually be generated with twirp-build)
 #[async_trait]
@@ -124,8 +159,8 @@ impl TestApiClient for Client {
         self.request("test.TestAPI/Ping", req).await
     }
 
-    async fn boom(&self, _req: PingRequest) -> Result<PingResponse> {
-        todo!()
+    async fn boom(&self, req: PingRequest) -> Result<PingResponse> {
+        self.request("test.TestAPI/Boom", req).await
     }
 }
 
@@ -2,6 +2,7 @@ use std::env;
 use std::path::PathBuf;
 
 use prost_wkt_build::*;
+use twirp_build::OptionType;
 
 fn main() {
     let out = PathBuf::from(env::var("OUT_DIR").expect("failed to load OUT_DIR from environment"));
@@ -13,8 +14,20 @@ fn main() {
         println!("cargo:rerun-if-changed={}", entry.display());
     }
 
+    let service_generator = twirp_build::service_generator()
+        .with_descriptor_set_path(&descriptor_file)
+        .with_method_option("required_role", 50001, OptionType::String)
+        .with_method_option("cache_ttl_seconds", 50002, OptionType::Int)
+        .with_method_handlers(true)
+        .with_schema_hash(true)
+        .with_arc_client(true)
+        .with_client_result_alias(true)
+        .with_mock_server(true)
+        .with_in_process_client(true)
+        .with_service_descriptor(true);
+
     prost_build
-        .service_generator(twirp_build::service_generator())
+        .service_generator(service_generator)
         .type_attribute(".", "#[derive(serde::Serialize,serde::Deserialize)]")
         .extern_path(".google.protobuf.Timestamp", "::prost_wkt_types::Timestamp")
         .file_descriptor_set_path(&descriptor_file)
@@ -8,6 +8,7 @@ use twirp::axum::body::Body;
 use twirp::axum::http;
 use twirp::axum::middleware::{self, Next};
 use twirp::axum::routing::get;
+use twirp::context::{RequestContext, RequestId};
 use twirp::{invalid_argument, Context, IntoTwirpResponse, Router, TwirpErrorResponse};
 
 pub mod service {
@@ -76,8 +77,9 @@ impl haberdash::HaberdasherApi for HaberdasherApiServer {
             return Err(HatError::InvalidSize);
         }
 
-        if let Some(id) = ctx.get::<RequestId>() {
-            println!("{id:?}");
+        let req_ctx = RequestContext::<()>::new(&ctx);
+        if let Some(id) = req_ctx.request_id() {
+            println!("{id} {:?}", req_ctx.method());
         };
 
         println!("got {req:?}");
@@ -102,9 +104,6 @@ impl haberdash::HaberdasherApi for HaberdasherApiServer {
 struct ResponseInfo(u16);
 
 /// Demonstrate pulling the request id out of an http header and sharing it with the rpc handlers.
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug, Default)]
-struct RequestId(String);
-
 async fn request_id_middleware(
     mut request: http::Request<Body>,
     next: Next,
@@ -106,6 +106,133 @@ mod test {
         assert_eq!(err.code, TwirpErrorCode::InvalidArgument);
     }
 
+    #[tokio::test]
+    async fn mock_server() {
+        let mock = haberdash::MockHaberdasherApi::default().expect_make_hat(|_, req| {
+            Ok(MakeHatResponse {
+                size: req.inches * 2,
+                ..Default::default()
+            })
+        });
+        let res = mock
+            .make_hat(Context::default(), MakeHatRequest { inches: 3 })
+            .await;
+        assert_eq!(res.unwrap().size, 6);
+
+        let res = haberdash::MockHaberdasherApi::default()
+            .make_hat(Context::default(), MakeHatRequest { inches: 3 })
+            .await;
+        assert_eq!(res.unwrap_err().code, TwirpErrorCode::Unimplemented);
+    }
+
+    #[tokio::test]
+    async fn in_process_client() {
+        let client = haberdash::InProcessHaberdasherApiClient::new(std::sync::Arc::new(
+            HaberdasherApiServer,
+        ));
+        let resp = client.make_hat(MakeHatRequest { inches: 4 }).await;
+        assert_eq!(resp.unwrap().size, 4);
+
+        let resp = client.make_hat(MakeHatRequest { inches: 0 }).await;
+        match resp.unwrap_err() {
+            twirp::ClientError::TwirpError { error, .. } => {
+                assert_eq!(error.code, TwirpErrorCode::InvalidArgument)
+            }
+            err => panic!("unexpected error: {err:?}"),
+        }
+    }
+
+    #[test]
+    fn method_options() {
+        assert_eq!(
+            haberdash::HABERDASHER_API_METHOD_OPTIONS,
+            &[
+                ("MakeHat", "required_role", "hatter"),
+                ("MakeHat", "cache_ttl_seconds", "60"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn method_handler_with_layer() {
+        use prost::Message;
+        use twirp::axum::body::{to_bytes, Body};
+        use twirp::axum::http::{HeaderValue, Request, Response};
+        use twirp::axum::middleware::map_response;
+        use twirp::tower::ServiceExt;
+
+        let app = Router::new().route(
+            &format!("{}/MakeHat", haberdash::SERVICE_FQN),
+            haberdash::make_hat_handler(HaberdasherApiServer).layer(map_response(
+                |mut resp: Response<Body>| async move {
+                    resp.headers_mut()
+                        .insert("x-route", HeaderValue::from_static("make-hat"));
+                    resp
+                },
+            )),
+        );
+        let req = Request::post(format!("{}/MakeHat", haberdash::SERVICE_FQN))
+            .header("content-type", "application/protobuf")
+            .body(Body::from(MakeHatRequest { inches: 3 }.encode_to_vec()))
+            .unwrap();
+
+        let resp = app.oneshot(req).await.unwrap();
+        assert!(resp.status().is_success(), "{resp:?}");
+        assert_eq!(resp.headers()["x-route"], "make-hat");
+        let body = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(MakeHatResponse::decode(body).unwrap().size, 3);
+    }
+
+    #[test]
+    fn service_descriptor() {
+        let descriptor = haberdash::SERVICE_DESCRIPTOR;
+        assert_eq!(descriptor.full_name, "service.haberdash.v1.HaberdasherAPI");
+        assert_eq!(format!("/{}", descriptor.full_name), haberdash::SERVICE_FQN);
+        let make_hat = descriptor.method("MakeHat").unwrap();
+        assert_eq!(
+            make_hat.path,
+            "/service.haberdash.v1.HaberdasherAPI/MakeHat"
+        );
+        assert_eq!(make_hat.input_type, ".service.haberdash.v1.MakeHatRequest");
+        assert_eq!(
+            make_hat.output_type,
+            ".service.haberdash.v1.MakeHatResponse"
+        );
+    }
+
+    #[test]
+    fn service_fqn() {
+        let fqn = "service.haberdash.v1.HaberdasherAPI";
+        assert_eq!(<HaberdasherApiServer as HaberdasherApi>::service_fqn(), fqn);
+        assert_eq!(<Client as HaberdasherApiClient>::service_fqn(), fqn);
+        assert_eq!(haberdash::SERVICE_DESCRIPTOR.full_name, fqn);
+        assert_eq!(
+            haberdash::haberdasher_api_paths::MAKE_HAT,
+            "service.haberdash.v1.HaberdasherAPI/MakeHat"
+        );
+    }
+
+    #[test]
+    fn method_enum() {
+        use haberdash::HaberdasherApiMethod;
+
+        assert_eq!(HaberdasherApiMethod::ALL, &[HaberdasherApiMethod::MakeHat]);
+        for method in HaberdasherApiMethod::ALL {
+            assert_eq!(
+                HaberdasherApiMethod::from_path(method.path()),
+                Some(*method)
+            );
+        }
+        assert_eq!(
+            HaberdasherApiMethod::from_path("/service.haberdash.v1.HaberdasherAPI/MakeHat"),
+            Some(HaberdasherApiMethod::MakeHat)
+        );
+        assert_eq!(
+            HaberdasherApiMethod::from_path("service.haberdash.v1.HaberdasherAPI/MakeCap"),
+            None
+        );
+    }
+
     /// A running network server task, bound to an arbitrary port on localhost, chosen by the OS
     struct NetServer {
         port: u16,
@@ -168,4 +295,20 @@ mod test {
 
         server.shutdown().await;
     }
+
+    #[tokio::test]
+    async fn test_arc_client() {
+        let server = NetServer::start(HaberdasherApiServer {}).await;
+
+        let url = Url::parse(&format!("http://localhost:{}/twirp/", server.port)).unwrap();
+        let client = std::sync::Arc::new(Client::from_base_url(url).unwrap());
+        let resp = client.make_hat(MakeHatRequest { inches: 2 }).await;
+        assert_eq!(resp.unwrap().size, 2);
+
+        let shared: std::sync::Arc<dyn HaberdasherApiClient> = client;
+        let resp = shared.make_hat(MakeHatRequest { inches: 3 }).await;
+        assert_eq!(resp.unwrap().size, 3);
+
+        server.shutdown().await;
+    }
 }
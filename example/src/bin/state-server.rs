@@ -0,0 +1,113 @@
+//! This example shares application state, held in the service type, between the twirp service and
+//! plain axum routes that access it through `State`.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use twirp::async_trait::async_trait;
+use twirp::axum::extract::State;
+use twirp::axum::routing::get;
+use twirp::{invalid_argument, Context, Router, TwirpErrorResponse};
+
+pub mod service {
+    pub mod haberdash {
+        pub mod v1 {
+            include!(concat!(env!("OUT_DIR"), "/service.haberdash.v1.rs"));
+        }
+    }
+}
+use service::haberdash::v1::{self as haberdash, MakeHatRequest, MakeHatResponse};
+
+/// A stand-in for a database connection pool (e.g. `sqlx::PgPool`), which is cheap to clone and
+/// shares its connections between clones.
+#[derive(Clone, Default)]
+struct DbPool(Arc<Mutex<Vec<MakeHatResponse>>>);
+
+impl DbPool {
+    fn insert_hat(&self, hat: MakeHatResponse) {
+        self.0.lock().expect("mutex poisoned").push(hat);
+    }
+
+    fn count_hats(&self) -> usize {
+        self.0.lock().expect("mutex poisoned").len()
+    }
+}
+
+/// The application state. It implements the twirp service, and is also the axum `State` of the
+/// other routes, so both see the same database pool.
+#[derive(Clone, Default)]
+struct AppState {
+    db: DbPool,
+}
+
+#[async_trait]
+impl haberdash::HaberdasherApi for AppState {
+    type Error = TwirpErrorResponse;
+
+    async fn make_hat(
+        &self,
+        _ctx: Context,
+        req: MakeHatRequest,
+    ) -> Result<MakeHatResponse, TwirpErrorResponse> {
+        if req.inches == 0 {
+            return Err(invalid_argument("inches"));
+        }
+
+        let hat = MakeHatResponse {
+            color: "black".to_string(),
+            name: "top hat".to_string(),
+            size: req.inches,
+            timestamp: None,
+        };
+        self.db.insert_hat(hat.clone());
+        Ok(hat)
+    }
+}
+
+async fn hat_count(State(state): State<AppState>) -> String {
+    format!("{}\n", state.db.count_hats())
+}
+
+fn app(state: AppState) -> Router {
+    // `router` holds its own clone of the state, so it can be nested into a router whose other
+    // routes get the state from `with_state`.
+    let twirp_routes = Router::new().nest(haberdash::SERVICE_FQN, haberdash::router(state.clone()));
+    Router::new()
+        .route("/hats/count", get(hat_count))
+        .with_state(state)
+        .nest("/twirp", twirp_routes)
+        .fallback(twirp::server::not_found_handler)
+}
+
+#[tokio::main]
+pub async fn main() {
+    let app = app(AppState::default());
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+    let tcp_listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .expect("failed to bind");
+    println!("Listening on {addr}");
+    if let Err(e) = twirp::axum::serve(tcp_listener, app).await {
+        eprintln!("server error: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::service::haberdash::v1::HaberdasherApi;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn shared_state() {
+        let state = AppState::default();
+        let _app = app(state.clone());
+
+        let res = state
+            .make_hat(Context::default(), MakeHatRequest { inches: 1 })
+            .await;
+        assert!(res.is_ok());
+        assert_eq!(hat_count(State(state)).await, "1\n");
+    }
+}